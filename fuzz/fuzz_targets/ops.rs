@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use object_store::testing::{run_ops, Op};
+
+fuzz_target!(|data: &[u8]| {
+    run_ops(&Op::decode(data));
+});
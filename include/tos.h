@@ -0,0 +1,41 @@
+/* C bindings for the twizzler object store. Build the crate with the
+ * `ffi` feature and link against the resulting library. */
+#ifndef TOS_H
+#define TOS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define TOS_OK 0
+#define TOS_ERR_INVALID -1
+#define TOS_ERR_NOT_FOUND -2
+#define TOS_ERR_EXISTS -3
+#define TOS_ERR_BUSY -4
+#define TOS_ERR_INTEGRITY -5
+#define TOS_ERR_MEDIA -6
+#define TOS_ERR_IO -7
+#define TOS_ERR_PANIC -8
+
+/* Format the disk if it doesn't hold a valid store. */
+#define TOS_OPEN_CREATE (1u << 0)
+/* Open the disk even if another process has it mounted. Can't be
+ * combined with TOS_OPEN_CREATE: tos_open returns TOS_ERR_INVALID if
+ * both are set. */
+#define TOS_OPEN_TAKEOVER (1u << 1)
+
+typedef struct TosStore TosStore;
+
+typedef struct TosObjId {
+    uint64_t hi;
+    uint64_t lo;
+} TosObjId;
+
+int tos_open(const char *path, const uint8_t root_key[32], uint32_t flags, TosStore **out);
+int tos_close(TosStore *store);
+int tos_create(const TosStore *store, TosObjId obj_id);
+int tos_read(const TosStore *store, TosObjId obj_id, uint8_t *buf, size_t len, uint64_t off);
+int tos_write(const TosStore *store, TosObjId obj_id, const uint8_t *buf, size_t len, uint64_t off);
+int tos_unlink(const TosStore *store, TosObjId obj_id);
+int tos_epoch(const TosStore *store);
+
+#endif
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long it takes for an access count to decay to half its value.
+pub const DEFAULT_HALF_LIFE: Duration = Duration::from_secs(300);
+
+/// Access counters for a single object. The counts are exponentially
+/// decayed so recent accesses weigh more than old ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ObjectAccess {
+    pub reads: f64,
+    pub writes: f64,
+    pub bytes: f64,
+}
+
+impl ObjectAccess {
+    /// A single number used to rank objects by how hot they are.
+    pub fn heat(&self) -> f64 {
+        self.reads + self.writes
+    }
+
+    fn decay(&mut self, factor: f64) {
+        self.reads *= factor;
+        self.writes *= factor;
+        self.bytes *= factor;
+    }
+}
+
+struct Entry {
+    access: ObjectAccess,
+    last_update: Instant,
+}
+
+pub(crate) struct AccessTracker {
+    entries: Mutex<HashMap<u128, Entry>>,
+    half_life: Duration,
+}
+
+impl AccessTracker {
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            half_life,
+        }
+    }
+
+    fn decay_factor(&self, elapsed: Duration) -> f64 {
+        0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64())
+    }
+
+    fn record(&self, obj_id: u128, f: impl FnOnce(&mut ObjectAccess)) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(obj_id).or_insert(Entry {
+            access: ObjectAccess::default(),
+            last_update: now,
+        });
+        let factor = self.decay_factor(now - entry.last_update);
+        entry.access.decay(factor);
+        entry.last_update = now;
+        f(&mut entry.access);
+    }
+
+    pub fn record_read(&self, obj_id: u128, bytes: usize) {
+        self.record(obj_id, |access| {
+            access.reads += 1.0;
+            access.bytes += bytes as f64;
+        });
+    }
+
+    pub fn record_write(&self, obj_id: u128, bytes: usize) {
+        self.record(obj_id, |access| {
+            access.writes += 1.0;
+            access.bytes += bytes as f64;
+        });
+    }
+
+    pub fn forget(&self, obj_id: u128) {
+        self.entries.lock().unwrap().remove(&obj_id);
+    }
+
+    /// Returns up to `top_n` objects ordered from hottest to coldest.
+    pub fn hottest(&self, top_n: usize) -> Vec<(u128, ObjectAccess)> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let mut out: Vec<(u128, ObjectAccess)> = entries
+            .iter()
+            .map(|(id, entry)| {
+                let mut access = entry.access;
+                access.decay(self.decay_factor(now - entry.last_update));
+                (*id, access)
+            })
+            .collect();
+        out.sort_by(|a, b| b.1.heat().total_cmp(&a.1.heat()));
+        out.truncate(top_n);
+        out
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{
+    fs::{Disk, FileSystem, FsConfig},
+    header::RawHeader,
+    layout::Layout,
+    meta::{derive_subkey, read_meta, write_meta},
+    object_store::{lock_poisoned, OpenChecks, META_KEY_LABEL},
+    superblock::Superblock,
+    FormatOptions, ObjectStore, ObjectStoreError,
+};
+use chacha20::cipher::StreamCipher;
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+const ADOPT_PATH: &str = "meta/adopt";
+/// Chunks encrypted between two persists of the KHF.
+const ADOPT_BATCH: usize = 4096;
+
+/// How far `adopt` has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AdoptProgress {
+    /// Objects whose data is all encrypted.
+    pub objects_done: u64,
+    pub objects_total: u64,
+    /// Chunks encrypted by this call, not counting those encrypted
+    /// before it was resumed.
+    pub chunks_encrypted: u64,
+}
+
+/// Where an adoption is, so that it can be resumed after a crash.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AdoptJournal {
+    /// The first object and chunk not yet encrypted.
+    next: (u128, u64),
+    /// Chunks being encrypted, with a hash of their plaintext. A chunk
+    /// that still hashes the same after a crash wasn't written yet.
+    in_flight: Vec<(u64, u64)>,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Turns a plain FAT volume holding objects unencrypted under
+    /// `ids/` into a store, encrypting their data in place a chunk at a
+    /// time. `progress` is called after every batch of chunks.
+    ///
+    /// An adoption that was interrupted is resumed by calling `adopt`
+    /// again, and adopting a volume that already is a store just opens
+    /// it. Every key is derived during adoption, so the next epoch
+    /// re-encrypts everything, as it would after writing it all.
+    ///
+    /// # Safety
+    /// The plaintext is overwritten in place, so it may survive on
+    /// devices that remap writes.
+    pub fn adopt(
+        disk: D,
+        root_key: [u8; 32],
+        mut progress: impl FnMut(AdoptProgress),
+    ) -> Result<Self, ObjectStoreError> {
+        let fs = FileSystem::open_fs(Arc::new(disk), FsConfig::default())?;
+        {
+            let disk = fs.disk();
+            let volume = fs.fs().lock().map_err(lock_poisoned)?;
+            if Superblock::load(&volume, root_key)?.is_none() {
+                // the journal goes first, so that a crash from here on
+                // leaves a store that is known to be partly plaintext.
+                let meta_key = derive_subkey(root_key, META_KEY_LABEL);
+                if read_meta::<_, AdoptJournal>(&volume, &meta_key, ADOPT_PATH)?.is_none() {
+                    write_meta(&volume, &meta_key, ADOPT_PATH, &AdoptJournal::default())?;
+                    disk.flush().map_err(Error::from)?;
+                }
+                let superblock = FormatOptions::new().superblock();
+                RawHeader::new(&superblock, Layout::from_boot_sector(disk)?).store(disk)?;
+                disk.flush().map_err(Error::from)?;
+                superblock.store(&volume, root_key)?;
+                disk.flush().map_err(Error::from)?;
+            }
+        }
+        let store = Self::from_fs(fs, None, root_key, OpenChecks::default())?;
+        store.finish_adoption(&mut progress)?;
+        Ok(store)
+    }
+
+    fn finish_adoption(&self, progress: &mut impl FnMut(AdoptProgress)) -> Result<(), Error> {
+        let Some(mut journal) = read_meta::<_, AdoptJournal>(
+            &*self.fs().lock().map_err(lock_poisoned)?,
+            &self.meta_key(),
+            ADOPT_PATH,
+        )?
+        else {
+            return Ok(());
+        };
+        let mut report = AdoptProgress::default();
+        for (chunk_id, sum) in std::mem::take(&mut journal.in_flight) {
+            let key = self.adopt_key(chunk_id)?;
+            if self.encrypt_chunk(chunk_id, &key, Some(sum))? {
+                report.chunks_encrypted += 1;
+            }
+        }
+        self.fs.disk().flush()?;
+        let mut ids = self.scan_object_ids(&*self.fs().lock().map_err(lock_poisoned)?)?;
+        ids.sort_unstable();
+        report.objects_total = ids.len() as u64;
+        // batches span objects, so small objects don't each cost a
+        // persist of the KHF.
+        let mut batch = Vec::with_capacity(ADOPT_BATCH);
+        let mut queued_objects = 0;
+        for obj_id in ids {
+            if obj_id >= journal.next.0 {
+                for chunk_id in self.object_chunks(obj_id)? {
+                    if (obj_id, chunk_id) < journal.next {
+                        continue;
+                    }
+                    batch.push((obj_id, chunk_id));
+                    if batch.len() == ADOPT_BATCH {
+                        report.chunks_encrypted += self.adopt_batch(&batch)?;
+                        report.objects_done = queued_objects;
+                        progress(report);
+                        batch.clear();
+                    }
+                }
+            }
+            queued_objects += 1;
+        }
+        if !batch.is_empty() {
+            report.chunks_encrypted += self.adopt_batch(&batch)?;
+        }
+        report.objects_done = queued_objects;
+        progress(report);
+        let fs = self.fs().lock().map_err(lock_poisoned)?;
+        fs.root_dir().remove(ADOPT_PATH)?;
+        self.fs.disk().flush()?;
+        Ok(())
+    }
+
+    /// Encrypts a batch of object chunks. Their keys are derived and
+    /// persisted in the KHF, and the batch recorded in the journal,
+    /// before any of them is written.
+    fn adopt_batch(&self, batch: &[(u128, u64)]) -> Result<u64, Error> {
+        let mut keys = Vec::with_capacity(batch.len());
+        let mut in_flight = Vec::with_capacity(batch.len());
+        for &(_, chunk_id) in batch {
+            keys.push(self.adopt_key(chunk_id)?);
+            in_flight.push((chunk_id, xxh3_64(&self.read_chunk(chunk_id)?)));
+        }
+        {
+            let fs = self.fs().lock().map_err(lock_poisoned)?;
+            self.store_superblock(&fs, false)?;
+            self.persist_khf(&fs)?;
+            let (obj_id, chunk_id) = batch[batch.len() - 1];
+            let next = (obj_id, chunk_id + 1);
+            write_meta(
+                &fs,
+                &self.meta_key(),
+                ADOPT_PATH,
+                &AdoptJournal { next, in_flight },
+            )?;
+        }
+        self.fs.disk().flush()?;
+        for (&(_, chunk_id), key) in batch.iter().zip(&keys) {
+            self.encrypt_chunk(chunk_id, key, None)?;
+        }
+        self.fs.disk().flush()?;
+        Ok(batch.len() as u64)
+    }
+
+    fn adopt_key(&self, chunk_id: u64) -> Result<[u8; 32], Error> {
+        self.chunk_key(self.layout.disk_offset(chunk_id))?
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "store isn't encrypted"))
+    }
+
+    fn read_chunk(&self, chunk_id: u64) -> Result<Vec<u8>, Error> {
+        let disk = self.fs.disk();
+        let disk_offset = self.layout.disk_offset(chunk_id);
+        let len = self.layout.chunk_len(chunk_id, disk.size()?)?;
+        let mut buf = vec![0; len as usize];
+        disk.read_exact_at(disk_offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Encrypts a chunk in place, unless `plaintext_sum` is given and
+    /// the chunk no longer matches it. Returns whether it was written.
+    fn encrypt_chunk(
+        &self,
+        chunk_id: u64,
+        key: &[u8; 32],
+        plaintext_sum: Option<u64>,
+    ) -> Result<bool, Error> {
+        let mut buf = self.read_chunk(chunk_id)?;
+        if plaintext_sum.is_some_and(|sum| sum != xxh3_64(&buf)) {
+            return Ok(false);
+        }
+        let disk_offset = self.layout.disk_offset(chunk_id);
+        self.layout
+            .cipher(disk_offset, *key)
+            .apply_keystream(&mut buf);
+        self.fs.disk().write_all_at(disk_offset, &buf)?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    object_store::CreateMode,
+    CreateOutcome, ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+};
+
+pub(crate) const ALLOCATOR_PATH: &str = "meta/allocator";
+
+/// The high 64 bits of the last id handed out by `allocate_id`. It is
+/// written before the id is used, so ids are never handed out twice,
+/// even across crashes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct IdAllocator {
+    high_water: u64,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn allocator_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<IdAllocator>>, Error> {
+        let mut allocator = self.allocator.lock().unwrap();
+        if allocator.is_none() {
+            *allocator = Some(read_meta(fs, &self.meta_key(), ALLOCATOR_PATH)?.unwrap_or_default());
+        }
+        Ok(allocator)
+    }
+
+    /// Reserves the next `n` high water marks and returns an id under
+    /// each with random low bits.
+    fn reserve_ids(&self, fs: &FatFs<D>, n: usize) -> Result<Vec<u128>, Error> {
+        let mut allocator = self.allocator_lock(fs)?;
+        let allocator = allocator.as_mut().unwrap();
+        let first = allocator.high_water + 1;
+        // the last high water mark is left out, since it would reach
+        // into the ids Twizzler reserves.
+        let high_water = allocator
+            .high_water
+            .checked_add(n as u64)
+            .filter(|high_water| *high_water < u64::MAX)
+            .ok_or_else(|| Error::new(ErrorKind::StorageFull, "object ids are exhausted"))?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            ALLOCATOR_PATH,
+            allocator,
+            |allocator| {
+                allocator.high_water = high_water;
+                true
+            },
+        )?;
+        Ok((first..=high_water)
+            .map(|high| (high as u128) << 64 | rand::random::<u64>() as u128)
+            .collect())
+    }
+
+    fn reserve_id(&self) -> Result<u128, Error> {
+        let fs = self.fs().lock().unwrap();
+        Ok(self.reserve_ids(&fs, 1)?[0])
+    }
+
+    /// Creates an object under an id that no other call has returned,
+    /// and returns the id. The low bits are random so that ids don't
+    /// reveal how many objects were allocated before them.
+    ///
+    /// Objects created with a caller chosen id can still take an id
+    /// the allocator would have picked, in which case another is
+    /// reserved.
+    pub fn allocate_id(&self) -> Result<u128, ObjectStoreError> {
+        loop {
+            let obj_id = self.reserve_id()?;
+            match self.create_object_excl(obj_id) {
+                Ok(()) => return Ok(obj_id),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Creates `n` objects as `allocate_id` would and returns their
+    /// ids, reserving them with one write and creating them all
+    /// without letting go of the volume.
+    pub fn create_many_unique(&self, n: usize) -> Result<Vec<u128>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let mut ids = Vec::with_capacity(n);
+        while ids.len() < n {
+            for obj_id in self.reserve_ids(&fs, n - ids.len())? {
+                if self.create_locked(&fs, obj_id, CreateMode::Open)? == CreateOutcome::Created {
+                    ids.push(obj_id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+}
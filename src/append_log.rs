@@ -0,0 +1,140 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    version::version_conflict,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+};
+
+pub(crate) const LOGS_PATH: &str = "meta/logs";
+
+/// How many bytes of each append log an epoch has made immutable.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct LogTable {
+    sealed: BTreeMap<u128, u64>,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn logs_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<LogTable>>, Error> {
+        let mut logs = self.logs.lock().unwrap();
+        if logs.is_none() {
+            *logs = Some(read_meta(fs, &self.meta_key(), LOGS_PATH)?.unwrap_or_default());
+        }
+        Ok(logs)
+    }
+
+    /// Creates an empty append log. Everything in a log when an epoch
+    /// finishes becomes immutable, so the log can only be added to
+    /// from there on, not rewritten or truncated. It can still be
+    /// unlinked.
+    ///
+    /// # Errors
+    /// `AlreadyExists` if the object exists.
+    pub fn create_log(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        self.create_object_excl(obj_id)?;
+        let fs = self.fs().lock().unwrap();
+        let mut logs = self.logs_lock(&fs)?;
+        update_meta(
+            &fs,
+            &self.meta_key(),
+            LOGS_PATH,
+            logs.as_mut().unwrap(),
+            |logs| {
+                logs.sealed.insert(obj_id, 0);
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Appends `buf` to the end of an object, returning the offset it
+    /// was written at. Concurrent appends each get their own range.
+    pub fn append_log(&self, obj_id: u128, buf: &[u8]) -> Result<u64, ObjectStoreError> {
+        loop {
+            let version = self.version(obj_id);
+            let off = self.disk_length(obj_id)?;
+            match self.write_patch(obj_id, &[(off, buf)], Some(version)) {
+                Ok(_) => return Ok(off),
+                Err(e) if version_conflict(&e).is_some() => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Returns how many bytes of a log are immutable, or `None` if the
+    /// object isn't a log.
+    pub fn log_sealed_len(&self, obj_id: u128) -> Result<Option<u64>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        Ok(self
+            .logs_lock(&fs)?
+            .as_ref()
+            .unwrap()
+            .sealed
+            .get(&obj_id)
+            .copied())
+    }
+
+    /// Fails with `PermissionDenied` if `obj_id` is a log and an edit
+    /// at `off` would change what an epoch has sealed.
+    pub(crate) fn check_log_edit(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        off: u64,
+    ) -> Result<(), Error> {
+        let logs = self.logs_lock(fs)?;
+        match logs.as_ref().unwrap().sealed.get(&obj_id) {
+            Some(&sealed) if off < sealed => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "log contents sealed by an epoch can't be changed",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes the current contents of every log immutable. Called as an
+    /// epoch finishes.
+    pub(crate) fn seal_logs(&self, fs: &mut FatFs<D>) -> Result<(), Error> {
+        let mut logs = self.logs_lock(fs)?;
+        let logs = logs.as_mut().unwrap();
+        if logs.sealed.is_empty() {
+            return Ok(());
+        }
+        let lens: Vec<(u128, u64)> = logs
+            .sealed
+            .keys()
+            .map(|&obj_id| Ok((obj_id, self.object_len_locked(fs, obj_id)?)))
+            .collect::<Result<_, Error>>()?;
+        update_meta(fs, &self.meta_key(), LOGS_PATH, logs, |logs| {
+            logs.sealed.extend(lens);
+            true
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn forget_log(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut logs = self.logs_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            LOGS_PATH,
+            logs.as_mut().unwrap(),
+            |logs| logs.sealed.remove(&obj_id).is_some(),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use crate::fs::Disk;
+use async_trait::async_trait;
+use std::io::Error;
+
+/// A disk whose I/O completes asynchronously, such as an NVMe queue
+/// pair that signals completions with interrupts. Operations are
+/// positional so that several can be in flight at once.
+#[async_trait]
+pub trait AsyncDisk: Send + Sync {
+    /// Reads into `buf` starting at byte `offset`, returning the number
+    /// of bytes read.
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error>;
+    /// Writes `buf` starting at byte `offset`, returning the number of
+    /// bytes written.
+    async fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Error>;
+    async fn flush(&self) -> Result<(), Error>;
+}
+
+/// Exposes a blocking `Disk` as an `AsyncDisk`. Every operation runs to
+/// completion on the polling thread, so this only exists to let sync
+/// disks be used wherever an `AsyncDisk` is expected.
+pub struct SyncDiskAdapter<D> {
+    disk: D,
+}
+
+impl<D: Disk> SyncDiskAdapter<D> {
+    pub fn new(disk: D) -> Self {
+        Self { disk }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+}
+
+#[async_trait]
+impl<D> AsyncDisk for SyncDiskAdapter<D>
+where
+    D: Disk + Send + Sync,
+    std::io::Error: From<D::Error>,
+{
+    async fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        Ok(self.disk.read_at(offset, buf)?)
+    }
+
+    async fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Error> {
+        Ok(self.disk.write_at(offset, buf)?)
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(self.disk.flush()?)
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{
+    context::{ErrorContext, Phase, ResultExt},
+    eof::check_in_bounds,
+    fs::{Disk, PAGE_SIZE},
+    object_store::{get_dir_path, lock_poisoned},
+    wrapped_extent::WrappedExtent,
+    AsyncDisk, ObjectStore, ObjectStoreError,
+};
+use chacha20::{cipher::StreamCipher, ChaCha20};
+use fatfs::{IoBase, Seek, SeekFrom};
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+/// A piece of a read that falls within a single page.
+struct ReadSpan {
+    disk_offset: u64,
+    /// Where the span starts in the caller's buffer.
+    at: usize,
+    len: usize,
+    cipher: Option<ChaCha20>,
+}
+
+/// Ciphertext and the disk offset it is written at.
+type Encrypted = (u64, Vec<u8>);
+
+#[derive(Default)]
+struct GateState {
+    shared: usize,
+    exclusive: bool,
+    /// Exclusive entries waiting, which hold back new shared ones.
+    exclusive_waiting: usize,
+    wakers: Vec<Waker>,
+}
+
+/// Lets reads and writes of object data be in flight together while
+/// keeping them apart from unlinks and epochs, which free the clusters
+/// and replace the keys that the I/O was planned with.
+#[derive(Default)]
+struct Gate {
+    state: Mutex<GateState>,
+}
+
+impl Gate {
+    fn shared(&self) -> Enter<'_> {
+        Enter {
+            gate: self,
+            exclusive: false,
+            queued: false,
+        }
+    }
+
+    fn exclusive(&self) -> Enter<'_> {
+        Enter {
+            gate: self,
+            exclusive: true,
+            queued: false,
+        }
+    }
+}
+
+struct Enter<'a> {
+    gate: &'a Gate,
+    exclusive: bool,
+    /// Whether this is counted in `exclusive_waiting`.
+    queued: bool,
+}
+
+impl<'a> Future for Enter<'a> {
+    type Output = GateGuard<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<GateGuard<'a>> {
+        let this = self.get_mut();
+        let mut state = this.gate.state.lock().unwrap();
+        let open = match this.exclusive {
+            true => !state.exclusive && state.shared == 0,
+            false => !state.exclusive && state.exclusive_waiting == 0,
+        };
+        if !open {
+            if this.exclusive && !this.queued {
+                state.exclusive_waiting += 1;
+                this.queued = true;
+            }
+            state.wakers.push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if this.exclusive {
+            state.exclusive = true;
+        } else {
+            state.shared += 1;
+        }
+        if this.queued {
+            state.exclusive_waiting -= 1;
+            this.queued = false;
+        }
+        Poll::Ready(GateGuard {
+            gate: this.gate,
+            exclusive: this.exclusive,
+        })
+    }
+}
+
+impl Drop for Enter<'_> {
+    fn drop(&mut self) {
+        // given up on while waiting, so shared entries it held back
+        // may go ahead.
+        if self.queued {
+            let mut state = self.gate.state.lock().unwrap();
+            state.exclusive_waiting -= 1;
+            state.wakers.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+struct GateGuard<'a> {
+    gate: &'a Gate,
+    exclusive: bool,
+}
+
+impl Drop for GateGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.gate.state.lock().unwrap();
+        if self.exclusive {
+            state.exclusive = false;
+        } else {
+            state.shared -= 1;
+        }
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+async fn read_exact_at(
+    disk: &dyn AsyncDisk,
+    mut offset: u64,
+    mut buf: &mut [u8],
+) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match disk.read_at(offset, buf).await {
+            Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+async fn write_all_at(disk: &dyn AsyncDisk, mut offset: u64, mut buf: &[u8]) -> Result<(), Error> {
+    while !buf.is_empty() {
+        match disk.write_at(offset, buf).await {
+            Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// An `ObjectStore` whose object data is read and written on an
+/// `AsyncDisk`, so that an executor can keep many calls in flight
+/// instead of blocking a thread on each. Metadata is still read and
+/// written through the store's `Disk` under its lock, which a call only
+/// holds while it plans its I/O.
+///
+/// Reads and writes that can't go straight to the disk, such as writes
+/// past the end of an object or reads of deduplicated objects, are
+/// done by `ObjectStore` on the polling thread instead.
+pub struct AsyncObjectStore<D: Disk> {
+    store: ObjectStore<D>,
+    disk: Box<dyn AsyncDisk>,
+    gate: Gate,
+}
+
+impl<D> AsyncObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Wraps `store`, with `disk` being the same device as the store's
+    /// disk.
+    pub fn new(store: ObjectStore<D>, disk: impl AsyncDisk + 'static) -> Self {
+        Self {
+            store,
+            disk: Box::new(disk),
+            gate: Gate::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> ObjectStore<D> {
+        self.store
+    }
+
+    pub fn close(self) -> Result<(), ObjectStoreError> {
+        self.store.close()
+    }
+
+    /// Returns true if the object was created and false if it already
+    /// existed.
+    pub async fn create_object(&self, obj_id: u128) -> Result<bool, ObjectStoreError> {
+        self.store.create_object(obj_id)
+    }
+
+    pub async fn read_exact(
+        &self,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let _shared = self.gate.shared().await;
+        let started = Instant::now();
+        let Some(spans) = self.store.plan_read(obj_id, off, buf.len())? else {
+            return self.store.read_exact(obj_id, buf, off);
+        };
+        for span in spans {
+            let dst = &mut buf[span.at..span.at + span.len];
+            read_exact_at(&*self.disk, span.disk_offset, dst)
+                .await
+                .context(ErrorContext::new(Phase::DiskRead).disk_offset(span.disk_offset))?;
+            if let Some(mut cipher) = span.cipher {
+                cipher.apply_keystream(dst);
+            }
+        }
+        self.store.record_read(obj_id, buf.len(), started);
+        Ok(())
+    }
+
+    pub async fn write_all(
+        &self,
+        obj_id: u128,
+        buf: &[u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let _shared = self.gate.shared().await;
+        let started = Instant::now();
+        let Some(encrypted) = self.store.plan_write(obj_id, buf, off)? else {
+            return self.store.write_all(obj_id, buf, off);
+        };
+        for (disk_offset, data) in encrypted {
+            write_all_at(&*self.disk, disk_offset, &data)
+                .await
+                .context(ErrorContext::new(Phase::DiskWrite).disk_offset(disk_offset))?;
+        }
+        let fs = self.store.fs().lock().map_err(lock_poisoned)?;
+        self.store
+            .finish_direct(&fs, [(obj_id, &[(off, buf)][..])], started)?;
+        Ok(())
+    }
+
+    /// Unlinks an object once the reads and writes in flight are done.
+    pub async fn unlink_object(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        let _exclusive = self.gate.exclusive().await;
+        self.store.unlink_object(obj_id)
+    }
+
+    /// Advances the epoch once the reads and writes in flight are done,
+    /// holding back new ones until it finishes. The epoch runs to the
+    /// end without yielding, since its rotated keys can't be left in
+    /// memory across an await.
+    pub async fn advance_epoch(&self) -> Result<(), ObjectStoreError> {
+        let _exclusive = self.gate.exclusive().await;
+        self.store.advance_epoch()
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Splits a read into per page spans with their ciphers, or returns
+    /// `None` if it can't be read straight from the disk.
+    fn plan_read(
+        &self,
+        obj_id: u128,
+        off: u64,
+        len: usize,
+    ) -> Result<Option<Vec<ReadSpan>>, Error> {
+        let fs = self.fs().lock().map_err(lock_poisoned)?;
+        let end = off + len as u64;
+        if !self.fs.disk().is_passthrough()
+            || self.is_deduplicated_locked(&fs, obj_id)?
+            || self
+                .unwritten_ranges(&fs, obj_id)?
+                .iter()
+                .any(|range| range.start < end && off < range.end)
+        {
+            return Ok(None);
+        }
+        let b64 = self.encode_obj_id(obj_id);
+        let mut file = get_dir_path(&fs, &b64)?
+            .open_file(&b64)
+            .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+        let object_len = file.seek(SeekFrom::End(0))?;
+        check_in_bounds(obj_id, off, len, object_len)?;
+        let extents: Vec<WrappedExtent> = file
+            .extents()
+            .map(|v| v.map(WrappedExtent::from))
+            .collect::<Result<_, _>>()?;
+        let mut spans = Vec::new();
+        let mut at = 0;
+        while at < len {
+            let file_off = off + at as u64;
+            let in_page = PAGE_SIZE - (file_off % PAGE_SIZE as u64) as usize;
+            let n = in_page.min(len - at);
+            let mut start = 0;
+            let disk_offset = extents.iter().find_map(|extent| {
+                let found = (file_off < start + extent.size())
+                    .then(|| extent.offset() + (file_off - start));
+                start += extent.size();
+                found
+            });
+            let Some(disk_offset) = disk_offset else {
+                return Ok(None);
+            };
+            let cipher = self.read_cipher(disk_offset).context(
+                ErrorContext::new(Phase::DeriveKey)
+                    .object(obj_id)
+                    .disk_offset(disk_offset),
+            )?;
+            spans.push(ReadSpan {
+                disk_offset,
+                at,
+                len: n,
+                cipher,
+            });
+            at += n;
+        }
+        Ok(Some(spans))
+    }
+
+    /// Encrypts a write for the disk offsets it goes to, or returns
+    /// `None` if it can't be written straight to the disk.
+    fn plan_write(
+        &self,
+        obj_id: u128,
+        buf: &[u8],
+        off: u64,
+    ) -> Result<Option<Vec<Encrypted>>, Error> {
+        let mut fs = self.fs().lock().map_err(lock_poisoned)?;
+        if !self.fs.disk().is_passthrough() {
+            return Ok(None);
+        }
+        let Some(segments) = self.plan_direct(&mut fs, obj_id, &[(off, buf)])? else {
+            return Ok(None);
+        };
+        segments
+            .into_iter()
+            .map(|segment| self.encrypt_segment(segment))
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+}
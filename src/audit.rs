@@ -0,0 +1,82 @@
+use crate::{fs::Disk, object_store::lock_poisoned, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{
+    collections::BTreeSet,
+    io::{Error, Write},
+};
+
+/// The chunks whose keys were used while an audit was running. Keys
+/// themselves are never recorded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyAudit {
+    /// Chunks whose keys encrypted a write.
+    pub written: BTreeSet<u64>,
+    /// Chunks whose keys were only used to read.
+    pub read: BTreeSet<u64>,
+    /// Written chunks whose derivation the KHF hadn't logged when the
+    /// key was used, so an epoch wouldn't securely delete them. Empty
+    /// unless something is wrong.
+    pub untracked: BTreeSet<u64>,
+}
+
+impl KeyAudit {
+    /// Writes one `chunk_id,use` line per chunk, where the use is
+    /// `write`, `read` or `untracked`, for loading into other tools.
+    pub fn export(&self, mut out: impl Write) -> Result<(), ObjectStoreError> {
+        writeln!(out, "chunk_id,use")?;
+        for chunk_id in &self.written {
+            let kind = if self.untracked.contains(chunk_id) {
+                "untracked"
+            } else {
+                "write"
+            };
+            writeln!(out, "{chunk_id},{kind}")?;
+        }
+        for chunk_id in self.read.difference(&self.written) {
+            writeln!(out, "{chunk_id},read")?;
+        }
+        Ok(())
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Starts recording which chunks have their keys used, throwing
+    /// away any audit already running. Meant for checking secure
+    /// deletion coverage during development, since every key lookup
+    /// takes an extra lock while it runs.
+    pub fn start_key_audit(&self) -> Result<(), ObjectStoreError> {
+        *self.audit.lock().map_err(lock_poisoned)? = Some(KeyAudit::default());
+        Ok(())
+    }
+
+    /// Stops recording and returns what was recorded, or `None` if no
+    /// audit was running.
+    pub fn stop_key_audit(&self) -> Result<Option<KeyAudit>, ObjectStoreError> {
+        Ok(self.audit.lock().map_err(lock_poisoned)?.take())
+    }
+
+    /// Records the use of a chunk's key if an audit is running.
+    pub(crate) fn audit_key_use(&self, chunk_id: u64, read_only: bool) -> Result<(), Error> {
+        let mut audit = self.audit.lock().map_err(lock_poisoned)?;
+        let Some(audit) = audit.as_mut() else {
+            return Ok(());
+        };
+        if read_only {
+            audit.read.insert(chunk_id);
+            return Ok(());
+        }
+        audit.written.insert(chunk_id);
+        if !self.key_logged(chunk_id)? {
+            audit.untracked.insert(chunk_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    context::{ErrorContext, Phase, ResultExt},
+    flags::ObjectFlags,
+    fs::{Disk, FatFs, PAGE_SIZE},
+    object_store::get_dir_path,
+    wrapped_extent::WrappedExtent,
+    ObjectStore, ObjectStoreError,
+};
+use chacha20::cipher::StreamCipher;
+use fatfs::{IoBase, Seek, SeekFrom};
+use std::{collections::BTreeMap, io::Error, time::Instant};
+
+/// A piece of a write that falls within a single chunk.
+pub(crate) struct Segment<'a> {
+    disk_offset: u64,
+    obj_id: u128,
+    buf: &'a [u8],
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Writes to many objects at once, taking the filesystem lock once
+    /// and issuing the encrypted writes in disk order. Writes to the
+    /// same object are applied in order, so later ones win where they
+    /// overlap.
+    ///
+    /// Objects whose writes extend past their end, overlap, or need
+    /// extra bookkeeping (deduplicated or MAC protected objects) are
+    /// written with `apply_patch` after the rest of the batch.
+    pub fn write_batch(&self, writes: &[(u128, u64, &[u8])]) -> Result<(), ObjectStoreError> {
+        let started = Instant::now();
+        let mut patches: BTreeMap<u128, Vec<(u64, &[u8])>> = BTreeMap::new();
+        for &(obj_id, off, buf) in writes {
+            patches.entry(obj_id).or_default().push((off, buf));
+        }
+        let mut slow = Vec::new();
+        {
+            let mut fs = self.fs().lock().unwrap();
+            let mut segments = Vec::new();
+            let mut direct = Vec::new();
+            for (&obj_id, patch) in &patches {
+                match self.plan_direct(&mut fs, obj_id, patch)? {
+                    Some(planned) => {
+                        segments.extend(planned);
+                        direct.push(obj_id);
+                    }
+                    None => slow.push(obj_id),
+                }
+            }
+            segments.sort_by_key(|segment| segment.disk_offset);
+            self.write_segments(segments)?;
+            self.finish_direct(
+                &fs,
+                direct.iter().map(|obj_id| (*obj_id, &patches[obj_id][..])),
+                started,
+            )?;
+        }
+        for obj_id in slow {
+            self.apply_patch(obj_id, &patches[&obj_id])?;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at `off` straight to the disk, skipping fatfs,
+    /// when it covers whole pages already in the object. Returns false,
+    /// having written nothing, when it has to go through `apply_patch`.
+    pub(crate) fn write_pages_direct(
+        &self,
+        obj_id: u128,
+        buf: &[u8],
+        off: u64,
+    ) -> Result<bool, Error> {
+        let started = Instant::now();
+        let page = PAGE_SIZE as u64;
+        if buf.is_empty() || !off.is_multiple_of(page) || !(buf.len() as u64).is_multiple_of(page) {
+            return Ok(false);
+        }
+        let patch = [(off, buf)];
+        let mut fs = self.fs().lock().unwrap();
+        let Some(segments) = self.plan_direct(&mut fs, obj_id, &patch)? else {
+            return Ok(false);
+        };
+        self.write_segments(segments)?;
+        self.finish_direct(&fs, [(obj_id, &patch[..])], started)?;
+        Ok(true)
+    }
+
+    /// Encrypts and writes each segment at its disk offset.
+    fn write_segments(&self, segments: Vec<Segment<'_>>) -> Result<(), Error> {
+        let disk = self.fs.disk();
+        for segment in segments {
+            let (disk_offset, data) = self.encrypt_segment(segment)?;
+            disk.write_all_at(disk_offset, &data)
+                .context(ErrorContext::new(Phase::DiskWrite).disk_offset(disk_offset))?;
+        }
+        Ok(())
+    }
+
+    /// Returns where a segment goes and its ciphertext.
+    pub(crate) fn encrypt_segment(&self, segment: Segment<'_>) -> Result<(u64, Vec<u8>), Error> {
+        let ctx = ErrorContext::new(Phase::Write)
+            .object(segment.obj_id)
+            .disk_offset(segment.disk_offset);
+        let mut data = segment.buf.to_vec();
+        if let Some(mut cipher) = self
+            .get_symmetric_cipher(segment.disk_offset)
+            .context(ctx)?
+        {
+            cipher.apply_keystream(&mut data);
+        }
+        Ok((segment.disk_offset, data))
+    }
+
+    /// Does the bookkeeping `write_patch` would have for patches written
+    /// directly.
+    pub(crate) fn finish_direct<'a>(
+        &self,
+        fs: &FatFs<D>,
+        written: impl IntoIterator<Item = (u128, &'a [(u64, &'a [u8])])> + Clone,
+        started: Instant,
+    ) -> Result<(), Error> {
+        // direct writes stay inside objects, but may land in holes.
+        let mut holes = self.holes_lock(fs)?;
+        let holes = holes.as_mut().unwrap();
+        let mut holes_changed = false;
+        for (obj_id, patch) in written.clone() {
+            for &(off, buf) in patch {
+                holes_changed |= holes.fill(obj_id, off, off + buf.len() as u64);
+            }
+        }
+        if holes_changed {
+            self.store_holes(fs, holes)?;
+        }
+        let mut versions = self.versions.lock().unwrap();
+        for (obj_id, patch) in written {
+            versions.bump(obj_id);
+            let written = patch.iter().map(|(_, buf)| buf.len()).sum();
+            self.record_write(obj_id, written, started);
+        }
+        Ok(())
+    }
+
+    /// Splits `patch` into per chunk segments, or returns `None` if it
+    /// can't be written straight to the disk.
+    pub(crate) fn plan_direct<'a>(
+        &self,
+        fs: &mut FatFs<D>,
+        obj_id: u128,
+        patch: &[(u64, &'a [u8])],
+    ) -> Result<Option<Vec<Segment<'a>>>, Error> {
+        if self
+            .check_flags(fs, obj_id, ObjectFlags::IMMUTABLE | ObjectFlags::SEALED)
+            .is_err()
+            || patch
+                .iter()
+                .any(|&(off, _)| self.check_log_edit(fs, obj_id, off).is_err())
+            || self.is_deduplicated_locked(fs, obj_id)?
+            || self.page_macs_locked(fs, obj_id)?.is_some()
+        {
+            return Ok(None);
+        }
+        let mut ranges: Vec<(u64, u64)> = patch
+            .iter()
+            .map(|&(off, buf)| (off, off + buf.len() as u64))
+            .collect();
+        ranges.sort();
+        if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+            return Ok(None);
+        }
+        let b64 = self.encode_obj_id(obj_id);
+        let mut file = match get_dir_path(fs, &b64)?.open_file(&b64) {
+            Ok(file) => file,
+            // let apply_patch report the missing object.
+            Err(_) => return Ok(None),
+        };
+        let len = file.seek(SeekFrom::End(0))?;
+        if ranges.last().is_some_and(|&(_, end)| end > len) {
+            return Ok(None);
+        }
+        let extents: Vec<WrappedExtent> = file
+            .extents()
+            .map(|v| v.map(WrappedExtent::from))
+            .collect::<Result<_, _>>()?;
+        let mut segments = Vec::new();
+        for &(off, buf) in patch {
+            let mut pos = 0;
+            while pos < buf.len() {
+                let file_off = off + pos as u64;
+                let in_page = PAGE_SIZE - (file_off % PAGE_SIZE as u64) as usize;
+                let n = in_page.min(buf.len() - pos);
+                let mut start = 0;
+                let disk_offset = extents.iter().find_map(|extent| {
+                    let found = (file_off < start + extent.size())
+                        .then(|| extent.offset() + (file_off - start));
+                    start += extent.size();
+                    found
+                });
+                let Some(disk_offset) = disk_offset else {
+                    return Ok(None);
+                };
+                segments.push(Segment {
+                    disk_offset,
+                    obj_id,
+                    buf: &buf[pos..pos + n],
+                });
+                pos += n;
+            }
+        }
+        Ok(Some(segments))
+    }
+}
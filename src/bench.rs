@@ -0,0 +1,147 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use rand::Rng;
+use std::{
+    io::{Error, ErrorKind},
+    time::{Duration, Instant},
+};
+
+/// The workload run by `self_benchmark`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BenchProfile {
+    /// Size of the scratch object the workloads run against.
+    pub object_size: u64,
+    /// Bytes moved by each operation.
+    pub io_size: usize,
+    /// Operations per random workload. The sequential workloads cover
+    /// the whole object.
+    pub random_ops: usize,
+}
+
+impl BenchProfile {
+    /// A short run for checking that a disk works at all.
+    pub fn quick() -> Self {
+        Self {
+            object_size: 4 << 20,
+            io_size: 4096,
+            random_ops: 256,
+        }
+    }
+
+    /// A longer run for judging whether a disk can keep up with pager
+    /// traffic.
+    pub fn standard() -> Self {
+        Self {
+            object_size: 64 << 20,
+            io_size: 4096,
+            random_ops: 4096,
+        }
+    }
+}
+
+impl Default for BenchProfile {
+    fn default() -> Self {
+        Self::quick()
+    }
+}
+
+/// Throughput and latencies of one workload.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WorkloadStats {
+    pub ops: usize,
+    pub bytes_per_sec: f64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl WorkloadStats {
+    fn from_latencies(mut latencies: Vec<Duration>, io_size: usize) -> Self {
+        latencies.sort_unstable();
+        let total: Duration = latencies.iter().sum();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        Self {
+            ops: latencies.len(),
+            bytes_per_sec: (latencies.len() * io_size) as f64 / total.as_secs_f64(),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: *latencies.last().unwrap(),
+        }
+    }
+}
+
+/// The results of `self_benchmark`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BenchReport {
+    pub sequential_write: WorkloadStats,
+    pub sequential_read: WorkloadStats,
+    pub random_write: WorkloadStats,
+    pub random_read: WorkloadStats,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Runs sequential and random reads and writes against a scratch
+    /// object and reports how fast the store is on this disk. The
+    /// scratch object is unlinked afterwards, so its keys are only
+    /// securely deleted by the next epoch.
+    pub fn self_benchmark(&self, profile: BenchProfile) -> Result<BenchReport, ObjectStoreError> {
+        let io_size = profile.io_size as u64;
+        if io_size == 0 || profile.object_size < io_size || profile.random_ops == 0 {
+            return Err(
+                Error::new(ErrorKind::InvalidInput, "benchmark profile moves no data").into(),
+            );
+        }
+        let obj_id = self.allocate_id()?;
+        let res = self.run_benchmark(obj_id, profile);
+        self.unlink_object(obj_id)?;
+        Ok(res?)
+    }
+
+    fn run_benchmark(&self, obj_id: u128, profile: BenchProfile) -> Result<BenchReport, Error> {
+        let io_size = profile.io_size as u64;
+        let pages = profile.object_size / io_size;
+        let mut data = vec![0u8; profile.io_size];
+        rand::thread_rng().fill(&mut data[..]);
+        let mut buf = vec![0u8; profile.io_size];
+        let sequential = || (0..pages).map(|i| i * io_size);
+        let random = || {
+            let mut rng = rand::thread_rng();
+            (0..profile.random_ops)
+                .map(|_| rng.gen_range(0..pages) * io_size)
+                .collect::<Vec<_>>()
+        };
+        let mut write = |off| Ok(self.write_all(obj_id, &data, off)?);
+        let sequential_write = time_ops(sequential(), profile.io_size, &mut write)?;
+        let random_write = time_ops(random(), profile.io_size, &mut write)?;
+        let mut read = |off| Ok(self.read_exact(obj_id, &mut buf, off)?);
+        Ok(BenchReport {
+            sequential_write,
+            sequential_read: time_ops(sequential(), profile.io_size, &mut read)?,
+            random_write,
+            random_read: time_ops(random(), profile.io_size, &mut read)?,
+        })
+    }
+}
+
+/// Times `op` at each of `offsets`.
+fn time_ops(
+    offsets: impl IntoIterator<Item = u64>,
+    io_size: usize,
+    mut op: impl FnMut(u64) -> Result<(), Error>,
+) -> Result<WorkloadStats, Error> {
+    let mut latencies = Vec::new();
+    for off in offsets {
+        let start = Instant::now();
+        op(off)?;
+        latencies.push(start.elapsed());
+    }
+    Ok(WorkloadStats::from_latencies(latencies, io_size))
+}
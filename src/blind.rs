@@ -0,0 +1,114 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    object_store::EncodedObjectId,
+    ObjectStore,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{collections::BTreeMap, io::Error, sync::MutexGuard};
+
+pub(crate) const BLIND_PATH: &str = "meta/blind";
+pub(crate) const ID_KEY_LABEL: &[u8] = b"object-store id blinding key";
+
+/// Maps blinded file names back to the object ids they stand for, so
+/// that objects can still be listed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct BlindIndex {
+    ids: BTreeMap<u128, u128>,
+}
+
+fn blind(id_key: &[u8; 32], obj_id: u128) -> u128 {
+    let mut hasher = Sha3_256::new();
+    hasher.update(id_key);
+    hasher.update(obj_id.to_le_bytes());
+    u128::from_le_bytes(hasher.finalize()[..16].try_into().unwrap())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn blind_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<BlindIndex>>, Error> {
+        let mut blinded = self.blinded.lock().unwrap();
+        if blinded.is_none() {
+            *blinded = Some(read_meta(fs, &self.meta_key(), BLIND_PATH)?.unwrap_or_default());
+        }
+        Ok(blinded)
+    }
+
+    /// Returns true if file names on disk are a keyed hash of the
+    /// object id rather than the id itself.
+    pub fn blinds_object_ids(&self) -> bool {
+        self.id_key.is_some()
+    }
+
+    /// Returns the file name of an object.
+    pub(crate) fn encode_obj_id(&self, obj_id: u128) -> EncodedObjectId {
+        let name = match &self.id_key {
+            Some(id_key) => blind(id_key, obj_id),
+            None => obj_id,
+        };
+        format!("{:0>32x}", name)
+    }
+
+    /// Path of an object's file relative to the root directory.
+    pub(crate) fn object_path(&self, obj_id: u128) -> String {
+        let b64 = self.encode_obj_id(obj_id);
+        format!("ids/{}/{}", &b64[0..1], b64)
+    }
+
+    /// Returns the object a file name stands for, or `None` if it isn't
+    /// the name of an object.
+    pub(crate) fn decode_obj_name(&self, fs: &FatFs<D>, name: &str) -> Result<Option<u128>, Error> {
+        if name.len() != 32 {
+            return Ok(None);
+        }
+        let Ok(name) = u128::from_str_radix(name, 16) else {
+            return Ok(None);
+        };
+        if self.id_key.is_none() {
+            return Ok(Some(name));
+        }
+        let blinded = self.blind_lock(fs)?;
+        Ok(blinded.as_ref().unwrap().ids.get(&name).copied())
+    }
+
+    /// Records the blinded name of an object that is being given a
+    /// file.
+    pub(crate) fn remember_obj_name(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let Some(id_key) = &self.id_key else {
+            return Ok(());
+        };
+        let mut blinded = self.blind_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            BLIND_PATH,
+            blinded.as_mut().unwrap(),
+            |blinded| blinded.ids.insert(blind(id_key, obj_id), obj_id).is_none(),
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn forget_obj_name(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let Some(id_key) = &self.id_key else {
+            return Ok(());
+        };
+        let mut blinded = self.blind_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            BLIND_PATH,
+            blinded.as_mut().unwrap(),
+            |blinded| blinded.ids.remove(&blind(id_key, obj_id)).is_some(),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::wrapped_extent::WrappedExtent;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+use zeroize::Zeroizing;
+
+/// In-memory cache of derived chunk keys. Keys are zeroed when they
+/// are evicted.
+pub(crate) struct KeyCache {
+    keys: Mutex<HashMap<u64, Zeroizing<[u8; 32]>>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, chunk_id: u64) -> Option<[u8; 32]> {
+        self.keys.lock().unwrap().get(&chunk_id).map(|key| **key)
+    }
+
+    pub fn insert(&self, chunk_id: u64, key: [u8; 32]) {
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(chunk_id, Zeroizing::new(key));
+    }
+
+    pub fn remove(&self, chunk_id: u64) {
+        self.keys.lock().unwrap().remove(&chunk_id);
+    }
+
+    /// Drops every cached key. Must be called whenever keys are
+    /// rotated.
+    pub fn clear(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+}
+
+/// In-memory cache of the extents that make up each object.
+pub(crate) struct ExtentCache {
+    extents: Mutex<HashMap<u128, HashSet<WrappedExtent>>>,
+}
+
+impl ExtentCache {
+    pub fn new() -> Self {
+        Self {
+            extents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, obj_id: u128) -> Option<HashSet<WrappedExtent>> {
+        self.extents.lock().unwrap().get(&obj_id).cloned()
+    }
+
+    pub fn insert(&self, obj_id: u128, extents: HashSet<WrappedExtent>) {
+        self.extents.lock().unwrap().insert(obj_id, extents);
+    }
+
+    pub fn remove(&self, obj_id: u128) {
+        self.extents.lock().unwrap().remove(&obj_id);
+    }
+
+    pub fn clear(&self) {
+        self.extents.lock().unwrap().clear();
+    }
+}
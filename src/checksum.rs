@@ -0,0 +1,52 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Which checksum to compute over written data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    /// XXH3, 64-bit. Much faster but not cryptographic.
+    Xxh3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    Sha256([u8; 32]),
+    Xxh3(u64),
+}
+
+impl ChecksumAlgorithm {
+    pub fn checksum(self, data: &[u8]) -> Checksum {
+        match self {
+            ChecksumAlgorithm::Sha256 => Checksum::Sha256(Sha256::digest(data).into()),
+            ChecksumAlgorithm::Xxh3 => Checksum::Xxh3(xxh3_64(data)),
+        }
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Like `write_all`, but also returns a checksum of the plaintext
+    /// that was written so that callers checking end-to-end integrity
+    /// don't have to read it back.
+    pub fn write_all_checksummed(
+        &self,
+        obj_id: u128,
+        buf: &[u8],
+        off: u64,
+        algorithm: ChecksumAlgorithm,
+    ) -> Result<Checksum, ObjectStoreError> {
+        let checksum = algorithm.checksum(buf);
+        self.write_all(obj_id, buf, off)?;
+        Ok(checksum)
+    }
+}
@@ -0,0 +1,46 @@
+use crate::meta::derive_subkey;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, ops::Range};
+
+pub(crate) const CHUNK_GENERATIONS_PATH: &str = "meta/chunk_generations";
+const CHUNK_KEY_LABEL: &[u8] = b"object-store chunk generation key";
+
+/// How many times each chunk of a volume keyed store has been freed.
+/// Every chunk shares the volume key, so a chunk's generation is folded
+/// into its key to keep what is written after the chunk is reused off
+/// the keystream its old contents were encrypted under.
+///
+/// Loaded when the store is opened rather than on first use, since keys
+/// are looked up from inside fatfs, where the table can't be read.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChunkGenerations {
+    generations: BTreeMap<u64, u64>,
+}
+
+impl ChunkGenerations {
+    /// Moves `ids` on to their next generation. Returns false if there
+    /// were none.
+    pub fn bump(&mut self, ids: Range<u64>) -> bool {
+        let bumped = !ids.is_empty();
+        for id in ids {
+            *self.generations.entry(id).or_default() += 1;
+        }
+        bumped
+    }
+
+    /// Returns the key of `chunk_id` given the key the KMS derived for
+    /// it. Chunks that were never freed keep that key, so that volumes
+    /// written before generations existed stay readable.
+    pub fn key(&self, chunk_id: u64, key: [u8; 32]) -> [u8; 32] {
+        let Some(generation) = self.generations.get(&chunk_id) else {
+            return key;
+        };
+        let label = [
+            CHUNK_KEY_LABEL,
+            &chunk_id.to_le_bytes(),
+            &generation.to_le_bytes(),
+        ]
+        .concat();
+        derive_subkey(key, &label)
+    }
+}
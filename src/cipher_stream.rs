@@ -0,0 +1,67 @@
+use crate::{fs::Disk, ObjectStore};
+use chacha20::ChaCha20;
+use fatfs::IoBase;
+use std::io::Error;
+
+/// The cipher of the chunk a proxy last touched. fatfs calls the proxy
+/// once per sector, so reusing the cipher for the next sector of the
+/// same chunk saves a key lookup and a ChaCha20 setup per sector.
+#[derive(Default)]
+pub(crate) struct CipherStream {
+    cipher: Option<ChaCha20>,
+    chunk_id: u64,
+    /// The disk offset the cipher's keystream is positioned at.
+    next_offset: u64,
+    /// Set when the stream only decrypts, so keys can be derived
+    /// without logging them.
+    reads: bool,
+}
+
+impl CipherStream {
+    pub fn for_reads() -> Self {
+        Self {
+            reads: true,
+            ..Default::default()
+        }
+    }
+
+    /// Forgets the cipher, so the next access sets up a fresh one.
+    pub fn reset(&mut self) {
+        self.cipher = None;
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the cipher for `len` bytes at `disk_offset`, continuing
+    /// the cipher in `stream` if the access picks up where the last one
+    /// left off in the same chunk. Returns `None` in plaintext mode.
+    pub(crate) fn stream_cipher<'a>(
+        &self,
+        stream: &'a mut CipherStream,
+        disk_offset: u64,
+        len: usize,
+    ) -> Result<Option<&'a mut ChaCha20>, Error> {
+        let chunk_id = self.layout.chunk_id(disk_offset);
+        let contiguous = stream.cipher.is_some()
+            && stream.next_offset == disk_offset
+            && stream.chunk_id == chunk_id;
+        if !contiguous {
+            stream.cipher = if stream.reads {
+                self.read_cipher(disk_offset)?
+            } else {
+                self.get_symmetric_cipher(disk_offset)?
+            };
+            stream.chunk_id = chunk_id;
+        }
+        stream.next_offset = disk_offset + len as u64;
+        Ok(stream.cipher.as_mut())
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{flags::ObjectFlags, fs::Disk, FormatOptions, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::ops::Bound;
+
+/// Objects are copied through a buffer of this size.
+const CHUNK_LEN: usize = 64 * 1024;
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Formats `disk` with `options` and copies every object into it,
+    /// encrypted under `root_key`, along with its tags, flags, seal,
+    /// expiry and index entries and the config id. Returns the new
+    /// store.
+    ///
+    /// Only live objects are copied, so nothing that was deleted,
+    /// trashed or left behind by old epochs reaches the new disk, which
+    /// can be smaller than this one. Chunks that are all zeros are
+    /// skipped and left unwritten by the next write past them.
+    /// Namespaces are recreated with fresh keys. Writes made while the
+    /// clone runs may or may not be copied.
+    pub fn clone_into(
+        &self,
+        disk: D,
+        root_key: [u8; 32],
+        options: FormatOptions,
+    ) -> Result<ObjectStore<D>, ObjectStoreError> {
+        let target = ObjectStore::format(disk, root_key, options)?;
+        let mut buf = vec![0u8; CHUNK_LEN];
+        for obj_id in self.get_all_object_ids()? {
+            target.create_object_excl(obj_id)?;
+            let len = self.disk_length(obj_id)?;
+            let mut done = 0;
+            let mut skipped = false;
+            while done < len {
+                let n = CHUNK_LEN.min((len - done) as usize);
+                self.read_exact(obj_id, &mut buf[..n], done)?;
+                skipped = buf[..n].iter().all(|b| *b == 0);
+                if !skipped {
+                    target.write_all(obj_id, &buf[..n], done)?;
+                }
+                done += n as u64;
+            }
+            if skipped {
+                target.write_all(obj_id, &[0], len - 1)?;
+            }
+            for tag in self.tags(obj_id) {
+                target.add_tag(obj_id, tag)?;
+            }
+            if let Some(deadline) = self.expiry(obj_id)? {
+                target.set_expiry(obj_id, deadline)?;
+            }
+            // only now, since immutable and sealed objects reject writes.
+            if self.sealed_hash(obj_id)?.is_some() {
+                target.seal_object(obj_id)?;
+            }
+            let flags = self.flags(obj_id)?;
+            if !flags.difference(ObjectFlags::SEALED).is_empty() {
+                target.set_flags(obj_id, flags)?;
+            }
+        }
+        for (key, obj_id) in self.index_range(Bound::Unbounded, Bound::Unbounded)? {
+            target.index_insert(&key, obj_id)?;
+        }
+        for label in self.namespaces()? {
+            target.create_namespace(&label)?;
+        }
+        if let Some(config_id) = self.get_config_id()? {
+            target.set_config_id(config_id)?;
+        }
+        Ok(target)
+    }
+
+    /// Provisions a node from a golden image: opens the template store
+    /// on `template_disk` and clones it into `new_disk` under
+    /// `new_root_key`, formatted like the template. The new store has
+    /// its own id and KHF, so nodes provisioned from the same template
+    /// share no key material with it or with each other.
+    ///
+    /// The template is closed again afterwards, and is left as it was
+    /// apart from its mount marker.
+    pub fn provision_from_template(
+        template_disk: D,
+        template_root_key: [u8; 32],
+        new_disk: D,
+        new_root_key: [u8; 32],
+    ) -> Result<ObjectStore<D>, ObjectStoreError> {
+        let template = ObjectStore::open(template_disk, template_root_key)?;
+        let options = template.format_options();
+        let target = template.clone_into(new_disk, new_root_key, options)?;
+        template.close()?;
+        Ok(target)
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use sha3::{Digest, Sha3_256};
+use std::io::{Error, ErrorKind};
+
+/// Derives the id of a content-addressed object from its contents.
+pub fn content_id(data: &[u8]) -> u128 {
+    let hash = Sha3_256::digest(data);
+    u128::from_be_bytes(hash[..16].try_into().unwrap())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Stores `data` as a sealed object whose id is derived from the
+    /// SHA3-256 hash of `data`. Putting the same data again returns the
+    /// existing object without writing anything.
+    ///
+    /// # Errors
+    /// `AlreadyExists` if a different object already uses the id,
+    /// including one that is still being put by another caller.
+    pub fn put_content_addressed(&self, data: &[u8]) -> Result<u128, ObjectStoreError> {
+        let hash: [u8; 32] = Sha3_256::digest(data).into();
+        let obj_id = content_id(data);
+        if !self.create_object(obj_id)? {
+            return match self.sealed_hash(obj_id)? {
+                Some(sealed) if sealed == hash => Ok(obj_id),
+                _ => Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "another object already uses this content id",
+                )
+                .into()),
+            };
+        }
+        self.write_all(obj_id, data, 0)?;
+        if self.seal_object(obj_id)? != hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "object changed while it was being put",
+            )
+            .into());
+        }
+        Ok(obj_id)
+    }
+}
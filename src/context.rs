@@ -0,0 +1,171 @@
+use std::{fmt, io::Error};
+
+/// The step of an operation during which an error occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Lookup,
+    Seek,
+    ExtentScan,
+    DeriveKey,
+    DiskRead,
+    DiskWrite,
+    Read,
+    Write,
+    KeyUpdate,
+    ReEncrypt,
+    PersistKhf,
+    ClearWal,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Phase::Lookup => "object lookup",
+            Phase::Seek => "seek",
+            Phase::ExtentScan => "extent scan",
+            Phase::DeriveKey => "key derivation",
+            Phase::DiskRead => "disk read",
+            Phase::DiskWrite => "disk write",
+            Phase::Read => "read",
+            Phase::Write => "write",
+            Phase::KeyUpdate => "key update",
+            Phase::ReEncrypt => "re-encryption",
+            Phase::PersistKhf => "khf persist",
+            Phase::ClearWal => "wal clear",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Describes where an error happened: which phase, which object, and
+/// which object/disk offsets were involved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub phase: Phase,
+    pub obj_id: Option<u128>,
+    pub offset: Option<u64>,
+    pub disk_offset: Option<u64>,
+}
+
+impl ErrorContext {
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            phase,
+            obj_id: None,
+            offset: None,
+            disk_offset: None,
+        }
+    }
+
+    pub fn object(mut self, obj_id: u128) -> Self {
+        self.obj_id = Some(obj_id);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn disk_offset(mut self, disk_offset: u64) -> Self {
+        self.disk_offset = Some(disk_offset);
+        self
+    }
+
+    /// Fills in any fields that are missing from `self` with the ones
+    /// from `outer`. The phase of `self` is kept since it is the more
+    /// specific of the two.
+    fn merge(&mut self, outer: &ErrorContext) {
+        self.obj_id = self.obj_id.or(outer.obj_id);
+        self.offset = self.offset.or(outer.offset);
+        self.disk_offset = self.disk_offset.or(outer.disk_offset);
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.phase)?;
+        if let Some(obj_id) = self.obj_id {
+            write!(f, " for object {:0>32x}", obj_id)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(disk_offset) = self.disk_offset {
+            write!(f, " (disk offset {})", disk_offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error annotated with the context it was raised in. Stored as the
+/// inner error of a `std::io::Error` with the same `ErrorKind` as the
+/// source so that existing kind checks keep working.
+#[derive(Debug)]
+pub struct ContextError {
+    context: ErrorContext,
+    source: Error,
+}
+
+impl ContextError {
+    pub fn context(&self) -> &ErrorContext {
+        &self.context
+    }
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.context, self.source)
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Returns the context attached to `err`, if any.
+pub fn error_context(err: &Error) -> Option<&ErrorContext> {
+    err.get_ref()?
+        .downcast_ref::<ContextError>()
+        .map(ContextError::context)
+}
+
+/// Attaches `context` to `err`. If `err` already carries a context then
+/// the two are merged instead of being nested.
+pub fn add_context(err: Error, context: ErrorContext) -> Error {
+    let kind = err.kind();
+    if err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<ContextError>())
+    {
+        let mut inner = err
+            .into_inner()
+            .unwrap()
+            .downcast::<ContextError>()
+            .unwrap();
+        inner.context.merge(&context);
+        return Error::new(kind, inner);
+    }
+    Error::new(
+        kind,
+        ContextError {
+            context,
+            source: err,
+        },
+    )
+}
+
+pub(crate) trait ResultExt<T> {
+    fn context(self, context: ErrorContext) -> Result<T, Error>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    Error: From<E>,
+{
+    fn context(self, context: ErrorContext) -> Result<T, Error> {
+        self.map_err(|e| add_context(e.into(), context))
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    fs::Disk, wal_log::WalOp, wrapped_extent::WrappedExtent, ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{collections::BTreeSet, io::Write, sync::atomic::Ordering};
+
+/// Which chunks the KHF keys and which old keys it has yet to forget,
+/// without any key material, so that secure deletion can be checked
+/// from outside the store. obliviate keeps the shape of its key forest
+/// private, so coverage is given per chunk, as the store tracks it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyCoverage {
+    /// Epochs the store has been through.
+    pub epoch: u64,
+    pub chunk_size: u64,
+    /// Chunks holding object data.
+    pub live: BTreeSet<u64>,
+    /// Chunks whose keys were used since the last epoch, which rotates
+    /// them.
+    pub logged: BTreeSet<u64>,
+    /// Chunks whose keys were deleted since the last epoch.
+    pub deleted: BTreeSet<u64>,
+    /// Chunks a failed epoch has rotated the keys of but not yet
+    /// re-encrypted, whose old keys are still held in memory.
+    pub rotating: BTreeSet<u64>,
+    /// The WAL holds entries from an earlier session, which obliviate
+    /// doesn't let the store list, so `logged` and `deleted` may be
+    /// missing chunks until the next epoch.
+    pub incomplete: bool,
+}
+
+impl KeyCoverage {
+    /// Whether a key `chunk_id` had before the last epoch may still be
+    /// recovered. Once this is false for every chunk of a deleted
+    /// object, its data is securely deleted.
+    pub fn old_key_recoverable(&self, chunk_id: u64) -> bool {
+        self.incomplete
+            || self.logged.contains(&chunk_id)
+            || self.deleted.contains(&chunk_id)
+            || self.rotating.contains(&chunk_id)
+    }
+
+    /// Writes an `epoch,chunk_id,state` line per chunk and state, where
+    /// the state is `live`, `logged`, `deleted` or `rotating`.
+    pub fn export(&self, mut out: impl Write) -> Result<(), ObjectStoreError> {
+        writeln!(out, "epoch,chunk_id,state")?;
+        for (state, chunks) in [
+            ("live", &self.live),
+            ("logged", &self.logged),
+            ("deleted", &self.deleted),
+            ("rotating", &self.rotating),
+        ] {
+            for chunk_id in chunks {
+                writeln!(out, "{},{chunk_id},{state}", self.epoch)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the chunks holding an object's data, to check against a
+    /// later `key_coverage` once it has been deleted.
+    pub fn object_chunks(&self, obj_id: u128) -> Result<BTreeSet<u64>, ObjectStoreError> {
+        Ok(self
+            .get_obj_segments(obj_id)?
+            .iter()
+            .flat_map(WrappedExtent::page_offsets)
+            .map(|page| self.layout.chunk_id(page))
+            .collect())
+    }
+
+    /// Returns which chunks are keyed and which old keys are yet to be
+    /// forgotten. Empty apart from `live` for stores that aren't keyed
+    /// by a KHF.
+    pub fn key_coverage(&self) -> Result<KeyCoverage, ObjectStoreError> {
+        let mut coverage = KeyCoverage {
+            epoch: self.generation.load(Ordering::Relaxed),
+            chunk_size: self.layout.chunk_size(),
+            ..Default::default()
+        };
+        for obj_id in self.get_all_object_ids()? {
+            coverage.live.extend(self.object_chunks(obj_id)?);
+        }
+        if let Some((entries, inherited_bytes)) =
+            self.wal_journal(|journal| (journal.entries(), journal.inherited_bytes))?
+        {
+            for entry in entries {
+                match entry.op {
+                    WalOp::Derive => coverage.logged.insert(entry.chunk_id),
+                    WalOp::Delete => coverage.deleted.insert(entry.chunk_id),
+                };
+            }
+            coverage.incomplete = inherited_bytes > 0;
+        }
+        if let Some(pending) = self.pending_epoch.lock().unwrap().as_ref() {
+            coverage.rotating.extend(pending.remaining.keys());
+        }
+        Ok(coverage)
+    }
+}
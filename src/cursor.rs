@@ -0,0 +1,64 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+
+/// A position in a sweep over every object in id order. Cursors can
+/// be persisted to resume a sweep after a restart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The last id handed out, or `None` before the first page.
+    after: Option<u128>,
+    done: bool,
+}
+
+impl Cursor {
+    /// A cursor at the start of the sweep.
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Whether every object has been listed.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns up to `limit` object ids after `cursor` in ascending
+    /// order, along with the cursor to pass for the next page.
+    ///
+    /// Pages are keyed by object id rather than by position, so an
+    /// object that exists for the whole sweep is listed exactly once no
+    /// matter what is created or unlinked in between. Objects created
+    /// or unlinked during the sweep may or may not be listed.
+    pub fn list_after(
+        &self,
+        cursor: Cursor,
+        limit: usize,
+    ) -> Result<(Vec<u128>, Cursor), ObjectStoreError> {
+        if cursor.done {
+            return Ok((Vec::new(), cursor));
+        }
+        let mut ids = {
+            let fs = self.fs().lock().unwrap();
+            self.object_ids_locked(&fs)?
+        };
+        ids.retain(|id| cursor.after.is_none_or(|after| *id > after));
+        ids.sort_unstable();
+        let done = ids.len() <= limit;
+        ids.truncate(limit);
+        let next = Cursor {
+            after: ids.last().copied().or(cursor.after),
+            done,
+        };
+        Ok((ids, next))
+    }
+}
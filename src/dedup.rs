@@ -0,0 +1,293 @@
+use crate::{
+    eof::check_in_bounds,
+    flags::ObjectFlags,
+    fs::{Disk, FatFile, FatFs, PAGE_SIZE},
+    meta::{read_meta, write_meta},
+    object_store::get_dir_path,
+    wrapped_extent::WrappedExtent,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::{IoBase, Seek, SeekFrom};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{collections::BTreeMap, io::Error, sync::MutexGuard};
+
+pub(crate) const DEDUP_PATH: &str = "meta/dedup";
+const POOL_DIR: &str = "dedup";
+/// File holding every shared page, one page per slot.
+const POOL_PATH: &str = "dedup/pool";
+
+pub(crate) type Fingerprint = [u8; 32];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SharedPage {
+    slot: u64,
+    refs: u64,
+}
+
+/// A deduplicated object is its length and the fingerprint of each of
+/// its pages.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DedupObject {
+    len: u64,
+    pages: Vec<Fingerprint>,
+}
+
+/// Refcounted map from page fingerprints to slots in the page pool.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DedupIndex {
+    pages: BTreeMap<Fingerprint, SharedPage>,
+    free: Vec<u64>,
+    next_slot: u64,
+    objects: BTreeMap<u128, DedupObject>,
+}
+
+impl DedupIndex {
+    /// Takes a reference to the page with `fingerprint`, returning its
+    /// slot and whether the slot is new and still has to be written.
+    fn acquire(&mut self, fingerprint: Fingerprint) -> (u64, bool) {
+        if let Some(page) = self.pages.get_mut(&fingerprint) {
+            page.refs += 1;
+            return (page.slot, false);
+        }
+        let slot = self.free.pop().unwrap_or_else(|| {
+            self.next_slot += 1;
+            self.next_slot - 1
+        });
+        self.pages.insert(fingerprint, SharedPage { slot, refs: 1 });
+        (slot, true)
+    }
+
+    /// Drops a reference to a page, returning its slot if it is no
+    /// longer used.
+    fn release(&mut self, fingerprint: &Fingerprint) -> Option<u64> {
+        let page = self.pages.get_mut(fingerprint)?;
+        page.refs -= 1;
+        if page.refs > 0 {
+            return None;
+        }
+        let slot = self.pages.remove(fingerprint)?.slot;
+        self.free.push(slot);
+        Some(slot)
+    }
+}
+
+/// How much space deduplication is saving.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Pages referenced by deduplicated objects.
+    pub logical_pages: u64,
+    /// Distinct pages actually stored.
+    pub stored_pages: u64,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn dedup_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<DedupIndex>>, Error> {
+        let mut dedup = self.dedup.lock().unwrap();
+        if dedup.is_none() {
+            *dedup = Some(read_meta(fs, &self.meta_key(), DEDUP_PATH)?.unwrap_or_default());
+        }
+        Ok(dedup)
+    }
+
+    /// Fingerprints are keyed so that they don't reveal page contents.
+    pub(crate) fn fingerprint(&self, page: &[u8]) -> Fingerprint {
+        let mut hasher = Sha3_256::new();
+        hasher.update(self.meta_key());
+        hasher.update(page);
+        hasher.finalize().into()
+    }
+
+    /// Moves an object into deduplicated storage, where each of its
+    /// pages is shared with every other deduplicated page holding the
+    /// same plaintext. Writes to a shared page copy it out first.
+    pub fn enable_dedup(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        let mut fs = self.fs().lock().unwrap();
+        if self.is_deduplicated_locked(&fs, obj_id)? {
+            return Ok(());
+        }
+        self.check_flags(&fs, obj_id, ObjectFlags::IMMUTABLE)?;
+        // dedup writes don't maintain page MACs.
+        self.forget_page_macs(&fs, obj_id)?;
+        let contents = self.read_all_locked(&mut fs, obj_id)?;
+        self.dedup_lock(&fs)?
+            .as_mut()
+            .unwrap()
+            .objects
+            .insert(obj_id, DedupObject::default());
+        self.dedup_write(&fs, obj_id, &contents, 0)?;
+        // the old copy of the data is no longer needed.
+        let b64 = self.encode_obj_id(obj_id);
+        let mut file = get_dir_path(&fs, &b64)?.open_file(&b64)?;
+        Ok(self.discard_contents(&fs, &mut file, obj_id)?)
+    }
+
+    pub fn is_deduplicated(&self, obj_id: u128) -> Result<bool, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        Ok(self.is_deduplicated_locked(&fs, obj_id)?)
+    }
+
+    pub(crate) fn is_deduplicated_locked(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+    ) -> Result<bool, Error> {
+        Ok(self.dedup_len_locked(fs, obj_id)?.is_some())
+    }
+
+    /// Returns the length of a deduplicated object, or `None` if the
+    /// object isn't deduplicated.
+    pub(crate) fn dedup_len_locked(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+    ) -> Result<Option<u64>, Error> {
+        let dedup = self.dedup_lock(fs)?;
+        Ok(dedup
+            .as_ref()
+            .unwrap()
+            .objects
+            .get(&obj_id)
+            .map(|object| object.len))
+    }
+
+    pub fn dedup_stats(&self) -> Result<DedupStats, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let dedup = self.dedup_lock(&fs)?;
+        let dedup = dedup.as_ref().unwrap();
+        Ok(DedupStats {
+            logical_pages: dedup
+                .objects
+                .values()
+                .map(|object| object.pages.len() as u64)
+                .sum(),
+            stored_pages: dedup.pages.len() as u64,
+        })
+    }
+
+    fn read_slot(
+        &self,
+        pool: &mut FatFile<'_, D>,
+        slot: u64,
+        page: &mut [u8],
+    ) -> Result<(), Error> {
+        pool.seek(SeekFrom::Start(slot * PAGE_SIZE as u64))?;
+        self.read_file(pool, page)
+    }
+
+    /// Deletes the key of a slot that is no longer used.
+    fn free_slot(&self, fs: &FatFs<D>, pool: &mut FatFile<'_, D>, slot: u64) -> Result<(), Error> {
+        let mut pos = slot * PAGE_SIZE as u64;
+        for extent in pool.extents() {
+            let extent = WrappedExtent::from(extent?);
+            if pos < extent.size() {
+                return self.delete_chunk_key(fs, extent.offset() + pos);
+            }
+            pos -= extent.size();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dedup_read(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), Error> {
+        let dedup = self.dedup_lock(fs)?;
+        let dedup = dedup.as_ref().unwrap();
+        let object = &dedup.objects[&obj_id];
+        check_in_bounds(obj_id, off, buf.len(), object.len)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut pool = fs.root_dir().open_file(POOL_PATH)?;
+        let mut page = vec![0u8; PAGE_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let n = (PAGE_SIZE - in_page).min(buf.len() - done);
+            let fingerprint = &object.pages[(pos / PAGE_SIZE as u64) as usize];
+            self.read_slot(&mut pool, dedup.pages[fingerprint].slot, &mut page)?;
+            buf[done..done + n].copy_from_slice(&page[in_page..in_page + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dedup_write(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        buf: &[u8],
+        off: u64,
+    ) -> Result<(), Error> {
+        let mut dedup = self.dedup_lock(fs)?;
+        let dedup = dedup.as_mut().unwrap();
+        let mut object = dedup.objects.remove(&obj_id).unwrap_or_default();
+        fs.root_dir().create_dir(POOL_DIR)?;
+        let mut pool = fs.root_dir().create_file(POOL_PATH)?;
+        let page_size = PAGE_SIZE as u64;
+        let end = off + buf.len() as u64;
+        let len = object.len.max(end);
+        let first = (off / page_size).min(object.pages.len() as u64);
+        let mut page = vec![0u8; PAGE_SIZE];
+        for idx in first..len.div_ceil(page_size) {
+            let page_start = idx * page_size;
+            let existing = object.pages.get(idx as usize).copied();
+            page.fill(0);
+            if let Some(fingerprint) = &existing {
+                self.read_slot(&mut pool, dedup.pages[fingerprint].slot, &mut page)?;
+            }
+            if page_start < end && off < page_start + page_size {
+                let from = off.max(page_start);
+                let to = end.min(page_start + page_size);
+                page[(from - page_start) as usize..(to - page_start) as usize]
+                    .copy_from_slice(&buf[(from - off) as usize..(to - off) as usize]);
+            }
+            let fingerprint = self.fingerprint(&page);
+            let (slot, new) = dedup.acquire(fingerprint);
+            if new {
+                pool.seek(SeekFrom::Start(slot * page_size))?;
+                self.write_file(&mut pool, &page)?;
+            }
+            match existing {
+                Some(old) => {
+                    object.pages[idx as usize] = fingerprint;
+                    if let Some(freed) = dedup.release(&old) {
+                        self.free_slot(fs, &mut pool, freed)?;
+                    }
+                }
+                None => object.pages.push(fingerprint),
+            }
+        }
+        object.len = len;
+        dedup.objects.insert(obj_id, object);
+        write_meta(fs, &self.meta_key(), DEDUP_PATH, &*dedup)
+    }
+
+    pub(crate) fn forget_dedup(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut dedup = self.dedup_lock(fs)?;
+        let dedup = dedup.as_mut().unwrap();
+        let Some(object) = dedup.objects.remove(&obj_id) else {
+            return Ok(());
+        };
+        let mut pool = fs.root_dir().open_file(POOL_PATH)?;
+        for fingerprint in &object.pages {
+            if let Some(freed) = dedup.release(fingerprint) {
+                self.free_slot(fs, &mut pool, freed)?;
+            }
+        }
+        write_meta(fs, &self.meta_key(), DEDUP_PATH, &*dedup)
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    fs::{Disk, FatFlavor},
+    header::CipherSuite,
+    mac::IntegrityHash,
+    metadata_disk::MetadataPlacement,
+    superblock::KeyMode,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+
+/// How a store was formatted and what state it is in, for tools and
+/// bug reports. Holds no key material or object data.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreDescription {
+    pub uuid: u128,
+    pub key_mode: KeyMode,
+    pub cipher_suite: CipherSuite,
+    pub integrity_hash: IntegrityHash,
+    pub fat_flavor: FatFlavor,
+    pub blind_ids: bool,
+    pub chunk_size: u64,
+    pub cluster_offset: u64,
+    pub metadata_placement: MetadataPlacement,
+    /// Bytes of the disk the store may use.
+    pub capacity: u64,
+    /// The number of epochs the store has been through.
+    pub epoch: u64,
+    /// Namespace labels, in label order.
+    pub namespaces: Vec<String>,
+    pub health: StoreHealth,
+}
+
+/// Conditions worth a look in a `StoreDescription`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreHealth {
+    /// The FAT volume recorded an I/O error.
+    pub volume_io_error: bool,
+    pub frozen: bool,
+    /// An epoch failed after rotating its keys, and has to be retried.
+    pub epoch_paused: bool,
+    /// Chunk keys deleted since the last epoch, which aren't securely
+    /// deleted yet.
+    pub pending_key_deletions: u64,
+    /// The key metadata has outgrown the quota, so epochs are refused.
+    pub over_metadata_quota: bool,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Describes the store.
+    pub fn describe(&self) -> Result<StoreDescription, ObjectStoreError> {
+        let volume_io_error = self.fs().lock().unwrap().read_status_flags()?.io_error();
+        let over_metadata_quota = match self.metadata_quota() {
+            Some(quota) => self.metadata_usage()?.total() > quota,
+            None => false,
+        };
+        Ok(StoreDescription {
+            uuid: self.store_uuid(),
+            key_mode: self.key_mode(),
+            cipher_suite: CipherSuite::for_key_mode(self.key_mode()),
+            integrity_hash: self.integrity_hash(),
+            fat_flavor: self.fat_flavor(),
+            blind_ids: self.blinds_object_ids(),
+            chunk_size: self.layout.chunk_size(),
+            cluster_offset: self.layout.cluster_offset(),
+            metadata_placement: self.metadata_placement(),
+            capacity: self.capacity()?,
+            epoch: self.generation.load(std::sync::atomic::Ordering::Relaxed),
+            namespaces: self.namespaces()?,
+            health: StoreHealth {
+                volume_io_error,
+                frozen: self.is_frozen(),
+                epoch_paused: self.epoch_paused(),
+                pending_key_deletions: self.pending_key_deletions(),
+                over_metadata_quota,
+            },
+        })
+    }
+}
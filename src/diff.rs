@@ -0,0 +1,250 @@
+use crate::{
+    dedup::Fingerprint,
+    fs::{Disk, PAGE_SIZE},
+    meta::{seal, unseal},
+    snapshot::{invalid, read_array, unwrap_key, Counting, Snapshot, NONCE_LEN, TAG_LEN},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Read, Write},
+};
+
+const MAGIC: &[u8; 8] = b"TOSDIFF\0";
+const VERSION: u32 = 1;
+/// Magic, version and the sealed archive key.
+const HEADER_LEN: usize = 8 + 4 + NONCE_LEN + 32 + TAG_LEN;
+/// Pages read from an object at a time.
+const PAGES_PER_READ: usize = 16;
+
+/// The length and page fingerprints of an object at the time of an
+/// export.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ObjectManifest {
+    len: u64,
+    pages: Vec<Fingerprint>,
+}
+
+/// What the receiving store held after the last diff was applied. The
+/// sender keeps it to export only what changed since. An empty
+/// manifest exports everything.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    objects: BTreeMap<u128, ObjectManifest>,
+}
+
+impl Manifest {
+    pub fn object_ids(&self) -> impl Iterator<Item = u128> + '_ {
+        self.objects.keys().copied()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DiffRecord {
+    /// Creates the object if needed, first emptying it if `truncate` is
+    /// set since objects can't shrink in place.
+    Object {
+        obj_id: u128,
+        truncate: bool,
+    },
+    Page {
+        obj_id: u128,
+        index: u64,
+        data: Vec<u8>,
+    },
+    Delete(u128),
+    End,
+}
+
+/// Records carry a sequence number so that dropped or reordered
+/// records are detected.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sequenced {
+    seq: u64,
+    record: DiffRecord,
+}
+
+/// What `apply_diff` changed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    pub objects_changed: u64,
+    pub pages_written: u64,
+    pub objects_deleted: u64,
+}
+
+struct DiffWriter<W> {
+    out: Counting<W>,
+    key: [u8; 32],
+    seq: u64,
+}
+
+impl<W: Write> DiffWriter<W> {
+    fn record(&mut self, record: DiffRecord) -> Result<(), Error> {
+        let plaintext = bincode::serialize(&Sequenced {
+            seq: self.seq,
+            record,
+        })
+        .map_err(Error::other)?;
+        let sealed = seal(&self.key, &plaintext);
+        self.out.put(&(sealed.len() as u32).to_le_bytes())?;
+        self.out.put(&sealed)?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+impl<D> Snapshot<'_, D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Streams the changes since `base` into `writer` for `apply_diff`
+    /// on another store, returning the manifest to diff against next
+    /// time. Pages whose fingerprint changed are sent whole; objects
+    /// that shrank are sent in full.
+    pub fn export_diff(
+        &mut self,
+        base: &Manifest,
+        writer: impl Write,
+        wrap_key: &[u8; 32],
+    ) -> Result<Manifest, Error> {
+        let key: [u8; 32] = rand::random();
+        let mut out = DiffWriter {
+            out: Counting {
+                inner: writer,
+                pos: 0,
+            },
+            key,
+            seq: 0,
+        };
+        out.out.put(MAGIC)?;
+        out.out.put(&VERSION.to_le_bytes())?;
+        out.out.put(&seal(wrap_key, &key))?;
+        let mut manifest = Manifest::default();
+        let ids = self.object_ids()?;
+        for obj_id in base.object_ids() {
+            if !ids.contains(&obj_id) {
+                out.record(DiffRecord::Delete(obj_id))?;
+            }
+        }
+        let mut buf = vec![0u8; PAGE_SIZE * PAGES_PER_READ];
+        for obj_id in ids {
+            let len = self.os.object_len_locked(&mut self.fs, obj_id)?;
+            let old = base.objects.get(&obj_id);
+            let truncate = old.is_none_or(|old| len < old.len);
+            let mut announced = false;
+            if truncate || old.is_some_and(|old| old.len != len) {
+                out.record(DiffRecord::Object { obj_id, truncate })?;
+                announced = true;
+            }
+            let mut current = ObjectManifest {
+                len,
+                pages: Vec::new(),
+            };
+            let mut off = 0;
+            while off < len {
+                let n = buf.len().min((len - off) as usize);
+                self.os
+                    .read_locked(&mut self.fs, obj_id, &mut buf[..n], off)?;
+                for page in buf[..n].chunks(PAGE_SIZE) {
+                    let index = current.pages.len();
+                    let fingerprint = self.os.fingerprint(page);
+                    let unchanged =
+                        !truncate && old.and_then(|old| old.pages.get(index)) == Some(&fingerprint);
+                    if !unchanged {
+                        if !announced {
+                            out.record(DiffRecord::Object { obj_id, truncate })?;
+                            announced = true;
+                        }
+                        out.record(DiffRecord::Page {
+                            obj_id,
+                            index: index as u64,
+                            data: page.to_vec(),
+                        })?;
+                    }
+                    current.pages.push(fingerprint);
+                }
+                off += n as u64;
+            }
+            manifest.objects.insert(obj_id, current);
+        }
+        out.record(DiffRecord::End)?;
+        out.out.inner.flush()?;
+        Ok(manifest)
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Applies a diff written by `Snapshot::export_diff`. Records are
+    /// applied as they are read, so a diff that turns out to be
+    /// truncated or tampered with may leave some of its changes
+    /// applied; re-sending it from the same base repairs that.
+    pub fn apply_diff(
+        &self,
+        mut reader: impl Read,
+        wrap_key: &[u8; 32],
+    ) -> Result<DiffStats, ObjectStoreError> {
+        let header: [u8; HEADER_LEN] = read_array(&mut reader)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a diff stream").into());
+        }
+        if u32::from_le_bytes(header[8..12].try_into().unwrap()) != VERSION {
+            return Err(invalid("unsupported diff stream version").into());
+        }
+        let key = unwrap_key(wrap_key, &header[12..])?;
+        let mut stats = DiffStats::default();
+        let mut seq = 0;
+        loop {
+            let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+            let mut sealed = vec![0u8; len];
+            reader.read_exact(&mut sealed)?;
+            let plaintext = unseal(&key, &sealed)
+                .ok_or_else(|| invalid("diff stream failed integrity check"))?;
+            let record: Sequenced = bincode::deserialize(&plaintext)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if record.seq != seq {
+                return Err(invalid("diff stream records out of order").into());
+            }
+            seq += 1;
+            match record.record {
+                DiffRecord::Object { obj_id, truncate } => {
+                    if truncate {
+                        self.create_or_truncate(obj_id)?;
+                    } else {
+                        self.create_object(obj_id)?;
+                    }
+                    stats.objects_changed += 1;
+                }
+                DiffRecord::Page {
+                    obj_id,
+                    index,
+                    data,
+                } => {
+                    self.write_all(obj_id, &data, index * PAGE_SIZE as u64)?;
+                    stats.pages_written += 1;
+                }
+                DiffRecord::Delete(obj_id) => match self.unlink_object(obj_id) {
+                    Ok(()) => stats.objects_deleted += 1,
+                    // already deleted by an earlier attempt.
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                },
+                DiffRecord::End => return Ok(stats),
+            }
+        }
+    }
+}
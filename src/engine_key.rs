@@ -0,0 +1,88 @@
+use crate::{
+    fs::{Disk, PAGE_SIZE},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::io::{Error, ErrorKind};
+use zeroize::Zeroizing;
+
+/// Everything an external engine needs to run the store's cipher over
+/// one page: ChaCha20 with `key` and `nonce`, starting the keystream at
+/// byte `keystream_offset`. The key is wiped when this is dropped.
+pub struct ZeroizingKey {
+    key: Zeroizing<[u8; 32]>,
+    nonce: [u8; 12],
+    keystream_offset: u64,
+    disk_offset: u64,
+}
+
+impl ZeroizingKey {
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    pub fn nonce(&self) -> [u8; 12] {
+        self.nonce
+    }
+
+    pub fn keystream_offset(&self) -> u64 {
+        self.keystream_offset
+    }
+
+    /// Where the page lives on the disk, for engines that DMA it
+    /// directly.
+    pub fn disk_offset(&self) -> u64 {
+        self.disk_offset
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Derives the key of one page of an object so that an external
+    /// engine can do the encryption. The derivation is recorded in the
+    /// WAL just as if the store had used the key itself. Returns `None`
+    /// if the store is in plaintext mode.
+    ///
+    /// The key is tied to where the page is on disk, so it must be
+    /// derived again if the object is rewritten or moved by an epoch.
+    pub fn derive_object_key(
+        &self,
+        obj_id: u128,
+        page: u64,
+    ) -> Result<Option<ZeroizingKey>, ObjectStoreError> {
+        let disk_offset = {
+            let mut fs = self.fs().lock().unwrap();
+            if self.is_deduplicated_locked(&fs, obj_id)? {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "deduplicated objects have no per-object page keys",
+                )
+                .into());
+            }
+            self.locate(&mut fs, obj_id, page * PAGE_SIZE as u64)?
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "page is past the end of the object",
+                    )
+                })?
+        };
+        let Some(key) = self.chunk_key(disk_offset)? else {
+            return Ok(None);
+        };
+        let (nonce, keystream_offset) = self.layout.chunk_nonce(disk_offset);
+        Ok(Some(ZeroizingKey {
+            key: Zeroizing::new(key),
+            nonce,
+            keystream_offset,
+            disk_offset,
+        }))
+    }
+}
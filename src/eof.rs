@@ -0,0 +1,91 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    time::Instant,
+};
+
+/// Returned with `ErrorKind::UnexpectedEof` when a read reaches past
+/// the end of an object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadPastEnd {
+    pub obj_id: u128,
+    pub offset: u64,
+    pub len: usize,
+    /// Length of the object at the time of the read.
+    pub object_len: u64,
+}
+
+impl fmt::Display for ReadPastEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read of {} bytes at offset {} is past the end of object {:0>32x} ({} bytes)",
+            self.len, self.offset, self.obj_id, self.object_len
+        )
+    }
+}
+
+impl std::error::Error for ReadPastEnd {}
+
+impl From<ReadPastEnd> for Error {
+    fn from(value: ReadPastEnd) -> Self {
+        Error::new(ErrorKind::UnexpectedEof, value)
+    }
+}
+
+/// Returns the details of a read past the end of an object, if that is
+/// what `err` is.
+pub fn read_past_end(err: &Error) -> Option<&ReadPastEnd> {
+    err.get_ref()?.downcast_ref::<ReadPastEnd>()
+}
+
+/// Fails if reading `len` bytes at `offset` would go past `object_len`.
+pub(crate) fn check_in_bounds(
+    obj_id: u128,
+    offset: u64,
+    len: usize,
+    object_len: u64,
+) -> Result<(), ReadPastEnd> {
+    if offset.saturating_add(len as u64) > object_len {
+        return Err(ReadPastEnd {
+            obj_id,
+            offset,
+            len,
+            object_len,
+        });
+    }
+    Ok(())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Reads up to `buf.len()` bytes at `off`, returning how many bytes
+    /// were read. Returns 0 when `off` is at or past the end of the
+    /// object.
+    pub fn read_at(
+        &self,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<usize, ObjectStoreError> {
+        let started = Instant::now();
+        let mut fs = self.fs().lock().unwrap();
+        let len = self.object_len_locked(&mut fs, obj_id)?;
+        let n = len.saturating_sub(off).min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.read_locked(&mut fs, obj_id, &mut buf[..n], off)?;
+        self.record_read(obj_id, n, started);
+        Ok(n)
+    }
+}
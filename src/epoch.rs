@@ -0,0 +1,239 @@
+use crate::{
+    context::{ErrorContext, Phase, ResultExt},
+    fs::Disk,
+    superblock::KeyMode,
+    wal_log::WalOp,
+    ObjectStore, ObjectStoreError,
+};
+use chacha20::cipher::StreamCipher;
+use fatfs::IoBase;
+use std::{collections::BTreeMap, fmt, io::Error, sync::atomic::Ordering, time::Instant};
+use zeroize::Zeroizing;
+
+/// Chunks of an epoch that still have to be re-encrypted, along with
+/// their previous keys. Kept in memory only, so that a failed epoch can
+/// be retried without rotating the keys again. Epochs never stop part
+/// way otherwise, since the keys here are lost with the process.
+pub(crate) struct PendingEpoch {
+    pub(crate) remaining: BTreeMap<u64, Zeroizing<[u8; 32]>>,
+    /// When the keys were rotated, for the epoch's latency.
+    pub(crate) started: Instant,
+}
+
+/// What `advance_epoch` would cost if it ran now.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EpochEstimate {
+    /// Chunks whose keys would be rotated or forgotten.
+    pub chunks_to_rekey: u64,
+    /// Bytes that would be read, decrypted and written back.
+    pub bytes_to_reencrypt: u64,
+    /// False when the WAL holds entries from before the store was
+    /// opened, which aren't counted.
+    pub exact: bool,
+}
+
+/// A chunk that couldn't be re-encrypted.
+#[derive(Debug)]
+pub struct ChunkFailure {
+    pub chunk_id: u64,
+    pub disk_offset: u64,
+    pub error: Error,
+}
+
+/// Returned by `advance_epoch` when some chunks couldn't be
+/// re-encrypted. Every other chunk was, and calling `advance_epoch`
+/// again only retries the failed ones.
+#[derive(Debug)]
+pub struct EpochIncomplete {
+    pub failed: Vec<ChunkFailure>,
+}
+
+impl fmt::Display for EpochIncomplete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} chunks failed to re-encrypt", self.failed.len())?;
+        if let Some(first) = self.failed.first() {
+            write!(f, ", first at {}: {}", first.disk_offset, first.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EpochIncomplete {}
+
+impl From<EpochIncomplete> for Error {
+    fn from(value: EpochIncomplete) -> Self {
+        Error::other(value)
+    }
+}
+
+/// Returns the failed chunks carried by `err`, if an epoch was left
+/// incomplete.
+pub fn epoch_incomplete(err: &Error) -> Option<&EpochIncomplete> {
+    err.get_ref()?.downcast_ref::<EpochIncomplete>()
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Estimates the cost of advancing the epoch now, without touching
+    /// the KHF or the disk.
+    pub fn estimate_epoch(&self) -> Result<EpochEstimate, ObjectStoreError> {
+        if self.key_mode() != KeyMode::Khf {
+            return Ok(EpochEstimate {
+                exact: true,
+                ..Default::default()
+            });
+        }
+        if let Some(pending) = self.pending_epoch.lock().unwrap().as_ref() {
+            // the keys were already rotated, only re-encryption is left.
+            let chunks = pending.remaining.len() as u64;
+            return Ok(EpochEstimate {
+                chunks_to_rekey: 0,
+                bytes_to_reencrypt: chunks * self.layout.chunk_size(),
+                exact: true,
+            });
+        }
+        let estimate = self.wal_journal(|journal| {
+            let entries = journal.entries();
+            let derived = entries
+                .iter()
+                .filter(|entry| entry.op == WalOp::Derive)
+                .count() as u64;
+            EpochEstimate {
+                chunks_to_rekey: entries.len() as u64,
+                bytes_to_reencrypt: derived * self.layout.chunk_size(),
+                exact: journal.inherited_bytes == 0,
+            }
+        })?;
+        Ok(estimate.unwrap_or_default())
+    }
+
+    /// Returns how many chunks a failed epoch left to re-encrypt.
+    pub fn pending_epoch_chunks(&self) -> usize {
+        self.pending_epoch
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |pending| pending.remaining.len())
+    }
+
+    /// Returns whether a failed epoch has rotated its keys but not
+    /// finished, even if every chunk has since been settled.
+    pub(crate) fn epoch_paused(&self) -> bool {
+        self.pending_epoch.lock().unwrap().is_some()
+    }
+
+    /// Moves one chunk from its previous key to its current one.
+    pub(crate) fn reencrypt_chunk(&self, id: u64, old_key: &[u8; 32]) -> Result<(), Error> {
+        let disk = self.fs.disk();
+        let disk_offset = self.layout.disk_offset(id);
+        let len = self.layout.chunk_len(id, disk.size()?)?;
+        let mut buf = vec![0; len as usize];
+        let ctx = ErrorContext::new(Phase::ReEncrypt).disk_offset(disk_offset);
+        disk.read_exact_at(disk_offset, buf.as_mut_slice())
+            .context(ErrorContext::new(Phase::DiskRead).disk_offset(disk_offset))?;
+        let mut cipher = self.layout.cipher(disk_offset, *old_key);
+        cipher.apply_keystream(&mut buf);
+        // not through get_symmetric_cipher, which would wait on the
+        // pending epoch to settle this very chunk.
+        if let Some(key) = self.lookup_key(disk_offset, false).context(ctx)? {
+            self.layout
+                .cipher(disk_offset, key)
+                .apply_keystream(&mut buf);
+        }
+        disk.write_all_at(disk_offset, &buf)
+            .context(ErrorContext::new(Phase::DiskWrite).disk_offset(disk_offset))?;
+        Ok(())
+    }
+
+    /// Re-encrypts every pending chunk, keeping the ones that fail for
+    /// the next attempt. Returns how many were re-encrypted.
+    ///
+    /// The pending epoch is locked around each chunk rather than the
+    /// whole pass, so reads wait for at most one chunk, and never see
+    /// one half re-encrypted since they settle it under the same lock.
+    pub(crate) fn reencrypt_pending(&self) -> Result<u64, Error> {
+        let mut failed = Vec::new();
+        let mut done = 0;
+        let mut next = 0;
+        loop {
+            let mut pending = self.pending_epoch.lock().unwrap();
+            let Some(pending) = pending.as_mut() else {
+                break;
+            };
+            let Some(id) = pending.remaining.range(next..).next().map(|(id, _)| *id) else {
+                break;
+            };
+            next = id + 1;
+            let old_key = pending.remaining.remove(&id).unwrap();
+            match self.reencrypt_chunk(id, &old_key) {
+                Ok(()) => {
+                    self.counters
+                        .epoch_chunks_done
+                        .fetch_add(1, Ordering::Relaxed);
+                    done += 1;
+                }
+                Err(error) => {
+                    failed.push(ChunkFailure {
+                        chunk_id: id,
+                        disk_offset: self.layout.disk_offset(id),
+                        error,
+                    });
+                    pending.remaining.insert(id, old_key);
+                }
+            }
+        }
+        if failed.is_empty() {
+            return Ok(done);
+        }
+        Err(EpochIncomplete { failed }.into())
+    }
+
+    /// Re-encrypts the chunk `chunk_id` if the epoch in progress hasn't
+    /// got to it yet, so that it can be used with its current key. Waits
+    /// if the epoch is re-encrypting it right now.
+    pub(crate) fn settle_chunk(&self, chunk_id: u64) -> Result<(), Error> {
+        let mut pending = self.pending_epoch.lock().unwrap();
+        let Some(pending) = pending.as_mut() else {
+            return Ok(());
+        };
+        let Some(old_key) = pending.remaining.remove(&chunk_id) else {
+            return Ok(());
+        };
+        if let Err(e) = self.reencrypt_chunk(chunk_id, &old_key) {
+            pending.remaining.insert(chunk_id, old_key);
+            return Err(e);
+        }
+        self.counters
+            .epoch_chunks_done
+            .fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Settles every chunk overlapping `len` bytes at `disk_offset`.
+    pub(crate) fn settle_range(&self, disk_offset: u64, len: u64) -> Result<(), Error> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = self.layout.chunk_id(disk_offset);
+        let last = self.layout.chunk_id(disk_offset + len - 1);
+        for chunk_id in first..=last {
+            self.settle_chunk(chunk_id)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the chunk `chunk_id` from a failed epoch, once its data is
+    /// gone and there is nothing left to re-encrypt.
+    pub(crate) fn unsettle_chunk(&self, chunk_id: u64) {
+        if let Some(pending) = self.pending_epoch.lock().unwrap().as_mut() {
+            pending.remaining.remove(&chunk_id);
+        }
+    }
+}
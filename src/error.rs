@@ -0,0 +1,131 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    ops::Deref,
+};
+
+/// What a store operation failed with. Every variant keeps the
+/// `std::io::Error` it was made from, so converting back to one loses
+/// nothing, and helpers that inspect a `std::io::Error`, like
+/// `no_space` or `error_context`, take it through `Deref`.
+#[derive(Debug)]
+pub enum ObjectStoreError {
+    /// The object, or something it needs, doesn't exist.
+    NotFound(Error),
+    AlreadyExists(Error),
+    /// The volume has no room left, or object ids are exhausted.
+    DiskFull(Error),
+    /// Deriving, deleting or persisting keys failed.
+    KmsError(Error),
+    /// Something read from the disk failed to decode or verify.
+    CorruptMetadata(Error),
+    Io(Error),
+}
+
+impl ObjectStoreError {
+    pub fn into_io(self) -> Error {
+        match self {
+            ObjectStoreError::NotFound(e)
+            | ObjectStoreError::AlreadyExists(e)
+            | ObjectStoreError::DiskFull(e)
+            | ObjectStoreError::KmsError(e)
+            | ObjectStoreError::CorruptMetadata(e)
+            | ObjectStoreError::Io(e) => e,
+        }
+    }
+}
+
+impl Deref for ObjectStoreError {
+    type Target = Error;
+
+    fn deref(&self) -> &Error {
+        match self {
+            ObjectStoreError::NotFound(e)
+            | ObjectStoreError::AlreadyExists(e)
+            | ObjectStoreError::DiskFull(e)
+            | ObjectStoreError::KmsError(e)
+            | ObjectStoreError::CorruptMetadata(e)
+            | ObjectStoreError::Io(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for ObjectStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl std::error::Error for ObjectStoreError {
+    // the inner error is what `Display` shows, so the chain picks up
+    // from its source rather than repeating it.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        (**self).source()
+    }
+}
+
+impl From<Error> for ObjectStoreError {
+    fn from(value: Error) -> Self {
+        if from_kms(&value) {
+            return ObjectStoreError::KmsError(value);
+        }
+        match value.kind() {
+            ErrorKind::NotFound => ObjectStoreError::NotFound(value),
+            ErrorKind::AlreadyExists => ObjectStoreError::AlreadyExists(value),
+            ErrorKind::StorageFull => ObjectStoreError::DiskFull(value),
+            ErrorKind::InvalidData => ObjectStoreError::CorruptMetadata(value),
+            _ => ObjectStoreError::Io(value),
+        }
+    }
+}
+
+impl<E> From<fatfs::Error<E>> for ObjectStoreError
+where
+    Error: From<fatfs::Error<E>>,
+{
+    fn from(value: fatfs::Error<E>) -> Self {
+        Error::from(value).into()
+    }
+}
+
+impl From<ObjectStoreError> for Error {
+    fn from(value: ObjectStoreError) -> Self {
+        value.into_io()
+    }
+}
+
+/// Marks an error as coming from the key management system.
+#[derive(Debug)]
+struct KmsFailure(Box<dyn std::error::Error + Send + Sync>);
+
+impl fmt::Display for KmsFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for KmsFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+pub(crate) fn kms_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::other(KmsFailure(e.into()))
+}
+
+/// Returns true if `err` was made by `kms_error`, even once context has
+/// been added to it.
+fn from_kms(err: &Error) -> bool {
+    let mut inner = err.get_ref();
+    while let Some(e) = inner {
+        if e.is::<KmsFailure>() {
+            return true;
+        }
+        inner = e
+            .source()
+            .and_then(|source| source.downcast_ref::<Error>())
+            .and_then(Error::get_ref);
+    }
+    false
+}
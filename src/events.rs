@@ -0,0 +1,129 @@
+use crate::{fs::Disk, superblock::KeyMode, ObjectStore};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{ops::Range, sync::Mutex};
+
+/// Events raised before a sink is attached are held until one is, up
+/// to this many.
+const MAX_PENDING_EVENTS: usize = 64;
+
+/// What was done to bring the KHF files back to a consistent state
+/// after an interrupted epoch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KhfRecovery {
+    /// The KHF written by the interrupted epoch was moved into place.
+    InstalledNew,
+    /// The epoch never finished writing its KHF, so the previous one
+    /// was put back.
+    RestoredPrevious,
+    /// The epoch had finished and only the previous KHF had to be wiped.
+    WipedPrevious,
+}
+
+/// Lifecycle events meant for operators, as opposed to developer
+/// logging.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreEvent {
+    Formatted {
+        uuid: u128,
+        key_mode: KeyMode,
+    },
+    Opened {
+        uuid: u128,
+        generation: u64,
+        /// Whether the store was closed cleanly.
+        clean: bool,
+    },
+    /// The disk was taken over from a store that still held the mount.
+    TookOver {
+        previous_owner: u128,
+    },
+    Recovered(KhfRecovery),
+    EpochStarted {
+        generation: u64,
+        /// Chunks that have to be re-encrypted.
+        chunks: u64,
+    },
+    /// Some chunks couldn't be re-encrypted. The epoch is retried by
+    /// the next `advance_epoch`.
+    EpochFailed {
+        generation: u64,
+        failed_chunks: u64,
+    },
+    EpochFinished {
+        generation: u64,
+    },
+    /// A verified read found pages that don't match their MACs.
+    IntegrityFailure {
+        obj_id: u128,
+        corrupt: Range<u64>,
+    },
+    Closed {
+        uuid: u128,
+    },
+}
+
+/// Receives the store's lifecycle events.
+pub trait EventSink: Send {
+    fn record(&mut self, event: &StoreEvent);
+}
+
+impl<F: FnMut(&StoreEvent) + Send> EventSink for F {
+    fn record(&mut self, event: &StoreEvent) {
+        self(event)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct EventLog {
+    sink: Mutex<Option<Box<dyn EventSink>>>,
+    pending: Mutex<Vec<StoreEvent>>,
+}
+
+impl EventLog {
+    pub fn with_pending(pending: Vec<StoreEvent>) -> Self {
+        Self {
+            sink: Mutex::new(None),
+            pending: Mutex::new(pending),
+        }
+    }
+
+    pub fn emit(&self, event: StoreEvent) {
+        let mut sink = self.sink.lock().unwrap();
+        match sink.as_mut() {
+            Some(sink) => sink.record(&event),
+            None => {
+                let mut pending = self.pending.lock().unwrap();
+                if pending.len() < MAX_PENDING_EVENTS {
+                    pending.push(event);
+                }
+            }
+        }
+    }
+
+    /// Attaches `sink`, first handing it every event raised while
+    /// there was no sink.
+    pub fn set_sink(&self, mut sink: Box<dyn EventSink>) {
+        let mut current = self.sink.lock().unwrap();
+        for event in self.pending.lock().unwrap().drain(..) {
+            sink.record(&event);
+        }
+        *current = Some(sink);
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Sends lifecycle events to `sink`. Events raised while opening the
+    /// store, before any sink could be attached, are delivered first.
+    pub fn set_event_sink(&self, sink: impl EventSink + 'static) {
+        self.events.set_sink(Box::new(sink));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::{atomic::Ordering, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const EXPIRY_PATH: &str = "meta/expiry";
+
+/// Deadlines of objects that have been given an expiry, stored as
+/// seconds since the unix epoch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ExpiryIndex {
+    deadlines: BTreeMap<u128, u64>,
+}
+
+/// The outcome of a call to `reap_expired`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReapReport {
+    /// Objects that were unlinked because they expired.
+    pub unlinked: Vec<u128>,
+    /// Number of chunk keys that have been deleted but will only be
+    /// securely forgotten by the next epoch.
+    pub pending_key_deletions: u64,
+}
+
+pub(crate) fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn expiry_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<ExpiryIndex>>, Error> {
+        let mut expiry = self.expiry.lock().unwrap();
+        if expiry.is_none() {
+            *expiry = Some(read_meta(fs, &self.meta_key(), EXPIRY_PATH)?.unwrap_or_default());
+        }
+        Ok(expiry)
+    }
+
+    /// Marks an object to be unlinked by the first `reap_expired` call
+    /// after `deadline`.
+    pub fn set_expiry(&self, obj_id: u128, deadline: SystemTime) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        get_dir_path(&fs, &b64)?.open_file(&b64)?;
+        let mut expiry = self.expiry_lock(&fs)?;
+        update_meta(
+            &fs,
+            &self.meta_key(),
+            EXPIRY_PATH,
+            expiry.as_mut().unwrap(),
+            |expiry| {
+                expiry.deadlines.insert(obj_id, to_secs(deadline));
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Removes the expiry of an object. Returns false if the object
+    /// had no expiry.
+    pub fn clear_expiry(&self, obj_id: u128) -> Result<bool, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        Ok(self.forget_expiry(&fs, obj_id)?)
+    }
+
+    pub fn expiry(&self, obj_id: u128) -> Result<Option<SystemTime>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let expiry = self.expiry_lock(&fs)?;
+        Ok(expiry
+            .as_ref()
+            .unwrap()
+            .deadlines
+            .get(&obj_id)
+            .map(|secs| UNIX_EPOCH + std::time::Duration::from_secs(*secs)))
+    }
+
+    /// Unlinks every object whose deadline has passed. The deleted
+    /// objects are only securely deleted after the next epoch.
+    pub fn reap_expired(&self) -> Result<ReapReport, ObjectStoreError> {
+        let mut unlinked = Vec::new();
+        for obj_id in self.expired_objects()? {
+            if self.reap_object(obj_id)? {
+                unlinked.push(obj_id);
+            }
+        }
+        Ok(ReapReport {
+            unlinked,
+            pending_key_deletions: self.pending_key_deletions(),
+        })
+    }
+
+    /// Returns the ids of the objects whose deadline has passed.
+    pub(crate) fn expired_objects(&self) -> Result<Vec<u128>, Error> {
+        let now = to_secs(SystemTime::now());
+        let fs = self.fs().lock().unwrap();
+        let expiry = self.expiry_lock(&fs)?;
+        Ok(expiry
+            .as_ref()
+            .unwrap()
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect())
+    }
+
+    /// Unlinks an expired object, returning false if it was already
+    /// gone.
+    pub(crate) fn reap_object(&self, obj_id: u128) -> Result<bool, Error> {
+        match self.unlink_object(obj_id) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                // already gone, just drop the stale deadline.
+                let fs = self.fs().lock().unwrap();
+                self.forget_expiry(&fs, obj_id)?;
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns how many chunk keys have been deleted since the last
+    /// epoch.
+    pub fn pending_key_deletions(&self) -> u64 {
+        self.pending_deletions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn forget_expiry(&self, fs: &FatFs<D>, obj_id: u128) -> Result<bool, Error> {
+        let mut expiry = self.expiry_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            EXPIRY_PATH,
+            expiry.as_mut().unwrap(),
+            |expiry| expiry.deadlines.remove(&obj_id).is_some(),
+        )
+    }
+}
@@ -0,0 +1,65 @@
+use crate::error::kms_error;
+use std::{
+    io::Error,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// A key management operation a fault can be injected into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KmsOp {
+    Derive,
+    Delete,
+    Persist,
+}
+
+/// Faults injected into the KHF and its WAL, so that tests can reach
+/// the error paths of epochs and unlinks. A countdown of zero is
+/// disarmed; otherwise the call that brings it to zero fails.
+#[derive(Debug, Default)]
+pub struct KmsFaults {
+    derives_left: AtomicU64,
+    deletes_left: AtomicU64,
+    fail_persist: AtomicBool,
+}
+
+/// Counts a call down, returning true if it is the one to fail.
+fn count_down(left: &AtomicU64) -> bool {
+    left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+        left.checked_sub(1)
+    }) == Ok(1)
+}
+
+impl KmsFaults {
+    /// Fails if a fault is due for `op`.
+    pub(crate) fn check(&self, op: KmsOp) -> Result<(), Error> {
+        let fail = match op {
+            KmsOp::Derive => count_down(&self.derives_left),
+            KmsOp::Delete => count_down(&self.deletes_left),
+            KmsOp::Persist => self.fail_persist.load(Ordering::Relaxed),
+        };
+        if fail {
+            return Err(kms_error(format!("injected {op:?} fault")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl KmsFaults {
+    /// Fails the `nth` key derivation from now, counting from 1. Zero
+    /// disarms the fault.
+    pub fn fail_derive(&self, nth: u64) {
+        self.derives_left.store(nth, Ordering::Relaxed);
+    }
+
+    /// Fails the `nth` key deletion from now, counting from 1. Zero
+    /// disarms the fault.
+    pub fn fail_delete(&self, nth: u64) {
+        self.deletes_left.store(nth, Ordering::Relaxed);
+    }
+
+    /// Fails every KHF persist until cleared.
+    pub fn fail_persist(&self, fail: bool) {
+        self.fail_persist.store(fail, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,217 @@
+//! C bindings for driving the store from non-Rust components.
+//!
+//! A store is opened on a file or block device path and handed out as
+//! an opaque `TosStore` pointer. Every call returns one of the `TOS_*`
+//! codes, with `TOS_OK` on success. See `include/tos.h`.
+use crate::{already_mounted, file_disk::FileDisk, integrity_error, media_mismatch, ObjectStore};
+use std::{
+    ffi::{c_char, c_int, CStr},
+    fs::OpenOptions,
+    io::{Error, ErrorKind},
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+pub const TOS_OK: c_int = 0;
+pub const TOS_ERR_INVALID: c_int = -1;
+pub const TOS_ERR_NOT_FOUND: c_int = -2;
+pub const TOS_ERR_EXISTS: c_int = -3;
+pub const TOS_ERR_BUSY: c_int = -4;
+pub const TOS_ERR_INTEGRITY: c_int = -5;
+pub const TOS_ERR_MEDIA: c_int = -6;
+pub const TOS_ERR_IO: c_int = -7;
+pub const TOS_ERR_PANIC: c_int = -8;
+
+/// Format the disk if it doesn't hold a valid store.
+pub const TOS_OPEN_CREATE: u32 = 1 << 0;
+/// Open the disk even if another process has it mounted. Can't be
+/// combined with `TOS_OPEN_CREATE`.
+pub const TOS_OPEN_TAKEOVER: u32 = 1 << 1;
+
+/// Opaque handle to an open store.
+pub struct TosStore(ObjectStore<FileDisk>);
+
+/// An object id split into halves since C has no portable 128 bit
+/// integer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TosObjId {
+    pub hi: u64,
+    pub lo: u64,
+}
+
+impl From<TosObjId> for u128 {
+    fn from(id: TosObjId) -> u128 {
+        ((id.hi as u128) << 64) | id.lo as u128
+    }
+}
+
+fn error_code(err: &Error) -> c_int {
+    if integrity_error(err).is_some() {
+        return TOS_ERR_INTEGRITY;
+    }
+    if media_mismatch(err).is_some() {
+        return TOS_ERR_MEDIA;
+    }
+    if already_mounted(err).is_some() {
+        return TOS_ERR_BUSY;
+    }
+    match err.kind() {
+        ErrorKind::NotFound => TOS_ERR_NOT_FOUND,
+        ErrorKind::AlreadyExists => TOS_ERR_EXISTS,
+        ErrorKind::InvalidInput | ErrorKind::UnexpectedEof => TOS_ERR_INVALID,
+        _ => TOS_ERR_IO,
+    }
+}
+
+/// Runs `f`, turning errors and panics into error codes. Panics must
+/// not unwind into C.
+fn guard(f: impl FnOnce() -> Result<(), Error>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => TOS_OK,
+        Ok(Err(e)) => error_code(&e),
+        Err(_) => TOS_ERR_PANIC,
+    }
+}
+
+/// Returns the store behind a handle, or an error if it is null.
+///
+/// # Safety
+/// `store` must be null or a handle from `tos_open` that hasn't been
+/// closed.
+unsafe fn store<'a>(store: *const TosStore) -> Result<&'a ObjectStore<FileDisk>, Error> {
+    store
+        .as_ref()
+        .map(|store| &store.0)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "null store handle"))
+}
+
+/// Opens the store on the disk at `path`, writing the handle to `out`.
+/// Fails with `TOS_ERR_INVALID` if `flags` has both `TOS_OPEN_CREATE`
+/// and `TOS_OPEN_TAKEOVER`.
+///
+/// # Safety
+/// `path` must be a nul terminated string, `root_key` must point to 32
+/// bytes and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn tos_open(
+    path: *const c_char,
+    root_key: *const u8,
+    flags: u32,
+    out: *mut *mut TosStore,
+) -> c_int {
+    guard(|| {
+        if path.is_null() || root_key.is_null() || out.is_null() {
+            return Err(Error::new(ErrorKind::InvalidInput, "null argument"));
+        }
+        if flags & TOS_OPEN_CREATE != 0 && flags & TOS_OPEN_TAKEOVER != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a store can't be both created and taken over",
+            ));
+        }
+        let path = CStr::from_ptr(path)
+            .to_str()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let root_key = *(root_key as *const [u8; 32]);
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let disk = FileDisk::new(file);
+        let os = if flags & TOS_OPEN_CREATE != 0 {
+            ObjectStore::open_or_format(disk, root_key)?
+        } else if flags & TOS_OPEN_TAKEOVER != 0 {
+            ObjectStore::open_takeover(disk, root_key)?
+        } else {
+            ObjectStore::open(disk, root_key)?
+        };
+        *out = Box::into_raw(Box::new(TosStore(os)));
+        Ok(())
+    })
+}
+
+/// Shuts the store down and frees the handle, even if shutting down
+/// fails.
+///
+/// # Safety
+/// `store` must be null or a handle from `tos_open` that hasn't been
+/// closed.
+#[no_mangle]
+pub unsafe extern "C" fn tos_close(store: *mut TosStore) -> c_int {
+    guard(|| {
+        if store.is_null() {
+            return Err(Error::new(ErrorKind::InvalidInput, "null store handle"));
+        }
+        Ok(Box::from_raw(store).0.close()?)
+    })
+}
+
+/// Creates an empty object. Fails with `TOS_ERR_EXISTS` if it exists.
+///
+/// # Safety
+/// `os` must be a live handle from `tos_open`.
+#[no_mangle]
+pub unsafe extern "C" fn tos_create(os: *const TosStore, obj_id: TosObjId) -> c_int {
+    guard(|| Ok(store(os)?.create_object_excl(obj_id.into())?))
+}
+
+/// Reads exactly `len` bytes at `off` into `buf`.
+///
+/// # Safety
+/// `os` must be a live handle from `tos_open` and `buf` must be valid
+/// for `len` bytes of writes.
+#[no_mangle]
+pub unsafe extern "C" fn tos_read(
+    os: *const TosStore,
+    obj_id: TosObjId,
+    buf: *mut u8,
+    len: usize,
+    off: u64,
+) -> c_int {
+    guard(|| {
+        let buf = match len {
+            0 => &mut [][..],
+            _ if buf.is_null() => return Err(Error::new(ErrorKind::InvalidInput, "null buffer")),
+            _ => std::slice::from_raw_parts_mut(buf, len),
+        };
+        Ok(store(os)?.read_exact(obj_id.into(), buf, off)?)
+    })
+}
+
+/// Writes `len` bytes from `buf` at `off`.
+///
+/// # Safety
+/// `os` must be a live handle from `tos_open` and `buf` must be valid
+/// for `len` bytes of reads.
+#[no_mangle]
+pub unsafe extern "C" fn tos_write(
+    os: *const TosStore,
+    obj_id: TosObjId,
+    buf: *const u8,
+    len: usize,
+    off: u64,
+) -> c_int {
+    guard(|| {
+        let buf = match len {
+            0 => &[][..],
+            _ if buf.is_null() => return Err(Error::new(ErrorKind::InvalidInput, "null buffer")),
+            _ => std::slice::from_raw_parts(buf, len),
+        };
+        Ok(store(os)?.write_all(obj_id.into(), buf, off)?)
+    })
+}
+
+/// Unlinks an object. It is only securely deleted by the next epoch.
+///
+/// # Safety
+/// `os` must be a live handle from `tos_open`.
+#[no_mangle]
+pub unsafe extern "C" fn tos_unlink(os: *const TosStore, obj_id: TosObjId) -> c_int {
+    guard(|| Ok(store(os)?.unlink_object(obj_id.into())?))
+}
+
+/// Advances the epoch, securely deleting unlinked objects.
+///
+/// # Safety
+/// `os` must be a live handle from `tos_open`.
+#[no_mangle]
+pub unsafe extern "C" fn tos_epoch(os: *const TosStore) -> c_int {
+    guard(|| Ok(store(os)?.advance_epoch()?))
+}
@@ -0,0 +1,49 @@
+use crate::Disk;
+use fatfs::IoBase;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "wasi")]
+use std::os::wasi::fs::FileExt;
+use std::{
+    fs::File,
+    io::{Error, Seek, SeekFrom},
+};
+
+/// A disk backed by a file or block device.
+pub struct FileDisk {
+    file: File,
+}
+
+impl FileDisk {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl IoBase for FileDisk {
+    type Error = Error;
+}
+
+impl Disk for FileDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.file.write_at(buf, offset)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.file.sync_data()
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        // the metadata of a block device says it is empty. Reads and
+        // writes take their own offsets, so moving the cursor is fine.
+        (&self.file).seek(SeekFrom::End(0))
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use bitflags::bitflags;
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+};
+
+pub(crate) const FLAGS_PATH: &str = "meta/flags";
+
+bitflags! {
+    /// Protections the store enforces on an object.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct ObjectFlags: u8 {
+        /// The object can't be unlinked.
+        const PINNED = 1;
+        /// The object can't be written to.
+        const IMMUTABLE = 1 << 1;
+        /// Set by `seal_object`. Like `IMMUTABLE`, but can't be cleared.
+        const SEALED = 1 << 2;
+    }
+}
+
+/// The flags of every object that has any set.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct FlagTable {
+    flags: BTreeMap<u128, u8>,
+}
+
+impl FlagTable {
+    pub fn get(&self, obj_id: u128) -> ObjectFlags {
+        self.flags
+            .get(&obj_id)
+            .map(|bits| ObjectFlags::from_bits_truncate(*bits))
+            .unwrap_or_default()
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn flags_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<FlagTable>>, Error> {
+        let mut flags = self.flags.lock().unwrap();
+        if flags.is_none() {
+            *flags = Some(read_meta(fs, &self.meta_key(), FLAGS_PATH)?.unwrap_or_default());
+        }
+        Ok(flags)
+    }
+
+    /// Replaces the flags of an object. `SEALED` is left as it is.
+    pub fn set_flags(&self, obj_id: u128, flags: ObjectFlags) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        get_dir_path(&fs, &b64)?.open_file(&b64)?;
+        let sealed = self.flags_locked(&fs, obj_id)? & ObjectFlags::SEALED;
+        Ok(self.store_flags(&fs, obj_id, flags.difference(ObjectFlags::SEALED) | sealed)?)
+    }
+
+    pub(crate) fn store_flags(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        flags: ObjectFlags,
+    ) -> Result<(), Error> {
+        let mut table = self.flags_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            FLAGS_PATH,
+            table.as_mut().unwrap(),
+            |table| {
+                if flags.is_empty() {
+                    table.flags.remove(&obj_id);
+                } else {
+                    table.flags.insert(obj_id, flags.bits());
+                }
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn flags(&self, obj_id: u128) -> Result<ObjectFlags, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        Ok(self.flags_locked(&fs, obj_id)?)
+    }
+
+    pub(crate) fn flags_locked(&self, fs: &FatFs<D>, obj_id: u128) -> Result<ObjectFlags, Error> {
+        Ok(self.flags_lock(fs)?.as_ref().unwrap().get(obj_id))
+    }
+
+    /// Fails with `PermissionDenied` if the object has any of the
+    /// `forbidden` flags.
+    pub(crate) fn check_flags(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        forbidden: ObjectFlags,
+    ) -> Result<(), Error> {
+        let set = self.flags_locked(fs, obj_id)? & forbidden;
+        if set.contains(ObjectFlags::PINNED) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "object is pinned"));
+        }
+        if set.contains(ObjectFlags::SEALED) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "object is sealed"));
+        }
+        if set.contains(ObjectFlags::IMMUTABLE) {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "object is immutable",
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn forget_flags(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut table = self.flags_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            FLAGS_PATH,
+            table.as_mut().unwrap(),
+            |table| table.flags.remove(&obj_id).is_some(),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+use crate::{
+    fs::{Disk, DiskLimits, SECTOR_SIZE},
+    object_store::lock_poisoned,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io::{Error, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+/// Whole sectors, by sector index.
+type Sectors = BTreeMap<u64, Box<[u8; SECTOR_SIZE]>>;
+
+#[derive(Default)]
+struct Overlay {
+    frozen: bool,
+    /// Sectors written while frozen.
+    sectors: Sectors,
+}
+
+/// Sits between the store and its disk. While frozen, writes land in
+/// an in-memory overlay instead of the disk so that the image can be
+/// copied, and reads see the overlay first.
+pub(crate) struct FreezableDisk<D> {
+    disk: Arc<D>,
+    /// Lets reads skip the overlay lock while not frozen. Writes always
+    /// take the lock so none can slip past a freeze or thaw.
+    frozen: AtomicBool,
+    overlay: Mutex<Overlay>,
+    /// For each open snapshot, the old contents of the sectors written
+    /// since it was taken.
+    snapshots: Mutex<Vec<Weak<Mutex<Sectors>>>>,
+    /// Accesses are split to fit these.
+    limits: DiskLimits,
+}
+
+impl<D: Disk> FreezableDisk<D> {
+    pub fn new(disk: Arc<D>) -> Self {
+        Self {
+            frozen: AtomicBool::new(false),
+            overlay: Mutex::new(Overlay::default()),
+            snapshots: Mutex::new(Vec::new()),
+            limits: disk.limits(),
+            disk,
+        }
+    }
+
+    /// Cuts `buf` down to what the disk can move in one access.
+    fn limit<'a>(&self, buf: &'a mut [u8]) -> &'a mut [u8] {
+        let len = buf.len().min(self.limits.max_transfer);
+        &mut buf[..len]
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the disk can be accessed without going through
+    /// this: it isn't frozen and no snapshot needs the sectors that
+    /// writes replace.
+    #[cfg(feature = "async")]
+    pub fn is_passthrough(&self) -> bool {
+        !self.is_frozen()
+            && self
+                .snapshots
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|snapshot| snapshot.strong_count() == 0)
+    }
+
+    pub fn freeze(&self) {
+        self.overlay.lock().unwrap().frozen = true;
+        self.frozen.store(true, Ordering::Release);
+    }
+
+    /// Writes the overlay back to the disk and stops buffering.
+    pub fn thaw(&self) -> Result<(), D::Error> {
+        let mut overlay = self.overlay.lock().unwrap();
+        for (sector, data) in &overlay.sectors {
+            self.preserve(sector * SECTOR_SIZE as u64, SECTOR_SIZE)?;
+            self.disk
+                .write_all_at(sector * SECTOR_SIZE as u64, &data[..])?;
+        }
+        self.disk.flush()?;
+        overlay.sectors.clear();
+        overlay.frozen = false;
+        self.frozen.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Captures the disk as it is now, including anything held back by
+    /// a freeze. Writes made after this keep a copy of what they
+    /// replace for as long as the snapshot is open.
+    pub fn snapshot(&self) -> SnapshotDisk<D> {
+        let overlay = self.overlay.lock().unwrap();
+        let preserved = Arc::new(Mutex::new(Sectors::new()));
+        self.snapshots
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&preserved));
+        SnapshotDisk {
+            disk: self.disk.clone(),
+            preserved,
+            written: Mutex::new(overlay.sectors.clone()),
+        }
+    }
+
+    /// Copies the sectors covering `len` bytes at `offset` into every
+    /// open snapshot that doesn't have them yet, before they are
+    /// overwritten. A snapshot reading the disk meanwhile holds its
+    /// lock, so it sees either the old sector or the copy.
+    fn preserve(&self, offset: u64, len: usize) -> Result<(), D::Error> {
+        let snapshots: Vec<_> = {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            snapshots.retain(|snapshot| snapshot.strong_count() > 0);
+            snapshots.iter().filter_map(Weak::upgrade).collect()
+        };
+        if snapshots.is_empty() || len == 0 {
+            return Ok(());
+        }
+        let sector_len = SECTOR_SIZE as u64;
+        for snapshot in snapshots {
+            let mut preserved = snapshot.lock().unwrap();
+            for sector in offset / sector_len..(offset + len as u64).div_ceil(sector_len) {
+                if let Entry::Vacant(entry) = preserved.entry(sector) {
+                    let mut data = Box::new([0u8; SECTOR_SIZE]);
+                    self.disk
+                        .read_exact_at(sector * sector_len, &mut data[..])?;
+                    entry.insert(data);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every sector sized piece of an access at
+    /// `offset`, as `(sector, offset within the sector, range of buf)`.
+    fn pieces(offset: u64, len: usize, mut f: impl FnMut(u64, usize, std::ops::Range<usize>)) {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % SECTOR_SIZE as u64) as usize;
+            let n = (SECTOR_SIZE - within).min(len - done);
+            f(pos / SECTOR_SIZE as u64, within, done..done + n);
+            done += n;
+        }
+    }
+}
+
+impl<D: Disk> IoBase for FreezableDisk<D> {
+    type Error = D::Error;
+}
+
+impl<D: Disk> Disk for FreezableDisk<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let buf = self.limit(buf);
+        if !self.is_frozen() {
+            return self.disk.read_at(offset, buf);
+        }
+        let overlay = self.overlay.lock().unwrap();
+        if !overlay.frozen {
+            drop(overlay);
+            return self.disk.read_at(offset, buf);
+        }
+        let mut result = Ok(());
+        Self::pieces(offset, buf.len(), |sector, within, range| {
+            if result.is_err() {
+                return;
+            }
+            match overlay.sectors.get(&sector) {
+                Some(data) => {
+                    buf[range.clone()].copy_from_slice(&data[within..within + range.len()])
+                }
+                None => {
+                    let pos = sector * SECTOR_SIZE as u64 + within as u64;
+                    result = self.disk.read_exact_at(pos, &mut buf[range]);
+                }
+            }
+        });
+        result.map(|()| buf.len())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let buf = &buf[..buf.len().min(self.limits.max_transfer)];
+        let mut overlay = self.overlay.lock().unwrap();
+        if !overlay.frozen {
+            drop(overlay);
+            self.preserve(offset, buf.len())?;
+            return self.disk.write_at(offset, buf);
+        }
+        let mut result = Ok(());
+        Self::pieces(offset, buf.len(), |sector, within, range| {
+            if result.is_err() {
+                return;
+            }
+            let data = match overlay.sectors.entry(sector) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    // a partial write needs the rest of the sector.
+                    let mut data = Box::new([0u8; SECTOR_SIZE]);
+                    if let Err(e) = self
+                        .disk
+                        .read_exact_at(sector * SECTOR_SIZE as u64, &mut data[..])
+                    {
+                        result = Err(e);
+                        return;
+                    }
+                    entry.insert(data)
+                }
+            };
+            data[within..within + range.len()].copy_from_slice(&buf[range]);
+        });
+        result.map(|()| buf.len())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        // nothing reaches the disk while frozen.
+        if self.is_frozen() {
+            return Ok(());
+        }
+        self.disk.flush()
+    }
+
+    /// The store only ever sees the part of the disk it may use.
+    fn size(&self) -> Result<u64, Self::Error> {
+        self.disk.capacity()
+    }
+
+    fn limits(&self) -> DiskLimits {
+        self.limits
+    }
+
+    fn reclaim(&self) -> Result<(), Self::Error> {
+        // nothing reaches the disk while frozen.
+        if self.is_frozen() {
+            return Ok(());
+        }
+        self.disk.reclaim()
+    }
+}
+
+/// The disk of a store as it was when `ObjectStore::open_snapshot`
+/// was called. Writes are kept in memory and never reach the disk.
+pub struct SnapshotDisk<D> {
+    disk: Arc<D>,
+    /// What the live store has overwritten since the snapshot.
+    preserved: Arc<Mutex<Sectors>>,
+    /// What the snapshot itself has written.
+    written: Mutex<Sectors>,
+}
+
+impl<D: Disk> SnapshotDisk<D> {
+    fn read_sector(
+        &self,
+        written: &Sectors,
+        sector: u64,
+        buf: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), D::Error> {
+        if let Some(data) = written.get(&sector) {
+            buf.copy_from_slice(&data[..]);
+            return Ok(());
+        }
+        let preserved = self.preserved.lock().unwrap();
+        match preserved.get(&sector) {
+            Some(data) => buf.copy_from_slice(&data[..]),
+            None => self.disk.read_exact_at(sector * SECTOR_SIZE as u64, buf)?,
+        }
+        Ok(())
+    }
+}
+
+impl<D: Disk> IoBase for SnapshotDisk<D> {
+    type Error = D::Error;
+}
+
+impl<D: Disk> Disk for SnapshotDisk<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = (self.disk.size()?.saturating_sub(offset)).min(buf.len() as u64) as usize;
+        let written = self.written.lock().unwrap();
+        let mut data = [0u8; SECTOR_SIZE];
+        let mut result = Ok(());
+        FreezableDisk::<D>::pieces(offset, len, |sector, within, range| {
+            if result.is_ok() {
+                result = self.read_sector(&written, sector, &mut data);
+                buf[range.clone()].copy_from_slice(&data[within..within + range.len()]);
+            }
+        });
+        result.map(|()| len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = (self.disk.size()?.saturating_sub(offset)).min(buf.len() as u64) as usize;
+        let mut written = self.written.lock().unwrap();
+        let mut result = Ok(());
+        FreezableDisk::<D>::pieces(offset, len, |sector, within, range| {
+            if result.is_err() {
+                return;
+            }
+            let mut data = Box::new([0u8; SECTOR_SIZE]);
+            result = self.read_sector(&written, sector, &mut data);
+            data[within..within + range.len()].copy_from_slice(&buf[range]);
+            written.insert(sector, data);
+        });
+        result.map(|()| len)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        self.disk.size()
+    }
+
+    fn capacity(&self) -> Result<u64, Self::Error> {
+        self.disk.capacity()
+    }
+
+    fn limits(&self) -> DiskLimits {
+        self.disk.limits()
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Stops anything from reaching the disk so that it can be copied
+    /// while the store stays open. Waits for the operation in progress
+    /// to finish and flushes the disk first. Reads and writes keep
+    /// working, with writes held in memory until `thaw`, so a freeze
+    /// should be kept short.
+    ///
+    /// The copy is of a store that was never closed, and is recovered
+    /// like one when it is opened. Stores with a metadata disk can't be
+    /// frozen, since the two disks wouldn't be copied at the same point.
+    pub fn freeze(&self) -> Result<(), ObjectStoreError> {
+        if self.metadata_fs.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "stores with a metadata disk can't be frozen",
+            )
+            .into());
+        }
+        let _fs = self.fs().lock().map_err(lock_poisoned)?;
+        let disk = self.fs.disk();
+        if disk.is_frozen() {
+            return Ok(());
+        }
+        disk.flush().map_err(Error::from)?;
+        disk.freeze();
+        Ok(())
+    }
+
+    /// Writes everything held back since `freeze` to the disk.
+    pub fn thaw(&self) -> Result<(), ObjectStoreError> {
+        let _fs = self.fs().lock().map_err(lock_poisoned)?;
+        self.fs.disk().thaw().map_err(Error::from)?;
+        Ok(())
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.fs.disk().is_frozen()
+    }
+
+    /// Opens the store as it is now as a separate store, which keeps
+    /// seeing this state while this one moves on. Anything written
+    /// through the snapshot stays in memory and is dropped with it.
+    ///
+    /// Like a copy taken with `freeze`, the snapshot is of a store that
+    /// was never closed, and is recovered like one. The sectors this
+    /// store overwrites are copied into memory while the snapshot is
+    /// open, so it should be dropped once it is no longer needed.
+    pub fn open_snapshot(&self) -> Result<ObjectStore<SnapshotDisk<D>>, ObjectStoreError> {
+        if self.metadata_fs.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "stores with a metadata disk can't be snapshotted",
+            )
+            .into());
+        }
+        let disk = {
+            let _fs = self.fs().lock().map_err(lock_poisoned)?;
+            let disk = self.fs.disk();
+            disk.flush().map_err(Error::from)?;
+            disk.snapshot()
+        };
+        ObjectStore::open_takeover(disk, self.root_key)
+    }
+}
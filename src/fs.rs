@@ -1,75 +1,348 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    io::{Error, ErrorKind},
+    sync::{Arc, Mutex, PoisonError},
+};
 
+use crate::freeze::FreezableDisk;
 use fatfs::{
-    FatType, FormatVolumeOptions, IoBase, LossyOemCpConverter, NullTimeProvider, ReadWriteSeek,
+    FatType, FormatVolumeOptions, IoBase, IoError, LossyOemCpConverter, Read, Seek, SeekFrom, Write,
 };
+use serde::{Deserialize, Serialize};
+
+/// A block device addressed by byte offset. Every access carries its
+/// own offset, so a disk has no cursor that concurrent users could
+/// move out from under each other.
+pub trait Disk: IoBase {
+    /// Reads into `buf` starting at `offset`, returning the number of
+    /// bytes read.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error>;
+    /// Writes `buf` starting at `offset`, returning the number of bytes
+    /// written.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error>;
+    fn flush(&self) -> Result<(), Self::Error>;
+    /// Returns the length of the disk in bytes.
+    fn size(&self) -> Result<u64, Self::Error>;
+
+    /// Returns how many bytes from the start of the disk the store may
+    /// use. Defaults to the whole disk.
+    fn capacity(&self) -> Result<u64, Self::Error> {
+        self.size()
+    }
+
+    /// Returns what the disk can do in a single access.
+    fn limits(&self) -> DiskLimits {
+        DiskLimits::default()
+    }
+
+    /// Called after every epoch. Disks that keep overwritten data
+    /// around, like the page map of `ZonedDisk`, erase it here, so that
+    /// the ciphertext the epoch replaced doesn't outlive its keys.
+    fn reclaim(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn read_exact_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), Self::Error> {
+        while !buf.is_empty() {
+            match self.read_at(offset, buf) {
+                Ok(0) => return Err(Self::Error::new_unexpected_eof_error()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.is_interrupted() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut offset: u64, mut buf: &[u8]) -> Result<(), Self::Error> {
+        while !buf.is_empty() {
+            match self.write_at(offset, buf) {
+                Ok(0) => return Err(Self::Error::new_write_zero_error()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.is_interrupted() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a disk can do in a single access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskLimits {
+    /// The most bytes a single read or write may move. Larger accesses
+    /// are split.
+    pub max_transfer: usize,
+    /// The alignment accesses are best made at. Whole page writes are
+    /// aligned to pages, but writes made through the filesystem may not
+    /// be aligned at all.
+    pub alignment: u64,
+}
+
+impl Default for DiskLimits {
+    fn default() -> Self {
+        Self {
+            max_transfer: usize::MAX,
+            alignment: 1,
+        }
+    }
+}
+
+/// The stateful Read/Write/Seek view of a disk that fatfs expects.
+/// Each filesystem gets its own cursor, so the position is never
+/// shared with anything else using the disk.
+pub(crate) struct DiskCursor<D> {
+    disk: Arc<FreezableDisk<D>>,
+    pos: u64,
+}
+
+impl<D: Disk> DiskCursor<D> {
+    pub fn new(disk: Arc<FreezableDisk<D>>) -> Self {
+        Self { disk, pos: 0 }
+    }
+}
+
+impl<D: Disk> IoBase for DiskCursor<D> {
+    type Error = D::Error;
+}
+
+impl<D: Disk> Read for DiskCursor<D> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.disk.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Disk> Write for DiskCursor<D> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.disk.write_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.disk.flush()
+    }
+}
+
+impl<D: Disk> Seek for DiskCursor<D> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.pos = match pos {
+            SeekFrom::Start(n) => n,
+            SeekFrom::Current(n) => self.pos.saturating_add_signed(n),
+            SeekFrom::End(n) => self.disk.size()?.saturating_add_signed(n),
+        };
+        Ok(self.pos)
+    }
+}
+
+/// The time provider and OEM codepage converter the volume is opened
+/// with. obliviate persists the KHF and WAL through a `FileSystem` with
+/// fatfs' default parameters, so these have to stay the defaults.
+/// Every directory entry is stamped 1980-01-01 and short names are
+/// ASCII only, anything else decoding to U+FFFD.
+pub(crate) type FsTimeProvider = fatfs::DefaultTimeProvider;
+pub(crate) type FsOemCpConverter = LossyOemCpConverter;
 
-pub trait Disk: fatfs::ReadWriteSeek + IoBase + Clone {}
+pub(crate) type FatFs<D> = fatfs::FileSystem<DiskCursor<D>, FsTimeProvider, FsOemCpConverter>;
+pub(crate) type FatDir<'a, D> = fatfs::Dir<'a, DiskCursor<D>, FsTimeProvider, FsOemCpConverter>;
+pub(crate) type FatFile<'a, D> = fatfs::File<'a, DiskCursor<D>, FsTimeProvider, FsOemCpConverter>;
 
-impl<T> Disk for T where T: ReadWriteSeek + IoBase + Clone {}
-#[derive(Clone)]
+/// Which FAT the volume is formatted with. Clusters are always one
+/// page, so the flavor bounds the size of the volume: FAT12 suits
+/// stores up to 16 MiB and FAT16 up to 256 MiB, where FAT32 would
+/// spend most of a tiny disk on its reserved sectors and FATs.
+///
+/// fatfs can't read or write exFAT, so volumes too large for page
+/// sized FAT32 clusters aren't supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FatFlavor {
+    Fat12,
+    Fat16,
+    #[default]
+    Fat32,
+}
+
+impl From<FatFlavor> for FatType {
+    fn from(flavor: FatFlavor) -> Self {
+        match flavor {
+            FatFlavor::Fat12 => FatType::Fat12,
+            FatFlavor::Fat16 => FatType::Fat16,
+            FatFlavor::Fat32 => FatType::Fat32,
+        }
+    }
+}
+
+impl From<FatType> for FatFlavor {
+    fn from(fat_type: FatType) -> Self {
+        match fat_type {
+            FatType::Fat12 => FatFlavor::Fat12,
+            FatType::Fat16 => FatFlavor::Fat16,
+            FatType::Fat32 => FatFlavor::Fat32,
+        }
+    }
+}
+
+/// Parameters the FAT volume is formatted and opened with. The OEM
+/// codepage and timestamps aren't among them: they are fatfs' defaults,
+/// which obliviate needs, so external tools see ASCII short names and
+/// 1980-01-01 on every entry.
+#[derive(Clone, Copy, Debug)]
+pub struct FsConfig {
+    update_accessed_date: bool,
+    volume_label: [u8; 11],
+    pub(crate) fat_flavor: FatFlavor,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            update_accessed_date: false,
+            volume_label: *b"NO NAME    ",
+            fat_flavor: FatFlavor::default(),
+        }
+    }
+}
+
+impl FsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the access date of a file every time it is read. Off by
+    /// default since it turns reads into writes.
+    pub fn update_accessed_date(mut self, update_accessed_date: bool) -> Self {
+        self.update_accessed_date = update_accessed_date;
+        self
+    }
+
+    /// The label external tools show for the volume, space padded to 11
+    /// bytes. Only used when formatting.
+    pub fn volume_label(mut self, label: &str) -> Result<Self, Error> {
+        if label.len() > 11 || !label.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "volume labels are at most 11 printable ascii bytes",
+            ));
+        }
+        self.volume_label = [b' '; 11];
+        self.volume_label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(self)
+    }
+
+    /// The FAT to format the volume with. Only used when formatting;
+    /// opening a volume reads its flavor from the boot sector.
+    pub fn fat_flavor(mut self, fat_flavor: FatFlavor) -> Self {
+        self.fat_flavor = fat_flavor;
+        self
+    }
+
+    fn fs_options(&self) -> fatfs::FsOptions<FsTimeProvider, FsOemCpConverter> {
+        fatfs::FsOptions::new()
+            .update_accessed_date(self.update_accessed_date)
+            .time_provider(FsTimeProvider::new())
+            .oem_cp_converter(FsOemCpConverter::new())
+    }
+}
+
+/// The disk is shared between the store and its filesystem, so it
+/// doesn't need to be `Clone`.
 pub(crate) struct FileSystem<D: Disk> {
-    disk: D,
-    fs: Arc<Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>>>,
+    disk: Arc<FreezableDisk<D>>,
+    fs: Arc<Mutex<FatFs<D>>>,
+    config: FsConfig,
 }
 
 pub const PAGE_SIZE: usize = 4096;
 pub const SECTOR_SIZE: usize = 512;
 
 impl<D: Disk> FileSystem<D> {
-    pub fn format(disk: &mut D) {
+    pub fn format(disk: &Arc<D>, config: FsConfig) -> Result<(), fatfs::Error<D::Error>> {
+        Self::format_volume(Arc::new(FreezableDisk::new(disk.clone())), config)
+    }
+
+    fn format_volume(
+        disk: Arc<FreezableDisk<D>>,
+        config: FsConfig,
+    ) -> Result<(), fatfs::Error<D::Error>> {
         let options = FormatVolumeOptions::new()
             .bytes_per_sector(SECTOR_SIZE as u16)
             .bytes_per_cluster(PAGE_SIZE as u32)
-            .fat_type(FatType::Fat32);
-        fatfs::format_volume(disk, options).unwrap();
-    }
-    /// Will attempt to open the filesystem
-    /// and will reformat the filesystem if it is unable to open it
-    pub fn open_fs(mut disk: D) -> FileSystem<D> {
-        let fs_options = fatfs::FsOptions::new().update_accessed_date(false);
-        let fs = fatfs::FileSystem::new(disk.clone(), fs_options);
-        if let Ok(fs) = fs {
-            return Self {
-                fs: Arc::new(Mutex::new(fs)),
-                disk,
-            };
-        }
-        drop(fs);
-        disk.seek(fatfs::SeekFrom::Start(0)).unwrap();
-        Self::format(&mut disk);
-        let fs = fatfs::FileSystem::new(disk.clone(), fs_options)
-            .expect("disk should be formatted now so no more errors.");
-        Self {
+            .fat_type(config.fat_flavor.into())
+            .volume_label(config.volume_label);
+        fatfs::format_volume(&mut DiskCursor::new(disk), options)
+    }
+    /// Will attempt to open the filesystem, returning an error if the
+    /// disk does not contain a valid volume.
+    pub fn open_fs(
+        disk: Arc<D>,
+        mut config: FsConfig,
+    ) -> Result<FileSystem<D>, fatfs::Error<D::Error>> {
+        let disk = Arc::new(FreezableDisk::new(disk));
+        let fs = fatfs::FileSystem::new(DiskCursor::new(disk.clone()), config.fs_options())?;
+        // so that reformatting keeps the flavor.
+        config.fat_flavor = fs.fat_type().into();
+        Ok(Self {
             fs: Arc::new(Mutex::new(fs)),
             disk,
+            config,
+        })
+    }
+    /// Will attempt to open the filesystem and will format the disk if
+    /// it holds no recognizable FAT volume. Also returns whether the
+    /// disk was formatted.
+    ///
+    /// Any other error, such as a failed read, is returned without
+    /// touching the disk.
+    pub fn open_or_format(
+        disk: Arc<D>,
+        config: FsConfig,
+    ) -> Result<(FileSystem<D>, bool), fatfs::Error<D::Error>> {
+        match Self::open_fs(disk.clone(), config) {
+            Ok(fs) => return Ok((fs, false)),
+            // fatfs' verdict on a boot sector it can't make sense of.
+            Err(fatfs::Error::CorruptedFileSystem) => {}
+            Err(e) => return Err(e),
         }
+        Self::format(&disk, config)?;
+        Ok((Self::open_fs(disk, config)?, true))
     }
 
-    pub fn reopen(&mut self) {
-        let fs_options = fatfs::FsOptions::new().update_accessed_date(false);
-        self.disk.seek(fatfs::SeekFrom::Start(0)).unwrap();
-        let fs = fatfs::FileSystem::new(self.disk.clone(), fs_options);
-        if let Ok(fs) = fs {
-            *self.fs.lock().unwrap() = fs;
-        }
-        self.disk.seek(fatfs::SeekFrom::Start(0)).unwrap();
-        let fs = fatfs::FileSystem::new(self.disk.clone(), fs_options)
-            .expect("disk should be formatted now so no more errors.");
-        *self.fs.lock().unwrap() = fs;
+    /// Formats the disk the filesystem is on again, dropping everything
+    /// on it.
+    pub fn reformat(&mut self) -> Result<(), fatfs::Error<D::Error>> {
+        Self::format_volume(self.disk.clone(), self.config)?;
+        self.reopen()
+    }
+
+    pub fn reopen(&mut self) -> Result<(), fatfs::Error<D::Error>> {
+        let fs =
+            fatfs::FileSystem::new(DiskCursor::new(self.disk.clone()), self.config.fs_options())?;
+        // the old filesystem is being thrown away so a poisoned lock
+        // doesn't matter here.
+        *self.fs.lock().unwrap_or_else(PoisonError::into_inner) = fs;
+        Ok(())
     }
 
-    pub fn fs(&self) -> &Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>> {
+    pub fn fs(&self) -> &Mutex<FatFs<D>> {
         &self.fs
     }
 
-    pub fn fs_as_owned(
-        &self,
-    ) -> Arc<Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>>> {
+    pub fn fs_as_owned(&self) -> Arc<Mutex<FatFs<D>>> {
         self.fs.clone()
     }
 
-    pub fn disk(&self) -> &D {
+    pub fn disk(&self) -> &FreezableDisk<D> {
         &self.disk
     }
+
+    pub fn config(&self) -> FsConfig {
+        self.config
+    }
 }
@@ -0,0 +1,119 @@
+use crate::{fs::Disk, object_store::lock_poisoned, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+};
+
+/// Which life of each object open handles refer to. An object gets a
+/// new incarnation the first time a handle is opened on it after it
+/// was created, and loses it when it is unlinked.
+#[derive(Debug, Default)]
+pub(crate) struct Incarnations {
+    next: u64,
+    live: HashMap<u128, u64>,
+}
+
+impl Incarnations {
+    /// Ends the current life of `obj_id`, invalidating its handles.
+    pub(crate) fn retire(&mut self, obj_id: u128) {
+        self.live.remove(&obj_id);
+    }
+}
+
+/// An open object, from `ObjectStore::open_object`. Once the object is
+/// unlinked every operation fails with `NotFound`, even if an object
+/// with the same id has been created since, so a handle never reaches
+/// clusters whose keys are waiting to be securely deleted.
+pub struct ObjectHandle<'a, D: Disk> {
+    store: &'a ObjectStore<D>,
+    obj_id: u128,
+    incarnation: u64,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Opens a handle on an existing object.
+    ///
+    /// # Errors
+    /// `NotFound` if the object doesn't exist.
+    pub fn open_object(&self, obj_id: u128) -> Result<ObjectHandle<'_, D>, ObjectStoreError> {
+        let mut incarnations = self.incarnations.lock().map_err(lock_poisoned)?;
+        self.disk_length(obj_id)?;
+        let Incarnations { next, live } = &mut *incarnations;
+        let incarnation = *live.entry(obj_id).or_insert_with(|| {
+            *next += 1;
+            *next
+        });
+        Ok(ObjectHandle {
+            store: self,
+            obj_id,
+            incarnation,
+        })
+    }
+}
+
+impl<'a, D> ObjectHandle<'a, D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn obj_id(&self) -> u128 {
+        self.obj_id
+    }
+
+    /// Whether the object the handle was opened on still exists.
+    pub fn is_valid(&self) -> Result<bool, ObjectStoreError> {
+        Ok(self.check().is_ok())
+    }
+
+    /// Fails unless the object is still in the life the handle was
+    /// opened in. The guard is held for the whole operation so that an
+    /// unlink can't slip in between the check and the access.
+    fn check(&self) -> Result<MutexGuard<'a, Incarnations>, Error> {
+        let incarnations = self.store.incarnations.lock().map_err(lock_poisoned)?;
+        if incarnations.live.get(&self.obj_id) != Some(&self.incarnation) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "object was unlinked after the handle was opened",
+            ));
+        }
+        Ok(incarnations)
+    }
+
+    pub fn len(&self) -> Result<u64, ObjectStoreError> {
+        let _live = self.check()?;
+        self.store.disk_length(self.obj_id)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, ObjectStoreError> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn read_exact(&self, buf: &mut [u8], off: u64) -> Result<(), ObjectStoreError> {
+        let _live = self.check()?;
+        self.store.read_exact(self.obj_id, buf, off)
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], off: u64) -> Result<usize, ObjectStoreError> {
+        let _live = self.check()?;
+        self.store.read_at(self.obj_id, buf, off)
+    }
+
+    pub fn write_all(&self, buf: &[u8], off: u64) -> Result<(), ObjectStoreError> {
+        let _live = self.check()?;
+        self.store.write_all(self.obj_id, buf, off)
+    }
+}
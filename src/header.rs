@@ -0,0 +1,219 @@
+use crate::{
+    fs::{Disk, SECTOR_SIZE},
+    layout::Layout,
+    mac::IntegrityHash,
+    superblock::{KeyMode, Superblock},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+const MAGIC: [u8; 8] = *b"TWZRAWHD";
+const VERSION: u32 = 1;
+/// The header lives in the FAT32 reserved sectors, after the boot
+/// sector and FSInfo and before the backup boot sector at sector 6.
+/// fatfs never reads or writes these sectors.
+pub(crate) const HEADER_OFFSET: u64 = 2 * SECTOR_SIZE as u64;
+pub(crate) const HEADER_LEN: usize = SECTOR_SIZE;
+/// Bytes past this are zeroed and reserved for future metadata.
+const USED_LEN: usize = 52;
+/// Sectors the header needs the boot sector to reserve.
+const HEADER_END_SECTOR: u64 = (HEADER_OFFSET + HEADER_LEN as u64) / SECTOR_SIZE as u64;
+/// Number of directories object files are spread across, one per
+/// leading hex digit of their name.
+pub(crate) const DIR_FANOUT: u16 = 16;
+
+/// How object data is encrypted on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    Plaintext,
+    /// ChaCha20 keyed per chunk or per volume, depending on the
+    /// `KeyMode`.
+    ChaCha20,
+}
+
+impl CipherSuite {
+    pub(crate) fn for_key_mode(key_mode: KeyMode) -> Self {
+        match key_mode {
+            KeyMode::Plaintext => CipherSuite::Plaintext,
+            KeyMode::Khf | KeyMode::Volume => CipherSuite::ChaCha20,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            CipherSuite::Plaintext => 0,
+            CipherSuite::ChaCha20 => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self, Error> {
+        match b {
+            0 => Ok(CipherSuite::Plaintext),
+            1 => Ok(CipherSuite::ChaCha20),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown cipher suite")),
+        }
+    }
+}
+
+/// A fixed size header at a known disk offset, readable without
+/// mounting the FAT volume. It mirrors the superblock so that tools
+/// can identify a store from the raw device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawHeader {
+    version: u32,
+    uuid: u128,
+    cipher_suite: CipherSuite,
+    integrity_hash: IntegrityHash,
+    fanout: u16,
+    epoch: u64,
+    layout: Option<Layout>,
+}
+
+/// Returns whether the volume reserves the sectors the header lives
+/// in. FAT32 volumes do, while FAT12 and FAT16 volumes start their FAT
+/// right after the boot sector and have no room for one.
+pub(crate) fn header_fits<D: Disk>(disk: &D) -> Result<bool, Error>
+where
+    std::io::Error: From<D::Error>,
+{
+    let mut reserved = [0u8; 2];
+    disk.read_exact_at(14, &mut reserved)?;
+    Ok(u16::from_le_bytes(reserved) as u64 >= HEADER_END_SECTOR)
+}
+
+impl RawHeader {
+    pub(crate) fn new(superblock: &Superblock, layout: Layout) -> Self {
+        Self {
+            version: VERSION,
+            uuid: superblock.uuid,
+            cipher_suite: CipherSuite::for_key_mode(superblock.key_mode),
+            integrity_hash: superblock.integrity_hash,
+            fanout: DIR_FANOUT,
+            epoch: superblock.generation,
+            layout: Some(layout),
+        }
+    }
+
+    pub fn magic(&self) -> [u8; 8] {
+        MAGIC
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn uuid(&self) -> u128 {
+        self.uuid
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
+
+    pub fn integrity_hash(&self) -> IntegrityHash {
+        self.integrity_hash
+    }
+
+    pub fn fanout(&self) -> u16 {
+        self.fanout
+    }
+
+    /// The number of epochs the store has been through.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// How chunk ids map onto the disk. `None` for headers written
+    /// before the layout was recorded.
+    pub fn layout(&self) -> Option<Layout> {
+        self.layout
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut out = [0u8; HEADER_LEN];
+        out[0..8].copy_from_slice(&MAGIC);
+        out[8..12].copy_from_slice(&self.version.to_le_bytes());
+        out[12..28].copy_from_slice(&self.uuid.to_le_bytes());
+        out[28] = self.cipher_suite.to_byte();
+        out[29] = self.integrity_hash.to_byte();
+        out[30..32].copy_from_slice(&self.fanout.to_le_bytes());
+        out[32..40].copy_from_slice(&self.epoch.to_le_bytes());
+        if let Some(layout) = self.layout {
+            out[40..48].copy_from_slice(&layout.cluster_offset().to_le_bytes());
+            out[48..USED_LEN].copy_from_slice(&(layout.chunk_size() as u32).to_le_bytes());
+        }
+        out
+    }
+
+    /// Returns `None` if there is no header, as on volumes formatted
+    /// before it existed.
+    fn from_bytes(buf: &[u8; HEADER_LEN]) -> Result<Option<Self>, Error> {
+        if buf[0..8] != MAGIC {
+            return Ok(None);
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported raw header version",
+            ));
+        }
+        let cluster_offset = u64::from_le_bytes(buf[40..48].try_into().unwrap());
+        let layout = match u32::from_le_bytes(buf[48..USED_LEN].try_into().unwrap()) {
+            0 => None,
+            chunk_size => Some(Layout::new(cluster_offset, chunk_size as u64)?),
+        };
+        Ok(Some(Self {
+            version,
+            uuid: u128::from_le_bytes(buf[12..28].try_into().unwrap()),
+            cipher_suite: CipherSuite::from_byte(buf[28])?,
+            integrity_hash: IntegrityHash::from_byte(buf[29])?,
+            fanout: u16::from_le_bytes(buf[30..32].try_into().unwrap()),
+            epoch: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
+            layout,
+        }))
+    }
+
+    pub(crate) fn load<D: Disk>(disk: &D) -> Result<Option<Self>, Error>
+    where
+        std::io::Error: From<D::Error>,
+    {
+        if !header_fits(disk)? {
+            return Ok(None);
+        }
+        let mut buf = [0u8; HEADER_LEN];
+        disk.read_exact_at(HEADER_OFFSET, &mut buf)?;
+        Self::from_bytes(&buf)
+    }
+
+    pub(crate) fn store<D: Disk>(&self, disk: &D) -> Result<(), Error>
+    where
+        std::io::Error: From<D::Error>,
+    {
+        if !header_fits(disk)? {
+            return Ok(());
+        }
+        disk.write_all_at(HEADER_OFFSET, &self.to_bytes())?;
+        Ok(())
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Reads the raw header straight off the disk. Returns `None` if
+    /// the store hasn't written one yet, which it does when it is
+    /// formatted and whenever the superblock is updated, or if the
+    /// volume has no room for one.
+    pub fn raw_header(&self) -> Result<Option<RawHeader>, ObjectStoreError> {
+        Ok(RawHeader::load(self.fs.disk())?)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{
+    fs::{Disk, DiskCursor, FatFile, FatFs, PAGE_SIZE},
+    meta::{read_meta, update_meta, write_meta},
+    ObjectStore,
+};
+use fatfs::{IoBase, ReadWriteProxy, Seek, SeekFrom};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Error, ops::Range, sync::MutexGuard};
+
+pub(crate) const HOLES_PATH: &str = "meta/holes";
+
+/// The parts of objects that a write past the end allocated but that
+/// were never written. Their clusters hold whatever was on the disk
+/// before, so they are read as zeros without touching the disk.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct HoleTable {
+    /// Sorted, disjoint byte ranges of each object.
+    holes: BTreeMap<u128, Vec<(u64, u64)>>,
+}
+
+impl HoleTable {
+    pub fn of(&self, obj_id: u128) -> Vec<Range<u64>> {
+        self.holes
+            .get(&obj_id)
+            .into_iter()
+            .flatten()
+            .map(|&(start, end)| start..end)
+            .collect()
+    }
+
+    /// Records `start..end` as never written. Holes are only made past
+    /// the end of an object, so the range goes last, and any hole from
+    /// `start` on was left by an extension a crash cut short.
+    pub fn punch(&mut self, obj_id: u128, start: u64, end: u64) {
+        let holes = self.holes.entry(obj_id).or_default();
+        holes.retain(|&(s, _)| s < start);
+        if let Some((_, last)) = holes.last_mut() {
+            *last = (*last).min(start);
+        }
+        match holes.last_mut() {
+            Some((_, last)) if *last == start => *last = end,
+            _ => holes.push((start, end)),
+        }
+    }
+
+    /// Marks `start..end` as written. Returns false if it didn't
+    /// overlap any hole.
+    pub fn fill(&mut self, obj_id: u128, start: u64, end: u64) -> bool {
+        let Some(holes) = self.holes.get(&obj_id) else {
+            return false;
+        };
+        if !holes.iter().any(|&(s, e)| s < end && e > start) {
+            return false;
+        }
+        let mut kept = Vec::new();
+        for &(s, e) in holes {
+            if e <= start || s >= end {
+                kept.push((s, e));
+                continue;
+            }
+            if s < start {
+                kept.push((s, start));
+            }
+            if e > end {
+                kept.push((end, e));
+            }
+        }
+        if kept.is_empty() {
+            self.holes.remove(&obj_id);
+        } else {
+            self.holes.insert(obj_id, kept);
+        }
+        true
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub(crate) fn holes_lock(
+        &self,
+        fs: &FatFs<D>,
+    ) -> Result<MutexGuard<'_, Option<HoleTable>>, Error> {
+        let mut holes = self.holes.lock().unwrap();
+        if holes.is_none() {
+            *holes = Some(read_meta(fs, &self.meta_key(), HOLES_PATH)?.unwrap_or_default());
+        }
+        Ok(holes)
+    }
+
+    /// Returns the never written ranges of an object, in order.
+    pub(crate) fn unwritten_ranges(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+    ) -> Result<Vec<Range<u64>>, Error> {
+        Ok(self.holes_lock(fs)?.as_ref().unwrap().of(obj_id))
+    }
+
+    pub(crate) fn store_holes(&self, fs: &FatFs<D>, holes: &HoleTable) -> Result<(), Error> {
+        write_meta(fs, &self.meta_key(), HOLES_PATH, holes)
+    }
+
+    pub(crate) fn forget_holes(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut holes = self.holes_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            HOLES_PATH,
+            holes.as_mut().unwrap(),
+            |holes| holes.holes.remove(&obj_id).is_some(),
+        )?;
+        Ok(())
+    }
+
+    /// Grows `file` by `len` bytes without writing them, so no keys
+    /// are derived for the new clusters.
+    pub(crate) fn extend_unwritten(
+        &self,
+        file: &mut FatFile<'_, D>,
+        len: u64,
+    ) -> Result<(), Error> {
+        file.seek(SeekFrom::End(0))?;
+        let mut rw_proxy = ReadWriteProxy::new(
+            file,
+            || {},
+            |_: &mut DiskCursor<D>,
+             _: u64,
+             buffer: &[u8]|
+             -> Result<usize, fatfs::Error<D::Error>> { Ok(buffer.len()) },
+        );
+        let zeroes = [0u8; PAGE_SIZE];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(PAGE_SIZE as u64) as usize;
+            fatfs::Write::write_all(&mut rw_proxy, &zeroes[..n])?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf` from `off` in `file`, filling the parts that fall in
+    /// `unwritten` with zeros instead of reading them.
+    pub(crate) fn read_file_at(
+        &self,
+        file: &mut FatFile<'_, D>,
+        buf: &mut [u8],
+        off: u64,
+        unwritten: &[Range<u64>],
+    ) -> Result<(), Error> {
+        let end = off + buf.len() as u64;
+        let mut pos = off;
+        for hole in unwritten {
+            let (start, stop) = (hole.start.max(off), hole.end.min(end));
+            if start >= stop {
+                continue;
+            }
+            if start > pos {
+                file.seek(SeekFrom::Start(pos))?;
+                self.read_file(file, &mut buf[(pos - off) as usize..(start - off) as usize])?;
+            }
+            buf[(start - off) as usize..(stop - off) as usize].fill(0);
+            pos = stop;
+        }
+        if pos < end {
+            file.seek(SeekFrom::Start(pos))?;
+            self.read_file(file, &mut buf[(pos - off) as usize..])?;
+        }
+        Ok(())
+    }
+}
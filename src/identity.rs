@@ -0,0 +1,110 @@
+use crate::{
+    fs::{Disk, FatFs},
+    header::RawHeader,
+    superblock::Superblock,
+    ObjectStore,
+};
+use fatfs::IoBase;
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    sync::atomic::Ordering,
+};
+
+/// Identifies which disk a store lives on and how far along it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaIdentity {
+    pub uuid: u128,
+    /// Bumped by every epoch, so an image restored from before an
+    /// epoch has a lower generation than the store it was taken from.
+    pub generation: u64,
+}
+
+/// Returned when a disk isn't the one the store expected, either
+/// because it was swapped for another store or because it was rolled
+/// back to an older image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaMismatch {
+    pub expected: MediaIdentity,
+    pub found: MediaIdentity,
+}
+
+impl fmt::Display for MediaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.expected.uuid != self.found.uuid {
+            write!(
+                f,
+                "expected store {:032x} but the disk holds store {:032x}",
+                self.expected.uuid, self.found.uuid
+            )
+        } else {
+            write!(
+                f,
+                "expected generation {} or later but the disk is at generation {}",
+                self.expected.generation, self.found.generation
+            )
+        }
+    }
+}
+
+impl std::error::Error for MediaMismatch {}
+
+impl From<MediaMismatch> for Error {
+    fn from(value: MediaMismatch) -> Self {
+        Error::new(ErrorKind::InvalidData, value)
+    }
+}
+
+/// Returns the mismatch carried by `err`, if the store refused to open
+/// a disk it didn't expect.
+pub fn media_mismatch(err: &Error) -> Option<&MediaMismatch> {
+    err.get_ref()?.downcast_ref::<MediaMismatch>()
+}
+
+/// Checks that `found` is the same store as `expected` and no older.
+pub(crate) fn check_media(expected: MediaIdentity, found: MediaIdentity) -> Result<(), Error> {
+    if found.uuid != expected.uuid || found.generation < expected.generation {
+        return Err(MediaMismatch { expected, found }.into());
+    }
+    Ok(())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the random id the store was given when it was formatted.
+    pub fn store_uuid(&self) -> u128 {
+        self.uuid
+    }
+
+    pub fn media_identity(&self) -> MediaIdentity {
+        MediaIdentity {
+            uuid: self.uuid,
+            generation: self.generation.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn store_superblock(&self, fs: &FatFs<D>, clean: bool) -> Result<(), Error> {
+        let superblock = Superblock {
+            key_mode: self.key_mode(),
+            uuid: self.uuid,
+            generation: self.generation.load(Ordering::Relaxed),
+            clean,
+            blind_ids: self.blinds_object_ids(),
+            integrity_hash: self.integrity_hash,
+            metadata: self.metadata_placement,
+        };
+        superblock.store(fs, self.root_key)?;
+        RawHeader::new(&superblock, self.layout).store(self.fs.disk())?;
+        // the clean flag orders the khf files around it, so it has to
+        // reach the disk before they are touched.
+        self.fs.disk().flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, read_raw, seal, unseal, write_meta, META_DIR, NONCE_LEN, TAG_LEN},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::{IoBase, Seek, SeekFrom, Write as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    ops::Bound,
+    sync::MutexGuard,
+};
+
+pub(crate) const INDEX_PATH: &str = "meta/index";
+/// Changes made to the index since it was last written whole.
+pub(crate) const INDEX_LOG_PATH: &str = "meta/index.log";
+/// The shortest key accepted by the secondary index.
+pub const MIN_INDEX_KEY_LEN: usize = 16;
+/// The longest key accepted by the secondary index.
+pub const MAX_INDEX_KEY_LEN: usize = 32;
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+/// An op byte, the key length, the key padded to the longest key and an
+/// object id, sealed on their own.
+pub(crate) const LOG_RECORD_LEN: usize = NONCE_LEN + 2 + MAX_INDEX_KEY_LEN + 16 + TAG_LEN;
+
+/// Ordered map from user chosen keys to object ids.
+///
+/// Kept on disk as the index written whole at the start of the last
+/// epoch, followed by a log of the changes made since, so that an
+/// update costs one record rather than a rewrite of the whole index.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SecondaryIndex {
+    entries: BTreeMap<Vec<u8>, u128>,
+}
+
+fn log_record(meta_key: &[u8; 32], op: u8, key: &[u8], obj_id: u128) -> Vec<u8> {
+    let mut plaintext = [0u8; 2 + MAX_INDEX_KEY_LEN + 16];
+    plaintext[0] = op;
+    plaintext[1] = key.len() as u8;
+    plaintext[2..2 + key.len()].copy_from_slice(key);
+    plaintext[2 + MAX_INDEX_KEY_LEN..].copy_from_slice(&obj_id.to_le_bytes());
+    seal(meta_key, &plaintext)
+}
+
+impl SecondaryIndex {
+    /// Applies the log on top of the index. Replaying changes the index
+    /// already has is harmless, so a crash between writing the index
+    /// whole and removing the log loses nothing.
+    fn replay<D>(&mut self, fs: &FatFs<D>, meta_key: &[u8; 32]) -> Result<(), Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+    {
+        let Some(raw) = read_raw(fs, INDEX_LOG_PATH)? else {
+            return Ok(());
+        };
+        let whole = raw.len() - raw.len() % LOG_RECORD_LEN;
+        for sealed in raw[..whole].chunks_exact(LOG_RECORD_LEN) {
+            let corrupt = || Error::new(ErrorKind::InvalidData, "index log failed integrity check");
+            let plaintext = unseal(meta_key, sealed).ok_or_else(corrupt)?;
+            let key_len = plaintext[1] as usize;
+            if key_len > MAX_INDEX_KEY_LEN {
+                return Err(corrupt());
+            }
+            let key = plaintext[2..2 + key_len].to_vec();
+            let obj_id =
+                u128::from_le_bytes(plaintext[2 + MAX_INDEX_KEY_LEN..].try_into().unwrap());
+            match plaintext[0] {
+                SET => self.entries.insert(key, obj_id),
+                REMOVE => self.entries.remove(&key),
+                _ => return Err(corrupt()),
+            };
+        }
+        if whole != raw.len() {
+            // a torn append, which was never acknowledged. It has to go
+            // before anything is appended after it.
+            let mut file = fs.root_dir().open_file(INDEX_LOG_PATH)?;
+            file.seek(SeekFrom::Start(whole as u64))?;
+            file.truncate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends `records` to the index log. A failed append is cut back off
+/// where possible, so that it can't misalign the records after it.
+fn append_log<D>(fs: &FatFs<D>, records: &[u8]) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    fs.root_dir().create_dir(META_DIR)?;
+    let mut file = fs.root_dir().create_file(INDEX_LOG_PATH)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    if let Err(e) = file.write_all(records) {
+        let _ = file
+            .seek(SeekFrom::Start(len))
+            .and_then(|_| file.truncate());
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn check_key(key: &[u8]) -> Result<(), Error> {
+    if !(MIN_INDEX_KEY_LEN..=MAX_INDEX_KEY_LEN).contains(&key.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "index keys must be between 16 and 32 bytes",
+        ));
+    }
+    Ok(())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the index, loading it from disk the first time it is
+    /// used so that stores which don't use the index don't pay for it.
+    fn index_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<SecondaryIndex>>, Error> {
+        let mut index = self.index.lock().unwrap();
+        if index.is_none() {
+            let meta_key = self.meta_key();
+            let mut loaded: SecondaryIndex =
+                read_meta(fs, &meta_key, INDEX_PATH)?.unwrap_or_default();
+            loaded.replay(fs, &meta_key)?;
+            *index = Some(loaded);
+        }
+        Ok(index)
+    }
+
+    /// Writes the index whole and removes its log, so that the log only
+    /// grows between epochs.
+    pub(crate) fn compact_index(&self, fs: &FatFs<D>) -> Result<(), Error> {
+        match fs.root_dir().open_file(INDEX_LOG_PATH) {
+            Ok(_) => {}
+            Err(fatfs::Error::NotFound) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let index = self.index_lock(fs)?;
+        write_meta(fs, &self.meta_key(), INDEX_PATH, index.as_ref().unwrap())?;
+        fs.root_dir().remove(INDEX_LOG_PATH)?;
+        Ok(())
+    }
+
+    /// Maps `key` to an existing object, returning the object `key`
+    /// previously mapped to.
+    pub fn index_insert(&self, key: &[u8], obj_id: u128) -> Result<Option<u128>, ObjectStoreError> {
+        check_key(key)?;
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        get_dir_path(&fs, &b64)?.open_file(&b64)?;
+        let mut index = self.index_lock(&fs)?;
+        append_log(&fs, &log_record(&self.meta_key(), SET, key, obj_id))?;
+        Ok(index.as_mut().unwrap().entries.insert(key.to_vec(), obj_id))
+    }
+
+    /// Removes `key` from the index, returning the object it mapped to.
+    pub fn index_remove(&self, key: &[u8]) -> Result<Option<u128>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let mut index = self.index_lock(&fs)?;
+        let index = index.as_mut().unwrap();
+        let Some(&obj_id) = index.entries.get(key) else {
+            return Ok(None);
+        };
+        append_log(&fs, &log_record(&self.meta_key(), REMOVE, key, obj_id))?;
+        Ok(index.entries.remove(key))
+    }
+
+    pub fn index_lookup(&self, key: &[u8]) -> Result<Option<u128>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let index = self.index_lock(&fs)?;
+        Ok(index.as_ref().unwrap().entries.get(key).copied())
+    }
+
+    /// Returns every entry with a key between `start` and `end`, in key
+    /// order.
+    pub fn index_range(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<Vec<(Vec<u8>, u128)>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let index = self.index_lock(&fs)?;
+        Ok(index
+            .as_ref()
+            .unwrap()
+            .entries
+            .range::<[u8], _>((start, end))
+            .map(|(key, obj_id)| (key.clone(), *obj_id))
+            .collect())
+    }
+
+    /// Drops every index entry that points at `obj_id`.
+    pub(crate) fn forget_index_entries(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut index = self.index_lock(fs)?;
+        let index = index.as_mut().unwrap();
+        let meta_key = self.meta_key();
+        let keys: Vec<Vec<u8>> = index
+            .entries
+            .iter()
+            .filter(|(_, id)| **id == obj_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<u8> = keys
+            .iter()
+            .flat_map(|key| log_record(&meta_key, REMOVE, key, obj_id))
+            .collect();
+        append_log(fs, &records)?;
+        for key in keys {
+            index.entries.remove(&key);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Buckets per power of two, so a bucket is at most a quarter as wide
+/// as the latencies in it.
+const SUB_BUCKETS: u64 = 4;
+/// Enough buckets for any latency in nanoseconds that fits a `u64`.
+const BUCKETS: usize = (SUB_BUCKETS * 63) as usize;
+
+/// The operations whose latencies are recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LatencyOp {
+    Create,
+    Read,
+    Write,
+    Unlink,
+    Epoch,
+}
+
+impl LatencyOp {
+    pub const ALL: [LatencyOp; 5] = [
+        LatencyOp::Create,
+        LatencyOp::Read,
+        LatencyOp::Write,
+        LatencyOp::Unlink,
+        LatencyOp::Epoch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LatencyOp::Create => "create",
+            LatencyOp::Read => "read",
+            LatencyOp::Write => "write",
+            LatencyOp::Unlink => "unlink",
+            LatencyOp::Epoch => "epoch",
+        }
+    }
+}
+
+/// Returns the bucket of a latency of `nanos`. Latencies under
+/// `SUB_BUCKETS` nanoseconds get a bucket each, and every power of two
+/// above that is split into `SUB_BUCKETS` equal buckets.
+fn bucket_of(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS {
+        return nanos as usize;
+    }
+    let octave = 63 - nanos.leading_zeros() as u64;
+    let sub = (nanos >> (octave - 2)) & (SUB_BUCKETS - 1);
+    (SUB_BUCKETS * (octave - 1) + sub) as usize
+}
+
+/// Returns the largest latency in nanoseconds that falls in `bucket`.
+fn bucket_max(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let octave = bucket / SUB_BUCKETS + 1;
+    let width = 1u64 << (octave - 2);
+    ((SUB_BUCKETS + bucket % SUB_BUCKETS) << (octave - 2)) + (width - 1)
+}
+
+/// A histogram of latencies that can be recorded into from many
+/// threads without locking.
+#[derive(Debug)]
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket_of(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        // counted from the buckets, which may be a little ahead of or
+        // behind `count` while records are in flight.
+        let count: u64 = buckets.iter().sum();
+        let quantile = |q: f64| {
+            let rank = ((count as f64 * q).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (bucket, n) in buckets.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    return Duration::from_nanos(bucket_max(bucket));
+                }
+            }
+            Duration::ZERO
+        };
+        LatencySummary {
+            count,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+        }
+    }
+}
+
+/// Percentiles of the latencies of one kind of operation. Each is
+/// rounded up to the bucket it falls in, so within 25% of the actual
+/// latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub sum: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+/// A histogram for every `LatencyOp`.
+#[derive(Debug, Default)]
+pub(crate) struct Latencies {
+    histograms: [LatencyHistogram; LatencyOp::ALL.len()],
+}
+
+impl Latencies {
+    pub fn get(&self, op: LatencyOp) -> &LatencyHistogram {
+        &self.histograms[op as usize]
+    }
+}
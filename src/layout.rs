@@ -0,0 +1,180 @@
+use crate::{
+    fs::{Disk, PAGE_SIZE, SECTOR_SIZE},
+    header::{header_fits, RawHeader},
+    ObjectStore,
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipherSeek},
+    ChaCha20,
+};
+use fatfs::IoBase;
+use std::io::{Error, ErrorKind};
+
+/// The largest key chunk a store can be formatted with.
+pub const MAX_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Maps between disk offsets and the chunk ids that keys and nonces
+/// are derived from. Chunk ids count chunks of one or more clusters
+/// from `cluster_offset`, the distance of cluster boundaries past a
+/// multiple of the cluster size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    cluster_offset: u64,
+    chunk_size: u64,
+}
+
+impl Layout {
+    /// The layout of volumes whose raw header doesn't record one. They
+    /// were all formatted with page sized clusters and numbered chunks
+    /// as if clusters started 1024 bytes past a page boundary.
+    pub(crate) const LEGACY: Layout = Layout {
+        cluster_offset: 1024,
+        chunk_size: PAGE_SIZE as u64,
+    };
+
+    pub(crate) fn new(cluster_offset: u64, chunk_size: u64) -> Result<Self, Error> {
+        if !chunk_size.is_power_of_two()
+            || !(PAGE_SIZE as u64..=MAX_CHUNK_SIZE).contains(&chunk_size)
+        {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "chunks must be a power of two pages, up to 1 MiB",
+            ));
+        }
+        if cluster_offset >= PAGE_SIZE as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "cluster offset is larger than a cluster",
+            ));
+        }
+        Ok(Self {
+            cluster_offset,
+            chunk_size,
+        })
+    }
+
+    /// Derives the layout from the FAT boot sector.
+    pub(crate) fn from_boot_sector<D: Disk>(disk: &D) -> Result<Self, Error>
+    where
+        std::io::Error: From<D::Error>,
+    {
+        let mut bpb = [0u8; SECTOR_SIZE];
+        disk.read_exact_at(0, &mut bpb)?;
+        let u16_at = |i: usize| u16::from_le_bytes([bpb[i], bpb[i + 1]]) as u64;
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = bpb[13] as u64;
+        let reserved_sectors = u16_at(14);
+        let fats = bpb[16] as u64;
+        let root_entries = u16_at(17);
+        let sectors_per_fat = match u16_at(22) {
+            0 => u32::from_le_bytes(bpb[36..40].try_into().unwrap()) as u64,
+            n => n,
+        };
+        if bytes_per_sector == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "bad boot sector"));
+        }
+        let root_dir_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+        let data_start =
+            (reserved_sectors + fats * sectors_per_fat + root_dir_sectors) * bytes_per_sector;
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+        if cluster_size != PAGE_SIZE as u64 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "clusters must be exactly one page",
+            ));
+        }
+        Self::new(data_start % cluster_size, cluster_size)
+    }
+
+    /// Returns the same layout keyed in chunks of `chunk_size` bytes.
+    pub(crate) fn with_chunk_size(self, chunk_size: u64) -> Result<Self, Error> {
+        Self::new(self.cluster_offset, chunk_size)
+    }
+
+    /// Returns the layout recorded in the raw header, falling back to
+    /// `LEGACY` for FAT32 volumes that predate it. Either way the chunks
+    /// have to be whole clusters of the volume.
+    pub(crate) fn load<D: Disk>(disk: &D) -> Result<Self, Error>
+    where
+        std::io::Error: From<D::Error>,
+    {
+        let volume = Self::from_boot_sector(disk)?;
+        let recorded = match RawHeader::load(disk)? {
+            Some(header) => header.layout().unwrap_or(Self::LEGACY),
+            // only FAT32 volumes were ever numbered the legacy way.
+            None if !header_fits(disk)? => volume,
+            None => Self::LEGACY,
+        };
+        if recorded.chunk_size % volume.chunk_size != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "recorded chunk size doesn't match the volume",
+            ));
+        }
+        Ok(recorded)
+    }
+
+    pub fn cluster_offset(&self) -> u64 {
+        self.cluster_offset
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn chunk_id(&self, disk_offset: u64) -> u64 {
+        (disk_offset - self.cluster_offset) / self.chunk_size
+    }
+
+    pub fn disk_offset(&self, chunk_id: u64) -> u64 {
+        chunk_id * self.chunk_size + self.cluster_offset
+    }
+
+    /// Returns how many bytes of `chunk_id` are on a disk of
+    /// `disk_size` bytes, as the last chunk may run past the end.
+    pub(crate) fn chunk_len(&self, chunk_id: u64, disk_size: u64) -> Result<u64, Error> {
+        let left = disk_size
+            .checked_sub(self.disk_offset(chunk_id))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "chunk is past the end of the disk",
+                )
+            })?;
+        Ok(self.chunk_size.min(left))
+    }
+
+    /// Returns the nonce and keystream position used to encrypt the
+    /// byte at `disk_offset`.
+    pub(crate) fn chunk_nonce(&self, disk_offset: u64) -> ([u8; 12], u64) {
+        let chunk_id = self.chunk_id(disk_offset);
+        let offset = disk_offset - chunk_id;
+        let bytes = chunk_id.to_le_bytes();
+        let nonce: [u8; 12] = [
+            0, 0, 0, 0, bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6],
+            bytes[7],
+        ];
+        (nonce, offset)
+    }
+
+    pub(crate) fn cipher(&self, disk_offset: u64, key: [u8; 32]) -> ChaCha20 {
+        let (nonce, offset) = self.chunk_nonce(disk_offset);
+        let mut cipher = ChaCha20::new(&key.into(), &nonce.into());
+        cipher.seek(offset);
+        cipher
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
@@ -1,103 +1,242 @@
 #![feature(iterator_try_collect)]
+#![cfg_attr(
+    all(target_os = "wasi", any(feature = "ffi", feature = "wasi")),
+    feature(wasi_ext)
+)]
+mod access;
+mod adopt;
+mod allocator;
+mod append_log;
+mod async_disk;
+#[cfg(feature = "async")]
+mod async_store;
+mod audit;
+mod batch;
+mod bench;
+mod blind;
+mod cache;
+mod checksum;
+mod chunk_generation;
+mod cipher_stream;
+mod clone;
+mod content;
+mod context;
+mod coverage;
+mod cursor;
+mod dedup;
+mod describe;
+mod diff;
+mod engine_key;
+mod eof;
+mod epoch;
+mod error;
+mod events;
+mod expiry;
+mod fault;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(any(feature = "ffi", feature = "wasi"))]
+mod file_disk;
 // mod disk;
+mod flags;
+mod freeze;
 mod fs;
+mod handle;
+mod header;
+mod holes;
+mod identity;
+mod index;
+mod latency;
+mod layout;
+mod mac;
+mod maintenance;
+mod manager;
+mod manifest;
+#[cfg(any(feature = "wasi", feature = "testing"))]
+mod mem_disk;
+mod meta;
+mod meta_key;
+mod metadata_disk;
+mod metrics;
+mod mount;
+mod namespace;
+mod obj_id;
 // mod nvme;
+mod object_key;
 mod object_store;
+mod overlay;
+mod partition;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod quota;
+mod rekey;
+mod relocate;
+mod seal;
+mod shared;
+mod shutdown;
+mod snapshot;
+mod space;
+mod superblock;
+mod tags;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod trace;
+mod trash;
+mod upload;
+mod version;
+mod wal_log;
 mod wrapped_extent;
+mod writeback;
+mod zoned;
 // pub use fs::FS;
+pub use access::ObjectAccess;
+pub use adopt::AdoptProgress;
+pub use async_disk::{AsyncDisk, SyncDiskAdapter};
+#[cfg(feature = "async")]
+pub use async_store::AsyncObjectStore;
+pub use audit::KeyAudit;
+pub use bench::{BenchProfile, BenchReport, WorkloadStats};
+pub use checksum::{Checksum, ChecksumAlgorithm};
+pub use content::content_id;
+pub use context::{add_context, error_context, ContextError, ErrorContext, Phase};
+pub use coverage::KeyCoverage;
+pub use cursor::Cursor;
+pub use dedup::DedupStats;
+pub use describe::{StoreDescription, StoreHealth};
+pub use diff::{DiffStats, Manifest};
+pub use engine_key::ZeroizingKey;
+pub use eof::{read_past_end, ReadPastEnd};
+pub use epoch::{epoch_incomplete, ChunkFailure, EpochEstimate, EpochIncomplete};
+pub use error::ObjectStoreError;
+pub use events::{EventSink, KhfRecovery, StoreEvent};
+pub use expiry::ReapReport;
+#[cfg(feature = "ffi")]
+pub use ffi::*;
+#[cfg(any(feature = "ffi", feature = "wasi"))]
+pub use file_disk::FileDisk;
+pub use flags::ObjectFlags;
+pub use freeze::SnapshotDisk;
+pub use fs::{Disk, DiskLimits, FatFlavor, FsConfig};
+pub use handle::ObjectHandle;
+pub use header::{CipherSuite, RawHeader};
+pub use identity::{media_mismatch, MediaIdentity, MediaMismatch};
+pub use index::{MAX_INDEX_KEY_LEN, MIN_INDEX_KEY_LEN};
+pub use latency::{LatencyOp, LatencySummary};
+pub use layout::{Layout, MAX_CHUNK_SIZE};
+pub use mac::{integrity_error, IntegrityError, IntegrityHash};
+pub use maintenance::{MaintenanceBacklog, MaintenanceReport};
+pub use manager::StoreManager;
+#[cfg(feature = "wasi")]
+pub use mem_disk::MemDisk;
+pub use metadata_disk::{MetadataPlacement, OpenOptions};
+pub use metrics::{MetricsSink, MetricsSnapshot};
+pub use mount::{already_mounted, AlreadyMounted, STALE_MOUNT_AFTER};
+pub use namespace::MAX_NAMESPACE_LEN;
+pub use obj_id::{TwzObjId, RESERVED_OBJ_IDS};
 pub use object_store::*;
+pub use overlay::OverlayDisk;
+pub use partition::{gpt_partitions, GptPartition, PartitionDisk};
+#[cfg(feature = "prometheus")]
+pub use prometheus::PrometheusText;
+pub use quota::MetadataUsage;
+pub use shared::{stale_store, Pinned, SharedStore, StaleStore};
+pub use shutdown::CloseOptions;
+pub use snapshot::Snapshot;
+pub use space::{no_space, NoSpace};
+pub use superblock::{FormatOptions, KeyMode};
+pub use tags::MAX_TAG_LEN;
+pub use upload::{PendingUpload, Upload};
+pub use version::{version_conflict, VersionConflict};
+pub use wal_log::{WalEntry, WalOp, WalReport};
+pub use writeback::{DirtyPage, WritebackCompletion};
+pub use zoned::{ZonedDevice, ZonedDisk};
 #[cfg(test)]
 mod tests {
-    use fatfs::{IoBase, StdIoWrapper};
+    use fatfs::IoBase;
     use object_store::ObjectStore;
     use std::{
         fs::{File, OpenOptions},
-        io::{Seek, Write},
         ops::Deref,
+        os::unix::fs::FileExt,
         path::Path,
-        sync::{Arc, LazyLock, Mutex, MutexGuard, RwLock},
+        sync::{Arc, LazyLock, Mutex, RwLock},
     };
-    #[derive(Clone)]
     struct FileDisk {
-        disk: Arc<Mutex<StdIoWrapper<File>>>,
-    }
-
-    fn arc_mutex_wrap<T>(v: T) -> Arc<Mutex<T>> {
-        Arc::new(Mutex::new(v))
+        file: File,
     }
 
     impl FileDisk {
-        fn file_wrap(file: File) -> Arc<Mutex<StdIoWrapper<File>>> {
-            arc_mutex_wrap(StdIoWrapper::new(file))
-        }
-
         pub fn open<T: AsRef<Path>>(path: T) -> Self {
-            let mut file = OpenOptions::new()
+            let file = OpenOptions::new()
                 .create(true)
+                .truncate(false)
                 .read(true)
                 .write(true)
                 .open(path)
                 .unwrap();
-            let target_len: u64 = 0x3_0000_0000;
-            let curr_len = file.seek(std::io::SeekFrom::End(0)).unwrap();
-            if curr_len < target_len {
-                for _ in (curr_len..target_len).step_by(4096) {
-                    file.write(&[0u8; 4096]).unwrap();
-                }
-                file.write(&[0u8; 4096]).unwrap();
-            }
-            file.seek(std::io::SeekFrom::Start(0)).unwrap();
-            let v = file.seek(std::io::SeekFrom::Current(0)).unwrap();
-            println!("{:?}", v);
-            Self {
-                disk: Self::file_wrap(file),
+            let target_len: u64 = 0x3_0000_0000 + 4096;
+            if file.metadata().unwrap().len() < target_len {
+                file.set_len(target_len).unwrap();
             }
-        }
-
-        fn lock(&self) -> MutexGuard<'_, StdIoWrapper<File>> {
-            self.disk.lock().unwrap()
+            Self { file }
         }
     }
 
     static OBJECT_STORE: LazyLock<Mutex<ObjectStore<FileDisk>>> = LazyLock::new(|| {
         let disk = FileDisk::open("/tmp/get_unique_id.img");
-        Mutex::new(ObjectStore::open(disk, [0u8; 32]))
+        // a previous test run never unmounts the shared image.
+        let os = match ObjectStore::open_takeover(disk, [0u8; 32]) {
+            Ok(os) => os,
+            Err(_) => {
+                ObjectStore::open_or_format(FileDisk::open("/tmp/get_unique_id.img"), [0u8; 32])
+                    .unwrap()
+            }
+        };
+        Mutex::new(os)
     });
 
     impl IoBase for FileDisk {
         type Error = std::io::Error;
     }
 
-    impl fatfs::Read for FileDisk {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-            self.lock().read(buf)
+    impl Disk for FileDisk {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.file.read_at(buf, offset)
         }
-    }
 
-    impl fatfs::Seek for FileDisk {
-        fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, Self::Error> {
-            self.lock().seek(pos)
+        fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.file.write_at(buf, offset)
         }
-    }
 
-    impl fatfs::Write for FileDisk {
-        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-            self.lock().write(buf)
+        fn flush(&self) -> Result<(), Self::Error> {
+            self.file.sync_data()
         }
 
-        fn flush(&mut self) -> Result<(), Self::Error> {
-            self.lock().flush()
+        fn size(&self) -> Result<u64, Self::Error> {
+            Ok(self.file.metadata()?.len())
         }
     }
 
     use super::*;
 
-    fn get_unique_id<OsRef: Deref<Target = ObjectStore<FileDisk>>>(fs: &OsRef) -> u128 {
-        let mut id: u128 = rand::random();
-        while !fs.create_object(id).unwrap() {
-            id = rand::random();
+    /// Drives a future that never has to wait to completion.
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+        fn raw() -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| {}, |_| {}, |_| {});
+        let waker = unsafe { Waker::from_raw(raw()) };
+        let mut fut = std::pin::pin!(fut);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return out;
+            }
         }
-        id
+    }
+
+    fn get_unique_id<OsRef: Deref<Target = ObjectStore<FileDisk>>>(fs: &OsRef) -> u128 {
+        fs.allocate_id().unwrap()
     }
 
     fn make_and_check_file<OsRef>(fs: &OsRef, buf1: &mut [u8], buf2: &mut [u8]) -> (Vec<u8>, u128)
@@ -123,6 +262,2395 @@ mod tests {
         os.unlink_object(0).unwrap();
     }
 
+    #[test]
+    fn error_context_on_missing_object() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.unlink_object(id).unwrap();
+        let mut buf = [0u8; 4];
+        let err = os
+            .read_exact(id, &mut buf, 16)
+            .expect_err("should be error");
+        assert!(err.kind() == std::io::ErrorKind::NotFound);
+        let ctx = error_context(&err).expect("error should carry context");
+        assert_eq!(ctx.obj_id, Some(id));
+    }
+
+    #[test]
+    fn hot_objects_tracks_access() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"asdf", 0).unwrap();
+        let mut buf = [0u8; 4];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        let (_, access) = os
+            .hot_objects(usize::MAX)
+            .into_iter()
+            .find(|(hot_id, _)| *hot_id == id)
+            .expect("object should be tracked");
+        assert!(access.reads > 0.0 && access.writes > 0.0);
+        os.unlink_object(id).unwrap();
+        assert!(os
+            .hot_objects(usize::MAX)
+            .iter()
+            .all(|(hot_id, _)| *hot_id != id));
+    }
+
+    #[test]
+    fn read_many_returns_in_request_order() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id1 = get_unique_id(&os);
+        let id2 = get_unique_id(&os);
+        os.write_all(id1, b"asdf", 0).unwrap();
+        os.write_all(id2, b"ghjk", 0).unwrap();
+        let missing = get_unique_id(&os);
+        os.unlink_object(missing).unwrap();
+        let out = os.read_many(&[(id2, 1, 3), (missing, 0, 1), (id1, 0, 4)]);
+        assert_eq!(out[0].as_ref().unwrap(), b"hjk");
+        assert!(out[1].is_err());
+        assert_eq!(out[2].as_ref().unwrap(), b"asdf");
+    }
+
+    #[test]
+    fn tags_survive_reopen_and_unlink() {
+        let mut os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        assert!(os.add_tag(id, "compartment-a").unwrap());
+        assert!(!os.add_tag(id, "compartment-a").unwrap());
+        os.reopen().unwrap();
+        assert!(os.objects_with_tag("compartment-a").contains(&id));
+        os.unlink_object(id).unwrap();
+        assert!(!os.objects_with_tag("compartment-a").contains(&id));
+    }
+
+    #[test]
+    fn secondary_index_lookup_and_range() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let key = id.to_be_bytes();
+        assert_eq!(os.index_insert(&key, id).unwrap(), None);
+        assert_eq!(os.index_lookup(&key).unwrap(), Some(id));
+        let range = os
+            .index_range(
+                std::ops::Bound::Included(&key[..]),
+                std::ops::Bound::Included(&key[..]),
+            )
+            .unwrap();
+        assert_eq!(range, vec![(key.to_vec(), id)]);
+        assert!(os.index_insert(b"short", id).is_err());
+        os.unlink_object(id).unwrap();
+        assert_eq!(os.index_lookup(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn index_log_is_replayed_and_compacted() {
+        use fatfs::{Seek as _, Write as _};
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/index_log.img"),
+            [0u8; 32],
+            FormatOptions::new().key_mode(KeyMode::Volume),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.create_object(2).unwrap();
+        os.index_insert(&[1u8; 16], 1).unwrap();
+        os.index_insert(&[2u8; 16], 2).unwrap();
+        os.index_insert(&[1u8; 16], 2).unwrap();
+        os.index_remove(&[2u8; 16]).unwrap();
+        {
+            let fs = os.fs().lock().unwrap();
+            let mut log = fs.root_dir().open_file(index::INDEX_LOG_PATH).unwrap();
+            // a torn append.
+            log.seek(fatfs::SeekFrom::End(0)).unwrap();
+            log.write_all(&[7u8; 5]).unwrap();
+        }
+        os.reopen().unwrap();
+        assert_eq!(os.index_lookup(&[1u8; 16]).unwrap(), Some(2));
+        assert_eq!(os.index_lookup(&[2u8; 16]).unwrap(), None);
+        os.index_insert(&[3u8; 16], 1).unwrap();
+        os.advance_epoch().unwrap();
+        assert!(os
+            .fs()
+            .lock()
+            .unwrap()
+            .root_dir()
+            .open_file(index::INDEX_LOG_PATH)
+            .is_err());
+        os.reopen().unwrap();
+        assert_eq!(os.index_lookup(&[1u8; 16]).unwrap(), Some(2));
+        assert_eq!(os.index_lookup(&[3u8; 16]).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn reap_expired_unlinks_past_deadlines() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let expired = get_unique_id(&os);
+        os.write_all(expired, b"temporary", 0).unwrap();
+        let kept = get_unique_id(&os);
+        os.set_expiry(expired, std::time::UNIX_EPOCH).unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        os.set_expiry(kept, later).unwrap();
+        let report = os.reap_expired().unwrap();
+        assert!(report.unlinked.contains(&expired));
+        assert!(!report.unlinked.contains(&kept));
+        assert!(os.disk_length(expired).is_err());
+        assert!(os.expiry(kept).unwrap().is_some());
+        assert!(os.clear_expiry(kept).unwrap());
+        assert_eq!(os.expiry(kept).unwrap(), None);
+    }
+
+    #[test]
+    fn trash_restore_and_purge() {
+        let os = OBJECT_STORE.lock().unwrap();
+        os.set_trash_retention(Some(std::time::Duration::ZERO));
+        let id = get_unique_id(&os);
+        os.write_all(id, b"recoverable", 0).unwrap();
+        os.unlink_object(id).unwrap();
+        assert!(os.trashed_objects().unwrap().contains(&id));
+        os.restore(id).unwrap();
+        let mut buf = [0u8; 11];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"recoverable");
+        os.unlink_object(id).unwrap();
+        assert!(os.purge().unwrap().contains(&id));
+        assert!(os.restore(id).is_err());
+        os.set_trash_retention(None);
+    }
+
+    #[test]
+    fn recreated_ids_start_without_trashed_metadata() {
+        let os = OBJECT_STORE.lock().unwrap();
+        os.set_trash_retention(Some(std::time::Duration::from_secs(3600)));
+        let id = get_unique_id(&os);
+        os.write_all(id, b"sealed away", 0).unwrap();
+        os.seal_object(id).unwrap();
+        os.unlink_object(id).unwrap();
+        assert!(os.trashed_objects().unwrap().contains(&id));
+        os.create_object(id).unwrap();
+        assert_eq!(os.sealed_hash(id).unwrap(), None);
+        assert!(os.flags(id).unwrap().is_empty());
+        os.write_all(id, b"fresh", 0).unwrap();
+        let mut buf = [0u8; 5];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"fresh");
+        assert!(!os.trashed_objects().unwrap().contains(&id));
+        os.set_trash_retention(None);
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn flags_protect_objects() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"boot", 0).unwrap();
+        os.set_flags(id, ObjectFlags::PINNED | ObjectFlags::IMMUTABLE)
+            .unwrap();
+        let err = os.write_all(id, b"oops", 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let err = os.unlink_object(id).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        os.set_flags(id, ObjectFlags::empty()).unwrap();
+        os.write_all(id, b"okay", 0).unwrap();
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn sealed_objects_reject_writes() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"final contents", 0).unwrap();
+        let hash = os.seal_object(id).unwrap();
+        assert_eq!(os.sealed_hash(id).unwrap(), Some(hash));
+        let err = os.write_all(id, b"x", 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        os.set_flags(id, ObjectFlags::empty()).unwrap();
+        assert!(os.flags(id).unwrap().contains(ObjectFlags::SEALED));
+        assert_eq!(os.read_verified(id).unwrap(), b"final contents");
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn content_addressed_puts_dedup() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let data = format!("kernel image {}", get_unique_id(&os));
+        let id = os.put_content_addressed(data.as_bytes()).unwrap();
+        assert_eq!(id, content_id(data.as_bytes()));
+        assert_eq!(os.put_content_addressed(data.as_bytes()).unwrap(), id);
+        assert_eq!(os.read_verified(id).unwrap(), data.as_bytes());
+        assert!(os.write_all(id, b"x", 0).is_err());
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn dedup_shares_identical_pages() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let page = vec![0x5au8; crate::fs::PAGE_SIZE];
+        let a = get_unique_id(&os);
+        let b = get_unique_id(&os);
+        for id in [a, b] {
+            os.write_all(id, &page, 0).unwrap();
+            os.enable_dedup(id).unwrap();
+        }
+        let before = os.dedup_stats().unwrap();
+        assert!(before.logical_pages > before.stored_pages);
+        // diverging one copy must not change the other.
+        os.write_all(b, b"diverged", 0).unwrap();
+        let mut buf = vec![0u8; crate::fs::PAGE_SIZE];
+        os.read_exact(a, &mut buf, 0).unwrap();
+        assert_eq!(buf, page);
+        os.read_exact(b, &mut buf[..8], 0).unwrap();
+        assert_eq!(&buf[..8], b"diverged");
+        os.unlink_object(a).unwrap();
+        os.unlink_object(b).unwrap();
+    }
+
+    #[test]
+    fn apply_patch_applies_edits_in_order() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, &[b'.'; 16], 0).unwrap();
+        os.apply_patch(id, &[(0, &b"ab"[..]), (8, b"cd"), (1, b"X")])
+            .unwrap();
+        let mut buf = [0u8; 16];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"aX......cd......");
+    }
+
+    #[test]
+    fn conditional_writes_detect_conflicts() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let seen = os.version(id);
+        let next = os.write_all_if_version(id, seen, b"first", 0).unwrap();
+        assert_eq!(next, seen + 1);
+        let err = os.write_all_if_version(id, seen, b"stale", 0).unwrap_err();
+        assert_eq!(
+            version_conflict(&err),
+            Some(&VersionConflict {
+                expected: seen,
+                actual: next
+            })
+        );
+        os.write_all(id, b"blind", 0).unwrap();
+        assert_eq!(os.version(id), next + 1);
+    }
+
+    #[test]
+    fn versions_survive_refused_writes_but_not_reopens() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/versions.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        let seen = os.write_all_if_version(1, os.version(1), b"a", 0).unwrap();
+        // far more than the disk holds, so refused before writing.
+        os.write_all(1, b"b", 1 << 40).unwrap_err();
+        assert_eq!(os.version(1), seen);
+        os.reopen().unwrap();
+        let err = os.write_all_if_version(1, seen, b"c", 0).unwrap_err();
+        assert!(version_conflict(&err).is_some());
+    }
+
+    #[test]
+    fn conditional_create() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let err = os.create_object_excl(id).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        os.write_all(id, b"stale", 0).unwrap();
+        os.create_or_truncate(id).unwrap();
+        assert_eq!(os.disk_length(id).unwrap(), 0);
+        os.unlink_object(id).unwrap();
+        os.create_object_excl(id).unwrap();
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn staged_upload_commits_out_of_order_parts() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"old contents", 0).unwrap();
+        let mut upload = os.begin_upload(id).unwrap();
+        upload.write_part(5, b"world").unwrap();
+        upload.write_part(0, b"hello").unwrap();
+        let mut buf = [0u8; 12];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"old contents");
+        upload.commit().unwrap();
+        let mut buf = [0u8; 10];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"helloworld");
+        assert_eq!(os.disk_length(id).unwrap(), 10);
+    }
+
+    #[test]
+    fn uploads_resume_after_reopen() {
+        let mut os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let mut upload = os.begin_upload(id).unwrap();
+        upload.write_part(0, b"first ").unwrap();
+        upload.write_part(12, b"third").unwrap();
+        drop(upload);
+        os.reopen().unwrap();
+        let pending = os.pending_uploads().unwrap();
+        let pending = pending.iter().find(|p| p.obj_id == id).unwrap();
+        assert_eq!(pending.durable_offset, 6);
+        assert_eq!(pending.received, vec![0..6, 12..17]);
+        let mut upload = os.resume_upload(id).unwrap();
+        let off = upload.durable_offset();
+        upload.write_part(off, b"second").unwrap();
+        assert_eq!(upload.durable_offset(), 17);
+        upload.commit().unwrap();
+        let mut buf = [0u8; 17];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"first secondthird");
+        assert!(os.pending_uploads().unwrap().iter().all(|p| p.obj_id != id));
+    }
+
+    #[test]
+    fn write_returns_checksum_of_plaintext() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Xxh3] {
+            let checksum = os
+                .write_all_checksummed(id, b"checked", 0, algorithm)
+                .unwrap();
+            let mut buf = [0u8; 7];
+            os.read_exact(id, &mut buf, 0).unwrap();
+            assert_eq!(checksum, algorithm.checksum(&buf));
+        }
+    }
+
+    #[test]
+    fn verified_reads_check_page_macs() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let mut buf = [0u8; 8];
+        assert!(os.read_exact_verified(id, &mut buf, 0).is_err());
+        os.write_all(id, &[7u8; 6000], 0).unwrap();
+        os.enable_page_macs(id).unwrap();
+        os.write_all(id, b"verified", 4090).unwrap();
+        os.read_exact_verified(id, &mut buf, 4090).unwrap();
+        assert_eq!(&buf, b"verified");
+        let err = os.read_exact_verified(id, &mut buf, 5999).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(integrity_error(&err).is_none());
+    }
+
+    #[test]
+    fn page_macs_can_use_blake3() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/blake3_macs.img"),
+            [0u8; 32],
+            FormatOptions::new().integrity_hash(IntegrityHash::Blake3),
+        )
+        .unwrap();
+        assert_eq!(os.integrity_hash(), IntegrityHash::Blake3);
+        let header = os.raw_header().unwrap().unwrap();
+        assert_eq!(header.integrity_hash(), IntegrityHash::Blake3);
+        os.create_object(1).unwrap();
+        os.write_all(1, &[3u8; 6000], 0).unwrap();
+        os.enable_page_macs(1).unwrap();
+        os.write_all(1, b"blake", 4094).unwrap();
+        os.reopen().unwrap();
+        let mut buf = [0u8; 5];
+        os.read_exact_verified(1, &mut buf, 4094).unwrap();
+        assert_eq!(&buf, b"blake");
+        os.reformat(FileDisk::open("/tmp/blake3_macs.img"), None)
+            .unwrap();
+        assert_eq!(os.integrity_hash(), IntegrityHash::Blake3);
+    }
+
+    #[test]
+    fn unlinking_invalidates_handles() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"handle", 0).unwrap();
+        let handle = os.open_object(id).unwrap();
+        let other = os.open_object(id).unwrap();
+        handle.write_all(b"H", 0).unwrap();
+        let mut buf = [0u8; 6];
+        other.read_exact(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"Handle");
+        os.unlink_object(id).unwrap();
+        let not_found = |err: ObjectStoreError| matches!(err, ObjectStoreError::NotFound(_));
+        assert!(not_found(handle.read_exact(&mut buf, 0).unwrap_err()));
+        assert!(not_found(handle.write_all(b"h", 0).unwrap_err()));
+        assert!(not_found(handle.len().unwrap_err()));
+        assert!(not_found(os.open_object(id).err().unwrap()));
+        // a new object under the same id doesn't revive old handles.
+        os.create_object(id).unwrap();
+        assert!(!other.is_valid().unwrap());
+        assert!(not_found(other.read_at(&mut buf, 0).unwrap_err()));
+        let fresh = os.open_object(id).unwrap();
+        assert!(fresh.is_empty().unwrap());
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn object_ids_are_validated() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let invalid = |raw: u128| TwzObjId::new(raw).unwrap_err().kind();
+        assert_eq!(invalid(0), std::io::ErrorKind::InvalidInput);
+        assert_eq!(invalid(u128::MAX), std::io::ErrorKind::InvalidInput);
+        assert_eq!(TwzObjId::try_from(7).unwrap().raw(), 7);
+        let id = os.allocate_obj_id().unwrap();
+        assert!(!RESERVED_OBJ_IDS.contains(&id.raw()));
+        os.write_all(id.into(), b"typed", 0).unwrap();
+        assert_eq!(os.disk_length(id.into()).unwrap(), 5);
+        assert_eq!(id.to_string().len(), 32);
+        os.unlink_object(id.into()).unwrap();
+    }
+
+    #[test]
+    fn reads_past_end() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"short", 0).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(os.read_at(id, &mut buf, 2).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ort");
+        assert_eq!(os.read_at(id, &mut buf, 5).unwrap(), 0);
+        assert_eq!(os.read_at(id, &mut buf, 100).unwrap(), 0);
+        let err = os.read_exact(id, &mut buf, 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            read_past_end(&err),
+            Some(&ReadPastEnd {
+                obj_id: id,
+                offset: 2,
+                len: 8,
+                object_len: 5
+            })
+        );
+    }
+
+    #[test]
+    fn sync_disk_adapter_round_trips() {
+        let disk = SyncDiskAdapter::new(FileDisk::open("/tmp/async_disk.img"));
+        let offset = 0x2_0000_0000;
+        assert_eq!(block_on(disk.write_at(offset, b"async")).unwrap(), 5);
+        block_on(disk.flush()).unwrap();
+        let mut buf = [0u8; 5];
+        assert_eq!(block_on(disk.read_at(offset, &mut buf)).unwrap(), 5);
+        assert_eq!(&buf, b"async");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_store_round_trips() {
+        fn assert_send<T: Send>(t: T) -> T {
+            t
+        }
+        let path = "/tmp/async_store.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        let os = AsyncObjectStore::new(os, SyncDiskAdapter::new(FileDisk::open(path)));
+        assert!(block_on(os.create_object(1)).unwrap());
+        // extends the object, so it is written by the store.
+        block_on(os.write_all(1, &[1u8; 3 * 4096], 0)).unwrap();
+        // within the object, so it is written on the async disk.
+        block_on(assert_send(os.write_all(1, &[2u8; 5000], 100))).unwrap();
+        let mut expected = vec![1u8; 3 * 4096];
+        expected[100..5100].fill(2);
+        let mut buf = vec![0u8; 3 * 4096];
+        block_on(assert_send(os.read_exact(1, &mut buf, 0))).unwrap();
+        assert_eq!(buf, expected);
+        block_on(os.advance_epoch()).unwrap();
+        buf.fill(0);
+        block_on(os.read_exact(1, &mut buf, 0)).unwrap();
+        assert_eq!(buf, expected);
+        block_on(os.unlink_object(1)).unwrap();
+        let err = block_on(os.read_exact(1, &mut buf, 0)).unwrap_err();
+        assert!(matches!(err, ObjectStoreError::NotFound(_)));
+        os.close().unwrap();
+    }
+
+    #[test]
+    fn open_refuses_unexpected_media() {
+        let path = "/tmp/media_identity.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        let identity = os.media_identity();
+        os.unmount().unwrap();
+        let os = ObjectStore::open_expecting(FileDisk::open(path), [0u8; 32], identity).unwrap();
+        assert_eq!(os.store_uuid(), identity.uuid);
+        os.unmount().unwrap();
+        let newer = MediaIdentity {
+            generation: identity.generation + 1,
+            ..identity
+        };
+        let err = ObjectStore::open_expecting(FileDisk::open(path), [0u8; 32], newer)
+            .err()
+            .unwrap();
+        assert_eq!(media_mismatch(&err).unwrap().found, identity);
+    }
+
+    #[test]
+    fn second_open_is_refused_until_unmount() {
+        let path = "/tmp/mount_marker.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        let err = ObjectStore::open(FileDisk::open(path), [0u8; 32])
+            .err()
+            .unwrap();
+        assert!(already_mounted(&err).is_some());
+        let taken = ObjectStore::open_takeover(FileDisk::open(path), [0u8; 32]).unwrap();
+        assert!(already_mounted(&os.heartbeat().unwrap_err()).is_some());
+        taken.unmount().unwrap();
+        ObjectStore::open(FileDisk::open(path), [0u8; 32])
+            .unwrap()
+            .unmount()
+            .unwrap();
+    }
+
+    #[test]
+    fn close_releases_the_disk() {
+        let path = "/tmp/close.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        let id = get_unique_id(&&os);
+        os.write_all(id, b"closed", 0).unwrap();
+        os.close().unwrap();
+        let os = ObjectStore::open(FileDisk::open(path), [0u8; 32]).unwrap();
+        let mut buf = [0u8; 6];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"closed");
+        os.close().unwrap();
+    }
+
+    #[test]
+    fn metrics_count_operations() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let before = os.metrics();
+        let id = get_unique_id(&os);
+        os.write_all(id, b"metrics", 0).unwrap();
+        os.read_exact(id, &mut [0u8; 7], 0).unwrap();
+        let after = os.metrics();
+        assert_eq!(after.objects_created, before.objects_created + 1);
+        assert_eq!(after.writes, before.writes + 1);
+        assert_eq!(after.bytes_read, before.bytes_read + 7);
+        #[cfg(feature = "prometheus")]
+        assert!(after
+            .to_prometheus()
+            .contains("# TYPE twizzler_object_store_writes_total counter"));
+    }
+
+    #[test]
+    fn events_reach_the_sink() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/events.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        os.set_event_sink(move |event: &StoreEvent| sink.lock().unwrap().push(event.clone()));
+        os.close().unwrap();
+        let seen = seen.lock().unwrap();
+        assert!(matches!(seen[0], StoreEvent::Opened { clean: true, .. }));
+        assert!(matches!(seen[1], StoreEvent::Formatted { .. }));
+        assert!(matches!(seen.last(), Some(StoreEvent::Closed { .. })));
+    }
+
+    #[test]
+    fn caller_keys_encrypt_end_to_end() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        let key = [9u8; 32];
+        os.write_all_with_key(id, &key, b"end to end", 0).unwrap();
+        let mut buf = [0u8; 6];
+        os.read_exact_with_key(id, &key, &mut buf, 4).unwrap();
+        assert_eq!(&buf, b"to end");
+        os.read_exact(id, &mut buf, 4).unwrap();
+        assert_ne!(&buf, b"to end");
+    }
+
+    #[test]
+    fn external_engine_decrypts_with_derived_key() {
+        use chacha20::{
+            cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+            ChaCha20,
+        };
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, &[5u8; 4096], 0).unwrap();
+        let key = os.derive_object_key(id, 0).unwrap().unwrap();
+        let mut page = [0u8; 4096];
+        os.fs
+            .disk()
+            .read_exact_at(key.disk_offset(), &mut page)
+            .unwrap();
+        let mut cipher = ChaCha20::new(key.key().into(), &key.nonce().into());
+        cipher.seek(key.keystream_offset());
+        cipher.apply_keystream(&mut page);
+        assert_eq!(page, [5u8; 4096]);
+        assert!(os.derive_object_key(id, 1).is_err());
+    }
+
+    /// Fails writes to one disk offset, to break an epoch part way.
+    struct FlakyDisk {
+        inner: FileDisk,
+        fail_at: std::sync::atomic::AtomicU64,
+    }
+
+    impl IoBase for FlakyDisk {
+        type Error = std::io::Error;
+    }
+
+    impl Disk for FlakyDisk {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.inner.read_at(offset, buf)
+        }
+
+        fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+            if offset == self.fail_at.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err(std::io::Error::other("injected write failure"));
+            }
+            self.inner.write_at(offset, buf)
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            self.inner.flush()
+        }
+
+        fn size(&self) -> Result<u64, Self::Error> {
+            self.inner.size()
+        }
+    }
+
+    /// A disk that only lets the store use part of it and moves at most
+    /// 1 KiB at a time, remembering the largest access it saw.
+    struct LimitedDisk {
+        inner: FileDisk,
+        largest: std::sync::atomic::AtomicUsize,
+    }
+
+    impl IoBase for LimitedDisk {
+        type Error = std::io::Error;
+    }
+
+    impl Disk for LimitedDisk {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.largest
+                .fetch_max(buf.len(), std::sync::atomic::Ordering::Relaxed);
+            self.inner.read_at(offset, buf)
+        }
+
+        fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.largest
+                .fetch_max(buf.len(), std::sync::atomic::Ordering::Relaxed);
+            self.inner.write_at(offset, buf)
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            self.inner.flush()
+        }
+
+        fn size(&self) -> Result<u64, Self::Error> {
+            self.inner.size()
+        }
+
+        fn capacity(&self) -> Result<u64, Self::Error> {
+            Ok(64 * 1024 * 1024)
+        }
+
+        fn limits(&self) -> DiskLimits {
+            DiskLimits {
+                max_transfer: 1024,
+                alignment: 512,
+            }
+        }
+    }
+
+    #[test]
+    fn stores_respect_disk_capacity_and_limits() {
+        let disk = LimitedDisk {
+            inner: FileDisk::open("/tmp/limited.img"),
+            largest: 0.into(),
+        };
+        let os = ObjectStore::format(
+            disk,
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat16),
+        )
+        .unwrap();
+        assert_eq!(os.capacity().unwrap(), 64 * 1024 * 1024);
+        assert_eq!(os.disk_limits().max_transfer, 1024);
+        os.create_object(1).unwrap();
+        os.write_all(1, &[5u8; 3 * 4096], 0).unwrap();
+        os.write_batch(&[(1, 4096, &[6u8; 4096])]).unwrap();
+        os.advance_epoch().unwrap();
+        let mut buf = vec![0u8; 3 * 4096];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert!(buf[..4096].iter().all(|b| *b == 5));
+        assert!(buf[4096..8192].iter().all(|b| *b == 6));
+        let largest = &os.fs.disk().inner().largest;
+        assert!(largest.load(std::sync::atomic::Ordering::Relaxed) <= 1024);
+    }
+
+    #[test]
+    fn failed_epoch_retries_only_the_remainder() {
+        let disk = FlakyDisk {
+            inner: FileDisk::open("/tmp/epoch_retry.img"),
+            fail_at: u64::MAX.into(),
+        };
+        let os = ObjectStore::format(disk, [0u8; 32], FormatOptions::new()).unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[3u8; 4096], 0).unwrap();
+        os.create_object(2).unwrap();
+        os.write_all(2, &[4u8; 4096], 0).unwrap();
+        let offset = os.derive_object_key(1, 0).unwrap().unwrap().disk_offset();
+        let fail_at = &os.fs.disk().inner().fail_at;
+        fail_at.store(offset, std::sync::atomic::Ordering::Relaxed);
+        let err = os.advance_epoch().unwrap_err();
+        let failed = &epoch_incomplete(&err).unwrap().failed;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].disk_offset, offset);
+        assert_eq!(os.pending_epoch_chunks(), 1);
+        fail_at.store(u64::MAX, std::sync::atomic::Ordering::Relaxed);
+        os.advance_epoch().unwrap();
+        assert_eq!(os.pending_epoch_chunks(), 0);
+        let mut buf = [0u8; 4096];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [3u8; 4096]);
+        os.read_exact(2, &mut buf, 0).unwrap();
+        assert_eq!(buf, [4u8; 4096]);
+    }
+
+    #[test]
+    fn failed_epoch_estimates_whole_chunks() {
+        let disk = FlakyDisk {
+            inner: FileDisk::open("/tmp/epoch_estimate.img"),
+            fail_at: u64::MAX.into(),
+        };
+        let os = ObjectStore::format(disk, [0u8; 32], FormatOptions::new().chunk_size(64 * 1024))
+            .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[3u8; 4096], 0).unwrap();
+        let offset = os.derive_object_key(1, 0).unwrap().unwrap().disk_offset();
+        let fail_at = &os.fs.disk().inner().fail_at;
+        fail_at.store(offset, std::sync::atomic::Ordering::Relaxed);
+        os.advance_epoch().unwrap_err();
+        assert_eq!(os.pending_epoch_chunks(), 1);
+        let estimate = os.estimate_epoch().unwrap();
+        assert_eq!(estimate.bytes_to_reencrypt, 64 * 1024);
+        fail_at.store(u64::MAX, std::sync::atomic::Ordering::Relaxed);
+        os.advance_epoch().unwrap();
+    }
+
+    #[test]
+    fn rekey_moves_object_to_new_chunks() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, &[6u8; 8192], 0).unwrap();
+        let before = os.derive_object_key(id, 0).unwrap().unwrap();
+        let pending = os.pending_key_deletions();
+        os.rekey_object(id).unwrap();
+        let after = os.derive_object_key(id, 0).unwrap().unwrap();
+        assert_ne!(before.disk_offset(), after.disk_offset());
+        assert_eq!(os.pending_key_deletions(), pending + 2);
+        let mut buf = [0u8; 8192];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(buf, [6u8; 8192]);
+    }
+
+    #[test]
+    fn key_coverage_shows_deleted_chunks_forgotten() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/key_coverage.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 8192], 0).unwrap();
+        let chunks = os.object_chunks(1).unwrap();
+        assert!(!chunks.is_empty());
+        assert!(chunks.is_subset(&os.key_coverage().unwrap().live));
+        os.unlink_object(1).unwrap();
+        let coverage = os.key_coverage().unwrap();
+        assert!(chunks.is_subset(&coverage.deleted));
+        assert!(chunks.iter().all(|c| coverage.old_key_recoverable(*c)));
+        os.advance_epoch().unwrap();
+        let coverage = os.key_coverage().unwrap();
+        assert!(chunks.iter().all(|c| !coverage.old_key_recoverable(*c)));
+        assert!(coverage.live.is_disjoint(&chunks));
+        let mut csv = Vec::new();
+        coverage.export(&mut csv).unwrap();
+        assert!(csv.starts_with(b"epoch,chunk_id,state\n"));
+    }
+
+    #[test]
+    fn describe_reports_format_and_state() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/describe.img"),
+            [0u8; 32],
+            FormatOptions::new().integrity_hash(IntegrityHash::Blake3),
+        )
+        .unwrap();
+        os.create_namespace("tenant").unwrap();
+        os.advance_epoch().unwrap();
+        let description = os.describe().unwrap();
+        assert_eq!(description.uuid, os.store_uuid());
+        assert_eq!(description.cipher_suite, CipherSuite::ChaCha20);
+        assert_eq!(description.integrity_hash, IntegrityHash::Blake3);
+        assert_eq!(description.epoch, 1);
+        assert_eq!(description.namespaces, ["tenant"]);
+        assert_eq!(description.health, StoreHealth::default());
+        let bytes = bincode::serialize(&description).unwrap();
+        assert_eq!(
+            bincode::deserialize::<StoreDescription>(&bytes).unwrap(),
+            description
+        );
+    }
+
+    #[test]
+    fn adopt_encrypts_a_plain_volume_in_place() {
+        let path = "/tmp/adopt.img";
+        let _ = std::fs::remove_file(path);
+        let page = crate::fs::PAGE_SIZE;
+        let disk = Arc::new(FileDisk::open(path));
+        crate::fs::FileSystem::format(&disk, FsConfig::default()).unwrap();
+        {
+            let volume = crate::fs::FileSystem::open_fs(disk, FsConfig::default()).unwrap();
+            let fs = volume.fs().lock().unwrap();
+            let name = format!("{:032x}", 7u128);
+            let mut file = fs
+                .root_dir()
+                .create_dir("ids")
+                .unwrap()
+                .create_dir(&name[0..1])
+                .unwrap()
+                .create_file(&name)
+                .unwrap();
+            fatfs::Write::write_all(&mut file, &vec![b'p'; 3 * page]).unwrap();
+        }
+        let mut reports = Vec::new();
+        let os = ObjectStore::adopt(FileDisk::open(path), [0u8; 32], |p| reports.push(p)).unwrap();
+        let last = reports.last().unwrap();
+        assert_eq!((last.objects_done, last.objects_total), (1, 1));
+        assert_eq!(last.chunks_encrypted, 3);
+        let mut buf = vec![0u8; 3 * page];
+        os.read_exact(7, &mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == b'p'));
+        for chunk_id in os.object_chunks(7).unwrap() {
+            let mut raw = vec![0u8; page];
+            os.fs
+                .disk()
+                .read_exact_at(os.layout.disk_offset(chunk_id), &mut raw)
+                .unwrap();
+            assert_ne!(raw, vec![b'p'; page]);
+        }
+        os.close().unwrap();
+        // adopting it again just opens it.
+        let os = ObjectStore::adopt(FileDisk::open(path), [0u8; 32], |_| panic!()).unwrap();
+        os.read_exact(7, &mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == b'p'));
+    }
+
+    #[test]
+    fn wal_and_khf_on_a_metadata_disk() {
+        let os = ObjectStore::format_with(
+            FileDisk::open("/tmp/metadata_data.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+            crate::OpenOptions::new()
+                .metadata_disk(FileDisk::open("/tmp/metadata_meta.img"))
+                .khf_on_metadata_disk(true),
+        )
+        .unwrap();
+        assert_eq!(os.metadata_placement(), MetadataPlacement::WalAndKhf);
+        os.create_object(1).unwrap();
+        os.write_all(1, b"on the data disk", 0).unwrap();
+        assert!(os.inspect_wal().unwrap().wal_bytes > 0);
+        os.advance_epoch().unwrap();
+        os.close().unwrap();
+        let err = ObjectStore::open(FileDisk::open("/tmp/metadata_data.img"), [0u8; 32])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let os = ObjectStore::open_with(
+            FileDisk::open("/tmp/metadata_data.img"),
+            [0u8; 32],
+            crate::OpenOptions::new().metadata_disk(FileDisk::open("/tmp/metadata_meta.img")),
+        )
+        .unwrap();
+        let mut buf = [0u8; 16];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"on the data disk");
+        let err = os.freeze().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        assert!(!os.is_frozen());
+    }
+
+    #[test]
+    fn append_logs_seal_on_epochs() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/append_log.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_log(1).unwrap();
+        assert_eq!(os.append_log(1, b"first ").unwrap(), 0);
+        assert_eq!(os.append_log(1, b"second").unwrap(), 6);
+        os.write_all(1, b"FIRST ", 0).unwrap();
+        os.advance_epoch().unwrap();
+        assert_eq!(os.log_sealed_len(1).unwrap(), Some(12));
+        let err = os.write_all(1, b"x", 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(os.append_log(1, b" third").unwrap(), 12);
+        let mut buf = [0u8; 18];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"FIRST second third");
+        os.unlink_object(1).unwrap();
+        assert_eq!(os.log_sealed_len(1).unwrap(), None);
+    }
+
+    #[test]
+    fn racing_creates_of_one_id_create_it_once() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/create_race.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let outcomes: Vec<CreateOutcome> = std::thread::scope(|s| {
+            let racers: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| os.try_create_object(7).unwrap()))
+                .collect();
+            racers.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        let created = outcomes
+            .iter()
+            .filter(|o| **o == CreateOutcome::Created)
+            .count();
+        assert_eq!(created, 1);
+        assert_eq!(os.metrics().objects_created, 1);
+
+        let ids = os.create_many_unique(100).unwrap();
+        let unique: std::collections::BTreeSet<u128> = ids.iter().copied().collect();
+        assert_eq!(unique.len(), 100);
+        let all = os.get_all_object_ids().unwrap();
+        assert!(ids.iter().all(|id| all.contains(id)));
+        assert!(!unique.contains(&os.allocate_id().unwrap()));
+    }
+
+    #[test]
+    fn errors_say_what_went_wrong() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/typed_errors.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let err = os.read_exact(1, &mut [0u8; 4], 0).unwrap_err();
+        assert!(matches!(err, ObjectStoreError::NotFound(_)));
+        os.create_object_excl(1).unwrap();
+        let err = os.create_object_excl(1).unwrap_err();
+        assert!(matches!(err, ObjectStoreError::AlreadyExists(_)));
+        os.kms_faults().unwrap().fail_derive(1);
+        let err = os.write_all(1, b"keys", 0).unwrap_err();
+        assert!(matches!(err, ObjectStoreError::KmsError(_)));
+        // the chain goes on from the inner error rather than repeating it.
+        let source = std::error::Error::source(&err).map(ToString::to_string);
+        assert_ne!(source, Some(err.to_string()));
+        let err = os.restore(2).unwrap_err();
+        assert!(matches!(err, ObjectStoreError::NotFound(_)));
+        // converting back keeps the kind and payload of the source.
+        let err = std::io::Error::from(os.create_object_excl(1).unwrap_err());
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn latency_histograms_record_operations() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/latency.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, b"latency", 0).unwrap();
+        os.read_exact(1, &mut [0u8; 7], 0).unwrap();
+        os.advance_epoch().unwrap();
+        os.unlink_object(1).unwrap();
+        let metrics = os.metrics();
+        for op in LatencyOp::ALL {
+            let summary = metrics.latency(op);
+            assert!(summary.count >= 1, "{} wasn't recorded", op.name());
+            assert!(summary.p50 <= summary.p90 && summary.p90 <= summary.p99);
+        }
+        #[cfg(feature = "prometheus")]
+        assert!(metrics
+            .to_prometheus()
+            .contains("twizzler_object_store_read_latency_seconds{quantile=\"0.5\"}"));
+    }
+
+    #[test]
+    fn relocate_moves_object_into_target() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/relocate.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let id = 990;
+        os.create_object(id).unwrap();
+        os.write_all(id, &[7u8; 3 * 4096], 0).unwrap();
+        let end = os
+            .get_obj_segments(id)
+            .unwrap()
+            .iter()
+            .map(|extent| extent.offset() + extent.size())
+            .max()
+            .unwrap();
+        let target = end + 32 * 4096..end + 96 * 4096;
+        os.relocate(id, target.clone()).unwrap();
+        for extent in os.get_obj_segments(id).unwrap() {
+            assert!(target.contains(&extent.offset()));
+            assert!(extent.offset() + extent.size() <= target.end);
+        }
+        let mut buf = [0u8; 3 * 4096];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(buf, [7u8; 3 * 4096]);
+        assert!(os.relocate(id, 0..0).is_err());
+    }
+
+    #[test]
+    fn growing_write_refreshes_cached_extents() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = get_unique_id(&os);
+        os.write_all(id, &[1u8; 4096], 0).unwrap();
+        let size = |os: &ObjectStore<FileDisk>| -> u64 {
+            os.get_obj_segments(id)
+                .unwrap()
+                .iter()
+                .map(|extent| extent.size())
+                .sum()
+        };
+        assert_eq!(size(&os), 4096);
+        os.write_all(id, &[2u8; 10], 4090).unwrap();
+        assert_eq!(size(&os), 4096);
+        os.write_all(id, &[3u8; 4096], 4096 * 4).unwrap();
+        assert_eq!(size(&os), 4096 * 5);
+    }
+
+    #[test]
+    fn blinded_ids_are_hidden_on_disk() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/blind.img"),
+            [0u8; 32],
+            FormatOptions::new().blind_ids(true),
+        )
+        .unwrap();
+        let id = 0x946;
+        os.create_object(id).unwrap();
+        assert_eq!(os.get_all_object_ids().unwrap(), [id]);
+        let plain = format!("{:0>32x}", id);
+        let fs = os.fs().lock().unwrap();
+        let dir = fs.root_dir().open_dir(&format!("ids/{}", &plain[..1]));
+        assert!(dir.map_or(true, |dir| dir
+            .iter()
+            .all(|entry| entry.unwrap().file_name() != plain)));
+        drop(fs);
+        os.unlink_object(id).unwrap();
+        assert!(os.get_all_object_ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn volume_label_is_written_at_format() {
+        assert!(FsConfig::new().volume_label("FAR TOO LONG").is_err());
+        let config = FsConfig::new().volume_label("TWIZZLER").unwrap();
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/label.img"),
+            [0u8; 32],
+            FormatOptions::new().fs_config(config),
+        )
+        .unwrap();
+        assert_eq!(os.fs().lock().unwrap().volume_label(), "TWIZZLER");
+    }
+
+    #[test]
+    fn raw_header_tracks_the_superblock() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/header.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let header = os.raw_header().unwrap().unwrap();
+        assert_eq!(header.magic(), *b"TWZRAWHD");
+        assert_eq!(header.uuid(), os.store_uuid());
+        assert_eq!(header.cipher_suite(), CipherSuite::ChaCha20);
+        assert_eq!(header.epoch(), 0);
+        os.advance_epoch().unwrap();
+        assert_eq!(os.raw_header().unwrap().unwrap().epoch(), 1);
+    }
+
+    #[test]
+    fn volume_keyed_stores_rekey_freed_chunks() {
+        let path = "/tmp/volume_reuse.img";
+        let os = ObjectStore::format(
+            FileDisk::open(path),
+            [0u8; 32],
+            FormatOptions::new().key_mode(KeyMode::Volume),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 4096], 0).unwrap();
+        os.write_all(1, &[2u8; 100], 50).unwrap();
+        let mut buf = [0u8; 4096];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf[50..150], [2u8; 100]);
+        let old = os.derive_object_key(1, 0).unwrap().unwrap();
+        os.unlink_object(1).unwrap();
+        let new = os.chunk_key(old.disk_offset()).unwrap().unwrap();
+        assert_ne!(&new, old.key());
+        os.create_object(2).unwrap();
+        os.write_all(2, &[3u8; 4096], 0).unwrap();
+        os.close().unwrap();
+
+        let os = ObjectStore::open(FileDisk::open(path), [0u8; 32]).unwrap();
+        assert_eq!(os.chunk_key(old.disk_offset()).unwrap(), Some(new));
+        os.read_exact(2, &mut buf, 0).unwrap();
+        assert_eq!(buf, [3u8; 4096]);
+        os.close().unwrap();
+        let err = ObjectStore::format(
+            FileDisk::open("/tmp/volume_chunks.img"),
+            [0u8; 32],
+            FormatOptions::new()
+                .key_mode(KeyMode::Volume)
+                .chunk_size(64 * 1024),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn tampered_superblocks_are_refused() {
+        use fatfs::{Seek as _, Write as _};
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/superblock.img"),
+            [0u8; 32],
+            FormatOptions::new().key_mode(KeyMode::Volume),
+        )
+        .unwrap();
+        os.reopen().unwrap();
+        {
+            let fs = os.fs().lock().unwrap();
+            let mut file = fs.root_dir().open_file("superblock").unwrap();
+            // the key mode byte, flipped to plaintext.
+            file.seek(fatfs::SeekFrom::Start(12)).unwrap();
+            file.write_all(&[2]).unwrap();
+        }
+        let err = os.reopen().unwrap_err();
+        assert!(matches!(err, ObjectStoreError::CorruptMetadata(_)));
+        assert_eq!(os.key_mode(), KeyMode::Volume);
+    }
+
+    #[test]
+    fn epochs_reseal_metadata_under_a_fresh_key() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/meta_key.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        assert!(os.add_tag(1, "kept").unwrap());
+        let before = os.meta_key();
+        os.advance_epoch().unwrap();
+        assert_ne!(os.meta_key(), before);
+        {
+            let fs = os.fs().lock().unwrap();
+            let keys = meta_key::MetaKeys::load(&fs, [0u8; 32]).unwrap();
+            assert_eq!(keys.current, os.meta_key());
+            assert_eq!(keys.previous, None);
+            let sealed = meta::read_raw(&fs, tags::TAGS_PATH).unwrap().unwrap();
+            assert!(meta::unseal(&before, &sealed).is_none());
+        }
+        os.reopen().unwrap();
+        assert!(os.objects_with_tag("kept").contains(&1));
+    }
+
+    #[test]
+    fn layout_is_recorded_at_format() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/layout.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let layout = os.layout();
+        assert_eq!(os.raw_header().unwrap().unwrap().layout(), Some(layout));
+        assert_eq!(layout.chunk_size(), 4096);
+        let offset = layout.disk_offset(7);
+        assert_eq!(offset % 4096, layout.cluster_offset());
+        assert_eq!(layout.chunk_id(offset), 7);
+    }
+
+    #[test]
+    fn chunks_can_span_several_clusters() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/large_chunks.img"),
+            [0u8; 32],
+            FormatOptions::new().chunk_size(64 * 1024),
+        )
+        .unwrap();
+        let layout = os.layout();
+        assert_eq!(layout.chunk_size(), 64 * 1024);
+        assert_eq!(layout.chunk_id(layout.cluster_offset() + 15 * 4096), 0);
+        os.create_object(1).unwrap();
+        os.write_all(1, &[8u8; 100_000], 0).unwrap();
+        os.create_object(2).unwrap();
+        os.write_all(2, &[9u8; 5000], 0).unwrap();
+        os.unlink_object(2).unwrap();
+        os.advance_epoch().unwrap();
+        os.reopen().unwrap();
+        assert_eq!(os.layout(), layout);
+        let mut buf = vec![0u8; 100_000];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 8));
+        assert!(ObjectStore::format(
+            FileDisk::open("/tmp/bad_chunks.img"),
+            [0u8; 32],
+            FormatOptions::new().chunk_size(6000),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn writes_past_the_end_leave_unwritten_holes() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/holes.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[5u8; 10], 100_000).unwrap();
+        os.write_all(1, &[6u8; 10], 50_000).unwrap();
+        for _ in 0..2 {
+            assert_eq!(os.disk_length(1).unwrap(), 100_010);
+            let mut buf = vec![1u8; 100_010];
+            os.read_exact(1, &mut buf, 0).unwrap();
+            assert!(buf[..50_000].iter().all(|b| *b == 0));
+            assert_eq!(buf[50_000..50_010], [6u8; 10]);
+            assert!(buf[50_010..100_000].iter().all(|b| *b == 0));
+            assert_eq!(buf[100_000..], [5u8; 10]);
+            os.reopen().unwrap();
+        }
+    }
+
+    #[test]
+    fn new_objects_never_expose_stale_clusters() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/stale_clusters.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[0xaa; 64 * 1024], 0).unwrap();
+        os.unlink_object(1).unwrap();
+        os.create_object(2).unwrap();
+        let mut buf = [1u8; 1];
+        assert!(os.read_exact(2, &mut buf, 0).is_err());
+        os.write_all(2, &[3u8; 10], 0).unwrap();
+        os.write_all(2, &[4u8; 10], 60_000).unwrap();
+        let mut buf = vec![1u8; 60_010];
+        os.read_exact(2, &mut buf, 0).unwrap();
+        assert_eq!(buf[..10], [3u8; 10]);
+        assert!(buf[10..60_000].iter().all(|b| *b == 0));
+        assert_eq!(buf[60_000..], [4u8; 10]);
+    }
+
+    #[test]
+    fn stores_open_on_gpt_partitions() {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open("/tmp/gpt.img")
+            .unwrap();
+        file.set_len(80 * 1024 * 1024).unwrap();
+        let mut header = [0u8; 512];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        file.write_all_at(&header, 512).unwrap();
+        let mut entry = [0u8; 128];
+        entry[..16].copy_from_slice(&[7u8; 16]);
+        entry[32..40].copy_from_slice(&2048u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&(2048u64 + 131_071).to_le_bytes());
+        for (i, c) in "store".encode_utf16().enumerate() {
+            entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&c.to_le_bytes());
+        }
+        file.write_all_at(&entry, 1024 + 128).unwrap();
+        // the boot partition in front of the store must survive it.
+        file.write_all_at(&[0xee; 4096], 8192).unwrap();
+        let disk = FileDisk { file };
+        let partitions = gpt_partitions(&disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].name, "store");
+        assert_eq!(partitions[0].byte_range(), 1 << 20..65 << 20);
+        // tables past the end of the address space, or with entries too
+        // long to allocate, are refused rather than read.
+        for (at, field) in [(72, u64::MAX.to_le_bytes().to_vec()), (84, vec![0xff; 4])] {
+            let mut bad = header;
+            bad[at..at + field.len()].copy_from_slice(&field);
+            disk.file.write_all_at(&bad, 512).unwrap();
+            let err = gpt_partitions(&disk).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        disk.file.write_all_at(&header, 512).unwrap();
+        let part = PartitionDisk::from_gpt(disk, &partitions[0]);
+        let mut os = ObjectStore::format(
+            part,
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat16),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[4u8; 5000], 0).unwrap();
+        os.reopen().unwrap();
+        let mut buf = [0u8; 5000];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [4u8; 5000]);
+        let disk = os.fs.disk().inner();
+        let mut boot = [0u8; 4096];
+        disk.inner().read_exact_at(8192, &mut boot).unwrap();
+        assert_eq!(boot, [0xee; 4096]);
+    }
+
+    #[test]
+    fn shared_stores_reopen_under_readers() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/shared_reopen.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[6u8; 4096], 0).unwrap();
+        let shared = Arc::new(SharedStore::new(os));
+        let pinned = shared.pin();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let mut buf = [0u8; 4096];
+                        match shared.with(|os| os.read_exact(1, &mut buf, 0)) {
+                            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                            res => res.unwrap(),
+                        }
+                        assert_eq!(buf, [6u8; 4096]);
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..3 {
+            shared.reopen().unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(shared.generation(), 3);
+        let err = pinned.with(|os| os.disk_length(1)).unwrap_err();
+        assert_eq!(
+            stale_store(&err),
+            Some(&StaleStore {
+                pinned: 0,
+                current: 3
+            })
+        );
+        assert_eq!(shared.pin().with(|os| os.disk_length(1)).unwrap(), 4096);
+    }
+
+    #[test]
+    fn snapshots_open_as_independent_stores() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/snapshot_mount.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 6000], 0).unwrap();
+        let snap = os.open_snapshot().unwrap();
+        os.write_all(1, &[2u8; 6000], 0).unwrap();
+        os.create_object(2).unwrap();
+        os.advance_epoch().unwrap();
+        snap.write_all(1, &[9u8; 10], 0).unwrap();
+        let mut buf = [0u8; 6000];
+        snap.read_exact(1, &mut buf[..10], 0).unwrap();
+        assert_eq!(buf[..10], [9u8; 10]);
+        snap.read_exact(1, &mut buf[10..], 10).unwrap();
+        assert!(buf[10..].iter().all(|b| *b == 1));
+        assert_eq!(snap.get_all_object_ids().unwrap(), [1]);
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [2u8; 6000]);
+        drop(snap);
+        os.write_all(1, &[3u8; 6000], 0).unwrap();
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [3u8; 6000]);
+    }
+
+    #[test]
+    fn key_audits_record_chunk_use() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/key_audit.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 100], 0).unwrap();
+        assert_eq!(os.stop_key_audit().unwrap(), None);
+        os.start_key_audit().unwrap();
+        os.write_all(1, &[2u8; 10], 8192).unwrap();
+        let mut buf = [0u8; 100];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        let audit = os.stop_key_audit().unwrap().unwrap();
+        assert!(!audit.written.is_empty());
+        assert!(!audit.read.is_empty());
+        assert!(audit.untracked.is_empty());
+        let mut csv = Vec::new();
+        audit.export(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("chunk_id,use\n"));
+        assert!(!csv.contains("untracked"));
+        assert!(csv.contains(",write\n"));
+    }
+
+    #[test]
+    fn close_can_run_a_final_epoch() {
+        let path = "/tmp/final_epoch.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        let generation = os.media_identity().generation;
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 8192], 0).unwrap();
+        let options = CloseOptions::new().final_epoch(true);
+        let ran = os
+            .close_with(options.epoch_budget(std::time::Duration::ZERO))
+            .unwrap();
+        assert!(!ran);
+        let os = ObjectStore::open(FileDisk::open(path), [0u8; 32]).unwrap();
+        assert_eq!(os.media_identity().generation, generation);
+        os.unlink_object(1).unwrap();
+        assert!(os.close_with(options).unwrap());
+        let os = ObjectStore::open(FileDisk::open(path), [0u8; 32]).unwrap();
+        assert_eq!(os.media_identity().generation, generation + 1);
+        os.close().unwrap();
+    }
+
+    #[test]
+    fn reads_racing_epochs_see_whole_chunks() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/epoch_race.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[9u8; 256 * 1024], 0).unwrap();
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut buf = vec![0u8; 256 * 1024];
+                for _ in 0..20 {
+                    os.read_exact(1, &mut buf, 0).unwrap();
+                    assert!(buf.iter().all(|b| *b == 9));
+                }
+            });
+            for _ in 0..5 {
+                os.write_all(1, &[9u8; 4096], 0).unwrap();
+                os.advance_epoch().unwrap();
+            }
+            reader.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn maintenance_works_within_a_budget() {
+        let path = "/tmp/maintenance.img";
+        let os =
+            ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 8192], 0).unwrap();
+        os.create_object(2).unwrap();
+        os.write_all(2, &[2u8; 8192], 0).unwrap();
+        os.set_expiry(2, std::time::UNIX_EPOCH).unwrap();
+        let report = os.maintenance(std::time::Duration::ZERO).unwrap();
+        assert!(report.reaped.is_empty());
+        assert_eq!(report.remaining.expired, 1);
+        assert_eq!(report.epochs_finished, 0);
+
+        os.write_all(1, &[3u8; 100], 0).unwrap();
+        let report = os.maintenance(std::time::Duration::from_secs(600)).unwrap();
+        assert_eq!(report.reaped, vec![2]);
+        assert_eq!(report.epochs_finished, 1);
+        assert_eq!(os.pending_epoch_chunks(), 0);
+        let mut buf = [0u8; 8192];
+        assert!(report.remaining.is_empty());
+        os.close().unwrap();
+        let os = ObjectStore::open(FileDisk::open(path), [0u8; 32]).unwrap();
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf[..100], [3u8; 100]);
+        assert_eq!(buf[100..], [1u8; 8092]);
+        os.close().unwrap();
+    }
+
+    #[test]
+    fn writes_that_cannot_fit_fail_before_allocating() {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open("/tmp/no_space.img")
+            .unwrap();
+        file.set_len(8 * 1024 * 1024).unwrap();
+        let os = ObjectStore::format(
+            FileDisk { file },
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat12),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 10], 0).unwrap();
+        let err = os
+            .write_all(1, &vec![2u8; 16 * 1024 * 1024], 0)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let no_space = no_space(&err).unwrap();
+        assert!(no_space.needed_clusters > no_space.free_clusters);
+        assert_eq!(os.disk_length(1).unwrap(), 10);
+        let mut buf = [0u8; 10];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [1u8; 10]);
+        os.write_all(1, &[3u8; 4096], 10).unwrap();
+    }
+
+    #[test]
+    fn metadata_quota_refuses_overflowing_epochs() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/metadata_quota.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 8192], 0).unwrap();
+        os.advance_epoch().unwrap();
+        let usage = os.metadata_usage().unwrap();
+        assert!(usage.lethe > 0);
+        assert_eq!(os.metrics().metadata_bytes, usage.total());
+        os.write_all(1, &[2u8; 8192], 0).unwrap();
+        os.set_metadata_quota(Some(usage.total()));
+        let err = os.advance_epoch().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(os.pending_epoch_chunks(), 0);
+        os.set_metadata_quota(None);
+        os.advance_epoch().unwrap();
+    }
+
+    #[test]
+    fn store_manager_routes_across_stores() {
+        let mut manager = StoreManager::new();
+        for path in ["/tmp/manager_a.img", "/tmp/manager_b.img"] {
+            let os =
+                ObjectStore::format(FileDisk::open(path), [0u8; 32], FormatOptions::new()).unwrap();
+            manager.add_store(os);
+        }
+        manager.route_range(0..=999, 0).unwrap();
+        manager.route_range(1000..=u128::MAX, 1).unwrap();
+        assert!(manager.route_range(500..=1500, 0).is_err());
+        manager.route_namespace("logs", 1).unwrap();
+        for obj_id in [5, 5000] {
+            manager.create_object(obj_id).unwrap();
+            manager.write_all(obj_id, &[obj_id as u8; 8], 0).unwrap();
+        }
+        assert_eq!(manager.stores()[0].get_all_object_ids().unwrap(), [5]);
+        assert_eq!(manager.stores()[1].get_all_object_ids().unwrap(), [5000]);
+        assert_eq!(manager.get_all_object_ids().unwrap(), [5, 5000]);
+        let mut buf = [0u8; 8];
+        manager.read_exact(5000, &mut buf, 0).unwrap();
+        assert_eq!(buf, [5000u128 as u8; 8]);
+        manager
+            .store_for_namespace("logs")
+            .unwrap()
+            .create_namespace("logs")
+            .unwrap();
+        assert_eq!(manager.namespaces().unwrap(), ["logs"]);
+        assert!(manager.stores()[0].namespaces().unwrap().is_empty());
+        manager.close().unwrap();
+    }
+
+    #[test]
+    fn whole_page_writes_go_straight_to_disk() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let page = crate::fs::PAGE_SIZE;
+        let id = 0x975a;
+        let _ = os.unlink_object(id);
+        os.create_object(id).unwrap();
+        os.write_all(id, &[1u8], 4 * page as u64 - 1).unwrap();
+        let version = os.version(id);
+        os.write_all(id, &vec![2u8; 2 * page], page as u64).unwrap();
+        assert_eq!(os.version(id), version + 1);
+        assert_eq!(os.disk_length(id).unwrap(), 4 * page as u64);
+        let mut buf = vec![9u8; 4 * page];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert!(buf[..page].iter().all(|b| *b == 0));
+        assert!(buf[page..3 * page].iter().all(|b| *b == 2));
+        assert!(buf[3 * page..4 * page - 1].iter().all(|b| *b == 0));
+        // past the end, so it has to go through fatfs.
+        os.write_all(id, &vec![3u8; page], 4 * page as u64).unwrap();
+        assert_eq!(os.disk_length(id).unwrap(), 5 * page as u64);
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn write_batch_spans_objects() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let page = crate::fs::PAGE_SIZE;
+        let (a, b) = (0x951a, 0x951b);
+        for id in [a, b] {
+            let _ = os.unlink_object(id);
+            os.create_object(id).unwrap();
+            os.write_all(id, &vec![0u8; 3 * page], 0).unwrap();
+        }
+        let x = vec![1u8; page + 10];
+        let y = [2u8; 20];
+        let past_end = [3u8; 8];
+        os.write_batch(&[
+            (b, 100, &y),
+            (a, page as u64 - 5, &x),
+            (b, 3 * page as u64, &past_end),
+        ])
+        .unwrap();
+        let mut buf = vec![0u8; x.len()];
+        os.read_exact(a, &mut buf, page as u64 - 5).unwrap();
+        assert_eq!(buf, x);
+        let mut buf = [0u8; 20];
+        os.read_exact(b, &mut buf, 100).unwrap();
+        assert_eq!(buf, y);
+        let mut buf = [0u8; 8];
+        os.read_exact(b, &mut buf, 3 * page as u64).unwrap();
+        assert_eq!(buf, past_end);
+        for id in [a, b] {
+            os.unlink_object(id).unwrap();
+        }
+    }
+
+    #[test]
+    fn writeback_completes_every_page() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let page = crate::fs::PAGE_SIZE;
+        let id = 0x952;
+        let _ = os.unlink_object(id);
+        os.create_object(id).unwrap();
+        os.write_all(id, &vec![0u8; 2 * page], 0).unwrap();
+        let frame = vec![7u8; page];
+        let short = [7u8; 10];
+        let pages = [
+            DirtyPage {
+                obj_id: id,
+                page_no: 1,
+                frame: &frame,
+            },
+            DirtyPage {
+                obj_id: id,
+                page_no: 0,
+                frame: &short,
+            },
+        ];
+        let mut results = Vec::new();
+        os.writeback(
+            &pages,
+            |page: &DirtyPage<'_>, result: std::io::Result<()>| {
+                results.push((page.page_no, result.is_ok()))
+            },
+        );
+        results.sort();
+        assert_eq!(results, [(0, false), (1, true)]);
+        let mut buf = vec![0u8; page];
+        os.read_exact(id, &mut buf, page as u64).unwrap();
+        assert_eq!(buf, frame);
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn list_after_survives_concurrent_changes() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/cursor.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        for id in 1..=5 {
+            os.create_object(id).unwrap();
+        }
+        let (first, cursor) = os.list_after(Cursor::start(), 2).unwrap();
+        assert_eq!(first, [1, 2]);
+        os.unlink_object(1).unwrap();
+        os.unlink_object(4).unwrap();
+        os.create_object(0).unwrap();
+        let (rest, cursor) = os.list_after(cursor, 10).unwrap();
+        assert_eq!(rest, [3, 5]);
+        assert!(cursor.is_done());
+    }
+
+    #[test]
+    fn object_ids_are_read_from_the_manifest() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/manifest.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        for id in [3, 1, 2] {
+            os.create_object(id).unwrap();
+        }
+        os.unlink_object(2).unwrap();
+        os.reopen().unwrap();
+        assert_eq!(os.get_all_object_ids().unwrap(), [1, 3]);
+        os.advance_epoch().unwrap();
+        os.create_object(4).unwrap();
+        os.reopen().unwrap();
+        assert_eq!(os.get_all_object_ids().unwrap(), [1, 3, 4]);
+    }
+
+    #[test]
+    fn allocated_ids_are_never_reused() {
+        let mut os = ObjectStore::format(
+            FileDisk::open("/tmp/allocator.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let first = os.allocate_id().unwrap();
+        os.unlink_object(first).unwrap();
+        os.reopen().unwrap();
+        let second = os.allocate_id().unwrap();
+        assert!(second >> 64 > first >> 64);
+        assert_eq!(os.get_all_object_ids().unwrap(), [second]);
+    }
+
+    #[test]
+    fn tiny_stores_can_be_fat16() {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open("/tmp/fat16.img")
+            .unwrap();
+        file.set_len(64 * 1024 * 1024).unwrap();
+        let mut os = ObjectStore::format(
+            FileDisk { file },
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat16),
+        )
+        .unwrap();
+        assert_eq!(os.fat_flavor(), FatFlavor::Fat16);
+        assert_eq!(os.raw_header().unwrap(), None);
+        os.create_object(1).unwrap();
+        os.write_all(1, &[7u8; 5000], 0).unwrap();
+        os.reopen().unwrap();
+        let mut buf = [0u8; 5000];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [7u8; 5000]);
+    }
+
+    #[test]
+    fn reads_after_an_epoch_leave_the_wal_alone() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/read_only_derive.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 100], 0).unwrap();
+        os.advance_epoch().unwrap();
+        let mut buf = [0u8; 100];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [1u8; 100]);
+        assert!(os.inspect_wal().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn clone_into_copies_live_objects() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/clone_source.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[3u8; 9000], 0).unwrap();
+        os.add_tag(1, b"golden").unwrap();
+        os.seal_object(1).unwrap();
+        os.create_object(2).unwrap();
+        os.unlink_object(2).unwrap();
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open("/tmp/clone_target.img")
+            .unwrap();
+        file.set_len(64 * 1024 * 1024).unwrap();
+        let clone = os
+            .clone_into(
+                FileDisk { file },
+                [1u8; 32],
+                FormatOptions::new().fat_flavor(FatFlavor::Fat16),
+            )
+            .unwrap();
+        assert_eq!(clone.get_all_object_ids().unwrap(), [1]);
+        assert_eq!(clone.read_verified(1).unwrap(), [3u8; 9000]);
+        assert_eq!(clone.tags(1), [b"golden".to_vec()]);
+        assert!(clone.flags(1).unwrap().contains(ObjectFlags::SEALED));
+    }
+
+    #[test]
+    fn provisioning_gives_nodes_fresh_keys() {
+        let template_path = "/tmp/template.img";
+        let template = ObjectStore::format(
+            FileDisk::open(template_path),
+            [0u8; 32],
+            FormatOptions::new().blind_ids(true),
+        )
+        .unwrap();
+        template.create_object(1).unwrap();
+        template.write_all(1, &[5u8; 5000], 0).unwrap();
+        let template_id = template.media_identity();
+        let template_key = template.derive_object_key(1, 0).unwrap().unwrap();
+        template.close().unwrap();
+        let node = ObjectStore::provision_from_template(
+            FileDisk::open(template_path),
+            [0u8; 32],
+            FileDisk::open("/tmp/provisioned.img"),
+            [2u8; 32],
+        )
+        .unwrap();
+        assert_ne!(node.media_identity().uuid, template_id.uuid);
+        assert!(node.blinds_object_ids());
+        let mut buf = [0u8; 5000];
+        node.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [5u8; 5000]);
+        let node_key = node.derive_object_key(1, 0).unwrap().unwrap();
+        assert_ne!(node_key.key(), template_key.key());
+        node.close().unwrap();
+        let template = ObjectStore::open(FileDisk::open(template_path), [0u8; 32]).unwrap();
+        assert_eq!(template.media_identity(), template_id);
+    }
+
+    #[test]
+    fn unlinks_delete_chunk_keys_in_runs() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/bulk_delete.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let chunks = 64 * 1024 / os.layout().chunk_size();
+        for obj_id in [1, 2] {
+            os.create_object(obj_id).unwrap();
+            os.write_all(obj_id, &[6u8; 64 * 1024], 0).unwrap();
+        }
+        os.advance_epoch().unwrap();
+        os.unlink_object(1).unwrap();
+        assert_eq!(os.pending_key_deletions(), chunks);
+        let deletes = |os: &ObjectStore<FileDisk>| {
+            let wal = os.inspect_wal().unwrap();
+            wal.entries.iter().filter(|e| e.op == WalOp::Delete).count() as u64
+        };
+        assert_eq!(deletes(&os), chunks);
+        // a failure part way through a run keeps the keys deleted
+        // before it.
+        os.kms_faults().unwrap().fail_delete(3);
+        assert!(os.unlink_object(2).is_err());
+        assert_eq!(os.pending_key_deletions(), chunks + 2);
+        assert_eq!(deletes(&os), chunks + 2);
+    }
+
+    #[test]
+    fn injected_kms_faults_fail_epochs_and_unlinks() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/kms_faults.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let faults = os.kms_faults().unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[4u8; 100], 0).unwrap();
+        faults.fail_persist(true);
+        assert!(os.advance_epoch().is_err());
+        faults.fail_persist(false);
+        os.advance_epoch().unwrap();
+        faults.fail_delete(1);
+        assert!(os.unlink_object(1).is_err());
+        let mut buf = [0u8; 100];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [4u8; 100]);
+        os.create_object(2).unwrap();
+        faults.fail_derive(1);
+        assert!(os.write_all(2, &[5u8; 100], 0).is_err());
+        os.unlink_object(1).unwrap();
+        assert_eq!(os.get_all_object_ids().unwrap(), [2]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn fuzz_ops_recover_from_a_crash() {
+        use crate::testing::{run_ops, Op};
+        run_ops(&[
+            Op::Create(1),
+            Op::Write {
+                obj_id: 1,
+                offset: 0,
+                len: 5000,
+                byte: 9,
+            },
+            Op::Epoch,
+            Op::Crash { after_writes: 3 },
+            Op::Create(2),
+            Op::Write {
+                obj_id: 2,
+                offset: 100,
+                len: 10,
+                byte: 1,
+            },
+            Op::Unlink(1),
+            Op::Read {
+                obj_id: 2,
+                offset: 100,
+                len: 10,
+            },
+        ]);
+    }
+
+    #[test]
+    fn inspect_wal_lists_pending_chunks() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/wal_log.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 100], 0).unwrap();
+        let report = os.inspect_wal().unwrap();
+        assert!(!report.entries.is_empty());
+        assert!(report.entries.iter().all(|entry| entry.op == WalOp::Derive));
+        os.unlink_object(1).unwrap();
+        let report = os.inspect_wal().unwrap();
+        assert!(report.entries.iter().all(|entry| entry.op == WalOp::Delete));
+        os.advance_epoch().unwrap();
+        assert!(os.inspect_wal().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn estimate_epoch_counts_touched_chunks() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/estimate.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let page = crate::fs::PAGE_SIZE;
+        os.create_object(1).unwrap();
+        os.write_all(1, &vec![1u8; 2 * page], 0).unwrap();
+        let estimate = os.estimate_epoch().unwrap();
+        assert!(estimate.exact);
+        assert_eq!(estimate.bytes_to_reencrypt, 2 * page as u64);
+        os.advance_epoch().unwrap();
+        assert_eq!(os.estimate_epoch().unwrap().chunks_to_rekey, 0);
+    }
+
+    #[test]
+    fn sector_reads_continue_the_chunk_cipher() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let id = 0x956;
+        let _ = os.unlink_object(id);
+        os.create_object(id).unwrap();
+        let data: Vec<u8> = (0..3 * crate::fs::PAGE_SIZE).map(|i| i as u8).collect();
+        os.write_all(id, &data, 0).unwrap();
+        // starts mid sector and crosses both chunk boundaries.
+        let mut buf = vec![0u8; data.len() - 700];
+        os.read_exact(id, &mut buf, 300).unwrap();
+        assert_eq!(buf, data[300..data.len() - 400]);
+        os.unlink_object(id).unwrap();
+    }
+
+    #[test]
+    fn frozen_store_leaves_the_image_alone() {
+        // small enough to read back whole.
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open("/tmp/freeze.img")
+            .unwrap();
+        file.set_len(8 * 1024 * 1024).unwrap();
+        let os = ObjectStore::format(
+            FileDisk { file },
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat12),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 100], 0).unwrap();
+        os.freeze().unwrap();
+        let image = std::fs::read("/tmp/freeze.img").unwrap();
+        os.write_all(1, &[2u8; 100], 0).unwrap();
+        os.create_object(2).unwrap();
+        let mut buf = [0u8; 100];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [2u8; 100]);
+        assert_eq!(std::fs::read("/tmp/freeze.img").unwrap(), image);
+        os.thaw().unwrap();
+        assert!(!os.is_frozen());
+        assert_ne!(std::fs::read("/tmp/freeze.img").unwrap(), image);
+        assert_eq!(os.get_all_object_ids().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rewrapping_a_namespace_leaves_the_others_alone() {
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/namespaces.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_namespace("a").unwrap();
+        os.create_namespace("b").unwrap();
+        assert!(os.create_namespace("a").is_err());
+        let a = os.namespace_key("a").unwrap().unwrap();
+        let b = os.namespace_key("b").unwrap().unwrap();
+        assert_ne!(*a, *b);
+        os.rewrap_namespace("a").unwrap();
+        assert_ne!(*os.namespace_key("a").unwrap().unwrap(), *a);
+        assert_eq!(*os.namespace_key("b").unwrap().unwrap(), *b);
+        assert!(os.delete_namespace("a").unwrap());
+        assert_eq!(os.namespaces().unwrap(), vec!["b".to_string()]);
+        assert!(os.namespace_key("a").unwrap().is_none());
+    }
+
+    #[test]
+    fn self_benchmark_reports_every_workload() {
+        let os = OBJECT_STORE.lock().unwrap();
+        let ids = os.get_all_object_ids().unwrap().len();
+        let profile = BenchProfile {
+            object_size: 64 * 1024,
+            io_size: 4096,
+            random_ops: 8,
+        };
+        let report = os.self_benchmark(profile).unwrap();
+        assert_eq!(report.sequential_write.ops, 16);
+        assert_eq!(report.random_read.ops, 8);
+        assert!(report.sequential_read.p50 <= report.sequential_read.max);
+        assert_eq!(os.get_all_object_ids().unwrap().len(), ids);
+    }
+
+    #[test]
+    fn snapshot_restores_onto_fresh_volume() {
+        let key = [7u8; 32];
+        let src = ObjectStore::format(
+            FileDisk::open("/tmp/snapshot_src.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        src.create_object(1).unwrap();
+        src.write_all(1, &[0xab; 70_000], 0).unwrap();
+        src.create_object(2).unwrap();
+        let mut archive = std::io::Cursor::new(Vec::new());
+        src.snapshot().export(&mut archive, &key).unwrap();
+        let dst = ObjectStore::format(
+            FileDisk::open("/tmp/snapshot_dst.img"),
+            [1u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        assert!(dst.import_snapshot(&mut archive, &[0u8; 32]).is_err());
+        let mut restored = dst.import_snapshot(&mut archive, &key).unwrap();
+        restored.sort();
+        assert_eq!(restored, [1, 2]);
+        let mut buf = vec![0u8; 70_000];
+        dst.read_exact(1, &mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xab));
+    }
+
+    #[test]
+    fn snapshot_stores_zero_pages_as_holes() {
+        let key = [7u8; 32];
+        let os = ObjectStore::format(
+            FileDisk::open("/tmp/snapshot_holes.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[0u8; 1 << 20], 0).unwrap();
+        os.write_all(1, &[5u8; 10], 300_000).unwrap();
+        let mut archive = std::io::Cursor::new(Vec::new());
+        let len = os.snapshot().export(&mut archive, &key).unwrap();
+        assert!(len < 64 * 1024);
+        os.unlink_object(1).unwrap();
+        os.import_snapshot(&mut archive, &key).unwrap();
+        assert_eq!(os.disk_length(1).unwrap(), 1 << 20);
+        let mut buf = vec![0u8; 1 << 20];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf[300_000..300_010], [5u8; 10]);
+        buf[300_000..300_010].fill(0);
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn diff_replicates_changes() {
+        let key = [7u8; 32];
+        let primary = ObjectStore::format(
+            FileDisk::open("/tmp/diff_primary.img"),
+            [0u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        let standby = ObjectStore::format(
+            FileDisk::open("/tmp/diff_standby.img"),
+            [1u8; 32],
+            FormatOptions::new(),
+        )
+        .unwrap();
+        primary.create_object(1).unwrap();
+        primary.write_all(1, &[1; 3 * 4096], 0).unwrap();
+        primary.create_object(2).unwrap();
+        let mut stream = Vec::new();
+        let base = primary
+            .snapshot()
+            .export_diff(&Manifest::default(), &mut stream, &key)
+            .unwrap();
+        standby.apply_diff(&stream[..], &key).unwrap();
+
+        primary.write_all(1, &[2; 10], 4096).unwrap();
+        primary.unlink_object(2).unwrap();
+        let mut stream = Vec::new();
+        primary
+            .snapshot()
+            .export_diff(&base, &mut stream, &key)
+            .unwrap();
+        let stats = standby.apply_diff(&stream[..], &key).unwrap();
+        assert_eq!(stats.pages_written, 1);
+        assert_eq!(stats.objects_deleted, 1);
+        let mut buf = [0u8; 4096 + 10];
+        standby.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf[4096..], [2; 10]);
+        assert!(standby.read_exact(2, &mut [], 0).is_err());
+    }
+
+    #[test]
+    fn overlays_share_a_read_only_base() {
+        let base_path = "/tmp/overlay_base.img";
+        let os = ObjectStore::format(FileDisk::open(base_path), [0u8; 32], FormatOptions::new())
+            .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, b"golden", 0).unwrap();
+        os.close().unwrap();
+        let read_base = |buf: &mut [u8]| {
+            FileDisk::open(base_path).read_exact_at(0, buf).unwrap();
+        };
+        let mut before = vec![0u8; 1 << 20];
+        read_base(&mut before);
+        let delta = |path: &str| {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(path)
+                .unwrap();
+            let size = FileDisk::open(base_path).size().unwrap();
+            file.set_len(OverlayDisk::<FileDisk>::delta_size(size))
+                .unwrap();
+            FileDisk { file }
+        };
+        let os = ObjectStore::open_overlay(
+            FileDisk::open(base_path),
+            delta("/tmp/overlay_a.img"),
+            [0u8; 32],
+        )
+        .unwrap();
+        os.write_all(1, b"custom", 0).unwrap();
+        os.close().unwrap();
+        let os = ObjectStore::open_overlay(
+            FileDisk::open(base_path),
+            delta("/tmp/overlay_b.img"),
+            [0u8; 32],
+        )
+        .unwrap();
+        let mut buf = [0u8; 6];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"golden");
+        os.close().unwrap();
+        let reopened = ObjectStore::open_overlay(
+            FileDisk::open(base_path),
+            FileDisk {
+                file: OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/tmp/overlay_a.img")
+                    .unwrap(),
+            },
+            [0u8; 32],
+        )
+        .unwrap();
+        reopened.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"custom");
+        let mut after = vec![0u8; 1 << 20];
+        read_base(&mut after);
+        assert_eq!(before, after);
+    }
+
+    /// Sixteen 1 MiB zones held in memory, counting resets.
+    #[derive(Clone, Default)]
+    struct MemZones {
+        zones: Arc<Mutex<Vec<Vec<u8>>>>,
+        resets: Arc<std::sync::atomic::AtomicU64>,
+    }
+
+    impl IoBase for MemZones {
+        type Error = std::io::Error;
+    }
+
+    impl ZonedDevice for MemZones {
+        fn zone_size(&self) -> u64 {
+            1 << 20
+        }
+
+        fn zone_count(&self) -> u64 {
+            16
+        }
+
+        fn write_pointer(&self, zone: u64) -> Result<u64, Self::Error> {
+            let zones = self.zones.lock().unwrap();
+            Ok(zones.get(zone as usize).map_or(0, |zone| zone.len() as u64))
+        }
+
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let zones = self.zones.lock().unwrap();
+            let zone = &zones[(offset / self.zone_size()) as usize];
+            let within = (offset % self.zone_size()) as usize;
+            let n = buf.len().min(zone.len() - within);
+            buf[..n].copy_from_slice(&zone[within..within + n]);
+            Ok(n)
+        }
+
+        fn append(&self, zone: u64, buf: &[u8]) -> Result<(), Self::Error> {
+            let mut zones = self.zones.lock().unwrap();
+            zones.resize(self.zone_count() as usize, Vec::new());
+            let zone = &mut zones[zone as usize];
+            assert!(zone.len() + buf.len() <= self.zone_size() as usize);
+            zone.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn reset(&self, zone: u64) -> Result<(), Self::Error> {
+            self.zones.lock().unwrap()[zone as usize].clear();
+            self.resets
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn zoned_disk_resets_zones_on_epochs() {
+        let device = MemZones::default();
+        let os = ObjectStore::format(
+            ZonedDisk::open(device.clone()).unwrap(),
+            [0u8; 32],
+            FormatOptions::new().fat_flavor(FatFlavor::Fat12),
+        )
+        .unwrap();
+        os.create_object(1).unwrap();
+        os.write_all(1, &[1u8; 16384], 0).unwrap();
+        os.advance_epoch().unwrap();
+        os.write_all(1, &[2u8; 16384], 0).unwrap();
+        os.advance_epoch().unwrap();
+        assert!(device.resets.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        os.close().unwrap();
+        let os = ObjectStore::open(ZonedDisk::open(device).unwrap(), [0u8; 32]).unwrap();
+        let mut buf = [0u8; 16384];
+        os.read_exact(1, &mut buf, 0).unwrap();
+        assert_eq!(buf, [2u8; 16384]);
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn mem_disk_round_trip() {
+        let os =
+            ObjectStore::format(MemDisk::new(512 << 20), [0u8; 32], FormatOptions::new()).unwrap();
+        let id = 938;
+        os.create_object(id).unwrap();
+        os.write_all(id, b"in memory", 0).unwrap();
+        let mut buf = [0u8; 9];
+        os.read_exact(id, &mut buf, 0).unwrap();
+        assert_eq!(&buf, b"in memory");
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_round_trip() {
+        FileDisk::open("/tmp/ffi.img");
+        let path = std::ffi::CString::new("/tmp/ffi.img").unwrap();
+        let mut os = std::ptr::null_mut();
+        let id = TosObjId { hi: 0, lo: 937 };
+        let mut buf = [0u8; 3];
+        unsafe {
+            assert_eq!(
+                tos_open(
+                    path.as_ptr(),
+                    [0u8; 32].as_ptr(),
+                    TOS_OPEN_CREATE | TOS_OPEN_TAKEOVER,
+                    &mut os
+                ),
+                TOS_ERR_INVALID
+            );
+            assert!(os.is_null());
+            assert_eq!(
+                tos_open(path.as_ptr(), [0u8; 32].as_ptr(), TOS_OPEN_CREATE, &mut os),
+                TOS_OK
+            );
+            let _ = tos_unlink(os, id);
+            assert_eq!(tos_create(os, id), TOS_OK);
+            assert_eq!(tos_create(os, id), TOS_ERR_EXISTS);
+            assert_eq!(tos_write(os, id, b"ffi".as_ptr(), 3, 0), TOS_OK);
+            assert_eq!(tos_read(os, id, buf.as_mut_ptr(), 3, 0), TOS_OK);
+            assert_eq!(tos_unlink(os, id), TOS_OK);
+            assert_eq!(tos_epoch(os), TOS_OK);
+            assert_eq!(tos_read(os, id, buf.as_mut_ptr(), 3, 0), TOS_ERR_NOT_FOUND);
+            assert_eq!(tos_close(os), TOS_OK);
+        }
+        assert_eq!(&buf, b"ffi");
+    }
+
     #[test]
     fn get_all_ids() {
         let _all_ids = OBJECT_STORE.lock().unwrap().get_all_object_ids().unwrap();
@@ -154,7 +2682,7 @@ mod tests {
         os.advance_epoch().unwrap();
         drop(os);
         let mut os = OBJECT_STORE.lock().unwrap();
-        os.reopen();
+        os.reopen().unwrap();
         drop(os);
         let os = OBJECT_STORE.lock().unwrap();
         let mut buf = [0u8; 4];
@@ -171,7 +2699,7 @@ mod tests {
             .map(|_i| make_and_check_file(&os, &mut working_bufs.0, &mut working_bufs.1))
             .collect::<Vec<_>>();
         os.advance_epoch().unwrap();
-        os.reopen();
+        os.reopen().unwrap();
 
         // println!("{:?}", KHF.lock().unwrap());
         for (value, id) in out {
@@ -190,7 +2718,7 @@ mod tests {
             // unlink
             os.unlink_object(id).unwrap();
             os.advance_epoch().unwrap();
-            os.reopen();
+            os.reopen().unwrap();
             // println!("{:?}", KHF.lock().unwrap());
             // make sure object is unlinked
             let v = os.read_exact(id, &mut buf, 0).expect_err("should be error");
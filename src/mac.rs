@@ -0,0 +1,297 @@
+use crate::{
+    eof::check_in_bounds,
+    events::StoreEvent,
+    fs::{Disk, FatFile, FatFs, PAGE_SIZE},
+    meta::{read_meta, update_meta},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::{IoBase, Seek, SeekFrom};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{Error, ErrorKind},
+    ops::Range,
+    sync::MutexGuard,
+    time::Instant,
+};
+
+pub(crate) const MACS_PATH: &str = "meta/macs";
+
+pub(crate) type PageMac = [u8; 16];
+
+/// The keyed hash page MACs are computed with. Chosen when the store is
+/// formatted, independently of the SHA3 the KHF derives keys with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IntegrityHash {
+    #[default]
+    Sha3,
+    /// Several times faster than SHA3 in software, using SIMD where
+    /// the CPU has it, which matters when scrubbing whole objects.
+    Blake3,
+}
+
+impl IntegrityHash {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            IntegrityHash::Sha3 => 0,
+            IntegrityHash::Blake3 => 1,
+        }
+    }
+
+    pub(crate) fn from_byte(b: u8) -> Result<Self, Error> {
+        match b {
+            0 => Ok(IntegrityHash::Sha3),
+            1 => Ok(IntegrityHash::Blake3),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown integrity hash")),
+        }
+    }
+}
+
+/// Per-page MACs of every object that has them enabled.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct MacTable {
+    objects: BTreeMap<u128, Vec<PageMac>>,
+}
+
+/// Returned by `read_exact_verified` when part of an object doesn't
+/// match its MACs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityError {
+    pub obj_id: u128,
+    /// The byte range of the first run of corrupt pages.
+    pub corrupt: Range<u64>,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "object {:x} is corrupt in bytes {}..{}",
+            self.obj_id, self.corrupt.start, self.corrupt.end
+        )
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+impl From<IntegrityError> for Error {
+    fn from(value: IntegrityError) -> Self {
+        Error::new(ErrorKind::InvalidData, value)
+    }
+}
+
+/// Returns the integrity failure carried by `err`, if any.
+pub fn integrity_error(err: &Error) -> Option<&IntegrityError> {
+    err.get_ref()?.downcast_ref::<IntegrityError>()
+}
+
+/// Pages covered by `len` bytes starting at `off`.
+pub(crate) fn page_range(off: u64, len: usize) -> Range<u64> {
+    let page_size = PAGE_SIZE as u64;
+    off / page_size..(off + len as u64).div_ceil(page_size)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn macs_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<MacTable>>, Error> {
+        let mut macs = self.macs.lock().unwrap();
+        if macs.is_none() {
+            *macs = Some(read_meta(fs, &self.meta_key(), MACS_PATH)?.unwrap_or_default());
+        }
+        Ok(macs)
+    }
+
+    /// MACs are bound to the object and page so that pages can't be
+    /// swapped around without being noticed.
+    fn page_mac(&self, obj_id: u128, page: u64, plaintext: &[u8]) -> PageMac {
+        match self.integrity_hash {
+            IntegrityHash::Sha3 => {
+                let mut hasher = Sha3_256::new();
+                hasher.update(self.meta_key());
+                hasher.update(obj_id.to_le_bytes());
+                hasher.update(page.to_le_bytes());
+                hasher.update(plaintext);
+                hasher.finalize()[..16].try_into().unwrap()
+            }
+            IntegrityHash::Blake3 => {
+                let mut hasher = blake3::Hasher::new_keyed(&self.meta_key());
+                hasher.update(&obj_id.to_le_bytes());
+                hasher.update(&page.to_le_bytes());
+                hasher.update(plaintext);
+                hasher.finalize().as_bytes()[..16].try_into().unwrap()
+            }
+        }
+    }
+
+    /// The hash page MACs are computed with.
+    pub fn integrity_hash(&self) -> IntegrityHash {
+        self.integrity_hash
+    }
+
+    /// Computes a MAC for every page of an object and keeps them up to
+    /// date on later writes, so that `read_exact_verified` can detect
+    /// corruption.
+    pub fn enable_page_macs(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        let mut fs = self.fs().lock().unwrap();
+        if self.is_deduplicated_locked(&fs, obj_id)? {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "deduplicated objects can't have page MACs",
+            )
+            .into());
+        }
+        self.store_page_macs(&fs, obj_id, Vec::new())?;
+        Ok(self.refresh_page_macs(&mut fs, obj_id)?)
+    }
+
+    pub(crate) fn page_macs_locked(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+    ) -> Result<Option<Vec<PageMac>>, Error> {
+        Ok(self
+            .macs_lock(fs)?
+            .as_ref()
+            .unwrap()
+            .objects
+            .get(&obj_id)
+            .cloned())
+    }
+
+    pub(crate) fn store_page_macs(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        page_macs: Vec<PageMac>,
+    ) -> Result<(), Error> {
+        let mut macs = self.macs_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            MACS_PATH,
+            macs.as_mut().unwrap(),
+            |macs| {
+                macs.objects.insert(obj_id, page_macs);
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Recomputes the MACs of `pages` from the plaintext in `file`,
+    /// growing or shrinking `macs` to the length of the file.
+    pub(crate) fn compute_page_macs(
+        &self,
+        file: &mut FatFile<'_, D>,
+        obj_id: u128,
+        mut macs: Vec<PageMac>,
+        pages: impl IntoIterator<Item = u64>,
+        unwritten: &[Range<u64>],
+    ) -> Result<Vec<PageMac>, Error> {
+        let len = file.seek(SeekFrom::End(0))?;
+        let count = len.div_ceil(PAGE_SIZE as u64);
+        macs.resize(count as usize, [0; 16]);
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for page in pages {
+            if page >= count {
+                continue;
+            }
+            let start = page * PAGE_SIZE as u64;
+            let buf = &mut buf[..(len - start).min(PAGE_SIZE as u64) as usize];
+            self.read_file_at(file, buf, start, unwritten)?;
+            macs[page as usize] = self.page_mac(obj_id, page, buf);
+        }
+        Ok(macs)
+    }
+
+    /// Recomputes every MAC of an object, if it has MACs enabled.
+    pub(crate) fn refresh_page_macs(&self, fs: &mut FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        if self.page_macs_locked(fs, obj_id)?.is_none() {
+            return Ok(());
+        }
+        let b64 = self.encode_obj_id(obj_id);
+        let unwritten = self.unwritten_ranges(fs, obj_id)?;
+        let macs = {
+            let mut file = get_dir_path(fs, &b64)?.open_file(&b64)?;
+            let len = file.seek(SeekFrom::End(0))?;
+            let pages = page_range(0, len as usize);
+            self.compute_page_macs(&mut file, obj_id, Vec::new(), pages, &unwritten)?
+        };
+        self.store_page_macs(fs, obj_id, macs)
+    }
+
+    /// Like `read_exact`, but checks every page the read touches
+    /// against its MAC.
+    ///
+    /// # Errors
+    /// An `IntegrityError` naming the corrupt bytes if any page doesn't
+    /// match, and `InvalidInput` if the object doesn't have page MACs.
+    pub fn read_exact_verified(
+        &self,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let started = Instant::now();
+        let mut fs = self.fs().lock().unwrap();
+        let Some(macs) = self.page_macs_locked(&fs, obj_id)? else {
+            return Err(
+                Error::new(ErrorKind::InvalidInput, "object does not have page MACs").into(),
+            );
+        };
+        let b64 = self.encode_obj_id(obj_id);
+        let len = get_dir_path(&fs, &b64)?
+            .open_file(&b64)?
+            .seek(SeekFrom::End(0))?;
+        check_in_bounds(obj_id, off, buf.len(), len).map_err(Error::from)?;
+        let pages = page_range(off, buf.len());
+        let start = pages.start * PAGE_SIZE as u64;
+        let mut plaintext = vec![0u8; ((pages.end * PAGE_SIZE as u64).min(len) - start) as usize];
+        self.read_locked(&mut fs, obj_id, &mut plaintext, start)?;
+        let mut corrupt: Option<Range<u64>> = None;
+        for (page, chunk) in pages.zip(plaintext.chunks(PAGE_SIZE)) {
+            let bytes = page * PAGE_SIZE as u64..page * PAGE_SIZE as u64 + chunk.len() as u64;
+            if macs.get(page as usize) != Some(&self.page_mac(obj_id, page, chunk)) {
+                match &mut corrupt {
+                    Some(range) => range.end = bytes.end,
+                    None => corrupt = Some(bytes),
+                }
+            } else if corrupt.is_some() {
+                break;
+            }
+        }
+        if let Some(corrupt) = corrupt {
+            self.events.emit(StoreEvent::IntegrityFailure {
+                obj_id,
+                corrupt: corrupt.clone(),
+            });
+            return Err(Error::from(IntegrityError { obj_id, corrupt }).into());
+        }
+        let from = (off - start) as usize;
+        buf.copy_from_slice(&plaintext[from..from + buf.len()]);
+        self.record_read(obj_id, buf.len(), started);
+        Ok(())
+    }
+
+    pub(crate) fn forget_page_macs(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut macs = self.macs_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            MACS_PATH,
+            macs.as_mut().unwrap(),
+            |macs| macs.objects.remove(&obj_id).is_some(),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::time::{Duration, Instant};
+
+/// Background work a store has queued up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceBacklog {
+    /// Objects past their expiry deadline.
+    pub expired: usize,
+    /// Trashed objects that have outlived the trash retention.
+    pub purgeable: usize,
+    /// Chunks a failed epoch has yet to re-encrypt.
+    pub epoch_chunks: usize,
+    /// Chunk keys deleted since the last epoch, which the next one
+    /// securely forgets.
+    pub key_deletions: u64,
+    /// An epoch failed after rotating its keys, and has to be retried.
+    pub epoch_paused: bool,
+}
+
+impl MaintenanceBacklog {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What a call to `maintenance` got done.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Expired objects that were unlinked.
+    pub reaped: Vec<u128>,
+    /// Trashed objects that were purged.
+    pub purged: Vec<u128>,
+    /// Epochs that finished, each securely deleting everything deleted
+    /// before it started.
+    pub epochs_finished: u32,
+    /// The work left for later calls.
+    pub remaining: MaintenanceBacklog,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the background work queued up, without doing any of it.
+    pub fn maintenance_backlog(&self) -> Result<MaintenanceBacklog, ObjectStoreError> {
+        Ok(MaintenanceBacklog {
+            expired: self.expired_objects()?.len(),
+            purgeable: self.purgeable_objects()?.len(),
+            epoch_chunks: self.pending_epoch_chunks(),
+            key_deletions: self.pending_key_deletions(),
+            epoch_paused: self.epoch_paused(),
+        })
+    }
+
+    /// Spends up to `budget` on queued background work, for embedders
+    /// without threads to run it on. Expired objects are reaped and the
+    /// trash purged first, then an epoch is advanced if keys are waiting
+    /// to be securely deleted.
+    ///
+    /// An epoch can't be paused part way, so one is only started if the
+    /// time left looks enough to finish it, going by how fast the last
+    /// epoch re-encrypted, and the next call tries again otherwise. The
+    /// budget is checked between objects, so a call can overrun it by
+    /// about one object, or by however much the epoch was misjudged.
+    pub fn maintenance(&self, budget: Duration) -> Result<MaintenanceReport, ObjectStoreError> {
+        let deadline = Instant::now() + budget;
+        let in_budget = || Instant::now() < deadline;
+        let mut report = MaintenanceReport::default();
+        for obj_id in self.expired_objects()? {
+            if !in_budget() {
+                break;
+            }
+            if self.reap_object(obj_id)? {
+                report.reaped.push(obj_id);
+            }
+        }
+        for obj_id in self.purgeable_objects()? {
+            if !in_budget() {
+                break;
+            }
+            self.purge_object(obj_id)?;
+            report.purged.push(obj_id);
+        }
+        while (self.epoch_paused() || self.pending_key_deletions() > 0)
+            && in_budget()
+            && self.epoch_fits(Some(deadline.saturating_duration_since(Instant::now())))?
+        {
+            self.advance_epoch()?;
+            report.epochs_finished += 1;
+        }
+        report.remaining = self.maintenance_backlog()?;
+        Ok(report)
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Error, ErrorKind},
+    ops::RangeInclusive,
+};
+
+/// Several stores, each on its own disk, presented as one. Objects are
+/// routed to a store by the range their id falls in, and namespaces by
+/// their label.
+pub struct StoreManager<D: Disk> {
+    stores: Vec<ObjectStore<D>>,
+    /// Disjoint id ranges, sorted by their start.
+    ranges: Vec<(RangeInclusive<u128>, usize)>,
+    namespaces: BTreeMap<String, usize>,
+}
+
+impl<D: Disk> Default for StoreManager<D> {
+    fn default() -> Self {
+        Self {
+            stores: Vec::new(),
+            ranges: Vec::new(),
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl<D> StoreManager<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an open store, returning the index routes refer to it by.
+    pub fn add_store(&mut self, store: ObjectStore<D>) -> usize {
+        self.stores.push(store);
+        self.stores.len() - 1
+    }
+
+    /// Opens the store on `disk` and adds it.
+    pub fn open_store(&mut self, disk: D, root_key: [u8; 32]) -> Result<usize, ObjectStoreError> {
+        Ok(self.add_store(ObjectStore::open(disk, root_key)?))
+    }
+
+    pub fn stores(&self) -> &[ObjectStore<D>] {
+        &self.stores
+    }
+
+    fn check_index(&self, store: usize) -> Result<(), Error> {
+        if store >= self.stores.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "no such store"));
+        }
+        Ok(())
+    }
+
+    /// Sends the objects with ids in `ids` to `store`.
+    ///
+    /// # Errors
+    /// `InvalidInput` if there is no such store or the range overlaps
+    /// one already routed.
+    pub fn route_range(
+        &mut self,
+        ids: RangeInclusive<u128>,
+        store: usize,
+    ) -> Result<(), ObjectStoreError> {
+        self.check_index(store)?;
+        if ids.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty id range").into());
+        }
+        let at = self
+            .ranges
+            .partition_point(|(range, _)| range.start() < ids.start());
+        let overlaps_prev = at > 0 && self.ranges[at - 1].0.end() >= ids.start();
+        let overlaps_next = self
+            .ranges
+            .get(at)
+            .is_some_and(|(range, _)| range.start() <= ids.end());
+        if overlaps_prev || overlaps_next {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "id range overlaps one already routed",
+            )
+            .into());
+        }
+        self.ranges.insert(at, (ids, store));
+        Ok(())
+    }
+
+    /// Sends the namespace `label` to `store`, replacing any earlier
+    /// route for it.
+    pub fn route_namespace(&mut self, label: &str, store: usize) -> Result<(), ObjectStoreError> {
+        self.check_index(store)?;
+        self.namespaces.insert(label.to_string(), store);
+        Ok(())
+    }
+
+    fn index_for(&self, obj_id: u128) -> Option<usize> {
+        let at = self
+            .ranges
+            .partition_point(|(range, _)| *range.start() <= obj_id);
+        let (range, store) = self.ranges.get(at.checked_sub(1)?)?;
+        range.contains(&obj_id).then_some(*store)
+    }
+
+    /// Returns the store holding `obj_id`.
+    ///
+    /// # Errors
+    /// `NotFound` if no range covers the id.
+    pub fn store_for(&self, obj_id: u128) -> Result<&ObjectStore<D>, ObjectStoreError> {
+        Ok(self
+            .index_for(obj_id)
+            .map(|store| &self.stores[store])
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no store for object id"))?)
+    }
+
+    /// Returns the store holding the namespace `label`.
+    ///
+    /// # Errors
+    /// `NotFound` if the namespace isn't routed.
+    pub fn store_for_namespace(&self, label: &str) -> Result<&ObjectStore<D>, ObjectStoreError> {
+        Ok(self
+            .namespaces
+            .get(label)
+            .map(|&store| &self.stores[store])
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no store for namespace"))?)
+    }
+
+    pub fn create_object(&self, obj_id: u128) -> Result<bool, ObjectStoreError> {
+        self.store_for(obj_id)?.create_object(obj_id)
+    }
+
+    pub fn unlink_object(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        self.store_for(obj_id)?.unlink_object(obj_id)
+    }
+
+    pub fn read_exact(
+        &self,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        self.store_for(obj_id)?.read_exact(obj_id, buf, off)
+    }
+
+    pub fn write_all(&self, obj_id: u128, buf: &[u8], off: u64) -> Result<(), ObjectStoreError> {
+        self.store_for(obj_id)?.write_all(obj_id, buf, off)
+    }
+
+    pub fn disk_length(&self, obj_id: u128) -> Result<u64, ObjectStoreError> {
+        self.store_for(obj_id)?.disk_length(obj_id)
+    }
+
+    /// Lists the ids of every reachable object in order. Objects a
+    /// store holds outside the ranges routed to it are left out.
+    pub fn get_all_object_ids(&self) -> Result<Vec<u128>, ObjectStoreError> {
+        let mut ids = BTreeSet::new();
+        for (index, store) in self.stores.iter().enumerate() {
+            ids.extend(
+                store
+                    .get_all_object_ids()?
+                    .into_iter()
+                    .filter(|&obj_id| self.index_for(obj_id) == Some(index)),
+            );
+        }
+        Ok(ids.into_iter().collect())
+    }
+
+    /// Lists the routed namespaces that exist in their store, in label
+    /// order.
+    pub fn namespaces(&self) -> Result<Vec<String>, ObjectStoreError> {
+        let mut out = Vec::new();
+        for (label, &store) in &self.namespaces {
+            if self.stores[store].namespace_key(label)?.is_some() {
+                out.push(label.clone());
+            }
+        }
+        Ok(out)
+    }
+
+    /// Closes every store, returning the first error once all have
+    /// been tried.
+    pub fn close(self) -> Result<(), ObjectStoreError> {
+        let mut first = Ok(());
+        for store in self.stores {
+            let res = store.close().map_err(Error::from);
+            if first.is_ok() {
+                first = res;
+            }
+        }
+        Ok(first?)
+    }
+}
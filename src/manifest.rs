@@ -0,0 +1,135 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_raw, seal, unseal, META_DIR, NONCE_LEN, TAG_LEN},
+    ObjectStore,
+};
+use fatfs::{IoBase, Seek, SeekFrom, Write as _};
+use std::{collections::BTreeSet, io::Error, sync::MutexGuard};
+
+pub(crate) const MANIFEST_PATH: &str = "meta/manifest";
+const CREATED: u8 = 1;
+const UNLINKED: u8 = 2;
+/// An op byte and an object id, sealed on their own.
+pub(crate) const RECORD_LEN: usize = NONCE_LEN + 1 + 16 + TAG_LEN;
+
+/// The ids of every listed object, loaded from the manifest: a log of
+/// sealed created and unlinked records.
+///
+/// Records are appended before an object file is created and after one
+/// is removed, so a crash can leave the manifest listing an object that
+/// doesn't exist but never missing one that does. Epochs rebuild it from
+/// the directories, which drops such ids and the unlinked records.
+#[derive(Debug, Default)]
+pub(crate) struct IdManifest {
+    ids: BTreeSet<u128>,
+}
+
+fn record(key: &[u8; 32], op: u8, obj_id: u128) -> Vec<u8> {
+    let mut plaintext = [0u8; 17];
+    plaintext[0] = op;
+    plaintext[1..].copy_from_slice(&obj_id.to_le_bytes());
+    seal(key, &plaintext)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn manifest_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<IdManifest>>, Error> {
+        let mut manifest = self.manifest.lock().unwrap();
+        if manifest.is_none() {
+            *manifest = Some(match self.read_manifest(fs)? {
+                Some(loaded) => loaded,
+                // stores that predate the manifest, or a torn append.
+                None => self.rebuild_manifest(fs)?,
+            });
+        }
+        Ok(manifest)
+    }
+
+    /// Replays the manifest, returning `None` if it is missing or any
+    /// record fails to unseal.
+    fn read_manifest(&self, fs: &FatFs<D>) -> Result<Option<IdManifest>, Error> {
+        let Some(raw) = read_raw(fs, MANIFEST_PATH)? else {
+            return Ok(None);
+        };
+        if raw.len() % RECORD_LEN != 0 {
+            return Ok(None);
+        }
+        let mut manifest = IdManifest::default();
+        for sealed in raw.chunks_exact(RECORD_LEN) {
+            let Some(plaintext) = unseal(&self.meta_key(), sealed) else {
+                return Ok(None);
+            };
+            let obj_id = u128::from_le_bytes(plaintext[1..].try_into().unwrap());
+            match plaintext[0] {
+                CREATED => manifest.ids.insert(obj_id),
+                UNLINKED => manifest.ids.remove(&obj_id),
+                _ => return Ok(None),
+            };
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Lists the object directories and rewrites the manifest with a
+    /// created record for each object found.
+    fn rebuild_manifest(&self, fs: &FatFs<D>) -> Result<IdManifest, Error> {
+        let manifest = IdManifest {
+            ids: self.scan_object_ids(fs)?.into_iter().collect(),
+        };
+        let mut raw = Vec::with_capacity(manifest.ids.len() * RECORD_LEN);
+        for &obj_id in &manifest.ids {
+            raw.extend_from_slice(&record(&self.meta_key(), CREATED, obj_id));
+        }
+        fs.root_dir().create_dir(META_DIR)?;
+        let mut file = fs.root_dir().create_file(MANIFEST_PATH)?;
+        file.truncate()?;
+        file.write_all(&raw)?;
+        Ok(manifest)
+    }
+
+    fn append_manifest(&self, fs: &FatFs<D>, op: u8, obj_id: u128) -> Result<(), Error> {
+        let mut manifest = self.manifest_lock(fs)?;
+        let ids = &mut manifest.as_mut().unwrap().ids;
+        let changed = match op {
+            CREATED => ids.insert(obj_id),
+            _ => ids.remove(&obj_id),
+        };
+        if changed {
+            let mut file = fs.root_dir().open_file(MANIFEST_PATH)?;
+            file.seek(SeekFrom::End(0))?;
+            file.write_all(&record(&self.meta_key(), op, obj_id))?;
+        }
+        Ok(())
+    }
+
+    /// Lists an object. Must be called before its file is created.
+    pub(crate) fn manifest_created(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        self.append_manifest(fs, CREATED, obj_id)
+    }
+
+    /// Stops listing an object. Must be called after its file is
+    /// removed.
+    pub(crate) fn manifest_unlinked(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        self.append_manifest(fs, UNLINKED, obj_id)
+    }
+
+    /// Returns the ids in the manifest, in id order.
+    pub(crate) fn manifest_ids(&self, fs: &FatFs<D>) -> Result<Vec<u128>, Error> {
+        let manifest = self.manifest_lock(fs)?;
+        Ok(manifest.as_ref().unwrap().ids.iter().copied().collect())
+    }
+
+    /// Rewrites the manifest from the directories, dropping unlinked
+    /// records and ids left behind by crashes.
+    pub(crate) fn compact_manifest(&self, fs: &FatFs<D>) -> Result<(), Error> {
+        let mut manifest = self.manifest.lock().unwrap();
+        *manifest = Some(self.rebuild_manifest(fs)?);
+        Ok(())
+    }
+}
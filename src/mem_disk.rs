@@ -0,0 +1,58 @@
+use crate::Disk;
+use fatfs::IoBase;
+use std::{io::Error, sync::RwLock};
+
+/// A fixed size disk held in memory, for sandboxes without a usable
+/// block device such as wasm32-wasi.
+pub struct MemDisk {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemDisk {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: RwLock::new(vec![0; size]),
+        }
+    }
+
+    /// Wraps an existing disk image.
+    pub fn from_image(image: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(image),
+        }
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.data.into_inner().unwrap()
+    }
+}
+
+impl IoBase for MemDisk {
+    type Error = Error;
+}
+
+impl Disk for MemDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let data = self.data.read().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut data = self.data.write().unwrap();
+        let start = (offset as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        data[start..start + n].copy_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+}
@@ -0,0 +1,184 @@
+use crate::fs::{Disk, FatFs};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use fatfs::{Read as _, Write as _};
+use serde::{de::DeserializeOwned, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::io::{Error, ErrorKind};
+
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 32;
+pub(crate) const META_DIR: &str = "meta";
+
+/// Derives a key for a specific purpose from the root key.
+pub(crate) fn derive_subkey(root_key: [u8; 32], label: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(label);
+    hasher.update(root_key);
+    hasher.finalize().into()
+}
+
+pub(crate) fn mac(key: &[u8; 32], nonce: &[u8], plaintext: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(key);
+    hasher.update(nonce);
+    hasher.update(plaintext);
+    hasher.finalize().into()
+}
+
+/// Encrypts and authenticates `plaintext`, laid out as
+/// `nonce || ciphertext || mac`.
+pub(crate) fn seal(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(plaintext);
+    let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+    cipher.apply_keystream(&mut out[NONCE_LEN..]);
+    out.extend_from_slice(&mac(key, &nonce, plaintext));
+    out
+}
+
+/// Reverses `seal`, returning `None` if `sealed` is truncated or fails
+/// its integrity check.
+pub(crate) fn unseal(key: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let mut plaintext = ciphertext.to_vec();
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    cipher.apply_keystream(&mut plaintext);
+    (mac(key, nonce, &plaintext) == tag).then_some(plaintext)
+}
+
+/// Reads a whole file, returning `None` if it doesn't exist.
+pub(crate) fn read_raw<D>(fs: &FatFs<D>, path: &str) -> Result<Option<Vec<u8>>, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let mut file = match fs.root_dir().open_file(path) {
+        Ok(file) => file,
+        Err(fatfs::Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        raw.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(raw))
+}
+
+/// Reads and decrypts a metadata file, returning `None` if it
+/// doesn't exist.
+///
+/// The file is laid out as in `seal`.
+pub(crate) fn read_meta<D, T>(fs: &FatFs<D>, key: &[u8; 32], path: &str) -> Result<Option<T>, Error>
+where
+    D: Disk,
+    T: DeserializeOwned,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let Some(raw) = read_raw(fs, path)? else {
+        return Ok(None);
+    };
+    if raw.len() < NONCE_LEN + TAG_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "metadata file truncated",
+        ));
+    }
+    let plaintext = unseal(key, &raw).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "metadata file failed integrity check",
+        )
+    })?;
+    bincode::deserialize(&plaintext)
+        .map(Some)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Encrypts and writes a metadata file, replacing its previous
+/// contents.
+pub(crate) fn write_meta<D, T>(
+    fs: &FatFs<D>,
+    key: &[u8; 32],
+    path: &str,
+    value: &T,
+) -> Result<(), Error>
+where
+    D: Disk,
+    T: Serialize,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let plaintext = bincode::serialize(value).map_err(Error::other)?;
+    fs.root_dir().create_dir(META_DIR)?;
+    let mut file = fs.root_dir().create_file(path)?;
+    file.truncate()?;
+    file.write_all(&seal(key, &plaintext))?;
+    Ok(())
+}
+
+/// Reseals a log of `record_len` byte records from `from` to `to`.
+/// Records already under `to` are left alone, and so are records that
+/// unseal under neither, for whoever loads the log to notice.
+pub(crate) fn reseal_records<D>(
+    fs: &FatFs<D>,
+    path: &str,
+    record_len: usize,
+    from: &[u8; 32],
+    to: &[u8; 32],
+) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let Some(mut raw) = read_raw(fs, path)? else {
+        return Ok(());
+    };
+    for record in raw.chunks_exact_mut(record_len) {
+        if unseal(to, record).is_none() {
+            if let Some(plaintext) = unseal(from, record) {
+                record.copy_from_slice(&seal(to, &plaintext));
+            }
+        }
+    }
+    let mut file = fs.root_dir().create_file(path)?;
+    file.truncate()?;
+    file.write_all(&raw)?;
+    Ok(())
+}
+
+/// Writes `table` as `update` leaves it, but only changes `table` once
+/// the write has succeeded, so that a failed write leaves it matching
+/// the disk. Nothing is written if `update` returns false, and neither
+/// is returned.
+pub(crate) fn update_meta<D, T>(
+    fs: &FatFs<D>,
+    key: &[u8; 32],
+    path: &str,
+    table: &mut T,
+    update: impl FnOnce(&mut T) -> bool,
+) -> Result<bool, Error>
+where
+    D: Disk,
+    T: Serialize + Clone,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let mut updated = table.clone();
+    if !update(&mut updated) {
+        return Ok(false);
+    }
+    write_meta(fs, key, path, &updated)?;
+    *table = updated;
+    Ok(true)
+}
@@ -0,0 +1,222 @@
+//! The key metadata files are sealed with.
+//!
+//! Metadata is rewritten in place, so older copies of it linger in freed
+//! clusters. The key is random rather than derived from the root key,
+//! and every epoch reseals the metadata under a fresh one and
+//! overwrites the key file, which leaves those copies unreadable even
+//! to someone holding the root key.
+
+use crate::{
+    freeze::FreezableDisk,
+    fs::{Disk, FatFs},
+    index::{INDEX_LOG_PATH, LOG_RECORD_LEN},
+    manifest::{MANIFEST_PATH, RECORD_LEN},
+    meta::{derive_subkey, read_raw, reseal_records, seal, unseal, META_DIR, NONCE_LEN, TAG_LEN},
+    object_store::META_KEY_LABEL,
+    relocate::BALLAST_PATH,
+    upload::UPLOAD_DIR,
+    ObjectStore,
+};
+use fatfs::{IoBase, Write as _};
+use std::io::{Error, ErrorKind};
+use zeroize::Zeroizing;
+
+pub(crate) const META_KEY_PATH: &str = "meta/key";
+const KEY_FILE_LABEL: &[u8] = b"object-store metadata key file";
+/// A flag byte, the current key and the previous key or zeroes.
+const PLAINTEXT_LEN: usize = 1 + 32 + 32;
+const SEALED_LEN: usize = NONCE_LEN + PLAINTEXT_LEN + TAG_LEN;
+/// Files of records that are sealed on their own, and their length.
+const RECORD_LOGS: [(&str, usize); 2] = [
+    (MANIFEST_PATH, RECORD_LEN),
+    (INDEX_LOG_PATH, LOG_RECORD_LEN),
+];
+
+/// The metadata key, and while an epoch is resealing the metadata, the
+/// key it is being resealed from.
+#[derive(Clone, Copy)]
+pub(crate) struct MetaKeys {
+    pub current: [u8; 32],
+    pub previous: Option<[u8; 32]>,
+}
+
+impl MetaKeys {
+    pub fn fresh() -> Self {
+        Self {
+            current: rand::random(),
+            previous: None,
+        }
+    }
+
+    /// Reads the key file. Stores that predate it seal their metadata
+    /// with a key derived from the root key until their first epoch.
+    pub fn load<D>(fs: &FatFs<D>, root_key: [u8; 32]) -> Result<Self, Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+    {
+        let Some(raw) = read_raw(fs, META_KEY_PATH)? else {
+            return Ok(Self {
+                current: derive_subkey(root_key, META_KEY_LABEL),
+                previous: None,
+            });
+        };
+        let plaintext = unseal(&derive_subkey(root_key, KEY_FILE_LABEL), &raw)
+            .filter(|plaintext| plaintext.len() == PLAINTEXT_LEN)
+            .map(Zeroizing::new)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "metadata key failed integrity check",
+                )
+            })?;
+        Ok(Self {
+            current: plaintext[1..33].try_into().unwrap(),
+            previous: (plaintext[0] == 1).then(|| plaintext[33..].try_into().unwrap()),
+        })
+    }
+
+    /// Overwrites the key file in place and flushes it. The file always
+    /// has the same length, so the keys it held before are overwritten
+    /// rather than left in a freed cluster.
+    pub fn store<D>(
+        &self,
+        fs: &FatFs<D>,
+        disk: &FreezableDisk<D>,
+        root_key: [u8; 32],
+    ) -> Result<(), Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+        std::io::Error: From<D::Error>,
+    {
+        let mut plaintext = Zeroizing::new([0u8; PLAINTEXT_LEN]);
+        plaintext[1..33].copy_from_slice(&self.current);
+        if let Some(previous) = &self.previous {
+            plaintext[0] = 1;
+            plaintext[33..].copy_from_slice(previous);
+        }
+        let sealed = seal(&derive_subkey(root_key, KEY_FILE_LABEL), &*plaintext);
+        debug_assert_eq!(sealed.len(), SEALED_LEN);
+        fs.root_dir().create_dir(META_DIR)?;
+        fs.root_dir()
+            .create_file(META_KEY_PATH)?
+            .write_all(&sealed)?;
+        disk.flush()?;
+        Ok(())
+    }
+
+    /// Reseals whatever is still sealed under the previous key with the
+    /// current one, then forgets the previous key. Files that are
+    /// already resealed are left alone, so an interrupted rotation is
+    /// finished by running this again.
+    pub fn finish_rotation<D>(
+        &mut self,
+        fs: &FatFs<D>,
+        disk: &FreezableDisk<D>,
+        root_key: [u8; 32],
+    ) -> Result<(), Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+        std::io::Error: From<D::Error>,
+    {
+        let Some(previous) = self.previous else {
+            return Ok(());
+        };
+        for path in sealed_files(fs)? {
+            let Some(raw) = read_raw(fs, &path)? else {
+                continue;
+            };
+            if unseal(&self.current, &raw).is_some() {
+                continue;
+            }
+            let plaintext = unseal(&previous, &raw).map(Zeroizing::new).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "metadata file failed integrity check",
+                )
+            })?;
+            let mut file = fs.root_dir().create_file(&path)?;
+            file.truncate()?;
+            file.write_all(&seal(&self.current, &plaintext))?;
+        }
+        for (path, record_len) in RECORD_LOGS {
+            reseal_records(fs, path, record_len, &previous, &self.current)?;
+        }
+        // nothing may be left under the previous key once it is wiped.
+        disk.flush()?;
+        let finished = Self {
+            current: self.current,
+            previous: None,
+        };
+        finished.store(fs, disk, root_key)?;
+        *self = finished;
+        Ok(())
+    }
+}
+
+/// Lists the files sealed whole under the metadata key.
+fn sealed_files<D>(fs: &FatFs<D>) -> Result<Vec<String>, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let mut out = Vec::new();
+    for (dir, suffix) in [(META_DIR, ""), (UPLOAD_DIR, ".state")] {
+        let dir_handle = match fs.root_dir().open_dir(dir) {
+            Ok(dir) => dir,
+            Err(fatfs::Error::NotFound) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in dir_handle.iter() {
+            let entry = entry?;
+            let path = format!("{}/{}", dir, entry.file_name());
+            // skips . and .., and the files that aren't sealed whole.
+            if entry.is_file()
+                && path.ends_with(suffix)
+                && path != META_KEY_PATH
+                && path != BALLAST_PATH
+                && !RECORD_LOGS.iter().any(|(log, _)| path == *log)
+            {
+                out.push(path);
+            }
+        }
+    }
+    Ok(out)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the key metadata files are currently sealed with. Only
+    /// changes under the filesystem lock.
+    pub(crate) fn meta_key(&self) -> [u8; 32] {
+        self.meta_keys.lock().unwrap().current
+    }
+
+    /// Reseals the metadata under a fresh key and wipes the old one.
+    /// The fresh key is written next to the old one before anything is
+    /// sealed with it, so that a crash part way leaves every file
+    /// readable under one of the two.
+    pub(crate) fn rotate_meta_key(&self, fs: &FatFs<D>) -> Result<(), Error> {
+        let disk = self.fs.disk();
+        let mut keys = self.meta_keys.lock().unwrap();
+        // a rotation that failed part way has to finish first, or the
+        // files it didn't get to would be left under a lost key.
+        keys.finish_rotation(fs, disk, self.root_key)?;
+        let rotating = MetaKeys {
+            current: rand::random(),
+            previous: Some(keys.current),
+        };
+        rotating.store(fs, disk, self.root_key)?;
+        *keys = rotating;
+        keys.finish_rotation(fs, disk, self.root_key)
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    fs::{Disk, FatFs, FileSystem, FsConfig},
+    object_store::lock_poisoned,
+    ObjectStore,
+};
+use fatfs::{IoBase, Read as _, Write as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind},
+    sync::Arc,
+};
+
+const OWNER_PATH: &str = "owner";
+const OWNER_MAGIC: [u8; 8] = *b"TWZMETA1";
+
+/// What a store keeps on a separate metadata disk. Chosen when the
+/// store is formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MetadataPlacement {
+    /// Everything is on the data disk.
+    #[default]
+    DataDisk,
+    /// The WAL is on the metadata disk, so the appends every key
+    /// derivation makes don't contend with data I/O.
+    Wal,
+    /// The WAL and the KHF are on the metadata disk.
+    WalAndKhf,
+}
+
+impl MetadataPlacement {
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            MetadataPlacement::DataDisk => 0,
+            MetadataPlacement::Wal => 1,
+            MetadataPlacement::WalAndKhf => 2,
+        }
+    }
+
+    pub(crate) fn from_byte(b: u8) -> Result<Self, Error> {
+        match b {
+            0 => Ok(MetadataPlacement::DataDisk),
+            1 => Ok(MetadataPlacement::Wal),
+            2 => Ok(MetadataPlacement::WalAndKhf),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "unknown metadata placement",
+            )),
+        }
+    }
+
+    /// Returns the volume the WAL is on, `None` meaning the data disk.
+    pub(crate) fn wal_volume<D: Disk>(
+        self,
+        metadata: Option<&FileSystem<D>>,
+    ) -> Option<&FileSystem<D>> {
+        metadata.filter(|_| self != MetadataPlacement::DataDisk)
+    }
+
+    /// Returns the volume the KHF is on, `None` meaning the data disk.
+    pub(crate) fn khf_volume<D: Disk>(
+        self,
+        metadata: Option<&FileSystem<D>>,
+    ) -> Option<&FileSystem<D>> {
+        metadata.filter(|_| self == MetadataPlacement::WalAndKhf)
+    }
+}
+
+/// Options used when opening or formatting a store.
+pub struct OpenOptions<D> {
+    pub(crate) config: FsConfig,
+    pub(crate) metadata_disk: Option<D>,
+    pub(crate) khf_on_metadata_disk: bool,
+}
+
+impl<D> Default for OpenOptions<D> {
+    fn default() -> Self {
+        Self {
+            config: FsConfig::default(),
+            metadata_disk: None,
+            khf_on_metadata_disk: false,
+        }
+    }
+}
+
+impl<D> OpenOptions<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The parameters the FAT volumes are opened with.
+    pub fn config(mut self, config: FsConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Keeps the WAL on `disk` instead of the data disk, so that a
+    /// small fast device can take the appends every key derivation
+    /// makes. A store formatted with a metadata disk can only be
+    /// opened with it, and one formatted without can't be given one.
+    pub fn metadata_disk(mut self, disk: D) -> Self {
+        self.metadata_disk = Some(disk);
+        self
+    }
+
+    /// Keeps the KHF on the metadata disk too. Only used when
+    /// formatting; opening follows what the store was formatted with.
+    pub fn khf_on_metadata_disk(mut self, khf_on_metadata_disk: bool) -> Self {
+        self.khf_on_metadata_disk = khf_on_metadata_disk;
+        self
+    }
+
+    pub(crate) fn placement(&self) -> MetadataPlacement {
+        match (&self.metadata_disk, self.khf_on_metadata_disk) {
+            (None, _) => MetadataPlacement::DataDisk,
+            (Some(_), false) => MetadataPlacement::Wal,
+            (Some(_), true) => MetadataPlacement::WalAndKhf,
+        }
+    }
+}
+
+/// Marks the metadata volume as belonging to the store `uuid`.
+pub(crate) fn stamp_owner<D>(fs: &FatFs<D>, uuid: u128) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let mut buf = [0u8; 24];
+    buf[..8].copy_from_slice(&OWNER_MAGIC);
+    buf[8..].copy_from_slice(&uuid.to_le_bytes());
+    let mut file = fs.root_dir().create_file(OWNER_PATH)?;
+    file.truncate()?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// Formats `disk` as the metadata disk of the store `uuid`.
+pub(crate) fn format_metadata_disk<D>(
+    disk: D,
+    config: FsConfig,
+    uuid: u128,
+) -> Result<FileSystem<D>, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let disk = Arc::new(disk);
+    FileSystem::format(&disk, config)?;
+    let fs = FileSystem::open_fs(disk, config)?;
+    stamp_owner(&*fs.fs().lock().map_err(lock_poisoned)?, uuid)?;
+    Ok(fs)
+}
+
+/// Fails unless `metadata` is the metadata disk the store `uuid` was
+/// formatted with, or both are absent.
+pub(crate) fn check_metadata_disk<D>(
+    placement: MetadataPlacement,
+    metadata: Option<&FileSystem<D>>,
+    uuid: u128,
+) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let metadata = match (placement, metadata) {
+        (MetadataPlacement::DataDisk, None) => return Ok(()),
+        (MetadataPlacement::DataDisk, Some(_)) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "store was formatted without a metadata disk",
+            ))
+        }
+        (_, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "store keeps its WAL on a metadata disk, which has to be opened with it",
+            ))
+        }
+        (_, Some(metadata)) => metadata,
+    };
+    let fs = metadata.fs().lock().map_err(lock_poisoned)?;
+    let mut buf = [0u8; 24];
+    let owner = match fs.root_dir().open_file(OWNER_PATH) {
+        Ok(mut file) => file.read_exact(&mut buf).ok().map(|()| buf),
+        Err(fatfs::Error::NotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    match owner {
+        Some(buf) if buf[..8] == OWNER_MAGIC => {
+            if u128::from_le_bytes(buf[8..].try_into().unwrap()) != uuid {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "metadata disk belongs to another store",
+                ));
+            }
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "metadata disk doesn't hold store metadata",
+        )),
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns what the store keeps on its metadata disk.
+    pub fn metadata_placement(&self) -> MetadataPlacement {
+        self.metadata_placement
+    }
+
+    /// Returns the volume the WAL is on.
+    pub(crate) fn wal_volume(&self) -> &FileSystem<D> {
+        self.metadata_placement
+            .wal_volume(self.metadata_fs.as_ref())
+            .unwrap_or(&self.fs)
+    }
+}
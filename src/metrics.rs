@@ -0,0 +1,196 @@
+use crate::{
+    fs::Disk,
+    latency::{Latencies, LatencyOp, LatencySummary},
+    ObjectStore,
+};
+use fatfs::IoBase;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Counters kept for the lifetime of the process. They are not reset
+/// by `reopen` or `reformat`.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub reads: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub writes: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub objects_created: AtomicU64,
+    pub objects_unlinked: AtomicU64,
+    pub key_derivations: AtomicU64,
+    pub key_cache_hits: AtomicU64,
+    pub epochs: AtomicU64,
+    /// Chunks the running epoch has to re-encrypt, 0 between epochs.
+    pub epoch_chunks_total: AtomicU64,
+    pub epoch_chunks_done: AtomicU64,
+    /// Bytes of key management metadata when it was last measured.
+    pub metadata_bytes: AtomicU64,
+    pub latencies: Latencies,
+}
+
+/// A point in time copy of the store's counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub reads: u64,
+    pub bytes_read: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub objects_created: u64,
+    pub objects_unlinked: u64,
+    pub key_derivations: u64,
+    pub key_cache_hits: u64,
+    pub epochs: u64,
+    pub generation: u64,
+    pub pending_key_deletions: u64,
+    pub epoch_chunks_total: u64,
+    pub epoch_chunks_done: u64,
+    pub metadata_bytes: u64,
+    /// Indexed by `LatencyOp`; see `latency`.
+    pub latencies: [LatencySummary; LatencyOp::ALL.len()],
+}
+
+/// Receives metrics one at a time, so they can be forwarded to any
+/// monitoring system.
+pub trait MetricsSink {
+    /// A value that only ever goes up.
+    fn counter(&mut self, name: &str, help: &str, value: u64);
+    /// A value that can go up and down.
+    fn gauge(&mut self, name: &str, help: &str, value: u64);
+    /// Percentiles of a latency. By default fed to `counter` and
+    /// `gauge`, in microseconds.
+    fn latency(&mut self, name: &str, help: &str, summary: &LatencySummary) {
+        self.counter(&format!("{name}_count"), help, summary.count);
+        for (percentile, value) in [
+            ("p50", summary.p50),
+            ("p90", summary.p90),
+            ("p99", summary.p99),
+        ] {
+            let micros = value.as_micros() as u64;
+            self.gauge(&format!("{name}_{percentile}_us"), help, micros);
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    pub fn latency(&self, op: LatencyOp) -> LatencySummary {
+        self.latencies[op as usize]
+    }
+
+    /// Feeds every metric to `sink`.
+    pub fn export(&self, sink: &mut impl MetricsSink) {
+        sink.counter("reads_total", "Reads served.", self.reads);
+        sink.counter("read_bytes_total", "Bytes read.", self.bytes_read);
+        sink.counter("writes_total", "Writes applied.", self.writes);
+        sink.counter("written_bytes_total", "Bytes written.", self.bytes_written);
+        sink.counter(
+            "objects_created_total",
+            "Objects created.",
+            self.objects_created,
+        );
+        sink.counter(
+            "objects_unlinked_total",
+            "Objects unlinked.",
+            self.objects_unlinked,
+        );
+        sink.counter(
+            "key_derivations_total",
+            "Chunk keys derived by the key manager.",
+            self.key_derivations,
+        );
+        sink.counter(
+            "key_cache_hits_total",
+            "Chunk keys served from the key cache.",
+            self.key_cache_hits,
+        );
+        sink.counter("epochs_total", "Epochs completed.", self.epochs);
+        sink.gauge("generation", "Generation of the store.", self.generation);
+        sink.gauge(
+            "pending_key_deletions",
+            "Chunk keys awaiting the next epoch.",
+            self.pending_key_deletions,
+        );
+        sink.gauge(
+            "epoch_chunks_total",
+            "Chunks the running epoch re-encrypts.",
+            self.epoch_chunks_total,
+        );
+        sink.gauge(
+            "epoch_chunks_done",
+            "Chunks the running epoch has re-encrypted.",
+            self.epoch_chunks_done,
+        );
+        sink.gauge(
+            "metadata_bytes",
+            "Bytes of key management metadata when last measured.",
+            self.metadata_bytes,
+        );
+        for op in LatencyOp::ALL {
+            sink.latency(
+                &format!("{}_latency", op.name()),
+                &format!("Latency of {} operations.", op.name()),
+                &self.latency(op),
+            );
+        }
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn metrics(&self) -> MetricsSnapshot {
+        let c = &self.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            reads: load(&c.reads),
+            bytes_read: load(&c.bytes_read),
+            writes: load(&c.writes),
+            bytes_written: load(&c.bytes_written),
+            objects_created: load(&c.objects_created),
+            objects_unlinked: load(&c.objects_unlinked),
+            key_derivations: load(&c.key_derivations),
+            key_cache_hits: load(&c.key_cache_hits),
+            epochs: load(&c.epochs),
+            generation: load(&self.generation),
+            pending_key_deletions: self.pending_key_deletions(),
+            epoch_chunks_total: load(&c.epoch_chunks_total),
+            epoch_chunks_done: load(&c.epoch_chunks_done),
+            metadata_bytes: load(&c.metadata_bytes),
+            latencies: LatencyOp::ALL.map(|op| c.latencies.get(op).summary()),
+        }
+    }
+
+    /// Records how long an operation that began at `started` took.
+    pub(crate) fn record_latency(&self, op: LatencyOp, started: Instant) {
+        self.counters.latencies.get(op).record(started.elapsed());
+    }
+
+    /// Records a read that began at `started` in the access tracker,
+    /// the counters and the latencies.
+    pub(crate) fn record_read(&self, obj_id: u128, bytes: usize, started: Instant) {
+        self.record_latency(LatencyOp::Read, started);
+        self.access.record_read(obj_id, bytes);
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_read
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a write that began at `started` in the access tracker,
+    /// the counters and the latencies.
+    pub(crate) fn record_write(&self, obj_id: u128, bytes: usize, started: Instant) {
+        self.record_latency(LatencyOp::Write, started);
+        self.access.record_write(obj_id, bytes);
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
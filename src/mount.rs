@@ -0,0 +1,134 @@
+use crate::{
+    expiry::to_secs,
+    fs::{Disk, FatFs},
+    meta::{read_meta, write_meta},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const MOUNT_PATH: &str = "meta/mount";
+/// A mount whose owner hasn't sent a heartbeat for this long is
+/// assumed to have crashed and may be taken over.
+pub const STALE_MOUNT_AFTER: Duration = Duration::from_secs(120);
+
+/// Written while a store is open so that a second process opening the
+/// same disk notices the first one.
+#[derive(Debug, Serialize, Deserialize)]
+struct MountMarker {
+    owner: u128,
+    heartbeat: u64,
+}
+
+/// Returned when the disk is already open somewhere else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlreadyMounted {
+    /// Random id of the store that holds the mount.
+    pub owner: u128,
+    pub heartbeat: SystemTime,
+}
+
+impl fmt::Display for AlreadyMounted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let age = SystemTime::now()
+            .duration_since(self.heartbeat)
+            .unwrap_or_default();
+        write!(
+            f,
+            "disk is mounted by {:032x}, last seen {}s ago",
+            self.owner,
+            age.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for AlreadyMounted {}
+
+impl From<AlreadyMounted> for Error {
+    fn from(value: AlreadyMounted) -> Self {
+        Error::new(ErrorKind::ResourceBusy, value)
+    }
+}
+
+/// Returns the mount carried by `err`, if the disk was refused because
+/// it is open somewhere else.
+pub fn already_mounted(err: &Error) -> Option<&AlreadyMounted> {
+    err.get_ref()?.downcast_ref::<AlreadyMounted>()
+}
+
+/// Claims the disk for `owner`. Fails if another owner has sent a
+/// heartbeat recently, unless `takeover` is set, in which case that
+/// owner is returned.
+pub(crate) fn claim_mount<D>(
+    fs: &FatFs<D>,
+    meta_key: &[u8; 32],
+    owner: u128,
+    takeover: bool,
+) -> Result<Option<u128>, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let now = to_secs(SystemTime::now());
+    let mut displaced = None;
+    if let Some(marker) = read_meta::<D, MountMarker>(fs, meta_key, MOUNT_PATH)? {
+        let fresh = now.saturating_sub(marker.heartbeat) < STALE_MOUNT_AFTER.as_secs();
+        if marker.owner != owner && fresh {
+            if !takeover {
+                return Err(AlreadyMounted {
+                    owner: marker.owner,
+                    heartbeat: UNIX_EPOCH + Duration::from_secs(marker.heartbeat),
+                }
+                .into());
+            }
+            displaced = Some(marker.owner);
+        }
+    }
+    write_meta(
+        fs,
+        meta_key,
+        MOUNT_PATH,
+        &MountMarker {
+            owner,
+            heartbeat: now,
+        },
+    )?;
+    Ok(displaced)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Refreshes the mount marker. Must be called more often than
+    /// `STALE_MOUNT_AFTER` to keep other processes from taking the disk
+    /// over. Fails with `AlreadyMounted` if the disk has been taken
+    /// over since, in which case this store must stop writing.
+    pub fn heartbeat(&self) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        Ok(claim_mount(&fs, &self.meta_key(), self.mount_owner, false).map(|_| ())?)
+    }
+
+    /// Removes the mount marker so the disk can be opened elsewhere
+    /// right away. Does nothing if the disk has been taken over.
+    pub fn unmount(&self) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        match read_meta::<D, MountMarker>(&fs, &self.meta_key(), MOUNT_PATH)? {
+            Some(marker) if marker.owner == self.mount_owner => {
+                fs.root_dir().remove(MOUNT_PATH)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+};
+use zeroize::Zeroizing;
+
+pub(crate) const NAMESPACES_PATH: &str = "meta/namespaces";
+const NAMESPACE_KEY_LABEL: &[u8] = b"object-store namespace key";
+pub const MAX_NAMESPACE_LEN: usize = 64;
+
+/// The salt of every namespace. A namespace's root key is derived from
+/// the store root, its salt and its label, so replacing the salt changes
+/// the root without touching any other namespace.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct NamespaceTable {
+    salts: BTreeMap<String, [u8; 32]>,
+}
+
+fn check_label(label: &str) -> Result<(), Error> {
+    if label.is_empty() || label.len() > MAX_NAMESPACE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "namespace labels must be between 1 and 64 bytes",
+        ));
+    }
+    Ok(())
+}
+
+/// Derives a namespace root from the store root with HKDF-SHA256, using
+/// the namespace's salt to extract and its label to expand.
+fn namespace_root(root_key: [u8; 32], salt: &[u8; 32], label: &str) -> Zeroizing<[u8; 32]> {
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(salt), &root_key)
+        .expand_multi_info(&[NAMESPACE_KEY_LABEL, label.as_bytes()], &mut *okm)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    okm
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn namespaces_lock(
+        &self,
+        fs: &FatFs<D>,
+    ) -> Result<MutexGuard<'_, Option<NamespaceTable>>, Error> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if namespaces.is_none() {
+            *namespaces =
+                Some(read_meta(fs, &self.meta_key(), NAMESPACES_PATH)?.unwrap_or_default());
+        }
+        Ok(namespaces)
+    }
+
+    /// Creates a namespace with a fresh root key.
+    pub fn create_namespace(&self, label: &str) -> Result<(), ObjectStoreError> {
+        check_label(label)?;
+        let fs = self.fs().lock().unwrap();
+        let mut namespaces = self.namespaces_lock(&fs)?;
+        let table = namespaces.as_mut().unwrap();
+        if table.salts.contains_key(label) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "namespace already exists").into());
+        }
+        update_meta(&fs, &self.meta_key(), NAMESPACES_PATH, table, |table| {
+            table.salts.insert(label.to_string(), rand::random());
+            true
+        })?;
+        Ok(())
+    }
+
+    /// Lists namespaces in label order.
+    pub fn namespaces(&self) -> Result<Vec<String>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let namespaces = self.namespaces_lock(&fs)?;
+        Ok(namespaces.as_ref().unwrap().salts.keys().cloned().collect())
+    }
+
+    /// Returns the root key of a namespace, or `None` if there is no
+    /// such namespace. It changes whenever the namespace is rewrapped.
+    ///
+    /// The store doesn't encrypt objects under namespace keys. They are
+    /// for callers to derive the keys of their own data from.
+    pub fn namespace_key(
+        &self,
+        label: &str,
+    ) -> Result<Option<Zeroizing<[u8; 32]>>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let namespaces = self.namespaces_lock(&fs)?;
+        Ok(namespaces
+            .as_ref()
+            .unwrap()
+            .salts
+            .get(label)
+            .map(|salt| namespace_root(self.root_key, salt, label)))
+    }
+
+    /// Replaces the root key of one namespace, leaving every other
+    /// namespace alone. Nothing in the store is re-encrypted, so data a
+    /// caller encrypted under the old root has to be moved over by the
+    /// caller before the old root is dropped.
+    pub fn rewrap_namespace(&self, label: &str) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let mut namespaces = self.namespaces_lock(&fs)?;
+        let table = namespaces.as_mut().unwrap();
+        if !table.salts.contains_key(label) {
+            return Err(Error::new(ErrorKind::NotFound, "no such namespace").into());
+        }
+        update_meta(&fs, &self.meta_key(), NAMESPACES_PATH, table, |table| {
+            table.salts.insert(label.to_string(), rand::random());
+            true
+        })?;
+        Ok(())
+    }
+
+    /// Forgets a namespace and its root key. Returns false if there was
+    /// no such namespace.
+    pub fn delete_namespace(&self, label: &str) -> Result<bool, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let mut namespaces = self.namespaces_lock(&fs)?;
+        Ok(update_meta(
+            &fs,
+            &self.meta_key(),
+            NAMESPACES_PATH,
+            namespaces.as_mut().unwrap(),
+            |table| table.salts.remove(label).is_some(),
+        )?)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    num::NonZeroU128,
+    ops::RangeInclusive,
+};
+
+/// Ids Twizzler keeps for objects the kernel defines itself, which are
+/// never stored.
+pub const RESERVED_OBJ_IDS: RangeInclusive<u128> = u128::MAX - 0xffff..=u128::MAX;
+
+/// A Twizzler object id, checked to be neither nil nor reserved.
+/// Converts into the `u128` the store's methods take, so that ids that
+/// came from elsewhere have to pass through `new` before they are used
+/// as object ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TwzObjId(NonZeroU128);
+
+impl TwzObjId {
+    /// # Errors
+    /// `InvalidInput` if `raw` is nil or in `RESERVED_OBJ_IDS`.
+    pub fn new(raw: u128) -> Result<Self, ObjectStoreError> {
+        let Some(id) = NonZeroU128::new(raw) else {
+            return Err(Error::new(ErrorKind::InvalidInput, "nil object id").into());
+        };
+        if RESERVED_OBJ_IDS.contains(&raw) {
+            return Err(Error::new(ErrorKind::InvalidInput, "reserved object id").into());
+        }
+        Ok(Self(id))
+    }
+
+    pub fn raw(self) -> u128 {
+        self.0.get()
+    }
+}
+
+impl TryFrom<u128> for TwzObjId {
+    type Error = Error;
+
+    fn try_from(raw: u128) -> Result<Self, Error> {
+        Ok(Self::new(raw)?)
+    }
+}
+
+impl From<TwzObjId> for u128 {
+    fn from(id: TwzObjId) -> u128 {
+        id.raw()
+    }
+}
+
+impl fmt::Display for TwzObjId {
+    /// Formats the id as 32 hex digits, as Twizzler prints them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.raw())
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Like `allocate_id`, but returns the id typed. Allocated ids are
+    /// never nil, and the allocator runs out before reaching the
+    /// reserved ids.
+    pub fn allocate_obj_id(&self) -> Result<TwzObjId, ObjectStoreError> {
+        TwzObjId::new(self.allocate_id()?)
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20,
+};
+use fatfs::IoBase;
+use std::io::{Error, ErrorKind};
+
+/// Returns the caller's cipher for an object, positioned at `off`. The
+/// nonce is taken from the object id so that a key shared between
+/// objects still gives each its own keystream.
+fn object_cipher(obj_id: u128, key: &[u8; 32], off: u64) -> Result<ChaCha20, Error> {
+    let nonce: [u8; 12] = obj_id.to_le_bytes()[..12].try_into().unwrap();
+    let mut cipher = ChaCha20::new(key.into(), &nonce.into());
+    cipher
+        .try_seek(off)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    Ok(cipher)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Encrypts `buf` with a key the caller manages before writing it,
+    /// so the store never sees the plaintext. The data is still
+    /// encrypted again under the store's own keys, which is what makes
+    /// unlinked objects securely deleted.
+    ///
+    /// Like the store's own encryption, rewriting a range reuses its
+    /// keystream, so callers should rotate the key if that matters.
+    pub fn write_all_with_key(
+        &self,
+        obj_id: u128,
+        key: &[u8; 32],
+        buf: &[u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let mut ciphertext = buf.to_vec();
+        object_cipher(obj_id, key, off)?.apply_keystream(&mut ciphertext);
+        self.write_all(obj_id, &ciphertext, off)
+    }
+
+    /// Reads data written by `write_all_with_key` and decrypts it with
+    /// the caller's key. A wrong key yields garbage rather than an
+    /// error.
+    pub fn read_exact_with_key(
+        &self,
+        obj_id: u128,
+        key: &[u8; 32],
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let mut cipher = object_cipher(obj_id, key, off)?;
+        self.read_exact(obj_id, buf, off)?;
+        cipher.apply_keystream(buf);
+        Ok(())
+    }
+}
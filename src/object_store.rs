@@ -1,15 +1,56 @@
 use crate::{
-    fs::{Disk, FileSystem, PAGE_SIZE},
+    access::{AccessTracker, ObjectAccess, DEFAULT_HALF_LIFE},
+    allocator::IdAllocator,
+    append_log::LogTable,
+    audit::KeyAudit,
+    blind::{BlindIndex, ID_KEY_LABEL},
+    cache::{ExtentCache, KeyCache},
+    chunk_generation::{ChunkGenerations, CHUNK_GENERATIONS_PATH},
+    cipher_stream::CipherStream,
+    context::{ErrorContext, Phase, ResultExt},
+    dedup::DedupIndex,
+    eof::check_in_bounds,
+    epoch::{epoch_incomplete, PendingEpoch},
+    error::kms_error,
+    events::{EventLog, KhfRecovery, StoreEvent},
+    expiry::ExpiryIndex,
+    fault::{KmsFaults, KmsOp},
+    flags::{FlagTable, ObjectFlags},
+    freeze::FreezableDisk,
+    fs::{
+        Disk, DiskCursor, DiskLimits, FatDir, FatFile, FatFlavor, FatFs, FileSystem, FsConfig,
+        PAGE_SIZE,
+    },
+    handle::Incarnations,
+    header::{header_fits, RawHeader},
+    holes::HoleTable,
+    identity::{check_media, MediaIdentity},
+    index::SecondaryIndex,
+    latency::LatencyOp,
+    layout::Layout,
+    mac::{page_range, IntegrityHash, MacTable},
+    manifest::IdManifest,
+    meta::{derive_subkey, read_meta, update_meta},
+    meta_key::MetaKeys,
+    metadata_disk::{
+        check_metadata_disk, format_metadata_disk, stamp_owner, MetadataPlacement, OpenOptions,
+    },
+    metrics::Counters,
+    mount::claim_mount,
+    namespace::NamespaceTable,
+    seal::SealTable,
+    space::check_space,
+    superblock::{FormatOptions, KeyMode, Superblock},
+    tags::{TagIndex, TAGS_PATH},
+    trace::{debug_event, op_span},
+    trash::TrashIndex,
+    version::{VersionConflict, VersionTable},
+    wal_log::{wal_len, WalJournal, WalOp, WAL_PATH},
     wrapped_extent::WrappedExtent,
+    ObjectStoreError,
 };
-use chacha20::{
-    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
-    ChaCha20,
-};
-use fatfs::{
-    DefaultTimeProvider, Dir, IoBase, LossyOemCpConverter, NullTimeProvider, Read as _,
-    ReadWriteProxy, Seek, SeekFrom, Write as _,
-};
+use chacha20::{cipher::StreamCipher, ChaCha20};
+use fatfs::{IoBase, Read as _, ReadWriteProxy, Seek, SeekFrom, Write as _};
 use obliviate_core::{
     consts::SECTOR_SIZE,
     crypter::{aes::Aes256Ctr, ivs::SequentialIvg},
@@ -22,32 +63,148 @@ use obliviate_core::{
 use rand::rngs::OsRng;
 use std::{
     collections::HashSet,
-    io::Error,
-    sync::{Arc, Mutex, MutexGuard},
+    io::{Error, ErrorKind},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+    time::{Duration, Instant},
 };
+use zeroize::Zeroizing;
 
-type EncodedObjectId = String;
+pub(crate) type EncodedObjectId = String;
 
-fn encode_obj_id(obj_id: u128) -> EncodedObjectId {
-    format!("{:0>32x}", obj_id)
+/// How `create_with` treats an object that already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CreateMode {
+    Open,
+    Exclusive,
+    Truncate,
+}
+
+/// What `try_create_object` found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CreateOutcome {
+    Created,
+    AlreadyExists,
 }
 pub type MyKhf = Khf<OsRng, SequentialIvg, Aes256Ctr, Sha3_256, SHA3_256_MD_SIZE>;
 pub struct ObjectStore<D: Disk> {
-    fs: FileSystem<D>,
+    pub(crate) fs: FileSystem<D>,
+    /// The volume on the metadata disk, if the store has one.
+    pub(crate) metadata_fs: Option<FileSystem<D>>,
+    pub(crate) metadata_placement: MetadataPlacement,
     kms: Kms<D>,
-    root_key: [u8; 32],
+    pub(crate) root_key: [u8; 32],
+    /// Keys used to encrypt metadata files such as the tag index.
+    pub(crate) meta_keys: Mutex<MetaKeys>,
+    pub(crate) access: AccessTracker,
+    pub(crate) keys: KeyCache,
+    /// How often each chunk has been freed, folded into its key in
+    /// volume key mode.
+    pub(crate) chunk_generations: Mutex<ChunkGenerations>,
+    pub(crate) extents: ExtentCache,
+    pub(crate) tags: Mutex<TagIndex>,
+    /// Loaded on first use.
+    pub(crate) index: Mutex<Option<SecondaryIndex>>,
+    /// Loaded on first use.
+    pub(crate) expiry: Mutex<Option<ExpiryIndex>>,
+    /// Chunk keys deleted since the last epoch.
+    pub(crate) pending_deletions: AtomicU64,
+    /// Loaded on first use.
+    pub(crate) trash: Mutex<Option<TrashIndex>>,
+    /// How long unlinked objects stay in the trash. Objects are
+    /// unlinked immediately when unset.
+    pub(crate) trash_retention: Mutex<Option<Duration>>,
+    /// Loaded on first use.
+    pub(crate) flags: Mutex<Option<FlagTable>>,
+    /// Loaded on first use.
+    pub(crate) seals: Mutex<Option<SealTable>>,
+    /// Loaded on first use.
+    pub(crate) dedup: Mutex<Option<DedupIndex>>,
+    pub(crate) versions: Mutex<VersionTable>,
+    /// Loaded on first use.
+    pub(crate) macs: Mutex<Option<MacTable>>,
+    pub(crate) incarnations: Mutex<Incarnations>,
+    pub(crate) uuid: u128,
+    pub(crate) generation: AtomicU64,
+    pub(crate) integrity_hash: IntegrityHash,
+    /// Random id written to the mount marker by this instance.
+    pub(crate) mount_owner: u128,
+    pub(crate) counters: Counters,
+    pub(crate) events: EventLog,
+    /// Set when file names are blinded.
+    pub(crate) id_key: Option<[u8; 32]>,
+    /// Loaded on first use.
+    pub(crate) blinded: Mutex<Option<BlindIndex>>,
+    /// Set while an epoch that failed part way is waiting to be retried.
+    pub(crate) pending_epoch: Mutex<Option<PendingEpoch>>,
+    pub(crate) layout: Layout,
+    /// Loaded on first use.
+    pub(crate) namespaces: Mutex<Option<NamespaceTable>>,
+    /// Loaded on first use.
+    pub(crate) manifest: Mutex<Option<IdManifest>>,
+    /// Loaded on first use.
+    pub(crate) allocator: Mutex<Option<IdAllocator>>,
+    /// Loaded on first use.
+    pub(crate) holes: Mutex<Option<HoleTable>>,
+    /// Loaded on first use.
+    pub(crate) logs: Mutex<Option<LogTable>>,
+    /// Set while a key audit is running.
+    pub(crate) audit: Mutex<Option<KeyAudit>>,
+    /// Bytes per second the last epoch re-encrypted at, or 0 before one
+    /// has run.
+    pub(crate) reencrypt_rate: AtomicU64,
+    /// Epochs are refused past this many bytes of key metadata when set.
+    pub(crate) metadata_quota: Mutex<Option<u64>>,
+}
+
+/// Checks made by `from_fs` before anything on the disk is touched.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct OpenChecks {
+    expected: Option<MediaIdentity>,
+    takeover: bool,
 }
 
 type MyWal<D> = SecureWAL<
-    D,
+    DiskCursor<D>,
     <MyKhf as KeyManagementScheme>::LogEntry,
     SequentialIvg,
     Aes256Ctr,
     SHA3_256_MD_SIZE,
 >;
-struct Kms<D: Disk> {
+/// The KHF and its WAL. Loading a large key forest is slow, so this is
+/// only read from disk the first time the store is used.
+struct KhfState<D: Disk> {
     wal: Mutex<MyWal<D>>,
     khf: Mutex<MyKhf>,
+    journal: Mutex<WalJournal>,
+    /// Chunks whose keys this process has logged a derivation of. Their
+    /// keys can be read back without touching the WAL.
+    derived: Mutex<HashSet<u64>>,
+}
+
+enum Kms<D: Disk> {
+    Khf {
+        fs: Arc<Mutex<FatFs<D>>>,
+        /// The volume the WAL is on, which may not be the KHF's.
+        wal_fs: Arc<Mutex<FatFs<D>>>,
+        root_key: [u8; 32],
+        /// Holds the error message if loading failed.
+        state: OnceLock<Result<KhfState<D>, String>>,
+        faults: KmsFaults,
+    },
+    Volume {
+        key: [u8; 32],
+    },
+    Plaintext,
+}
+
+/// Derives the single key used for all data when the store is in
+/// `KeyMode::Volume`.
+fn volume_key(root_key: [u8; 32]) -> [u8; 32] {
+    derive_subkey(root_key, b"object-store volume key")
 }
 
 impl<D> Kms<D>
@@ -55,51 +212,286 @@ where
     D: Disk,
     std::io::Error: From<fatfs::Error<D::Error>>,
 {
-    fn open_khf(
-        fs: Arc<Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>>>,
-        root_key: [u8; 32],
-    ) -> MyKhf {
-        let khf = MyKhf::load(root_key, "lethe/khf", &fs.lock().unwrap())
-            .unwrap_or_else(|_e| MyKhf::new());
-        khf
+    fn open_khf(fs: &Mutex<FatFs<D>>, root_key: [u8; 32]) -> Result<MyKhf, Error> {
+        let fs = fs.lock().map_err(lock_poisoned)?;
+        // a missing khf just means that no epoch has happened yet. One
+        // that can't be read or decrypted holds every chunk key, so it
+        // must never be replaced by an empty one.
+        match fs.root_dir().open_file("lethe/khf") {
+            Err(fatfs::Error::NotFound) => return Ok(MyKhf::new()),
+            v => drop(v?),
+        }
+        MyKhf::load(root_key, "lethe/khf", &fs).map_err(kms_error)
     }
 
-    fn open_wal(
-        fs: Arc<Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>>>,
-        root_key: [u8; 32],
-    ) -> SecureWAL<
-        D,
-        <MyKhf as KeyManagementScheme>::LogEntry,
-        SequentialIvg,
-        Aes256Ctr,
-        SHA3_256_MD_SIZE,
-    > {
-        fs.lock().unwrap().root_dir().create_dir("lethe").unwrap();
-        SecureWAL::open("lethe/wal".to_string(), root_key, fs.clone()).unwrap()
+    fn open_wal(fs: &Arc<Mutex<FatFs<D>>>, root_key: [u8; 32]) -> Result<MyWal<D>, Error> {
+        fs.lock()
+            .map_err(lock_poisoned)?
+            .root_dir()
+            .create_dir("lethe")?;
+        SecureWAL::open(WAL_PATH.to_string(), root_key, fs.clone()).map_err(kms_error)
+    }
+
+    fn open_journal(fs: &Mutex<FatFs<D>>) -> Result<WalJournal, Error> {
+        Ok(WalJournal::new(wal_len(
+            &*fs.lock().map_err(lock_poisoned)?,
+        )?))
     }
+
+    /// Opens the keys of a store on `fs`, with the WAL and KHF on
+    /// `metadata` where `placement` says so.
     pub fn open(
-        fs: Arc<Mutex<fatfs::FileSystem<D, NullTimeProvider, LossyOemCpConverter>>>,
+        fs: &FileSystem<D>,
+        metadata: Option<&FileSystem<D>>,
+        placement: MetadataPlacement,
         root_key: [u8; 32],
+        key_mode: KeyMode,
     ) -> Self {
-        Self {
-            khf: Mutex::new(Self::open_khf(fs.clone(), root_key)),
-            wal: Mutex::new(Self::open_wal(fs, root_key)),
+        match key_mode {
+            KeyMode::Khf => Self::Khf {
+                fs: placement.khf_volume(metadata).unwrap_or(fs).fs_as_owned(),
+                wal_fs: placement.wal_volume(metadata).unwrap_or(fs).fs_as_owned(),
+                root_key,
+                state: OnceLock::new(),
+                faults: KmsFaults::default(),
+            },
+            KeyMode::Volume => Self::Volume {
+                key: volume_key(root_key),
+            },
+            KeyMode::Plaintext => Self::Plaintext,
+        }
+    }
+
+    /// Loads the KHF and WAL if they haven't been yet. Loading locks the
+    /// filesystem, so this must be called before the filesystem lock is
+    /// taken. Failures are kept and returned by every later key
+    /// operation.
+    pub fn load(&self) {
+        let _ = self.khf_state();
+    }
+
+    fn khf_state(&self) -> Result<Option<&KhfState<D>>, Error> {
+        let Kms::Khf {
+            fs,
+            wal_fs,
+            root_key,
+            state,
+            ..
+        } = self
+        else {
+            return Ok(None);
+        };
+        state
+            .get_or_init(|| {
+                Ok(KhfState {
+                    khf: Mutex::new(Self::open_khf(fs, *root_key).map_err(|e| e.to_string())?),
+                    wal: Mutex::new(Self::open_wal(wal_fs, *root_key).map_err(|e| e.to_string())?),
+                    journal: Mutex::new(Self::open_journal(wal_fs).map_err(|e| e.to_string())?),
+                    derived: Mutex::new(HashSet::new()),
+                })
+            })
+            .as_ref()
+            .map(Some)
+            .map_err(|e| kms_error(e.clone()))
+    }
+
+    /// Fails if a fault is due for `op`. Faults are only injected into
+    /// a KHF.
+    fn inject(&self, op: KmsOp) -> Result<(), Error> {
+        match self {
+            Kms::Khf { faults, .. } => faults.check(op),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn faults(&self) -> Option<&KmsFaults> {
+        match self {
+            Kms::Khf { faults, .. } => Some(faults),
+            _ => None,
+        }
+    }
+
+    pub fn key_mode(&self) -> KeyMode {
+        match self {
+            Kms::Khf { .. } => KeyMode::Khf,
+            Kms::Volume { .. } => KeyMode::Volume,
+            Kms::Plaintext => KeyMode::Plaintext,
+        }
+    }
+
+    /// Returns the key of a chunk, recording the derivation in the WAL.
+    /// Returns `None` if the store is not encrypted.
+    pub fn derive(&self, chunk_id: u64) -> Result<Option<[u8; 32]>, Error> {
+        self.inject(KmsOp::Derive)?;
+        match self {
+            Kms::Khf { .. } => {
+                let KhfState {
+                    wal,
+                    khf,
+                    journal,
+                    derived,
+                } = self.khf_state()?.unwrap();
+                let key = khf
+                    .lock()
+                    .unwrap()
+                    .derive_mut(&wal.lock().unwrap(), chunk_id)
+                    .map_err(kms_error)?;
+                journal.lock().unwrap().record(chunk_id, WalOp::Derive);
+                derived.lock().unwrap().insert(chunk_id);
+                Ok(Some(key))
+            }
+            Kms::Volume { key } => Ok(Some(*key)),
+            Kms::Plaintext => Ok(None),
+        }
+    }
+
+    /// Like `derive`, but for keys that are only used to read. Chunks
+    /// whose derivation has already been logged are derived without
+    /// appending to the WAL or mutating the KHF.
+    pub fn derive_for_read(&self, chunk_id: u64) -> Result<Option<[u8; 32]>, Error> {
+        match self.khf_state()? {
+            Some(KhfState { khf, derived, .. }) if derived.lock().unwrap().contains(&chunk_id) => {
+                self.inject(KmsOp::Derive)?;
+                let key = khf.lock().unwrap().derive(chunk_id).map_err(kms_error)?;
+                Ok(Some(key))
+            }
+            _ => self.derive(chunk_id),
+        }
+    }
+
+    /// Returns true if this process has logged a derivation of the
+    /// chunk's key, or if there is no KHF to log it in.
+    pub fn logged(&self, chunk_id: u64) -> Result<bool, Error> {
+        Ok(match self.khf_state()? {
+            Some(KhfState { derived, .. }) => derived.lock().unwrap().contains(&chunk_id),
+            None => true,
+        })
+    }
+
+    /// Forgets the keys of a run of chunks at the next epoch, while only
+    /// taking the KHF and WAL locks once. obliviate has no range record,
+    /// so the WAL still gets an entry per chunk. Stops at the first
+    /// failure, returning how many keys were deleted before it.
+    pub fn delete_range(&self, ids: Range<u64>) -> (u64, Result<(), Error>) {
+        let state = match self.khf_state() {
+            Ok(state) => state,
+            Err(e) => return (0, Err(e)),
+        };
+        let Some(KhfState {
+            wal,
+            khf,
+            journal,
+            derived,
+        }) = state
+        else {
+            return (ids.end - ids.start, Ok(()));
+        };
+        let mut khf = khf.lock().unwrap();
+        let wal = wal.lock().unwrap();
+        let mut journal = journal.lock().unwrap();
+        let mut derived = derived.lock().unwrap();
+        let mut deleted = 0;
+        for id in ids {
+            let res = self
+                .inject(KmsOp::Delete)
+                .and_then(|()| khf.delete(&wal, id).map_err(kms_error));
+            if let Err(e) = res {
+                return (deleted, Err(e));
+            }
+            journal.record(id, WalOp::Delete);
+            derived.remove(&id);
+            deleted += 1;
+        }
+        (deleted, Ok(()))
+    }
+
+    /// Derives the keys of several chunks for reading, as in
+    /// `derive_for_read`, while only taking the KHF and WAL locks once.
+    pub fn derive_many(&self, chunk_ids: &[u64]) -> Result<Vec<Option<[u8; 32]>>, Error> {
+        match self {
+            Kms::Khf { .. } => {
+                let KhfState {
+                    wal,
+                    khf,
+                    journal,
+                    derived,
+                } = self.khf_state()?.unwrap();
+                let mut khf = khf.lock().unwrap();
+                let wal = wal.lock().unwrap();
+                let mut journal = journal.lock().unwrap();
+                let mut derived = derived.lock().unwrap();
+                chunk_ids
+                    .iter()
+                    .map(|id| {
+                        self.inject(KmsOp::Derive)?;
+                        if derived.contains(id) {
+                            return Ok(Some(khf.derive(*id).map_err(kms_error)?));
+                        }
+                        let key = khf.derive_mut(&wal, *id).map_err(kms_error)?;
+                        journal.record(*id, WalOp::Derive);
+                        derived.insert(*id);
+                        Ok(Some(key))
+                    })
+                    .collect()
+            }
+            Kms::Volume { key } => Ok(vec![Some(*key); chunk_ids.len()]),
+            Kms::Plaintext => Ok(vec![None; chunk_ids.len()]),
+        }
+    }
+
+    /// Rotates keys, returning the previous key of every chunk that
+    /// needs to be re-encrypted.
+    pub fn update(&self) -> Result<Vec<(u64, [u8; 32])>, Error> {
+        match self.khf_state()? {
+            Some(KhfState { wal, khf, .. }) => khf
+                .lock()
+                .unwrap()
+                .update(&wal.lock().unwrap())
+                .map_err(kms_error),
+            None => Ok(Vec::new()),
         }
     }
 
-    pub fn khf_lock(&self) -> MutexGuard<'_, MyKhf> {
-        self.khf.lock().unwrap()
+    /// Returns a copy of the KHF to persist, so that the KHF lock
+    /// isn't held while the copy is serialized.
+    pub fn shadow(&self) -> Result<Option<MyKhf>, Error> {
+        self.inject(KmsOp::Persist)?;
+        Ok(self
+            .khf_state()?
+            .map(|KhfState { khf, .. }| khf.lock().unwrap().clone()))
     }
 
-    pub fn wal_lock(&self) -> MutexGuard<'_, MyWal<D>> {
-        self.wal.lock().unwrap()
+    pub fn clear_wal(&self) -> Result<(), Error> {
+        match self.khf_state()? {
+            Some(KhfState { wal, journal, .. }) => {
+                wal.lock().unwrap().clear().map_err(kms_error)?;
+                journal.lock().unwrap().clear();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn journal<T>(&self, f: impl FnOnce(&WalJournal) -> T) -> Result<Option<T>, Error> {
+        Ok(self
+            .khf_state()?
+            .map(|state| f(&state.journal.lock().unwrap())))
     }
 }
 
-fn get_dir_path<'a, D>(
-    fs: &'a mut fatfs::FileSystem<D, DefaultTimeProvider, LossyOemCpConverter>,
+/// Derives the metadata key of stores that predate `meta/key`, and of
+/// volumes being adopted.
+pub(crate) const META_KEY_LABEL: &[u8] = b"object-store metadata key";
+
+pub(crate) fn lock_poisoned<T>(_: PoisonError<T>) -> Error {
+    Error::other("lock poisoned")
+}
+
+pub(crate) fn get_dir_path<'a, D>(
+    fs: &'a FatFs<D>,
     encoded_obj_id: &EncodedObjectId,
-) -> Result<Dir<'a, D, DefaultTimeProvider, LossyOemCpConverter>, Error>
+) -> Result<FatDir<'a, D>, Error>
 where
     D: Disk,
     std::io::Error: From<fatfs::Error<D::Error>>,
@@ -125,46 +517,216 @@ where
     /// # Safety
     /// Might not securely delete what used to be on the disk.
     ///
-    /// # Panics
+    /// # Errors
     /// When there is a Disk error or when a lock is not
     /// able to be claimed
-    pub fn reformat(&mut self, mut disk: D, root_key: Option<[u8; 32]>) {
-        FileSystem::format(&mut disk);
+    pub fn reformat(
+        &mut self,
+        disk: D,
+        root_key: Option<[u8; 32]>,
+    ) -> Result<(), ObjectStoreError> {
+        let options = self.format_options();
+        let mut superblock = options.superblock();
+        superblock.metadata = self.metadata_placement;
         self.root_key = root_key.unwrap_or(self.root_key);
-        self.fs = FileSystem::open_fs(disk);
-        self.kms = Kms::open(self.fs.fs_as_owned(), self.root_key);
+        if let Some(metadata) = &mut self.metadata_fs {
+            metadata.reformat()?;
+            stamp_owner(
+                &*metadata.fs().lock().map_err(lock_poisoned)?,
+                superblock.uuid,
+            )?;
+        }
+        self.fs = Self::format_fs(disk, &superblock, &options, self.root_key)?;
+        {
+            let fs = self.fs.fs().lock().map_err(lock_poisoned)?;
+            let meta_keys = MetaKeys::load(&fs, self.root_key)?;
+            claim_mount(&fs, &meta_keys.current, self.mount_owner, true)?;
+            self.meta_keys = Mutex::new(meta_keys);
+        }
+        self.kms = Kms::open(
+            &self.fs,
+            self.metadata_fs.as_ref(),
+            self.metadata_placement,
+            self.root_key,
+            options.key_mode,
+        );
+        self.access = AccessTracker::new(DEFAULT_HALF_LIFE);
+        self.keys.clear();
+        self.chunk_generations = Mutex::new(ChunkGenerations::default());
+        self.extents.clear();
+        self.tags = Mutex::new(TagIndex::default());
+        self.index = Mutex::new(None);
+        self.expiry = Mutex::new(None);
+        self.pending_deletions = AtomicU64::new(0);
+        self.trash = Mutex::new(None);
+        self.flags = Mutex::new(None);
+        self.seals = Mutex::new(None);
+        self.dedup = Mutex::new(None);
+        self.versions = Mutex::new(VersionTable::fresh());
+        self.macs = Mutex::new(None);
+        self.incarnations = Mutex::new(Incarnations::default());
+        self.pending_epoch = Mutex::new(None);
+        self.namespaces = Mutex::new(None);
+        self.manifest = Mutex::new(None);
+        self.allocator = Mutex::new(None);
+        self.holes = Mutex::new(None);
+        self.logs = Mutex::new(None);
+        self.layout = Layout::load(self.fs.disk())?;
+        self.id_key = superblock
+            .blind_ids
+            .then(|| derive_subkey(self.root_key, ID_KEY_LABEL));
+        self.blinded = Mutex::new(None);
+        self.uuid = superblock.uuid;
+        self.generation = AtomicU64::new(superblock.generation);
+        self.integrity_hash = superblock.integrity_hash;
+        self.events.emit(StoreEvent::Formatted {
+            uuid: superblock.uuid,
+            key_mode: superblock.key_mode,
+        });
+        Ok(())
     }
     /// Reopens Object Store from disk.
-    /// Useful for testing persistance/recovery
-    pub fn reopen(&mut self) {
-        self.fs.reopen();
-        Self::restore_khf(&self.fs().lock().unwrap());
-        self.kms = Kms::open(self.fs.fs_as_owned(), self.root_key);
+    /// Useful for testing persistance/recovery. Outside the crate, go
+    /// through `SharedStore::reopen`.
+    ///
+    /// Fails with a `MediaMismatch` if the disk now holds a different
+    /// store or an older image of this one.
+    pub(crate) fn reopen(&mut self) -> Result<(), ObjectStoreError> {
+        let key_mode = self.key_mode();
+        self.fs.reopen()?;
+        if let Some(metadata) = &mut self.metadata_fs {
+            metadata.reopen()?;
+        }
+        let superblock =
+            Superblock::load(&*self.fs.fs().lock().map_err(lock_poisoned)?, self.root_key)?
+                .unwrap_or_default();
+        check_media(self.media_identity(), superblock.identity())?;
+        {
+            let fs = self.fs.fs().lock().map_err(lock_poisoned)?;
+            let mut meta_keys = MetaKeys::load(&fs, self.root_key)?;
+            meta_keys.finish_rotation(&fs, self.fs.disk(), self.root_key)?;
+            claim_mount(&fs, &meta_keys.current, self.mount_owner, false)?;
+            self.meta_keys = Mutex::new(meta_keys);
+        }
+        self.generation = AtomicU64::new(superblock.generation);
+        self.events.emit(StoreEvent::Opened {
+            uuid: superblock.uuid,
+            generation: superblock.generation,
+            clean: superblock.clean,
+        });
+        if key_mode == KeyMode::Khf && !superblock.clean {
+            let volume = self
+                .metadata_placement
+                .khf_volume(self.metadata_fs.as_ref())
+                .unwrap_or(&self.fs);
+            let fs = volume.fs().lock().map_err(lock_poisoned)?;
+            if let Some(recovery) = Self::restore_khf(&fs, volume.disk())? {
+                self.events.emit(StoreEvent::Recovered(recovery));
+            }
+        }
+        self.kms = Kms::open(
+            &self.fs,
+            self.metadata_fs.as_ref(),
+            self.metadata_placement,
+            self.root_key,
+            key_mode,
+        );
+        self.keys.clear();
+        self.extents.clear();
+        let fs = self.fs.fs().lock().map_err(lock_poisoned)?;
+        self.tags = Mutex::new(read_meta(&fs, &self.meta_key(), TAGS_PATH)?.unwrap_or_default());
+        self.chunk_generations = Mutex::new(
+            read_meta(&fs, &self.meta_key(), CHUNK_GENERATIONS_PATH)?.unwrap_or_default(),
+        );
+        self.index = Mutex::new(None);
+        self.expiry = Mutex::new(None);
+        self.trash = Mutex::new(None);
+        self.flags = Mutex::new(None);
+        self.seals = Mutex::new(None);
+        self.dedup = Mutex::new(None);
+        self.versions = Mutex::new(VersionTable::fresh());
+        self.macs = Mutex::new(None);
+        self.incarnations = Mutex::new(Incarnations::default());
+        self.pending_epoch = Mutex::new(None);
+        self.blinded = Mutex::new(None);
+        self.namespaces = Mutex::new(None);
+        self.manifest = Mutex::new(None);
+        self.allocator = Mutex::new(None);
+        self.holes = Mutex::new(None);
+        self.logs = Mutex::new(None);
+        self.layout = Layout::load(self.fs.disk())?;
+        Ok(())
     }
 
-    fn fs(&self) -> &Mutex<fatfs::FileSystem<D>> {
+    /// The options that would format a store like this one.
+    pub(crate) fn format_options(&self) -> FormatOptions {
+        FormatOptions::new()
+            .key_mode(self.key_mode())
+            .blind_ids(self.blinds_object_ids())
+            .fs_config(self.fs.config())
+            .chunk_size(self.layout.chunk_size())
+            .integrity_hash(self.integrity_hash)
+    }
+
+    /// Returns how the data in this store is keyed.
+    pub fn key_mode(&self) -> KeyMode {
+        self.kms.key_mode()
+    }
+
+    /// Returns the FAT the volume is formatted with.
+    pub fn fat_flavor(&self) -> FatFlavor {
+        self.fs.config().fat_flavor
+    }
+
+    /// Returns how many bytes of the disk the store may use.
+    pub fn capacity(&self) -> Result<u64, ObjectStoreError> {
+        Ok(self.fs.disk().size().map_err(Error::from)?)
+    }
+
+    pub fn disk_limits(&self) -> DiskLimits {
+        self.fs.disk().limits()
+    }
+
+    pub(crate) fn fs(&self) -> &Mutex<FatFs<D>> {
+        // the khf is loaded lazily and needs the filesystem lock, so it
+        // has to be loaded before the lock is handed out.
+        self.kms.load();
         self.fs.fs()
     }
-    fn wipe_old_khf_file(fs: &MutexGuard<'_, fatfs::FileSystem<D>>) {
+    fn wipe_old_khf_file(
+        fs: &MutexGuard<'_, FatFs<D>>,
+        disk: &FreezableDisk<D>,
+    ) -> Result<(), Error> {
         let old_file = fs.root_dir().open_file("old/khf");
         let mut old_file = match old_file {
-            Err(fatfs::Error::NotFound) => return,
-            v => v.unwrap(),
+            Err(fatfs::Error::NotFound) => return Ok(()),
+            v => v?,
         };
         // override old file with zeroes
         let extents_ct = old_file.extents().collect::<Vec<_>>().len();
         for _ in 0..extents_ct {
-            old_file.write(&[0u8; PAGE_SIZE]).unwrap();
+            old_file.write(&[0u8; PAGE_SIZE])?;
         }
+        // the zeroes must be on the disk before the file is let go of.
+        disk.flush()?;
         // delete old file
-        fs.root_dir().remove("old/khf").unwrap();
+        fs.root_dir().remove("old/khf")?;
+        disk.flush()?;
+        Ok(())
     }
-    fn restore_khf(fs: &MutexGuard<'_, fatfs::FileSystem<D>>) {
-        let lethe = fs.root_dir().create_dir("lethe/").unwrap();
+    /// Finishes or rolls back an interrupted epoch, returning what had
+    /// to be done. The disk is flushed after every step, so a write
+    /// caching device can't persist a later step without the ones
+    /// before it.
+    fn restore_khf(
+        fs: &MutexGuard<'_, FatFs<D>>,
+        disk: &FreezableDisk<D>,
+    ) -> Result<Option<KhfRecovery>, Error> {
+        let lethe = fs.root_dir().create_dir("lethe/")?;
         let tmp_khf = fs.root_dir().open_file("tmp/khf");
         let old_khf = fs.root_dir().open_file("old/khf");
         // Step one: save khf to old/khf if khf exists.
-        let step_one = || {
+        let step_one = || -> Result<(), Error> {
             let res = lethe.rename("khf", &fs.root_dir(), "old/khf");
             match res {
                 Err(fatfs::Error::NotFound) => {
@@ -173,20 +735,24 @@ where
                     // However if there was one we should make sure to
                     // save it.
                 }
-                r => r.unwrap(),
+                r => r?,
             };
+            disk.flush()?;
+            Ok(())
         };
         // Step two: write what's in tmp/khf to lethe/khf
         // and delete the old khf file.
-        let step_two = || {
-            fs.root_dir().rename("tmp/khf", &lethe, "khf").unwrap();
-            Self::wipe_old_khf_file(&fs);
+        let step_two = || -> Result<(), Error> {
+            fs.root_dir().rename("tmp/khf", &lethe, "khf")?;
+            disk.flush()?;
+            Self::wipe_old_khf_file(fs, disk)
         };
-        match (tmp_khf, old_khf) {
+        let recovery = match (tmp_khf, old_khf) {
             (Ok(_new), Ok(_old)) => {
                 // don't need to do step one since the prev khf is already
                 // in old/khf.
-                step_two();
+                step_two()?;
+                KhfRecovery::InstalledNew
             }
             (Err(fatfs::Error::NotFound), Ok(_old)) => {
                 // if there isn't a new khf and there isn't an existing
@@ -196,53 +762,307 @@ where
                     Err(fatfs::Error::AlreadyExists) => {
                         // just didn't get to deleting old/khf
                         // delete it now:
-                        Self::wipe_old_khf_file(&fs);
+                        Self::wipe_old_khf_file(fs, disk)?;
+                        KhfRecovery::WipedPrevious
                     }
-                    v => v.unwrap(),
-                };
+                    v => {
+                        v?;
+                        disk.flush()?;
+                        KhfRecovery::RestoredPrevious
+                    }
+                }
             }
             (Ok(_new), Err(fatfs::Error::NotFound)) => {
-                step_one();
-                step_two();
+                step_one()?;
+                step_two()?;
+                KhfRecovery::InstalledNew
             }
             (Err(fatfs::Error::NotFound), Err(fatfs::Error::NotFound)) => {
                 // how it should be after an epoch.
+                return Ok(None);
             }
-            (e, e2) => {
-                e.unwrap();
-                e2.unwrap();
-                panic!("unexpected error during restoration")
+            (Err(e), _) | (Ok(_), Err(e)) => {
+                // unexpected error during restoration
+                return Err(e.into());
             }
         };
+        Ok(Some(recovery))
+    }
+    /// Opens the object store on a disk that is already formatted.
+    /// Returns an error instead of reformatting if the disk
+    /// does not contain a valid volume.
+    pub fn open(disk: D, root_key: [u8; 32]) -> Result<Self, ObjectStoreError> {
+        Self::open_with(disk, root_key, OpenOptions::new())
+    }
+    /// Like `open`, but opens the FAT volume with `config`. The config
+    /// is kept for as long as the store is open.
+    pub fn open_with_config(
+        disk: D,
+        root_key: [u8; 32],
+        config: FsConfig,
+    ) -> Result<Self, ObjectStoreError> {
+        Self::open_with(disk, root_key, OpenOptions::new().config(config))
+    }
+    /// Like `open`, but opens the store as `options` say.
+    ///
+    /// # Errors
+    /// `InvalidInput` if a metadata disk is given to a store formatted
+    /// without one, or missing for a store formatted with one, and
+    /// `InvalidData` if the metadata disk is another store's.
+    pub fn open_with(
+        disk: D,
+        root_key: [u8; 32],
+        options: OpenOptions<D>,
+    ) -> Result<Self, ObjectStoreError> {
+        let fs = FileSystem::open_fs(Arc::new(disk), options.config)?;
+        let metadata = options
+            .metadata_disk
+            .map(|disk| FileSystem::open_fs(Arc::new(disk), options.config))
+            .transpose()?;
+        Ok(Self::from_fs(
+            fs,
+            metadata,
+            root_key,
+            OpenChecks::default(),
+        )?)
     }
     /// Will either open the disk if it is properly formatted
     /// or will reformat the disk.
     /// # Safety
-    /// If the disk gets corrupted then it might not securely delete
-    /// what used to be on the disk.
-    pub fn open(disk: D, root_key: [u8; 32]) -> Self {
-        let fs = FileSystem::open_fs(disk);
-        let fs_ref = fs.fs_as_owned();
-        Self::restore_khf(&fs.fs().lock().unwrap());
-        let out = Self {
+    /// Only a disk without a recognizable FAT volume is formatted, and
+    /// formatting might not securely delete what used to be on it. Any
+    /// other failure to open, such as a read error, is returned.
+    pub fn open_or_format(disk: D, root_key: [u8; 32]) -> Result<Self, ObjectStoreError> {
+        let (fs, formatted) = FileSystem::open_or_format(Arc::new(disk), FsConfig::default())?;
+        let store = Self::from_fs(fs, None, root_key, OpenChecks::default())?;
+        if formatted {
+            store.events.emit(StoreEvent::Formatted {
+                uuid: store.uuid,
+                key_mode: store.key_mode(),
+            });
+        }
+        Ok(store)
+    }
+    /// Formats the disk with the given options and opens the new store.
+    /// # Safety
+    /// Might not securely delete what used to be on the disk.
+    pub fn format(
+        disk: D,
+        root_key: [u8; 32],
+        options: FormatOptions,
+    ) -> Result<Self, ObjectStoreError> {
+        Self::format_with(disk, root_key, options, OpenOptions::new())
+    }
+    /// Like `format`, also formatting the metadata disk of
+    /// `open_options` if it has one.
+    /// # Safety
+    /// Might not securely delete what used to be on either disk.
+    pub fn format_with(
+        disk: D,
+        root_key: [u8; 32],
+        options: FormatOptions,
+        open_options: OpenOptions<D>,
+    ) -> Result<Self, ObjectStoreError> {
+        let mut superblock = options.superblock();
+        superblock.metadata = open_options.placement();
+        let metadata = open_options
+            .metadata_disk
+            .map(|disk| format_metadata_disk(disk, options.fs_config, superblock.uuid))
+            .transpose()?;
+        let fs = Self::format_fs(disk, &superblock, &options, root_key)?;
+        let store = Self::from_fs(fs, metadata, root_key, OpenChecks::default())?;
+        store.events.emit(StoreEvent::Formatted {
+            uuid: superblock.uuid,
+            key_mode: superblock.key_mode,
+        });
+        Ok(store)
+    }
+
+    /// Opens the store even if another process appears to have it
+    /// open. Only safe once that process is known to be gone.
+    pub fn open_takeover(disk: D, root_key: [u8; 32]) -> Result<Self, ObjectStoreError> {
+        let fs = FileSystem::open_fs(Arc::new(disk), FsConfig::default())?;
+        let checks = OpenChecks {
+            takeover: true,
+            ..Default::default()
+        };
+        Ok(Self::from_fs(fs, None, root_key, checks)?)
+    }
+
+    /// Opens the store, refusing with a `MediaMismatch` if the disk
+    /// holds a different store or an image older than `expected`. A
+    /// rolled back disk would otherwise be opened with a KHF that no
+    /// longer matches the keys its data was written with.
+    pub fn open_expecting(
+        disk: D,
+        root_key: [u8; 32],
+        expected: MediaIdentity,
+    ) -> Result<Self, ObjectStoreError> {
+        let fs = FileSystem::open_fs(Arc::new(disk), FsConfig::default())?;
+        let checks = OpenChecks {
+            expected: Some(expected),
+            ..Default::default()
+        };
+        Ok(Self::from_fs(fs, None, root_key, checks)?)
+    }
+
+    fn format_fs(
+        disk: D,
+        superblock: &Superblock,
+        options: &FormatOptions,
+        root_key: [u8; 32],
+    ) -> Result<FileSystem<D>, Error> {
+        let disk = Arc::new(disk);
+        FileSystem::format(&disk, options.fs_config)?;
+        let fs = FileSystem::open_fs(disk, options.fs_config)?;
+        {
+            let volume = fs.fs().lock().map_err(lock_poisoned)?;
+            superblock.store(&volume, root_key)?;
+            MetaKeys::fresh().store(&volume, fs.disk(), root_key)?;
+        }
+        let mut layout = Layout::from_boot_sector(fs.disk())?;
+        if let Some(chunk_size) = options
+            .chunk_size
+            .filter(|size| *size != layout.chunk_size())
+        {
+            // a freed cluster moves its whole chunk on to a new key,
+            // which would lose the chunk's other clusters.
+            if superblock.key_mode == KeyMode::Volume {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "volume keyed stores need chunks of one cluster",
+                ));
+            }
+            // without a header, the layout is read back from the boot sector.
+            if !header_fits(fs.disk())? {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "chunks larger than a cluster need room for the raw header",
+                ));
+            }
+            layout = layout.with_chunk_size(chunk_size)?;
+        }
+        RawHeader::new(superblock, layout).store(fs.disk())?;
+        Ok(fs)
+    }
+
+    pub(crate) fn from_fs(
+        fs: FileSystem<D>,
+        metadata: Option<FileSystem<D>>,
+        root_key: [u8; 32],
+        checks: OpenChecks,
+    ) -> Result<Self, Error> {
+        let mount_owner = rand::random();
+        let mut events = Vec::new();
+        let layout = Layout::load(fs.disk())?;
+        let (superblock, meta_keys, tags, chunk_generations) = {
+            let disk = fs.disk();
+            let fs = fs.fs().lock().map_err(lock_poisoned)?;
+            let mut superblock = Superblock::load(&fs, root_key)?.unwrap_or_default();
+            if let Some(expected) = checks.expected {
+                check_media(expected, superblock.identity())?;
+            }
+            let mut meta_keys = MetaKeys::load(&fs, root_key)?;
+            // only a crash or a failed epoch leaves a rotation unfinished.
+            meta_keys.finish_rotation(&fs, disk, root_key)?;
+            let meta_key = meta_keys.current;
+            if let Some(previous_owner) = claim_mount(&fs, &meta_key, mount_owner, checks.takeover)?
+            {
+                events.push(StoreEvent::TookOver { previous_owner });
+            }
+            events.push(StoreEvent::Opened {
+                uuid: superblock.uuid,
+                generation: superblock.generation,
+                clean: superblock.clean,
+            });
+            if superblock.uuid == 0 {
+                // the volume predates store ids, give it one now.
+                superblock.uuid = rand::random();
+                superblock.store(&fs, root_key)?;
+            }
+            check_metadata_disk(superblock.metadata, metadata.as_ref(), superblock.uuid)?;
+            // a clean store has no half finished epoch to recover from.
+            if superblock.key_mode == KeyMode::Khf && !superblock.clean {
+                let recovery = match superblock.metadata.khf_volume(metadata.as_ref()) {
+                    Some(volume) => Self::restore_khf(
+                        &volume.fs().lock().map_err(lock_poisoned)?,
+                        volume.disk(),
+                    )?,
+                    None => Self::restore_khf(&fs, disk)?,
+                };
+                if let Some(recovery) = recovery {
+                    events.push(StoreEvent::Recovered(recovery));
+                }
+            }
+            let tags = read_meta(&fs, &meta_key, TAGS_PATH)?.unwrap_or_default();
+            let chunk_generations =
+                read_meta(&fs, &meta_key, CHUNK_GENERATIONS_PATH)?.unwrap_or_default();
+            (superblock, meta_keys, tags, chunk_generations)
+        };
+        let kms = Kms::open(
+            &fs,
+            metadata.as_ref(),
+            superblock.metadata,
+            root_key,
+            superblock.key_mode,
+        );
+        Ok(Self {
             fs,
-            kms: Kms::open(fs_ref, root_key),
+            metadata_fs: metadata,
+            metadata_placement: superblock.metadata,
+            kms,
             root_key,
-        };
-        out
+            meta_keys: Mutex::new(meta_keys),
+            access: AccessTracker::new(DEFAULT_HALF_LIFE),
+            keys: KeyCache::new(),
+            chunk_generations: Mutex::new(chunk_generations),
+            extents: ExtentCache::new(),
+            tags: Mutex::new(tags),
+            index: Mutex::new(None),
+            expiry: Mutex::new(None),
+            pending_deletions: AtomicU64::new(0),
+            trash: Mutex::new(None),
+            trash_retention: Mutex::new(None),
+            flags: Mutex::new(None),
+            seals: Mutex::new(None),
+            dedup: Mutex::new(None),
+            versions: Mutex::new(VersionTable::fresh()),
+            macs: Mutex::new(None),
+            incarnations: Mutex::new(Incarnations::default()),
+            uuid: superblock.uuid,
+            generation: AtomicU64::new(superblock.generation),
+            integrity_hash: superblock.integrity_hash,
+            mount_owner,
+            counters: Counters::default(),
+            events: EventLog::with_pending(events),
+            pending_epoch: Mutex::new(None),
+            layout,
+            namespaces: Mutex::new(None),
+            manifest: Mutex::new(None),
+            allocator: Mutex::new(None),
+            holes: Mutex::new(None),
+            logs: Mutex::new(None),
+            audit: Mutex::new(None),
+            reencrypt_rate: AtomicU64::new(0),
+            metadata_quota: Mutex::new(None),
+            id_key: superblock
+                .blind_ids
+                .then(|| derive_subkey(root_key, ID_KEY_LABEL)),
+            blinded: Mutex::new(None),
+        })
     }
 
     /// Returns the disk length of a given object on disk.
-    pub fn disk_length(&self, obj_id: u128) -> Result<u64, Error> {
-        let mut fs = self.fs().lock().unwrap();
-        let id = encode_obj_id(obj_id);
-        let dir = get_dir_path(&mut fs, &id)?;
+    pub fn disk_length(&self, obj_id: u128) -> Result<u64, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let id = self.encode_obj_id(obj_id);
+        let dir = get_dir_path(&fs, &id)?;
         let mut file = dir.open_file(&id)?;
         let len = file.seek(SeekFrom::End(0))?;
         Ok(len)
     }
     /// Either gets a previously set config_id from disk or returns None
-    pub fn get_config_id(&self) -> Result<Option<u128>, Error> {
+    pub fn get_config_id(&self) -> Result<Option<u128>, ObjectStoreError> {
         let fs = self.fs().lock().unwrap();
         let file = fs.root_dir().open_file("config_id");
         let mut file = match file {
@@ -255,7 +1075,7 @@ where
         Ok(Some(u128::from_le_bytes(buf)))
     }
     /// Stores a config_id onto the disk.
-    pub fn set_config_id(&self, id: u128) -> Result<(), Error> {
+    pub fn set_config_id(&self, id: u128) -> Result<(), ObjectStoreError> {
         let fs = self.fs().lock().unwrap();
         let mut file = fs.root_dir().create_file("config_id")?;
         file.truncate()?;
@@ -265,72 +1085,296 @@ where
     }
 
     /// Returns true if file was created and false if the file already existed.
-    pub fn create_object(&self, obj_id: u128) -> Result<bool, Error> {
-        let b64 = encode_obj_id(obj_id);
-        let mut fs = self.fs().lock().unwrap();
-        let subdir = get_dir_path(&mut fs, &b64)?;
-        // try to open it to check if it exists.
+    ///
+    /// New objects are empty, and any range later skipped over by a
+    /// write past the end reads as zeros, whatever its clusters held.
+    pub fn create_object(&self, obj_id: u128) -> Result<bool, ObjectStoreError> {
+        Ok(self.try_create_object(obj_id)? == CreateOutcome::Created)
+    }
+
+    /// Creates an object unless it already exists. Callers racing to
+    /// create the same id get exactly one `Created` between them.
+    pub fn try_create_object(&self, obj_id: u128) -> Result<CreateOutcome, ObjectStoreError> {
+        Ok(self.create_with(obj_id, CreateMode::Open)?)
+    }
+
+    /// Creates an object.
+    ///
+    /// # Errors
+    /// `AlreadyExists` if the object already exists.
+    pub fn create_object_excl(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        match self.create_with(obj_id, CreateMode::Exclusive)? {
+            CreateOutcome::Created => Ok(()),
+            CreateOutcome::AlreadyExists => {
+                Err(Error::new(ErrorKind::AlreadyExists, "object already exists").into())
+            }
+        }
+    }
+
+    /// Creates an object, or empties it if it already exists. The
+    /// discarded data is securely deleted by the next epoch.
+    pub fn create_or_truncate(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        self.create_with(obj_id, CreateMode::Truncate)?;
+        Ok(())
+    }
+
+    fn create_with(&self, obj_id: u128, mode: CreateMode) -> Result<CreateOutcome, Error> {
+        let fs = self.fs().lock().unwrap();
+        self.create_locked(&fs, obj_id, mode)
+    }
+
+    /// Creates an object with the volume already locked, so that many
+    /// can be created without letting go of it.
+    pub(crate) fn create_locked(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        mode: CreateMode,
+    ) -> Result<CreateOutcome, Error> {
+        let started = Instant::now();
+        let b64 = self.encode_obj_id(obj_id);
+        let subdir = get_dir_path(fs, &b64)?;
+        // a single probe tells us if the object exists, and the handle
+        // it returns is reused for truncating. The volume stays locked
+        // until the file is created, so racing callers can't both miss.
         let res = subdir.open_file(&b64);
+        if let Err(fatfs::Error::NotFound) = res {
+            self.forget_previous_incarnation(fs, obj_id)?;
+        }
+        if mode == CreateMode::Truncate {
+            self.check_flags(fs, obj_id, ObjectFlags::IMMUTABLE | ObjectFlags::SEALED)?;
+            self.check_log_edit(fs, obj_id, 0)?;
+            self.forget_dedup(fs, obj_id)?;
+            self.forget_holes(fs, obj_id)?;
+            if self.page_macs_locked(fs, obj_id)?.is_some() {
+                self.store_page_macs(fs, obj_id, Vec::new())?;
+            }
+        }
         match res {
-            Ok(_) => Ok(false),
-            Err(e) => match e {
-                fatfs::Error::NotFound => {
-                    // khf.derive_mut(&wal, hash_obj_id(obj_id))
-                    //     .expect("shouldn't panic since khf implementation doesn't panic");
-                    subdir.create_file(&b64)?;
-                    Ok(true)
+            Ok(mut file) => {
+                if mode == CreateMode::Truncate {
+                    self.discard_contents(fs, &mut file, obj_id)?;
+                    self.versions.lock().unwrap().bump(obj_id);
                 }
-                _ => Err(e.into()),
-            },
+                Ok(CreateOutcome::AlreadyExists)
+            }
+            Err(fatfs::Error::NotFound) => {
+                // listing objects needs their names to be known before
+                // their files are.
+                self.remember_obj_name(fs, obj_id)?;
+                self.manifest_created(fs, obj_id)?;
+                subdir.create_file(&b64)?;
+                self.counters
+                    .objects_created
+                    .fetch_add(1, Ordering::Relaxed);
+                self.record_latency(LatencyOp::Create, started);
+                Ok(CreateOutcome::Created)
+            }
+            Err(e) => Err(e.into()),
         }
     }
 
+    /// Truncates an object's file to nothing, deleting the keys of the
+    /// pages it used to hold.
+    pub(crate) fn discard_contents(
+        &self,
+        fs: &FatFs<D>,
+        file: &mut FatFile<'_, D>,
+        obj_id: u128,
+    ) -> Result<(), Error> {
+        let extents: Vec<WrappedExtent> = file
+            .extents()
+            .map(|v| v.map(WrappedExtent::from))
+            .try_collect()?;
+        for page in extents.iter().flat_map(WrappedExtent::page_offsets) {
+            self.delete_chunk_key(fs, page)?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.truncate()?;
+        self.extents.remove(obj_id);
+        Ok(())
+    }
+
+    /// Runs `f` on the WAL journal, or returns `None` if there is no
+    /// WAL.
+    pub(crate) fn wal_journal<T>(
+        &self,
+        f: impl FnOnce(&WalJournal) -> T,
+    ) -> Result<Option<T>, Error> {
+        self.kms().journal(f)
+    }
+
+    /// Returns true if the KHF has logged the derivation of a chunk's
+    /// key, as every chunk written since it was opened must have.
+    pub(crate) fn key_logged(&self, chunk_id: u64) -> Result<bool, Error> {
+        self.kms().logged(chunk_id)
+    }
+
     fn kms(&self) -> &Kms<D> {
+        self.kms.load();
         &self.kms
     }
-    /// unlinks (aka deletes) the object at `obj_id`.
+
+    /// The faults injected into the KHF, or `None` if the store isn't
+    /// keyed by one.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn kms_faults(&self) -> Option<&KmsFaults> {
+        self.kms.faults()
+    }
+    /// unlinks (aka deletes) the object at `obj_id`. When a trash
+    /// retention is set the object is moved to the trash instead.
     /// # Safety
     /// To do secure deletion on deletes you must call an epoch
     /// before saving.
-    pub fn unlink_object(&self, obj_id: u128) -> Result<(), Error> {
-        let b64 = encode_obj_id(obj_id);
+    pub fn unlink_object(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        let started = Instant::now();
+        // taken before the filesystem lock, like handles do.
+        let mut incarnations = self.incarnations.lock().map_err(lock_poisoned)?;
+        let fs = self.fs().lock().unwrap();
+        self.check_flags(&fs, obj_id, ObjectFlags::PINNED)?;
+        if self.trash_retention().is_some() {
+            drop(fs);
+            self.move_to_trash(obj_id)?;
+        } else {
+            self.destroy_object(&fs, obj_id, &self.object_path(obj_id))?;
+            self.manifest_unlinked(&fs, obj_id)?;
+            self.forget_metadata(&fs, obj_id)?;
+        }
+        incarnations.retire(obj_id);
+        self.counters
+            .objects_unlinked
+            .fetch_add(1, Ordering::Relaxed);
+        self.record_latency(LatencyOp::Unlink, started);
+        Ok(())
+    }
+
+    /// Deletes the keys of the object stored at `path` and removes the
+    /// file.
+    pub(crate) fn destroy_object(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+        path: &str,
+    ) -> Result<(), Error> {
         // let (khf, wal) = (kms.khf_mut(), kms.wal_mut());
         // khf.delete(&wal, hash_obj_id(obj_id))
         //     .map_err(Error::other)?;
         let extents = {
-            let mut fs = self.fs().lock().unwrap();
-            let subdir = get_dir_path(&mut fs, &b64)?;
-            let mut file = subdir.open_file(&b64)?;
+            let mut file = fs.root_dir().open_file(path)?;
             file.extents().collect::<Vec<_>>().into_iter()
         };
         for extent in extents {
-            let id = extent?.offset / crate::fs::PAGE_SIZE as u64;
-            let kms = self.kms();
+            let extent = WrappedExtent::from(extent?);
+            let mut pages = extent.page_offsets();
+            let Some(first) = pages.next() else {
+                continue;
+            };
+            let last = pages.last().unwrap_or(first);
+            // an extent is contiguous on disk, and so are its chunks.
+            self.delete_chunk_keys(
+                fs,
+                self.layout.chunk_id(first)..self.layout.chunk_id(last) + 1,
+            )?;
+        }
+        fs.root_dir().remove(path)?;
+        self.access.forget(obj_id);
+        self.extents.remove(obj_id);
+        Ok(())
+    }
+
+    /// Deletes the key of the chunk at `disk_offset` so that it is
+    /// securely forgotten by the next epoch.
+    pub(crate) fn delete_chunk_key(&self, fs: &FatFs<D>, disk_offset: u64) -> Result<(), Error> {
+        let id = self.layout.chunk_id(disk_offset);
+        self.delete_chunk_keys(fs, id..id + 1)
+    }
 
-            kms.khf_lock()
-                .delete(&kms.wal_lock(), id)
-                .map_err(Error::other)?;
+    /// Deletes the keys of the chunks `ids`, as one batch in the KMS.
+    /// With a volume key there is nothing to delete, so the chunks move
+    /// on to a new generation instead, and whatever reuses them gets a
+    /// fresh key.
+    pub(crate) fn delete_chunk_keys(&self, fs: &FatFs<D>, ids: Range<u64>) -> Result<(), Error> {
+        let (deleted, res) = self.kms().delete_range(ids.clone());
+        match self.key_mode() {
+            KeyMode::Khf => {
+                self.pending_deletions.fetch_add(deleted, Ordering::Relaxed);
+            }
+            KeyMode::Volume => {
+                let mut generations = self.chunk_generations.lock().unwrap();
+                update_meta(
+                    fs,
+                    &self.meta_key(),
+                    CHUNK_GENERATIONS_PATH,
+                    &mut *generations,
+                    |generations| generations.bump(ids.start..ids.start + deleted),
+                )?;
+            }
+            KeyMode::Plaintext => {}
         }
-        let mut fs = self.fs().lock().unwrap();
-        let subdir = get_dir_path(&mut fs, &b64)?;
-        subdir.remove(&b64)?;
+        for id in ids.start..ids.start + deleted {
+            self.unsettle_chunk(id);
+            self.keys.remove(id);
+        }
+        res
+    }
+
+    /// Drops the tags, index entries and expiry of an object.
+    pub(crate) fn forget_metadata(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        self.forget_tags(fs, obj_id)?;
+        self.forget_index_entries(fs, obj_id)?;
+        self.forget_expiry(fs, obj_id)?;
+        self.forget_contents_metadata(fs, obj_id)?;
+        self.forget_obj_name(fs, obj_id)?;
         Ok(())
     }
 
-    pub fn get_all_object_ids(&self) -> Result<Vec<u128>, Error> {
+    /// Drops what describes the contents of an object: its flags, seal,
+    /// dedup pages, page MACs, holes and log state.
+    fn forget_contents_metadata(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        self.forget_flags(fs, obj_id)?;
+        self.forget_seal(fs, obj_id)?;
+        self.forget_dedup(fs, obj_id)?;
+        self.forget_page_macs(fs, obj_id)?;
+        self.forget_holes(fs, obj_id)?;
+        self.forget_log(fs, obj_id)?;
+        Ok(())
+    }
+
+    /// Readies a free id for a new object. A trashed object with the id
+    /// keeps the metadata of its contents, which would otherwise carry
+    /// over to the new object, so the trashed copy is purged and its
+    /// contents metadata dropped. Tags and index entries stay with the
+    /// id.
+    pub(crate) fn forget_previous_incarnation(
+        &self,
+        fs: &FatFs<D>,
+        obj_id: u128,
+    ) -> Result<(), Error> {
+        self.discard_trashed(fs, obj_id)?;
+        self.forget_contents_metadata(fs, obj_id)
+    }
+
+    /// Returns the id of every object, in id order. The ids are read from
+    /// the id manifest rather than the object directories.
+    pub fn get_all_object_ids(&self) -> Result<Vec<u128>, ObjectStoreError> {
         let fs = self.fs().lock().unwrap();
+        Ok(self.object_ids_locked(&fs)?)
+    }
+
+    pub(crate) fn object_ids_locked(&self, fs: &FatFs<D>) -> Result<Vec<u128>, Error> {
+        self.manifest_ids(fs)
+    }
+
+    /// Lists the ids of objects by walking every object directory.
+    pub(crate) fn scan_object_ids(&self, fs: &FatFs<D>) -> Result<Vec<u128>, Error> {
         let id_root = fs.root_dir().create_dir("ids")?;
         let mut out = Vec::new();
         for folder in id_root.iter() {
             let folder = folder?;
             for file in folder.to_dir().iter() {
                 let file = file?;
-                let name = file.file_name();
-                if name.len() != 32 {
-                    continue; // ., ..
-                }
-                let id = u128::from_str_radix(&name, 16);
-                if let Ok(id) = id {
+                // skips . and ..
+                if let Some(id) = self.decode_obj_name(fs, &file.file_name())? {
                     out.push(id);
                 }
             }
@@ -338,36 +1382,222 @@ where
         Ok(out)
     }
 
-    fn get_symmetric_cipher(&self, disk_offset: u64) -> Result<ChaCha20, Error> {
+    /// Returns the cipher for the chunk at `disk_offset`, or `None` if
+    /// the store is in plaintext mode.
+    pub(crate) fn get_symmetric_cipher(&self, disk_offset: u64) -> Result<Option<ChaCha20>, Error> {
+        self.cipher_for(disk_offset, false)
+    }
+
+    /// Returns the cipher for reading the chunk at `disk_offset`, which
+    /// doesn't log a derivation for chunks that already have one.
+    pub(crate) fn read_cipher(&self, disk_offset: u64) -> Result<Option<ChaCha20>, Error> {
+        self.cipher_for(disk_offset, true)
+    }
+
+    fn cipher_for(&self, disk_offset: u64, read_only: bool) -> Result<Option<ChaCha20>, Error> {
+        self.settle_chunk(self.layout.chunk_id(disk_offset))?;
+        let Some(key) = self.lookup_key(disk_offset, read_only)? else {
+            return Ok(None);
+        };
+        Ok(Some(self.layout.cipher(disk_offset, key)))
+    }
+
+    /// Returns the key of the chunk at `disk_offset`, going through the
+    /// key cache and recording the derivation like any other use.
+    pub(crate) fn chunk_key(&self, disk_offset: u64) -> Result<Option<[u8; 32]>, Error> {
+        self.settle_chunk(self.layout.chunk_id(disk_offset))?;
+        self.lookup_key(disk_offset, false)
+    }
+
+    pub(crate) fn lookup_key(
+        &self,
+        disk_offset: u64,
+        read_only: bool,
+    ) -> Result<Option<[u8; 32]>, Error> {
         let kms = self.kms();
-        let chunk_id = disk_offset_to_id(disk_offset);
-        println!("Chunk id: {}", chunk_id);
-        let key = kms
-            .khf_lock()
-            .derive_mut(&kms.wal_lock(), chunk_id)
-            .map_err(Error::other)?;
-        println!("Key for {}:{:?}", disk_offset, key);
-        get_symmetric_cipher_from_key(disk_offset, key)
-    }
-
-    pub fn read_exact(&self, obj_id: u128, buf: &mut [u8], off: u64) -> Result<(), Error> {
-        let b64 = encode_obj_id(obj_id);
+        let chunk_id = self.layout.chunk_id(disk_offset);
+        debug_event!(chunk_id, read_only, "key lookup");
+        let key = match self.keys.get(chunk_id) {
+            Some(key) => {
+                self.counters.key_cache_hits.fetch_add(1, Ordering::Relaxed);
+                key
+            }
+            None => {
+                self.counters
+                    .key_derivations
+                    .fetch_add(1, Ordering::Relaxed);
+                let derived = if read_only {
+                    kms.derive_for_read(chunk_id)
+                } else {
+                    kms.derive(chunk_id)
+                };
+                let Some(key) = derived
+                    .context(ErrorContext::new(Phase::DeriveKey).disk_offset(disk_offset))?
+                else {
+                    return Ok(None);
+                };
+                let key = self.chunk_generations.lock().unwrap().key(chunk_id, key);
+                self.keys.insert(chunk_id, key);
+                key
+            }
+        };
+        self.audit_key_use(chunk_id, read_only)?;
+        Ok(Some(key))
+    }
+
+    pub fn read_exact(
+        &self,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), ObjectStoreError> {
+        let _span = op_span!("read", obj_id = format_args!("{obj_id:0>32x}"), off);
+        let started = Instant::now();
+        let mut fs = self.fs().lock().unwrap();
+        self.read_locked(&mut fs, obj_id, buf, off)?;
+        self.record_read(obj_id, buf.len(), started);
+        Ok(())
+    }
+
+    /// Services several reads while taking the filesystem lock once.
+    /// Each request is `(obj_id, offset, len)`. Requests are issued in
+    /// order of their location on disk to cut down on seeking, but the
+    /// results are returned in the order of `requests`.
+    pub fn read_many(
+        &self,
+        requests: &[(u128, u64, usize)],
+    ) -> Vec<Result<Vec<u8>, ObjectStoreError>> {
+        let _span = op_span!("read_many", requests = requests.len());
+        let started = Instant::now();
         let mut fs = self.fs().lock().unwrap();
-        let subdir = get_dir_path(&mut fs, &b64)?;
+        let mut order: Vec<(u64, usize)> = requests
+            .iter()
+            .enumerate()
+            .map(|(i, &(obj_id, off, _))| {
+                let disk_offset = self
+                    .locate(&mut fs, obj_id, off)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(u64::MAX);
+                (disk_offset, i)
+            })
+            .collect();
+        order.sort_unstable();
+        let mut out: Vec<Option<Result<Vec<u8>, ObjectStoreError>>> =
+            std::iter::repeat_with(|| None)
+                .take(requests.len())
+                .collect();
+        for (_, i) in order {
+            let (obj_id, off, len) = requests[i];
+            let mut buf = vec![0u8; len];
+            let res = self.read_locked(&mut fs, obj_id, &mut buf, off);
+            if res.is_ok() {
+                self.record_read(obj_id, len, started);
+            }
+            out[i] = Some(res.map(|_| buf).map_err(ObjectStoreError::from));
+        }
+        out.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Returns the disk offset backing byte `off` of an object, or
+    /// `None` if `off` is past the allocated end of the object.
+    pub(crate) fn locate(
+        &self,
+        fs: &mut FatFs<D>,
+        obj_id: u128,
+        off: u64,
+    ) -> Result<Option<u64>, Error> {
+        let b64 = self.encode_obj_id(obj_id);
+        let subdir = get_dir_path(fs, &b64)?;
         let mut file = subdir.open_file(&b64)?;
-        file.seek(fatfs::SeekFrom::Start(off))?;
+        let mut pos = 0;
+        for extent in file.extents() {
+            let extent = WrappedExtent::from(extent?);
+            if off < pos + extent.size() {
+                return Ok(Some(extent.offset() + (off - pos)));
+            }
+            pos += extent.size();
+        }
+        Ok(None)
+    }
+
+    /// Reads the whole contents of an object.
+    pub(crate) fn read_all_locked(
+        &self,
+        fs: &mut FatFs<D>,
+        obj_id: u128,
+    ) -> Result<Vec<u8>, Error> {
+        let len = self.object_len_locked(fs, obj_id)?;
+        let mut buf = vec![0u8; len as usize];
+        self.read_locked(fs, obj_id, &mut buf, 0)?;
+        Ok(buf)
+    }
+
+    /// Returns the number of bytes in an object.
+    pub(crate) fn object_len_locked(&self, fs: &mut FatFs<D>, obj_id: u128) -> Result<u64, Error> {
+        if let Some(len) = self.dedup_len_locked(fs, obj_id)? {
+            return Ok(len);
+        }
+        let b64 = self.encode_obj_id(obj_id);
+        let len = get_dir_path(fs, &b64)?
+            .open_file(&b64)
+            .context(ErrorContext::new(Phase::Lookup).object(obj_id))?
+            .seek(SeekFrom::End(0))?;
+        Ok(len)
+    }
+
+    pub(crate) fn read_locked(
+        &self,
+        fs: &mut FatFs<D>,
+        obj_id: u128,
+        buf: &mut [u8],
+        off: u64,
+    ) -> Result<(), Error> {
+        let ctx = ErrorContext::new(Phase::Read).object(obj_id).offset(off);
+        let b64 = self.encode_obj_id(obj_id);
+        let dedup = self
+            .is_deduplicated_locked(fs, obj_id)
+            .context(ctx.clone())?;
+        let unwritten = self.unwritten_ranges(fs, obj_id).context(ctx.clone())?;
+        let subdir = get_dir_path(fs, &b64).context(ctx.clone())?;
+        if dedup {
+            subdir
+                .open_file(&b64)
+                .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+            return self.dedup_read(fs, obj_id, buf, off).context(ctx);
+        }
+        let mut file = subdir
+            .open_file(&b64)
+            .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+        let len = file
+            .seek(fatfs::SeekFrom::End(0))
+            .context(ErrorContext::new(Phase::Seek).object(obj_id))?;
+        check_in_bounds(obj_id, off, buf.len(), len)?;
+        self.read_file_at(&mut file, buf, off, &unwritten)
+            .context(ctx)
+    }
+
+    /// Reads from the current position of `file`, decrypting each chunk
+    /// with the key of its disk offset.
+    pub(crate) fn read_file(&self, file: &mut FatFile<'_, D>, buf: &mut [u8]) -> Result<(), Error> {
+        let mut stream = CipherStream::for_reads();
         let mut rw_proxy = ReadWriteProxy::new(
-            &mut file,
-            |disk: &mut D,
+            file,
+            |disk: &mut DiskCursor<D>,
              disk_offset: u64,
              buffer: &mut [u8]|
              -> Result<usize, fatfs::Error<D::Error>> {
-                let out = disk.read(buffer)?;
-                println!("reading @ {}", disk_offset);
-                let mut cipher = self
-                    .get_symmetric_cipher(disk_offset)
-                    .map_err(Error::other)?;
-                cipher.apply_keystream(buffer);
+                // before reading, so no bytes under a key an epoch is
+                // replacing are decrypted with the new one.
+                self.settle_range(disk_offset, buffer.len() as u64)?;
+                let out = disk
+                    .read(buffer)
+                    .map_err(Error::from)
+                    .context(ErrorContext::new(Phase::DiskRead).disk_offset(disk_offset))?;
+                debug_event!(disk_offset, len = out, "chunk read");
+                if let Some(cipher) = self.stream_cipher(&mut stream, disk_offset, out)? {
+                    cipher.apply_keystream(&mut buffer[..out]);
+                }
                 Ok(out)
             },
             || {},
@@ -376,114 +1606,465 @@ where
         Ok(())
     }
 
-    pub fn get_obj_segments(&self, obj_id: u128) -> Result<HashSet<WrappedExtent>, Error> {
-        let b64 = encode_obj_id(obj_id);
+    pub fn get_obj_segments(
+        &self,
+        obj_id: u128,
+    ) -> Result<HashSet<WrappedExtent>, ObjectStoreError> {
+        let b64 = self.encode_obj_id(obj_id);
         // call to get_khf_locks to make sure that khf is already initialized for
         // the later "get_symmetric_cipher" call
-        let mut fs = self.fs().lock().unwrap();
-        let subdir = get_dir_path(&mut fs, &b64)?;
+        if let Some(extents) = self.extents.get(obj_id) {
+            return Ok(extents);
+        }
+        let fs = self.fs().lock().unwrap();
+        let subdir = get_dir_path(&fs, &b64)?;
         let mut file = subdir.open_file(&b64)?;
         let out_hm: HashSet<WrappedExtent> = file
             .extents()
             .map(|v| v.map(WrappedExtent::from))
             .try_collect()?;
+        self.extents.insert(obj_id, out_hm.clone());
         Ok(out_hm)
     }
 
-    pub fn write_all(&self, obj_id: u128, buf: &[u8], off: u64) -> Result<(), Error> {
-        let b64 = encode_obj_id(obj_id);
-        let mut fs = self.fs().lock().unwrap();
-        let subdir = get_dir_path(&mut fs, &b64)?;
-        let mut file = subdir.open_file(&b64)?;
-        let _new_pos = file.seek(fatfs::SeekFrom::Start(off))?;
-        let extents_before: HashSet<WrappedExtent> = file
-            .extents()
-            .map(|v| v.map(WrappedExtent::from))
-            .try_collect()?;
+    /// Warms the extent map and derived keys of every object in
+    /// `obj_ids` in a single pass. If `read_data` is set the object
+    /// pages are also read from the disk so that any caching below
+    /// the store is warmed as well. Objects that don't exist are
+    /// skipped.
+    pub fn prefetch(&self, obj_ids: &[u128], read_data: bool) -> Result<(), ObjectStoreError> {
+        let mut chunk_ids = Vec::new();
+        {
+            let fs = self.fs().lock().unwrap();
+            for &obj_id in obj_ids {
+                let b64 = self.encode_obj_id(obj_id);
+                let subdir = get_dir_path(&fs, &b64)?;
+                let mut file = match subdir.open_file(&b64) {
+                    Ok(file) => file,
+                    Err(fatfs::Error::NotFound) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let extents: HashSet<WrappedExtent> = file
+                    .extents()
+                    .map(|v| v.map(WrappedExtent::from))
+                    .try_collect()
+                    .context(ErrorContext::new(Phase::ExtentScan).object(obj_id))?;
+                chunk_ids.extend(
+                    extents
+                        .iter()
+                        .flat_map(WrappedExtent::page_offsets)
+                        .map(|page| self.layout.chunk_id(page)),
+                );
+                self.extents.insert(obj_id, extents);
+            }
+        }
+        chunk_ids.sort_unstable();
+        chunk_ids.dedup();
+        let missing: Vec<u64> = chunk_ids
+            .iter()
+            .copied()
+            .filter(|id| self.keys.get(*id).is_none())
+            .collect();
+        self.counters
+            .key_derivations
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+        let keys = self
+            .kms()
+            .derive_many(&missing)
+            .context(ErrorContext::new(Phase::DeriveKey))?;
+        let generations = self.chunk_generations.lock().unwrap();
+        for (id, key) in missing.into_iter().zip(keys) {
+            if let Some(key) = key {
+                self.keys.insert(id, generations.key(id, key));
+            }
+        }
+        drop(generations);
+        if read_data {
+            let disk = self.fs.disk();
+            let mut buf = vec![0u8; PAGE_SIZE];
+            for id in chunk_ids {
+                let disk_offset = self.layout.disk_offset(id);
+                disk.read_exact_at(disk_offset, &mut buf)
+                    .context(ErrorContext::new(Phase::DiskRead).disk_offset(disk_offset))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_all(&self, obj_id: u128, buf: &[u8], off: u64) -> Result<(), ObjectStoreError> {
+        // the pager writes whole pages, which mostly skip fatfs.
+        if self.write_pages_direct(obj_id, buf, off)? {
+            return Ok(());
+        }
+        self.apply_patch(obj_id, &[(off, buf)])
+    }
+
+    /// Applies a list of `(offset, bytes)` edits to an object while only
+    /// taking the filesystem lock and scanning the object's extents
+    /// once. Edits are applied in order, so later edits win where they
+    /// overlap.
+    pub fn apply_patch(
+        &self,
+        obj_id: u128,
+        patch: &[(u64, &[u8])],
+    ) -> Result<(), ObjectStoreError> {
+        self.write_patch(obj_id, patch, None)?;
+        Ok(())
+    }
+
+    /// Applies `patch`, first checking that the object is at version
+    /// `expected` if one is given. Returns the new version.
+    pub(crate) fn write_patch(
+        &self,
+        obj_id: u128,
+        patch: &[(u64, &[u8])],
+        expected: Option<u64>,
+    ) -> Result<u64, Error> {
+        let _span = op_span!("write", obj_id = format_args!("{obj_id:0>32x}"));
+        let started = Instant::now();
+        let ctx = ErrorContext::new(Phase::Write).object(obj_id);
+        let written = patch.iter().map(|(_, buf)| buf.len()).sum();
+        let b64 = self.encode_obj_id(obj_id);
+        let fs = self.fs().lock().unwrap();
+        self.check_flags(&fs, obj_id, ObjectFlags::IMMUTABLE | ObjectFlags::SEALED)
+            .context(ctx.clone())?;
+        for &(off, _) in patch {
+            self.check_log_edit(&fs, obj_id, off)
+                .context(ctx.clone().offset(off))?;
+        }
+        let dedup = self
+            .is_deduplicated_locked(&fs, obj_id)
+            .context(ctx.clone())?;
+        let macs = self.page_macs_locked(&fs, obj_id).context(ctx.clone())?;
+        let mut holes = self.holes_lock(&fs).context(ctx.clone())?;
+        let holes = holes.as_mut().unwrap();
+        // checked up front, since running out part way would leave the
+        // object partly written.
+        let free_clusters = fs.stats().map_err(Error::from)?.free_clusters() as u64;
+        let subdir = get_dir_path(&fs, &b64).context(ctx.clone())?;
+        let actual = self.versions.lock().unwrap().get(obj_id);
+        if let Some(expected) = expected.filter(|&expected| expected != actual) {
+            return Err(VersionConflict { expected, actual }.into());
+        }
+        if dedup {
+            subdir
+                .open_file(&b64)
+                .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+            // bumped once nothing can refuse the write, since a write
+            // that fails part way may still have changed the object.
+            let version = self.versions.lock().unwrap().bump(obj_id);
+            for &(off, buf) in patch {
+                self.dedup_write(&fs, obj_id, buf, off)
+                    .context(ctx.clone().offset(off))?;
+            }
+            self.record_write(obj_id, written, started);
+            return Ok(version);
+        }
+        let mut file = subdir
+            .open_file(&b64)
+            .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+        // scanning the extents twice dominates writes to large
+        // fragmented objects, so the check is only made in debug builds.
+        #[cfg(debug_assertions)]
+        let scan_ctx = ErrorContext::new(Phase::ExtentScan).object(obj_id);
+        #[cfg(debug_assertions)]
+        let extents_before: HashSet<WrappedExtent> = match self.extents.get(obj_id) {
+            Some(extents) => extents,
+            None => file
+                .extents()
+                .map(|v| v.map(WrappedExtent::from))
+                .try_collect()
+                .context(scan_ctx.clone())?,
+        };
+        let len_before = file.seek(SeekFrom::End(0)).context(ctx.clone())?;
+        let end = patch
+            .iter()
+            .map(|&(off, buf)| off + buf.len() as u64)
+            .fold(len_before, u64::max);
+        check_space(obj_id, len_before, end, free_clusters)?;
+        let version = self.versions.lock().unwrap().bump(obj_id);
+        let mut len = len_before;
+        let mut holes_changed = false;
+        for &(off, buf) in patch {
+            if off > len {
+                // the hole goes on record before the stale clusters it
+                // covers become part of the object, so a crash can't
+                // expose them.
+                holes.punch(obj_id, len, off);
+                drop(file);
+                self.store_holes(&fs, holes).context(ctx.clone())?;
+                file = get_dir_path(&fs, &b64)
+                    .context(ctx.clone())?
+                    .open_file(&b64)
+                    .context(ErrorContext::new(Phase::Lookup).object(obj_id))?;
+                self.extend_unwritten(&mut file, off - len)
+                    .context(ctx.clone().offset(len))?;
+            }
+            let _new_pos = file
+                .seek(fatfs::SeekFrom::Start(off))
+                .context(ErrorContext::new(Phase::Seek).object(obj_id).offset(off))?;
+            self.write_file(&mut file, buf)
+                .context(ctx.clone().offset(off))?;
+            let end = off + buf.len() as u64;
+            holes_changed |= holes.fill(obj_id, off, end);
+            len = len.max(end);
+        }
+        #[cfg(debug_assertions)]
+        {
+            let extents_after: HashSet<WrappedExtent> = file
+                .extents()
+                .map(|v| v.map(WrappedExtent::from))
+                .try_collect()
+                .context(scan_ctx)?;
+            // Should never remove extents from a file by writing to it.
+            assert_eq!(extents_before.difference(&extents_after).next(), None);
+        }
+        let len_after = file.seek(SeekFrom::End(0)).context(ctx.clone())?;
+        // only growing past the last allocated page changes the extents.
+        let page = PAGE_SIZE as u64;
+        if len_after.div_ceil(page) > len_before.div_ceil(page) {
+            self.extents.remove(obj_id);
+        }
+        let macs = match macs {
+            Some(macs) => {
+                // pages past the old end may have been left unwritten as well.
+                let grown = page_range(
+                    len_before,
+                    (len_after.max(len_before) - len_before) as usize,
+                );
+                let touched = patch
+                    .iter()
+                    .flat_map(|&(off, buf)| page_range(off, buf.len()));
+                let pages = touched.chain(grown);
+                let unwritten = holes.of(obj_id);
+                Some(
+                    self.compute_page_macs(&mut file, obj_id, macs, pages, &unwritten)
+                        .context(ctx.clone())?,
+                )
+            }
+            None => None,
+        };
+        drop(file);
+        if holes_changed {
+            self.store_holes(&fs, holes).context(ctx.clone())?;
+        }
+        if let Some(macs) = macs {
+            self.store_page_macs(&fs, obj_id, macs).context(ctx)?;
+        }
+        self.record_write(obj_id, written, started);
+        Ok(version)
+    }
+
+    /// Writes at the current position of `file`, encrypting each chunk
+    /// with the key of its disk offset.
+    pub(crate) fn write_file(&self, file: &mut FatFile<'_, D>, buf: &[u8]) -> Result<(), Error> {
+        let mut stream = CipherStream::default();
         let mut rw_proxy = ReadWriteProxy::new(
-            &mut file,
+            file,
             || {},
-            |disk: &mut D, offset: u64, buffer: &[u8]| -> Result<usize, fatfs::Error<D::Error>> {
-                println!("writing @ {}", offset);
-                let mut cipher = self.get_symmetric_cipher(offset)?;
-                let mut encrypted = vec![0u8; buffer.len()];
-                cipher
-                    .apply_keystream_b2b(buffer, &mut encrypted)
-                    .map_err(Error::other)?;
-                let out = disk.write(&encrypted)?;
+            |disk: &mut DiskCursor<D>,
+             offset: u64,
+             buffer: &[u8]|
+             -> Result<usize, fatfs::Error<D::Error>> {
+                debug_event!(disk_offset = offset, len = buffer.len(), "chunk write");
+                let out = match self.stream_cipher(&mut stream, offset, buffer.len())? {
+                    Some(cipher) => {
+                        let mut encrypted = vec![0u8; buffer.len()];
+                        cipher
+                            .apply_keystream_b2b(buffer, &mut encrypted)
+                            .map_err(Error::other)?;
+                        disk.write(&encrypted)
+                    }
+                    None => disk.write(buffer),
+                }
+                .map_err(Error::from)
+                .context(ErrorContext::new(Phase::DiskWrite).disk_offset(offset))?;
+                if out < buffer.len() {
+                    // the keystream ran ahead of what reached the disk.
+                    stream.reset();
+                }
                 Ok(out)
             },
         );
         fatfs::Write::write_all(&mut rw_proxy, buf)?;
-        let extents_after: HashSet<WrappedExtent> = file
-            .extents()
-            .map(|v| v.map(WrappedExtent::from))
-            .try_collect()?;
-        // Should never add extents to a file after writing to a file.
-        assert_eq!(extents_before.difference(&extents_after).next(), None);
         Ok(())
     }
 
-    pub fn advance_epoch(&self) -> Result<(), Error> {
+    /// Returns up to `top_n` of the most frequently accessed objects,
+    /// hottest first. Counts decay over time so that objects which
+    /// haven't been touched recently cool off.
+    pub fn hot_objects(&self, top_n: usize) -> Vec<(u128, ObjectAccess)> {
+        self.access.hottest(top_n)
+    }
+
+    /// Rotates the keys of every chunk touched since the last epoch and
+    /// persists the KHF. Does nothing when the store isn't keyed by a
+    /// KHF.
+    ///
+    /// The rotated keys are only held in memory until the KHF is
+    /// persisted, so the epoch re-encrypts every chunk before returning
+    /// rather than leaving any for a later call.
+    pub fn advance_epoch(&self) -> Result<(), ObjectStoreError> {
+        Ok(self.run_epoch()?)
+    }
+
+    fn run_epoch(&self) -> Result<(), Error> {
+        let _span = op_span!("epoch");
+        {
+            let fs = self.fs().lock().unwrap();
+            self.compact_manifest(&fs)?;
+            self.compact_index(&fs)?;
+        }
         let kms = self.kms();
-        let updated_keys = kms
-            .khf_lock()
-            .update(&kms.wal_lock())
-            .map_err(Error::other)?;
-        for (id, key) in updated_keys {
-            println!("{}", id_to_disk_offset(id));
-            let mut buf = vec![0; PAGE_SIZE];
-            let mut disk = self.fs.disk().clone();
-            let disk_offset = id_to_disk_offset(id);
-            disk.seek(SeekFrom::Start(disk_offset))?;
-            disk.read_exact(buf.as_mut_slice())?;
-            let mut cipher =
-                get_symmetric_cipher_from_key(disk_offset, key).map_err(Error::other)?;
-            cipher.apply_keystream(&mut buf);
-            disk.seek(SeekFrom::Start(disk_offset))?;
-            let mut cipher = self
-                .get_symmetric_cipher(disk_offset)
-                .map_err(Error::other)?;
-            cipher.apply_keystream(&mut buf);
-            disk.write_all(&buf)?;
+        if kms.key_mode() != KeyMode::Khf {
+            return Ok(());
+        }
+        let chunks = {
+            // the filesystem lock is taken first, like the data path does
+            // before settling chunks.
+            let fs = self.fs().lock().unwrap();
+            let mut pending = self.pending_epoch.lock().unwrap();
+            if pending.is_none() {
+                self.check_metadata_quota(&fs)?;
+                // until the epoch finishes, the khf files may need recovery.
+                self.store_superblock(&fs, false)?;
+                let updated_keys = kms.update().context(ErrorContext::new(Phase::KeyUpdate))?;
+                // every cached key is stale now that the keys have been rotated.
+                self.keys.clear();
+                self.pending_deletions.store(0, Ordering::Relaxed);
+                *pending = Some(PendingEpoch {
+                    remaining: updated_keys
+                        .into_iter()
+                        .map(|(id, key)| (id, Zeroizing::new(key)))
+                        .collect(),
+                    started: Instant::now(),
+                });
+            }
+            pending.as_ref().unwrap().remaining.len() as u64
+        };
+        self.counters.epoch_chunks_done.store(0, Ordering::Relaxed);
+        self.counters
+            .epoch_chunks_total
+            .store(chunks, Ordering::Relaxed);
+        self.events.emit(StoreEvent::EpochStarted {
+            generation: self.generation.load(Ordering::Relaxed),
+            chunks,
+        });
+        let started = Instant::now();
+        let done = match self.reencrypt_pending() {
+            Ok(done) => done,
+            Err(e) => {
+                let failed_chunks = epoch_incomplete(&e).map_or(0, |e| e.failed.len() as u64);
+                self.events.emit(StoreEvent::EpochFailed {
+                    generation: self.generation.load(Ordering::Relaxed),
+                    failed_chunks,
+                });
+                return Err(e);
+            }
+        };
+        let elapsed = started.elapsed().as_secs_f64();
+        if done > 0 && elapsed > 0.0 {
+            let bytes = done * self.layout.chunk_size();
+            self.reencrypt_rate
+                .store((bytes as f64 / elapsed) as u64, Ordering::Relaxed);
         }
+        let epoch_started = match self.pending_epoch.lock().unwrap().take() {
+            Some(epoch) => epoch.started,
+            // finished by another caller.
+            None => return Ok(()),
+        };
         let kms = self.kms();
         {
-            let mut khf = kms.khf_lock();
-            let fs = self.fs().lock().unwrap();
-            fs.root_dir().create_dir("tmp/")?;
-            fs.root_dir().create_dir("old/")?;
-            khf.persist(self.root_key, "tmp/khf", &fs)
-                .map_err(Error::other)?;
-            Self::wipe_old_khf_file(&fs);
-            // let lethe = fs.root_dir().create_dir("lethe/")?;
-            Self::restore_khf(&fs);
-        }
-        kms.wal_lock().clear().map_err(Error::other)?;
+            let mut fs = self.fs().lock().unwrap();
+            // don't clobber the khf of a store that took the disk over.
+            claim_mount(&fs, &self.meta_key(), self.mount_owner, false)?;
+            self.persist_khf(&fs)?;
+            self.seal_logs(&mut fs)?;
+            self.rotate_meta_key(&fs)?;
+        }
+        kms.clear_wal()
+            .context(ErrorContext::new(Phase::ClearWal))?;
+        self.metadata_usage()?;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.store_superblock(&*self.fs().lock().unwrap(), true)?;
+        self.fs.disk().reclaim()?;
+        self.counters.epochs.fetch_add(1, Ordering::Relaxed);
+        self.record_latency(LatencyOp::Epoch, epoch_started);
+        self.events.emit(StoreEvent::EpochFinished {
+            generation: self.generation.load(Ordering::Relaxed),
+        });
+        self.counters.epoch_chunks_total.store(0, Ordering::Relaxed);
+        self.counters.epoch_chunks_done.store(0, Ordering::Relaxed);
         Ok(())
     }
-}
 
-pub fn disk_offset_to_id(offset: u64) -> u64 {
-    (offset - 1024) / super::fs::PAGE_SIZE as u64
-}
+    /// Writes a shadow copy of the KHF to tmp/khf and then moves it
+    /// into place. Keys keep being derived from the live KHF while the
+    /// copy is written.
+    pub(crate) fn persist_khf(&self, fs: &MutexGuard<'_, FatFs<D>>) -> Result<(), Error> {
+        let Some(mut shadow) = self.kms().shadow()? else {
+            return Ok(());
+        };
+        match self
+            .metadata_placement
+            .khf_volume(self.metadata_fs.as_ref())
+        {
+            Some(volume) => self.install_khf(
+                &mut shadow,
+                &volume.fs().lock().map_err(lock_poisoned)?,
+                volume.disk(),
+            ),
+            None => self.install_khf(&mut shadow, fs, self.fs.disk()),
+        }
+    }
+
+    fn install_khf(
+        &self,
+        shadow: &mut MyKhf,
+        fs: &MutexGuard<'_, FatFs<D>>,
+        disk: &FreezableDisk<D>,
+    ) -> Result<(), Error> {
+        fs.root_dir().create_dir("tmp/")?;
+        fs.root_dir().create_dir("old/")?;
+        shadow
+            .persist(self.root_key, "tmp/khf", fs)
+            .map_err(kms_error)
+            .context(ErrorContext::new(Phase::PersistKhf))?;
+        // tmp/khf has to be complete before anything is moved for it.
+        disk.flush()?;
+        Self::wipe_old_khf_file(fs, disk)?;
+        Self::restore_khf(fs, disk).map(|_| ())
+    }
 
-pub fn id_to_disk_offset(id: u64) -> u64 {
-    id * super::fs::PAGE_SIZE as u64 + 1024
+    /// Shuts the store down. A failed epoch is finished, the KHF is
+    /// persisted, the WAL cleared and the store marked clean so that the
+    /// next open can skip recovery, then the mount is released and the
+    /// disk flushed.
+    pub fn close(self) -> Result<(), ObjectStoreError> {
+        if self.epoch_paused() {
+            self.advance_epoch()?;
+        }
+        {
+            let fs = self.fs().lock().map_err(lock_poisoned)?;
+            claim_mount(&fs, &self.meta_key(), self.mount_owner, false)?;
+            if self.key_mode() == KeyMode::Khf {
+                self.store_superblock(&fs, false)?;
+                self.persist_khf(&fs)?;
+            }
+        }
+        self.kms()
+            .clear_wal()
+            .context(ErrorContext::new(Phase::ClearWal))?;
+        self.store_superblock(&*self.fs().lock().map_err(lock_poisoned)?, true)?;
+        self.unmount()?;
+        self.fs.disk().flush().map_err(Error::from)?;
+        if let Some(metadata) = &self.metadata_fs {
+            metadata.disk().flush().map_err(Error::from)?;
+        }
+        self.events.emit(StoreEvent::Closed { uuid: self.uuid });
+        Ok(())
+    }
 }
 
 // // FIXME should use a randomly generated root key for each device.
 // pub const ROOT_KEY: [u8; 32] = [0; 32];
-
-fn get_symmetric_cipher_from_key(disk_offset: u64, key: [u8; 32]) -> Result<ChaCha20, Error> {
-    let chunk_id = disk_offset_to_id(disk_offset);
-    let offset = disk_offset - chunk_id;
-    let bytes = chunk_id.to_le_bytes();
-    let nonce: [u8; 12] = [
-        0, 0, 0, 0, bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
-    ];
-
-    let mut cipher = ChaCha20::new(&key.into(), &nonce.into());
-    cipher.seek(offset);
-    Ok(cipher)
-}
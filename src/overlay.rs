@@ -0,0 +1,231 @@
+use crate::{
+    fs::{Disk, PAGE_SIZE},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{
+    collections::BTreeSet,
+    io::{Error, ErrorKind},
+    sync::Mutex,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+const OVERLAY_MAGIC: [u8; 8] = *b"TOSOVLY1";
+
+/// Which pages the delta holds, and which pages of that bitmap have
+/// changed since the last flush.
+struct Bitmap {
+    bits: Vec<u8>,
+    dirty: BTreeSet<usize>,
+}
+
+impl Bitmap {
+    fn get(&self, page: u64) -> bool {
+        self.bits[(page / 8) as usize] & (1 << (page % 8)) != 0
+    }
+
+    fn set(&mut self, page: u64) {
+        self.bits[(page / 8) as usize] |= 1 << (page % 8);
+        self.dirty.insert((page / 8) as usize / PAGE_SIZE);
+    }
+}
+
+/// A disk made of a shared read-only base image and a writable delta.
+/// Pages the store hasn't written are read from the base, and the base
+/// is never written to, so many stores can be opened from one golden
+/// image, each with a small delta of its own.
+///
+/// The delta holds written pages at their offsets in the base, so it
+/// is best a sparse file or thinly provisioned volume, followed by a
+/// header page and a bitmap of the pages it holds. See `delta_size`.
+pub struct OverlayDisk<D> {
+    base: D,
+    delta: D,
+    size: u64,
+    /// Where the header page is in the delta, with the bitmap after it.
+    header_at: u64,
+    /// Identifies the base, so a delta isn't opened over another one.
+    base_sum: u64,
+    bitmap: Mutex<Bitmap>,
+}
+
+impl<D> OverlayDisk<D>
+where
+    D: Disk,
+    std::io::Error: From<D::Error>,
+{
+    /// Returns how large the delta has to be for a base of `base_size`
+    /// bytes.
+    pub fn delta_size(base_size: u64) -> u64 {
+        let page = PAGE_SIZE as u64;
+        let bitmap = base_size.div_ceil(page).div_ceil(8);
+        base_size.next_multiple_of(page) + page + bitmap.next_multiple_of(page)
+    }
+
+    /// Layers `delta` over `base`. A delta that is all zeros is taken
+    /// to be new.
+    ///
+    /// # Errors
+    /// `InvalidInput` if `delta` is smaller than `delta_size`, and
+    /// `InvalidData` if it holds anything but a delta of `base`.
+    pub fn new(base: D, delta: D) -> Result<Self, Error> {
+        let page = PAGE_SIZE as u64;
+        let size = base.size()?;
+        if delta.size()? < Self::delta_size(size) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "delta disk is smaller than delta_size",
+            ));
+        }
+        let mut first = vec![0u8; PAGE_SIZE.min(size as usize)];
+        base.read_exact_at(0, &mut first)?;
+        let base_sum = xxh3_64(&first);
+        let header_at = size.next_multiple_of(page);
+        let mut header = [0u8; PAGE_SIZE];
+        delta.read_exact_at(header_at, &mut header)?;
+        let mut bits = vec![0u8; size.div_ceil(page).div_ceil(8) as usize];
+        if header[..8] == OVERLAY_MAGIC {
+            let made_for = (
+                u64::from_le_bytes(header[8..16].try_into().unwrap()),
+                u64::from_le_bytes(header[16..24].try_into().unwrap()),
+            );
+            if made_for != (size, base_sum) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "delta disk was made for another base",
+                ));
+            }
+            delta.read_exact_at(header_at + page, &mut bits)?;
+        } else if header.iter().any(|b| *b != 0) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "delta disk doesn't hold a delta",
+            ));
+        }
+        Ok(Self {
+            base,
+            delta,
+            size,
+            header_at,
+            base_sum,
+            bitmap: Mutex::new(Bitmap {
+                bits,
+                dirty: BTreeSet::new(),
+            }),
+        })
+    }
+
+    pub fn base(&self) -> &D {
+        &self.base
+    }
+
+    pub fn into_inner(self) -> (D, D) {
+        (self.base, self.delta)
+    }
+}
+
+impl<D: Disk> IoBase for OverlayDisk<D> {
+    type Error = D::Error;
+}
+
+impl<D> Disk for OverlayDisk<D>
+where
+    D: Disk,
+    std::io::Error: From<D::Error>,
+{
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let page = PAGE_SIZE as u64;
+        let end = (offset + buf.len() as u64).min(self.size);
+        let bitmap = self.bitmap.lock().unwrap();
+        let mut pos = offset;
+        while pos < end {
+            let n = (page - pos % page).min(end - pos);
+            let at = (pos - offset) as usize;
+            let dst = &mut buf[at..at + n as usize];
+            if bitmap.get(pos / page) {
+                self.delta.read_exact_at(pos, dst)?;
+            } else {
+                self.base.read_exact_at(pos, dst)?;
+            }
+            pos += n;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let page = PAGE_SIZE as u64;
+        let end = (offset + buf.len() as u64).min(self.size);
+        let mut bitmap = self.bitmap.lock().unwrap();
+        let mut data = [0u8; PAGE_SIZE];
+        let mut pos = offset;
+        while pos < end {
+            let n = (page - pos % page).min(end - pos);
+            let at = (pos - offset) as usize;
+            let src = &buf[at..at + n as usize];
+            if n < page && !bitmap.get(pos / page) {
+                // copy the rest of the page up from the base first.
+                let start = pos - pos % page;
+                let data = &mut data[..(self.size - start).min(page) as usize];
+                self.base.read_exact_at(start, data)?;
+                let within = (pos % page) as usize;
+                data[within..within + src.len()].copy_from_slice(src);
+                self.delta.write_all_at(start, data)?;
+            } else {
+                self.delta.write_all_at(pos, src)?;
+            }
+            bitmap.set(pos / page);
+            pos += n;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut bitmap = self.bitmap.lock().unwrap();
+        if bitmap.dirty.is_empty() {
+            return self.delta.flush();
+        }
+        // the pages have to be durable before the bits saying they are
+        // in the delta.
+        self.delta.flush()?;
+        let mut header = [0u8; PAGE_SIZE];
+        header[..8].copy_from_slice(&OVERLAY_MAGIC);
+        header[8..16].copy_from_slice(&self.size.to_le_bytes());
+        header[16..24].copy_from_slice(&self.base_sum.to_le_bytes());
+        self.delta.write_all_at(self.header_at, &header)?;
+        let bits_at = self.header_at + PAGE_SIZE as u64;
+        for index in std::mem::take(&mut bitmap.dirty) {
+            let start = index * PAGE_SIZE;
+            let end = (start + PAGE_SIZE).min(bitmap.bits.len());
+            self.delta
+                .write_all_at(bits_at + start as u64, &bitmap.bits[start..end])?;
+        }
+        self.delta.flush()
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.size)
+    }
+
+    fn reclaim(&self) -> Result<(), Self::Error> {
+        self.delta.reclaim()
+    }
+}
+
+impl<D> ObjectStore<OverlayDisk<D>>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Opens the store on `base` with every write going to `delta`, as
+    /// in `OverlayDisk`. `base` should be a closed store, and is never
+    /// written to. Every store opened from one base shares its keys and
+    /// identity; `provision_from_template` makes independent copies
+    /// instead.
+    pub fn open_overlay(base: D, delta: D, root_key: [u8; 32]) -> Result<Self, ObjectStoreError> {
+        Self::open(OverlayDisk::new(base, delta)?, root_key)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{
+    fs::{Disk, DiskLimits, SECTOR_SIZE},
+    ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::io::{Error, ErrorKind};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Entries are at least this long; longer ones carry extra fields after
+/// the name.
+const GPT_ENTRY_LEN: usize = 128;
+/// Longer entries are taken to be garbage rather than allocated for.
+const MAX_GPT_ENTRY_LEN: usize = 4096;
+
+/// A byte range of another disk, so a store can share a device with a
+/// boot partition. Offsets are relative to the start of the range, and
+/// accesses past its end behave as at the end of a disk.
+pub struct PartitionDisk<D> {
+    disk: D,
+    start: u64,
+    len: u64,
+}
+
+impl<D: Disk> PartitionDisk<D> {
+    pub fn new(disk: D, start: u64, len: u64) -> Self {
+        Self { disk, start, len }
+    }
+
+    /// Wraps the byte range of a partition found by `gpt_partitions`.
+    pub fn from_gpt(disk: D, partition: &GptPartition) -> Self {
+        let range = partition.byte_range();
+        Self::new(disk, range.start, range.end - range.start)
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn into_inner(self) -> D {
+        self.disk
+    }
+
+    /// Clamps an access of `len` bytes at `offset` to the partition.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        self.len.saturating_sub(offset).min(len as u64) as usize
+    }
+}
+
+impl<D: Disk> IoBase for PartitionDisk<D> {
+    type Error = D::Error;
+}
+
+impl<D: Disk> Disk for PartitionDisk<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.clamp(offset, buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        self.disk.read_at(self.start + offset, &mut buf[..n])
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.clamp(offset, buf.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        self.disk.write_at(self.start + offset, &buf[..n])
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.disk.flush()
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.len.min(self.disk.size()?.saturating_sub(self.start)))
+    }
+
+    fn limits(&self) -> DiskLimits {
+        self.disk.limits()
+    }
+
+    fn reclaim(&self) -> Result<(), Self::Error> {
+        self.disk.reclaim()
+    }
+}
+
+/// A partition listed in a GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The partition type, as stored on disk (mixed endian).
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    /// Inclusive, as in the table.
+    pub last_lba: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    pub fn byte_range(&self) -> std::ops::Range<u64> {
+        let sector = SECTOR_SIZE as u64;
+        self.first_lba * sector..(self.last_lba + 1) * sector
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Lists the partitions in the primary GUID partition table of `disk`,
+/// in table order, skipping unused entries. Assumes 512 byte sectors.
+/// The CRCs aren't checked, so a damaged table may list garbage.
+///
+/// # Errors
+/// `InvalidData` if the disk has no GPT header.
+pub fn gpt_partitions<D>(disk: &D) -> Result<Vec<GptPartition>, ObjectStoreError>
+where
+    D: Disk,
+    std::io::Error: From<D::Error>,
+{
+    let mut header = [0u8; SECTOR_SIZE];
+    disk.read_exact_at(SECTOR_SIZE as u64, &mut header)
+        .map_err(Error::from)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Err(Error::new(ErrorKind::InvalidData, "no GPT header").into());
+    }
+    let entries_lba = u64_at(&header, 72);
+    let count = u32_at(&header, 80) as u64;
+    let entry_len = u32_at(&header, 84) as usize;
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed GPT header");
+    if !(GPT_ENTRY_LEN..=MAX_GPT_ENTRY_LEN).contains(&entry_len) || count > 1024 {
+        return Err(malformed().into());
+    }
+    let entries_start = entries_lba
+        .checked_mul(SECTOR_SIZE as u64)
+        .ok_or_else(malformed)?;
+    let mut out = Vec::new();
+    let mut entry = vec![0u8; entry_len];
+    for i in 0..count {
+        let at = entries_start
+            .checked_add(i * entry_len as u64)
+            .ok_or_else(malformed)?;
+        disk.read_exact_at(at, &mut entry).map_err(Error::from)?;
+        let type_guid: [u8; 16] = entry[..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        out.push(GptPartition {
+            type_guid,
+            unique_guid: entry[16..32].try_into().unwrap(),
+            first_lba: u64_at(&entry, 32),
+            last_lba: u64_at(&entry, 40),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Ok(out)
+}
@@ -0,0 +1,62 @@
+use crate::{
+    latency::LatencySummary,
+    metrics::{MetricsSink, MetricsSnapshot},
+};
+use std::fmt::Write;
+
+/// Prefix of every exported metric name.
+const NAMESPACE: &str = "twizzler_object_store";
+
+/// Renders metrics in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct PrometheusText {
+    out: String,
+}
+
+impl PrometheusText {
+    fn metric(&mut self, kind: &str, name: &str, help: &str, value: u64) {
+        let _ = writeln!(self.out, "# HELP {NAMESPACE}_{name} {help}");
+        let _ = writeln!(self.out, "# TYPE {NAMESPACE}_{name} {kind}");
+        let _ = writeln!(self.out, "{NAMESPACE}_{name} {value}");
+    }
+
+    pub fn into_string(self) -> String {
+        self.out
+    }
+}
+
+impl MetricsSink for PrometheusText {
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.metric("counter", name, help, value);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.metric("gauge", name, help, value);
+    }
+
+    /// Exports the latency as a summary in seconds.
+    fn latency(&mut self, name: &str, help: &str, summary: &LatencySummary) {
+        let name = format!("{NAMESPACE}_{name}_seconds");
+        let _ = writeln!(self.out, "# HELP {name} {help}");
+        let _ = writeln!(self.out, "# TYPE {name} summary");
+        for (quantile, value) in [
+            ("0.5", summary.p50),
+            ("0.9", summary.p90),
+            ("0.99", summary.p99),
+        ] {
+            let seconds = value.as_secs_f64();
+            let _ = writeln!(self.out, "{name}{{quantile=\"{quantile}\"}} {seconds}");
+        }
+        let _ = writeln!(self.out, "{name}_sum {}", summary.sum.as_secs_f64());
+        let _ = writeln!(self.out, "{name}_count {}", summary.count);
+    }
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics as a Prometheus scrape response.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = PrometheusText::default();
+        self.export(&mut text);
+        text.into_string()
+    }
+}
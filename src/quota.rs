@@ -0,0 +1,127 @@
+use crate::{
+    fs::{Disk, FatDir, FatFs, PAGE_SIZE},
+    object_store::lock_poisoned,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{
+    io::{Error, ErrorKind},
+    sync::atomic::Ordering,
+};
+
+/// Space taken on the disk by the key management metadata, in bytes of
+/// whole clusters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetadataUsage {
+    /// The KHF and WAL.
+    pub lethe: u64,
+    /// A KHF being persisted.
+    pub tmp: u64,
+    /// The KHF being replaced.
+    pub old: u64,
+}
+
+impl MetadataUsage {
+    pub fn total(&self) -> u64 {
+        self.lethe + self.tmp + self.old
+    }
+}
+
+/// Adds up the clusters of every file under `dir`.
+fn dir_usage<D>(dir: &FatDir<'_, D>) -> Result<u64, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let mut bytes = 0;
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        bytes += if entry.is_dir() {
+            dir_usage(&entry.to_dir())?
+        } else {
+            entry.len().next_multiple_of(PAGE_SIZE as u64)
+        };
+    }
+    Ok(bytes)
+}
+
+fn volume_usage<D>(fs: &FatFs<D>) -> Result<MetadataUsage, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    let usage_of = |path: &str| match fs.root_dir().open_dir(path) {
+        Ok(dir) => dir_usage(&dir),
+        Err(fatfs::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    };
+    Ok(MetadataUsage {
+        lethe: usage_of("lethe")?,
+        tmp: usage_of("tmp")?,
+        old: usage_of("old")?,
+    })
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Refuses epochs that would take the key management metadata past
+    /// `quota` bytes. Passing `None` lifts the limit.
+    pub fn set_metadata_quota(&self, quota: Option<u64>) {
+        *self.metadata_quota.lock().unwrap() = quota;
+    }
+
+    pub fn metadata_quota(&self) -> Option<u64> {
+        *self.metadata_quota.lock().unwrap()
+    }
+
+    /// Measures the key management metadata, also updating the
+    /// `metadata_bytes` metric.
+    pub fn metadata_usage(&self) -> Result<MetadataUsage, ObjectStoreError> {
+        Ok(self.metadata_usage_locked(&self.fs().lock().unwrap())?)
+    }
+
+    pub(crate) fn metadata_usage_locked(&self, fs: &FatFs<D>) -> Result<MetadataUsage, Error> {
+        let mut usage = volume_usage(fs)?;
+        if let Some(metadata) = &self.metadata_fs {
+            let more = volume_usage(&*metadata.fs().lock().map_err(lock_poisoned)?)?;
+            usage.lethe += more.lethe;
+            usage.tmp += more.tmp;
+            usage.old += more.old;
+        }
+        self.counters
+            .metadata_bytes
+            .store(usage.total(), Ordering::Relaxed);
+        Ok(usage)
+    }
+
+    /// Fails if persisting the KHF could overflow the quota. The new
+    /// KHF is written next to the current one before replacing it, and
+    /// is assumed to be no larger than the current one plus the WAL.
+    pub(crate) fn check_metadata_quota(&self, fs: &FatFs<D>) -> Result<(), Error> {
+        let Some(quota) = self.metadata_quota() else {
+            return Ok(());
+        };
+        let usage = self.metadata_usage_locked(fs)?;
+        if usage.total() + usage.lethe > quota {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "an epoch could take key metadata to {} bytes, past the quota of {quota}",
+                    usage.total() + usage.lethe
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
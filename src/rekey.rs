@@ -0,0 +1,106 @@
+use crate::{
+    fs::{Disk, FatFs},
+    relocate::{check_within, BALLAST_PATH},
+    superblock::KeyMode,
+    wrapped_extent::WrappedExtent,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{
+    io::{Error, ErrorKind},
+    ops::Range,
+};
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Moves an object onto freshly allocated chunks, so that its data
+    /// is encrypted under keys that were never used for it before. The
+    /// old keys are deleted and securely forgotten by the next epoch,
+    /// without having to wait for that epoch to re-encrypt every chunk.
+    ///
+    /// # Errors
+    /// `Unsupported` unless the store uses per-chunk KHF keys, and
+    /// `InvalidInput` for deduplicated objects, whose pages are shared.
+    pub fn rekey_object(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        if self.key_mode() != KeyMode::Khf {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "only KHF stores have per-chunk keys",
+            )
+            .into());
+        }
+        let mut fs = self.fs().lock().unwrap();
+        Ok(self.move_object_locked(&mut fs, obj_id, None)?)
+    }
+
+    /// Copies an object onto newly allocated clusters, inside `target`
+    /// if given, and deletes the keys of the chunks it leaves.
+    pub(crate) fn move_object_locked(
+        &self,
+        fs: &mut FatFs<D>,
+        obj_id: u128,
+        target: Option<&Range<u64>>,
+    ) -> Result<(), Error> {
+        if self.is_deduplicated_locked(fs, obj_id)? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "deduplicated objects can't be moved",
+            ));
+        }
+        let contents = self.read_all_locked(fs, obj_id)?;
+        let path = self.object_path(obj_id);
+        let tmp_path = format!("{path}.rekey");
+        let old_extents: Vec<WrappedExtent> = {
+            let mut file = fs.root_dir().open_file(&path)?;
+            file.extents()
+                .map(|v| v.map(WrappedExtent::from))
+                .try_collect()?
+        };
+        // the copy is allocated while the old clusters are still in use,
+        // so none of its clusters can be the old ones. Chunks larger than
+        // a cluster may still be shared, and are re-encrypted by the next
+        // epoch.
+        let mut tmp = fs.root_dir().create_file(&tmp_path)?;
+        tmp.truncate()?;
+        if let Some(target) = target {
+            self.fill_up_to(fs, target)?;
+        }
+        let res = self
+            .write_file(&mut tmp, &contents)
+            .and_then(|()| match target {
+                Some(target) => check_within(&mut tmp, target),
+                None => Ok(()),
+            });
+        if target.is_some() {
+            fs.root_dir().remove(BALLAST_PATH)?;
+        }
+        drop(tmp);
+        if let Err(e) = res {
+            fs.root_dir().remove(&tmp_path)?;
+            return Err(e);
+        }
+        let mut chunk_ids: Vec<u64> = old_extents
+            .iter()
+            .flat_map(WrappedExtent::page_offsets)
+            .map(|page| self.layout.chunk_id(page))
+            .collect();
+        chunk_ids.sort_unstable();
+        chunk_ids.dedup();
+        for id in chunk_ids {
+            self.delete_chunk_key(fs, self.layout.disk_offset(id))?;
+        }
+        fs.root_dir().remove(&path)?;
+        fs.root_dir().rename(&tmp_path, &fs.root_dir(), &path)?;
+        // the copy was written in full, zeros and all.
+        self.forget_holes(fs, obj_id)?;
+        self.extents.remove(obj_id);
+        Ok(())
+    }
+}
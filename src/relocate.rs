@@ -0,0 +1,101 @@
+use crate::{
+    fs::{Disk, FatFile, FatFs, PAGE_SIZE},
+    meta::META_DIR,
+    wrapped_extent::WrappedExtent,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::{IoBase, Seek, SeekFrom, Write as _};
+use std::{
+    io::{Error, ErrorKind},
+    ops::Range,
+};
+
+/// Holds the free clusters in front of a relocation's target region
+/// while the object is copied, and is removed afterwards.
+pub(crate) const BALLAST_PATH: &str = "meta/ballast";
+
+/// Fails unless every cluster of `file` starts inside `target`.
+pub(crate) fn check_within<D>(file: &mut FatFile<'_, D>, target: &Range<u64>) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    for extent in file.extents() {
+        let extent = WrappedExtent::from(extent?);
+        if extent.page_offsets().any(|page| !target.contains(&page)) {
+            return Err(Error::new(
+                ErrorKind::StorageFull,
+                "too few free clusters in the target region",
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Moves an object's data into the disk byte range `target`, onto
+    /// clusters it has never used, and deletes the keys of the chunks
+    /// it leaves. Used to defragment, to clear the end of a volume
+    /// before shrinking it, and to place hot objects in the fast zone
+    /// of a zoned or SMR disk.
+    ///
+    /// FAT hands out clusters in order from the last one it allocated,
+    /// so the free clusters before `target` are first taken up by a
+    /// ballast file. That can mean writing most of the free space when
+    /// the allocator has already passed `target`.
+    ///
+    /// # Errors
+    /// `InvalidInput` if `target` is empty or past the end of the disk,
+    /// or the object is deduplicated, and `StorageFull` if `target`
+    /// doesn't have enough free clusters. The object is unchanged on
+    /// error.
+    pub fn relocate(&self, obj_id: u128, target: Range<u64>) -> Result<(), ObjectStoreError> {
+        if target.is_empty() || target.end > self.capacity()? {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "target region is empty or past the end of the disk",
+            )
+            .into());
+        }
+        let mut fs = self.fs().lock().unwrap();
+        Ok(self.move_object_locked(&mut fs, obj_id, Some(&target))?)
+    }
+
+    /// Grows the ballast file a cluster at a time until the allocator
+    /// reaches `target`, leaving the next allocation there.
+    pub(crate) fn fill_up_to(&self, fs: &FatFs<D>, target: &Range<u64>) -> Result<(), Error> {
+        fs.root_dir().create_dir(META_DIR)?;
+        let mut ballast = fs.root_dir().create_file(BALLAST_PATH)?;
+        ballast.truncate()?;
+        let page = [0u8; PAGE_SIZE];
+        let mut len = 0;
+        loop {
+            if let Err(e) = ballast.write_all(&page) {
+                drop(ballast);
+                fs.root_dir().remove(BALLAST_PATH)?;
+                return Err(Error::new(ErrorKind::StorageFull, Error::from(e)));
+            }
+            len += PAGE_SIZE as u64;
+            let last = ballast
+                .extents()
+                .last()
+                .transpose()?
+                .map(WrappedExtent::from)
+                .and_then(|extent| extent.page_offsets().last());
+            if last.is_some_and(|last| target.contains(&last)) {
+                // hand the cluster back; the copy is allocated from here on.
+                ballast.seek(SeekFrom::Start(len - PAGE_SIZE as u64))?;
+                ballast.truncate()?;
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{
+    flags::ObjectFlags,
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+    time::Instant,
+};
+
+pub(crate) const SEALS_PATH: &str = "meta/seals";
+
+/// The content hash each sealed object was sealed with.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SealTable {
+    hashes: BTreeMap<u128, [u8; 32]>,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn seals_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<SealTable>>, Error> {
+        let mut seals = self.seals.lock().unwrap();
+        if seals.is_none() {
+            *seals = Some(read_meta(fs, &self.meta_key(), SEALS_PATH)?.unwrap_or_default());
+        }
+        Ok(seals)
+    }
+
+    /// Records the SHA3-256 hash of an object's contents and rejects
+    /// any further writes to it. Sealing an already sealed object
+    /// returns the hash it was sealed with.
+    pub fn seal_object(&self, obj_id: u128) -> Result<[u8; 32], ObjectStoreError> {
+        let mut fs = self.fs().lock().unwrap();
+        if let Some(hash) = self.seals_lock(&fs)?.as_ref().unwrap().hashes.get(&obj_id) {
+            return Ok(*hash);
+        }
+        let contents = self.read_all_locked(&mut fs, obj_id)?;
+        let hash: [u8; 32] = Sha3_256::digest(&contents).into();
+        {
+            let mut seals = self.seals_lock(&fs)?;
+            update_meta(
+                &fs,
+                &self.meta_key(),
+                SEALS_PATH,
+                seals.as_mut().unwrap(),
+                |seals| {
+                    seals.hashes.insert(obj_id, hash);
+                    true
+                },
+            )?;
+        }
+        let flags = self.flags_locked(&fs, obj_id)?;
+        self.store_flags(&fs, obj_id, flags | ObjectFlags::SEALED)?;
+        Ok(hash)
+    }
+
+    /// Returns the hash an object was sealed with, or `None` if it
+    /// isn't sealed.
+    pub fn sealed_hash(&self, obj_id: u128) -> Result<Option<[u8; 32]>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let seals = self.seals_lock(&fs)?;
+        Ok(seals.as_ref().unwrap().hashes.get(&obj_id).copied())
+    }
+
+    /// Reads the whole of a sealed object and checks it against the
+    /// hash it was sealed with.
+    ///
+    /// # Errors
+    /// `InvalidInput` if the object isn't sealed and `InvalidData` if
+    /// its contents no longer match.
+    pub fn read_verified(&self, obj_id: u128) -> Result<Vec<u8>, ObjectStoreError> {
+        let started = Instant::now();
+        let mut fs = self.fs().lock().unwrap();
+        let Some(expected) = self
+            .seals_lock(&fs)?
+            .as_ref()
+            .unwrap()
+            .hashes
+            .get(&obj_id)
+            .copied()
+        else {
+            return Err(Error::new(ErrorKind::InvalidInput, "object is not sealed").into());
+        };
+        let contents = self.read_all_locked(&mut fs, obj_id)?;
+        if <[u8; 32]>::from(Sha3_256::digest(&contents)) != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "object does not match its sealed hash",
+            )
+            .into());
+        }
+        self.record_read(obj_id, contents.len(), started);
+        Ok(contents)
+    }
+
+    pub(crate) fn forget_seal(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut seals = self.seals_lock(fs)?;
+        update_meta(
+            fs,
+            &self.meta_key(),
+            SEALS_PATH,
+            seals.as_mut().unwrap(),
+            |seals| seals.hashes.remove(&obj_id).is_some(),
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{fs::Disk, object_store::lock_poisoned, ObjectStore, ObjectStoreError};
+use arc_swap::ArcSwapOption;
+use fatfs::IoBase;
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Returned by a `Pinned` store once it has been reopened since the
+/// pin was taken, so state derived from the old generation isn't used
+/// against the new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaleStore {
+    pub pinned: u64,
+    pub current: u64,
+}
+
+impl fmt::Display for StaleStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pinned to store generation {} but it has been reopened as generation {}",
+            self.pinned, self.current
+        )
+    }
+}
+
+impl std::error::Error for StaleStore {}
+
+impl From<StaleStore> for Error {
+    fn from(value: StaleStore) -> Self {
+        Error::other(value)
+    }
+}
+
+/// Returns the generations carried by `err`, if it was returned because
+/// the store was reopened under a pin.
+pub fn stale_store(err: &Error) -> Option<&StaleStore> {
+    err.get_ref()?.downcast_ref::<StaleStore>()
+}
+
+/// A store that can be reopened while other threads use it.
+///
+/// Each generation of the store sits behind an `ArcSwap`, and every
+/// operation runs on the generation it loaded. A reopen takes the
+/// current generation out, so operations that start while it runs fail
+/// with `Interrupted` rather than wait, lets the ones in flight finish
+/// on the old generation, and only then reopens the store as the next
+/// generation.
+pub struct SharedStore<D: Disk> {
+    /// `None` while a reopen is running.
+    current: ArcSwapOption<Generation<D>>,
+    generation: AtomicU64,
+    /// Held for the length of a reopen.
+    reopening: Mutex<()>,
+}
+
+struct Generation<D: Disk> {
+    store: ObjectStore<D>,
+    generation: u64,
+    /// Set when the reopen that made this generation failed part way,
+    /// leaving the store in no state fit to use.
+    broken: bool,
+}
+
+/// A view of a `SharedStore` that fails with `StaleStore` once the
+/// store is reopened.
+pub struct Pinned<'a, D: Disk> {
+    shared: &'a SharedStore<D>,
+    generation: u64,
+}
+
+impl<D> SharedStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(store: ObjectStore<D>) -> Self {
+        Self {
+            current: ArcSwapOption::from_pointee(Generation {
+                store,
+                generation: 0,
+                broken: false,
+            }),
+            generation: AtomicU64::new(0),
+            reopening: Mutex::new(()),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Runs `f` on the store. `f` may fail with an `ObjectStoreError`
+    /// or a `std::io::Error`.
+    ///
+    /// # Errors
+    /// `Interrupted` while the store is being reopened, and an error if
+    /// the last reopen failed, until one succeeds.
+    pub fn with<T, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&ObjectStore<D>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.with_generation(|current| f(&current.store))
+    }
+
+    fn with_generation<T, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&Generation<D>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        // a full `Arc`, so that a reopen waits for `f` to finish.
+        let Some(current) = self.current.load_full() else {
+            return Err(Error::new(ErrorKind::Interrupted, "the store is being reopened").into());
+        };
+        if current.broken {
+            return Err(Error::other("the store failed to reopen").into());
+        }
+        f(&current)
+    }
+
+    /// Pins the current generation.
+    pub fn pin(&self) -> Pinned<'_, D> {
+        Pinned {
+            shared: self,
+            generation: self.generation(),
+        }
+    }
+
+    /// Reopens the store once the operations in flight have finished.
+    /// Pins taken before now go stale, even if the reopen fails.
+    pub fn reopen(&self) -> Result<(), ObjectStoreError> {
+        let _reopening = self.reopening.lock().map_err(lock_poisoned)?;
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        let mut old = self
+            .current
+            .swap(None)
+            .expect("only a reopen takes the store out");
+        let mut store = loop {
+            match Arc::try_unwrap(old) {
+                Ok(old) => break old.store,
+                Err(in_flight) => {
+                    old = in_flight;
+                    std::thread::yield_now();
+                }
+            }
+        };
+        let res = store.reopen();
+        self.current.store(Some(Arc::new(Generation {
+            store,
+            generation,
+            broken: res.is_err(),
+        })));
+        res
+    }
+
+    pub fn into_inner(self) -> Result<ObjectStore<D>, ObjectStoreError> {
+        Ok(self
+            .current
+            .into_inner()
+            .and_then(Arc::into_inner)
+            .map(|current| current.store)
+            .ok_or_else(|| Error::other("the store is still in use"))?)
+    }
+}
+
+impl<D> Pinned<'_, D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Runs `f` on the store if it hasn't been reopened since the pin
+    /// was taken.
+    pub fn with<T, E: From<Error>>(
+        &self,
+        f: impl FnOnce(&ObjectStore<D>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.shared.with_generation(|current| {
+            // the generation `f` runs on, which can't be reopened
+            // before `f` is done.
+            if current.generation != self.generation {
+                return Err(Error::from(StaleStore {
+                    pinned: self.generation,
+                    current: current.generation,
+                })
+                .into());
+            }
+            f(&current.store)
+        })
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{io::Error, sync::atomic::Ordering, time::Duration};
+
+/// Re-encryption speed assumed before an epoch has been timed, in bytes
+/// per second.
+const DEFAULT_REENCRYPT_RATE: u64 = 64 * 1024 * 1024;
+
+/// Options used when closing a store.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CloseOptions {
+    final_epoch: bool,
+    epoch_budget: Option<Duration>,
+}
+
+impl CloseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances the epoch before closing, so everything deleted during
+    /// the session is securely deleted by the time the store is closed.
+    pub fn final_epoch(mut self, final_epoch: bool) -> Self {
+        self.final_epoch = final_epoch;
+        self
+    }
+
+    /// Skips the final epoch if it's expected to take longer than
+    /// `budget`, going by how fast the last epoch re-encrypted.
+    pub fn epoch_budget(mut self, budget: Duration) -> Self {
+        self.epoch_budget = Some(budget);
+        self
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Closes the store like `close`, first running a final epoch if
+    /// `options` ask for one and it fits the budget. Returns whether the
+    /// final epoch ran.
+    ///
+    /// If the epoch fails the store isn't closed, and is recovered like
+    /// a crashed one when it is next opened.
+    pub fn close_with(self, options: CloseOptions) -> Result<bool, ObjectStoreError> {
+        let ran = options.final_epoch && self.epoch_fits(options.epoch_budget)?;
+        if ran {
+            self.advance_epoch()?;
+        }
+        self.close()?;
+        Ok(ran)
+    }
+
+    /// Returns whether the next epoch is expected to take no longer than
+    /// `budget`.
+    pub(crate) fn epoch_fits(&self, budget: Option<Duration>) -> Result<bool, Error> {
+        let Some(budget) = budget else {
+            return Ok(true);
+        };
+        let bytes = self.estimate_epoch()?.bytes_to_reencrypt;
+        let rate = match self.reencrypt_rate.load(Ordering::Relaxed) {
+            0 => DEFAULT_REENCRYPT_RATE,
+            rate => rate,
+        };
+        Ok(Duration::from_secs_f64(bytes as f64 / rate as f64) <= budget)
+    }
+}
@@ -0,0 +1,380 @@
+use crate::{
+    fs::{Disk, FatFs, PAGE_SIZE},
+    meta::{seal, unseal},
+    ObjectStore, ObjectStoreError,
+};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20,
+};
+use fatfs::IoBase;
+use sha3::{Digest, Sha3_256};
+use std::{
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    sync::MutexGuard,
+};
+
+const MAGIC: &[u8; 8] = b"TOSSNAP\0";
+/// Version 1 archives store objects whole, version 2 as runs of data
+/// and holes.
+const VERSION: u32 = 2;
+const DATA_RUN: u8 = 1;
+const HOLE_RUN: u8 = 2;
+/// Kind and length in front of each run.
+const RUN_HEADER_LEN: usize = 1 + 8;
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 32;
+/// Objects are streamed through a buffer of this size.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Magic, version and the sealed archive key.
+const HEADER_LEN: usize = 8 + 4 + NONCE_LEN + 32 + TAG_LEN;
+/// Id, length and nonce in front of each object.
+const ENTRY_HEADER_LEN: usize = 16 + 8 + NONCE_LEN;
+/// Id, entry offset, length and mac of each object in the index.
+const INDEX_ENTRY_LEN: usize = 16 + 8 + 8 + TAG_LEN;
+/// Index offset followed by the magic.
+const TRAILER_LEN: usize = 8 + 8;
+
+/// A consistent view of the store. Writes to the store wait until the
+/// snapshot is dropped.
+pub struct Snapshot<'a, D: Disk> {
+    pub(crate) os: &'a ObjectStore<D>,
+    pub(crate) fs: MutexGuard<'a, FatFs<D>>,
+}
+
+/// An object listed in an archive's index.
+struct IndexEntry {
+    obj_id: u128,
+    offset: u64,
+    len: u64,
+    mac: [u8; TAG_LEN],
+}
+
+pub(crate) fn invalid(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Recovers an archive key sealed under `wrap_key`.
+pub(crate) fn unwrap_key(wrap_key: &[u8; 32], sealed: &[u8]) -> Result<[u8; 32], Error> {
+    unseal(wrap_key, sealed)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| invalid("wrong key for snapshot archive"))
+}
+
+fn object_mac(key: &[u8; 32], obj_id: u128) -> Sha3_256 {
+    let mut hasher = Sha3_256::new();
+    hasher.update(key);
+    hasher.update(obj_id.to_le_bytes());
+    hasher
+}
+
+fn run_header(kind: u8, len: u64) -> [u8; RUN_HEADER_LEN] {
+    let mut header = [0u8; RUN_HEADER_LEN];
+    header[0] = kind;
+    header[1..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+/// Splits `data` into runs of pages that are all zeros and runs of
+/// pages that aren't, returning whether each run is a hole.
+fn page_runs(data: &[u8]) -> Vec<(bool, Range<usize>)> {
+    let mut runs: Vec<(bool, Range<usize>)> = Vec::new();
+    for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+        let start = i * PAGE_SIZE;
+        let hole = page.iter().all(|b| *b == 0);
+        match runs.last_mut() {
+            Some((last, range)) if *last == hole => range.end = start + page.len(),
+            _ => runs.push((hole, start..start + page.len())),
+        }
+    }
+    runs
+}
+
+/// Encrypts `plaintext` in place and writes it to the archive, adding
+/// it to the object's mac first.
+fn put_encrypted<W: Write>(
+    out: &mut Counting<W>,
+    cipher: &mut ChaCha20,
+    mac: &mut Sha3_256,
+    plaintext: &mut [u8],
+) -> Result<(), Error> {
+    mac.update(&*plaintext);
+    cipher.apply_keystream(plaintext);
+    out.put(plaintext)
+}
+
+/// Reads and decrypts an object's entry, adding the plaintext to its
+/// mac.
+struct EntryReader<R> {
+    reader: R,
+    cipher: ChaCha20,
+    mac: Sha3_256,
+}
+
+impl<R: Read> EntryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.reader.read_exact(buf)?;
+        self.cipher.apply_keystream(buf);
+        self.mac.update(&*buf);
+        Ok(())
+    }
+}
+
+/// Tracks how far into the archive the writer is, since the index
+/// records where each object starts.
+pub(crate) struct Counting<W> {
+    pub(crate) inner: W,
+    pub(crate) pos: u64,
+}
+
+impl<W: Write> Counting<W> {
+    pub(crate) fn put(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.inner.write_all(bytes)?;
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl<D> Snapshot<'_, D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn object_ids(&self) -> Result<Vec<u128>, ObjectStoreError> {
+        Ok(self.os.object_ids_locked(&self.fs)?)
+    }
+
+    /// Streams every object into `writer` as an archive that can be
+    /// restored with `import_snapshot`, returning the archive's length.
+    /// Objects are encrypted under a fresh key, which is stored wrapped
+    /// with `wrap_key`.
+    ///
+    /// The archive is a header, each object's data, then an index of
+    /// where every object starts, so readers can seek straight to an
+    /// object. Only object contents are exported. Pages that are all
+    /// zeros are stored as holes, so mostly empty objects stay small.
+    pub fn export(
+        &mut self,
+        writer: impl Write,
+        wrap_key: &[u8; 32],
+    ) -> Result<u64, ObjectStoreError> {
+        let archive_key: [u8; 32] = rand::random();
+        let mut out = Counting {
+            inner: writer,
+            pos: 0,
+        };
+        out.put(MAGIC)?;
+        out.put(&VERSION.to_le_bytes())?;
+        out.put(&seal(wrap_key, &archive_key))?;
+        let mut index = Vec::new();
+        let mut buf = vec![0u8; CHUNK_LEN];
+        for obj_id in self.object_ids()? {
+            let len = self.os.object_len_locked(&mut self.fs, obj_id)?;
+            let nonce: [u8; NONCE_LEN] = rand::random();
+            let offset = out.pos;
+            out.put(&obj_id.to_le_bytes())?;
+            out.put(&len.to_le_bytes())?;
+            out.put(&nonce)?;
+            let mut cipher = ChaCha20::new((&archive_key).into(), &nonce.into());
+            let mut mac = object_mac(&archive_key, obj_id);
+            // holes are held back so that they span chunks.
+            let mut hole = 0;
+            let mut done = 0;
+            while done < len {
+                let n = CHUNK_LEN.min((len - done) as usize);
+                self.os
+                    .read_locked(&mut self.fs, obj_id, &mut buf[..n], done)?;
+                for (is_hole, range) in page_runs(&buf[..n]) {
+                    if is_hole {
+                        hole += range.len() as u64;
+                        continue;
+                    }
+                    if hole > 0 {
+                        let mut header = run_header(HOLE_RUN, hole);
+                        put_encrypted(&mut out, &mut cipher, &mut mac, &mut header)?;
+                        hole = 0;
+                    }
+                    let mut header = run_header(DATA_RUN, range.len() as u64);
+                    put_encrypted(&mut out, &mut cipher, &mut mac, &mut header)?;
+                    put_encrypted(&mut out, &mut cipher, &mut mac, &mut buf[range])?;
+                }
+                done += n as u64;
+            }
+            if hole > 0 {
+                let mut header = run_header(HOLE_RUN, hole);
+                put_encrypted(&mut out, &mut cipher, &mut mac, &mut header)?;
+            }
+            index.push(IndexEntry {
+                obj_id,
+                offset,
+                len,
+                mac: mac.finalize().into(),
+            });
+        }
+        let index_offset = out.pos;
+        out.put(&(index.len() as u64).to_le_bytes())?;
+        for entry in &index {
+            out.put(&entry.obj_id.to_le_bytes())?;
+            out.put(&entry.offset.to_le_bytes())?;
+            out.put(&entry.len.to_le_bytes())?;
+            out.put(&entry.mac)?;
+        }
+        out.put(&index_offset.to_le_bytes())?;
+        out.put(MAGIC)?;
+        out.inner.flush()?;
+        Ok(out.pos)
+    }
+}
+
+pub(crate) fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], Error> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Takes a consistent snapshot of the store, blocking writers until
+    /// it is dropped.
+    pub fn snapshot(&self) -> Snapshot<'_, D> {
+        Snapshot {
+            os: self,
+            fs: self.fs().lock().unwrap(),
+        }
+    }
+
+    /// Restores every object in an archive written by
+    /// `Snapshot::export`, which must start at the beginning of
+    /// `reader`, replacing objects with the same id. Returns
+    /// the ids restored. An object whose data fails its integrity check
+    /// is unlinked again before the error is returned.
+    ///
+    /// Holes are never written out: the next write past one leaves it
+    /// unwritten, and a trailing hole is made by writing the last byte.
+    pub fn import_snapshot(
+        &self,
+        mut reader: impl Read + Seek,
+        wrap_key: &[u8; 32],
+    ) -> Result<Vec<u128>, ObjectStoreError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header: [u8; HEADER_LEN] = read_array(&mut reader)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a snapshot archive").into());
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid("unsupported snapshot archive version").into());
+        }
+        let archive_key = unwrap_key(wrap_key, &header[12..])?;
+        reader.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+        let trailer: [u8; TRAILER_LEN] = read_array(&mut reader)?;
+        if &trailer[8..] != MAGIC {
+            return Err(invalid("snapshot archive truncated").into());
+        }
+        reader.seek(SeekFrom::Start(u64::from_le_bytes(
+            trailer[..8].try_into().unwrap(),
+        )))?;
+        let count = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut index = Vec::new();
+        for _ in 0..count {
+            let raw: [u8; INDEX_ENTRY_LEN] = read_array(&mut reader)?;
+            index.push(IndexEntry {
+                obj_id: u128::from_le_bytes(raw[..16].try_into().unwrap()),
+                offset: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+                len: u64::from_le_bytes(raw[24..32].try_into().unwrap()),
+                mac: raw[32..].try_into().unwrap(),
+            });
+        }
+        let mut buf = vec![0u8; CHUNK_LEN];
+        let mut restored = Vec::new();
+        for entry in index {
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            let header: [u8; ENTRY_HEADER_LEN] = read_array(&mut reader)?;
+            if header[..16] != entry.obj_id.to_le_bytes()
+                || header[16..24] != entry.len.to_le_bytes()
+            {
+                return Err(invalid("snapshot archive index doesn't match its data").into());
+            }
+            let nonce: [u8; NONCE_LEN] = header[24..].try_into().unwrap();
+            let mut entry_reader = EntryReader {
+                reader: &mut reader,
+                cipher: ChaCha20::new((&archive_key).into(), &nonce.into()),
+                mac: object_mac(&archive_key, entry.obj_id),
+            };
+            self.create_or_truncate(entry.obj_id)?;
+            if version == 1 {
+                self.import_data(&mut entry_reader, &mut buf, entry.obj_id, 0..entry.len)?;
+            } else {
+                self.import_runs(&mut entry_reader, &mut buf, entry.obj_id, entry.len)?;
+            }
+            if entry_reader.mac.finalize().as_slice() != entry.mac {
+                self.unlink_object(entry.obj_id)?;
+                return Err(invalid("snapshot archive failed integrity check").into());
+            }
+            restored.push(entry.obj_id);
+        }
+        Ok(restored)
+    }
+
+    /// Restores an object stored as runs of data and holes.
+    fn import_runs(
+        &self,
+        reader: &mut EntryReader<impl Read>,
+        buf: &mut [u8],
+        obj_id: u128,
+        len: u64,
+    ) -> Result<(), Error> {
+        let mut done = 0;
+        let mut trailing_hole = false;
+        while done < len {
+            let mut run = [0u8; RUN_HEADER_LEN];
+            reader.read(&mut run)?;
+            let run_len = u64::from_le_bytes(run[1..].try_into().unwrap());
+            if run_len == 0 || run_len > len - done {
+                return Err(invalid("snapshot archive has a malformed run"));
+            }
+            match run[0] {
+                DATA_RUN => self.import_data(reader, buf, obj_id, done..done + run_len)?,
+                HOLE_RUN => {}
+                _ => return Err(invalid("snapshot archive has a malformed run")),
+            }
+            trailing_hole = run[0] == HOLE_RUN;
+            done += run_len;
+        }
+        if trailing_hole {
+            self.write_all(obj_id, &[0], len - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the bytes of an entry covering `range` of an object into
+    /// it.
+    fn import_data(
+        &self,
+        reader: &mut EntryReader<impl Read>,
+        buf: &mut [u8],
+        obj_id: u128,
+        range: Range<u64>,
+    ) -> Result<(), Error> {
+        let mut done = range.start;
+        while done < range.end {
+            let n = buf.len().min((range.end - done) as usize);
+            reader.read(&mut buf[..n])?;
+            self.write_all(obj_id, &buf[..n], done)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+}
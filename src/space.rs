@@ -0,0 +1,59 @@
+use crate::fs::PAGE_SIZE;
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+};
+
+/// Returned with `ErrorKind::StorageFull` when a write would need more
+/// clusters than the volume has free. Nothing was written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoSpace {
+    pub obj_id: u128,
+    /// Clusters the write would have allocated.
+    pub needed_clusters: u64,
+    pub free_clusters: u64,
+}
+
+impl fmt::Display for NoSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "writing object {:0>32x} needs {} clusters but only {} are free",
+            self.obj_id, self.needed_clusters, self.free_clusters
+        )
+    }
+}
+
+impl std::error::Error for NoSpace {}
+
+impl From<NoSpace> for Error {
+    fn from(value: NoSpace) -> Self {
+        Error::new(ErrorKind::StorageFull, value)
+    }
+}
+
+/// Returns the details of a write refused for lack of space, if that is
+/// what `err` is.
+pub fn no_space(err: &Error) -> Option<&NoSpace> {
+    err.get_ref()?.downcast_ref::<NoSpace>()
+}
+
+/// Fails if growing an object from `len` to `new_len` bytes needs more
+/// than `free_clusters` clusters, which are always one page.
+pub(crate) fn check_space(
+    obj_id: u128,
+    len: u64,
+    new_len: u64,
+    free_clusters: u64,
+) -> Result<(), NoSpace> {
+    let page = PAGE_SIZE as u64;
+    let needed_clusters = new_len.div_ceil(page).saturating_sub(len.div_ceil(page));
+    if needed_clusters > free_clusters {
+        return Err(NoSpace {
+            obj_id,
+            needed_clusters,
+            free_clusters,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use crate::{
+    fs::{Disk, FatFlavor, FatFs, FsConfig},
+    identity::MediaIdentity,
+    mac::IntegrityHash,
+    meta::{derive_subkey, mac, TAG_LEN},
+    metadata_disk::MetadataPlacement,
+};
+use fatfs::{Read as _, Write as _};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+const MAGIC: [u8; 8] = *b"TWZOBJST";
+const VERSION: u32 = 3;
+const SUPERBLOCK_PATH: &str = "superblock";
+const SUPERBLOCK_KEY_LABEL: &[u8] = b"object-store superblock key";
+
+/// How object data is keyed on disk. Chosen when the store is formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum KeyMode {
+    /// Every chunk has its own key tracked by the KHF, giving secure
+    /// deletion on epochs.
+    #[default]
+    Khf,
+    /// All data is encrypted with a single volume key derived from
+    /// the root key. There is no KHF, WAL or epoch overhead, and
+    /// therefore no secure deletion.
+    ///
+    /// Freeing a chunk moves it on to a new generation, which gives it
+    /// a new key, so a reused cluster never repeats the keystream of its
+    /// old contents. Bytes rewritten in place do reuse it.
+    Volume,
+    /// Data is stored unencrypted. Only meant for debugging and for
+    /// measuring the cost of the crypto path.
+    Plaintext,
+}
+
+impl KeyMode {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyMode::Khf => 0,
+            KeyMode::Volume => 1,
+            KeyMode::Plaintext => 2,
+        }
+    }
+
+    /// A label written next to the mode byte so that the mode can be
+    /// read off of a raw image with a hex editor.
+    fn label(self) -> &'static [u8] {
+        match self {
+            KeyMode::Khf => b"khf",
+            KeyMode::Volume => b"volume key",
+            KeyMode::Plaintext => b"PLAINTEXT",
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self, Error> {
+        match b {
+            0 => Ok(KeyMode::Khf),
+            1 => Ok(KeyMode::Volume),
+            2 => Ok(KeyMode::Plaintext),
+            _ => Err(Error::new(ErrorKind::InvalidData, "unknown key mode")),
+        }
+    }
+}
+
+/// Format parameters recorded in the root of the volume, followed by a
+/// MAC under a key derived from the root key. The key mode and clean
+/// flag decide how the rest of the disk is trusted, so a superblock
+/// that fails its MAC is refused rather than read.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub(crate) struct Superblock {
+    pub key_mode: KeyMode,
+    /// Random id chosen at format. 0 for volumes formatted before
+    /// stores had an id.
+    pub uuid: u128,
+    /// Bumped on every epoch.
+    pub generation: u64,
+    /// Set when the KHF files were left settled, by a finished epoch
+    /// or a close. Opening a clean store skips KHF recovery.
+    pub clean: bool,
+    /// Object file names are a keyed hash of the object id.
+    pub blind_ids: bool,
+    /// Stored as 0, meaning SHA3, by versions that predate the choice.
+    pub integrity_hash: IntegrityHash,
+    /// What is kept on a separate metadata disk, if anything.
+    pub metadata: MetadataPlacement,
+}
+
+impl Superblock {
+    pub fn identity(&self) -> MediaIdentity {
+        MediaIdentity {
+            uuid: self.uuid,
+            generation: self.generation,
+        }
+    }
+
+    /// Bytes covered by the MAC, which follows them.
+    const BODY_LEN: usize = 64;
+    const LEN: usize = Self::BODY_LEN + TAG_LEN;
+
+    fn to_bytes(&self, key: &[u8; 32]) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0..8].copy_from_slice(&MAGIC);
+        out[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        out[12] = self.key_mode.to_byte();
+        out[13] = self.clean as u8;
+        out[14] = self.blind_ids as u8;
+        out[15] = self.integrity_hash.to_byte();
+        let label = self.key_mode.label();
+        out[16..16 + label.len()].copy_from_slice(label);
+        out[32..48].copy_from_slice(&self.uuid.to_le_bytes());
+        out[48..56].copy_from_slice(&self.generation.to_le_bytes());
+        out[56] = self.metadata.to_byte();
+        let tag = mac(key, &[], &out[..Self::BODY_LEN]);
+        out[Self::BODY_LEN..].copy_from_slice(&tag);
+        out
+    }
+
+    fn from_bytes(buf: &[u8], key: &[u8; 32]) -> Result<Self, Error> {
+        if buf.len() < 12 || buf[0..8] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "bad superblock magic"));
+        }
+        let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        // earlier versions carried no MAC, so nothing they say can be
+        // trusted.
+        if version != VERSION || buf.len() < Self::LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported superblock version",
+            ));
+        }
+        let (body, tag) = buf[..Self::LEN].split_at(Self::BODY_LEN);
+        if mac(key, &[], body) != tag {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "superblock failed integrity check",
+            ));
+        }
+        Ok(Self {
+            key_mode: KeyMode::from_byte(buf[12])?,
+            uuid: u128::from_le_bytes(buf[32..48].try_into().unwrap()),
+            generation: u64::from_le_bytes(buf[48..56].try_into().unwrap()),
+            clean: buf[13] == 1,
+            blind_ids: buf[14] == 1,
+            integrity_hash: IntegrityHash::from_byte(buf[15])?,
+            metadata: MetadataPlacement::from_byte(buf[56])?,
+        })
+    }
+
+    /// Reads the superblock, checking its MAC with a key derived from
+    /// `root_key`. Volumes formatted before the superblock existed
+    /// don't have one, so `None` is returned for them.
+    pub fn load<D>(fs: &FatFs<D>, root_key: [u8; 32]) -> Result<Option<Self>, Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+    {
+        let mut file = match fs.root_dir().open_file(SUPERBLOCK_PATH) {
+            Ok(file) => file,
+            Err(fatfs::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = [0u8; Self::LEN];
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let key = derive_subkey(root_key, SUPERBLOCK_KEY_LABEL);
+        Ok(Some(Self::from_bytes(&buf[..len], &key)?))
+    }
+
+    pub fn store<D>(&self, fs: &FatFs<D>, root_key: [u8; 32]) -> Result<(), Error>
+    where
+        D: Disk,
+        std::io::Error: From<fatfs::Error<D::Error>>,
+    {
+        let mut file = fs.root_dir().create_file(SUPERBLOCK_PATH)?;
+        file.truncate()?;
+        let key = derive_subkey(root_key, SUPERBLOCK_KEY_LABEL);
+        file.write_all(&self.to_bytes(&key))?;
+        Ok(())
+    }
+}
+
+/// Options used when formatting a new store.
+#[derive(Clone, Debug, Default)]
+pub struct FormatOptions {
+    pub(crate) key_mode: KeyMode,
+    pub(crate) blind_ids: bool,
+    pub(crate) fs_config: FsConfig,
+    pub(crate) chunk_size: Option<u64>,
+    pub(crate) integrity_hash: IntegrityHash,
+}
+
+impl FormatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key_mode(mut self, key_mode: KeyMode) -> Self {
+        self.key_mode = key_mode;
+        self
+    }
+
+    /// Names object files with a keyed hash of the object id, so that
+    /// raw disk access doesn't reveal which objects exist.
+    pub fn blind_ids(mut self, blind_ids: bool) -> Self {
+        self.blind_ids = blind_ids;
+        self
+    }
+
+    /// The parameters the new volume is opened with.
+    pub fn fs_config(mut self, fs_config: FsConfig) -> Self {
+        self.fs_config = fs_config;
+        self
+    }
+
+    pub fn fat_flavor(mut self, fat_flavor: FatFlavor) -> Self {
+        self.fs_config = self.fs_config.fat_flavor(fat_flavor);
+        self
+    }
+
+    /// Keys data in chunks of `chunk_size` bytes instead of one key per
+    /// cluster. Larger chunks shrink the KHF and need fewer derivations
+    /// per byte, but secure deletion works a chunk at a time. Must be a
+    /// power of two pages, up to `MAX_CHUNK_SIZE`, and needs a volume
+    /// with room for the raw header. Volume keyed stores can't have
+    /// chunks larger than a cluster.
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// The hash page MACs are computed with. Defaults to SHA3.
+    pub fn integrity_hash(mut self, integrity_hash: IntegrityHash) -> Self {
+        self.integrity_hash = integrity_hash;
+        self
+    }
+
+    pub(crate) fn superblock(&self) -> Superblock {
+        Superblock {
+            key_mode: self.key_mode,
+            uuid: rand::random(),
+            generation: 0,
+            clean: true,
+            blind_ids: self.blind_ids,
+            integrity_hash: self.integrity_hash,
+            metadata: MetadataPlacement::DataDisk,
+        }
+    }
+}
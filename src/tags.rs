@@ -0,0 +1,133 @@
+use crate::{
+    fs::{Disk, FatFs},
+    meta::update_meta,
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Error, ErrorKind},
+};
+
+pub(crate) const TAGS_PATH: &str = "meta/tags";
+/// The longest tag that can be attached to an object.
+pub const MAX_TAG_LEN: usize = 255;
+
+/// Maps each tag to the set of objects carrying it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct TagIndex {
+    tags: BTreeMap<Vec<u8>, BTreeSet<u128>>,
+}
+
+impl TagIndex {
+    /// Returns true if the tag wasn't already on the object.
+    pub fn add(&mut self, obj_id: u128, tag: &[u8]) -> bool {
+        self.tags.entry(tag.to_vec()).or_default().insert(obj_id)
+    }
+
+    /// Returns true if the tag was on the object.
+    pub fn remove(&mut self, obj_id: u128, tag: &[u8]) -> bool {
+        let Some(objects) = self.tags.get_mut(tag) else {
+            return false;
+        };
+        let removed = objects.remove(&obj_id);
+        if objects.is_empty() {
+            self.tags.remove(tag);
+        }
+        removed
+    }
+
+    /// Removes every tag from an object. Returns true if the object
+    /// had any tags.
+    pub fn remove_object(&mut self, obj_id: u128) -> bool {
+        let mut removed = false;
+        self.tags.retain(|_, objects| {
+            removed |= objects.remove(&obj_id);
+            !objects.is_empty()
+        });
+        removed
+    }
+
+    pub fn objects_with(&self, tag: &[u8]) -> Vec<u128> {
+        self.tags
+            .get(tag)
+            .map(|objects| objects.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn tags_of(&self, obj_id: u128) -> Vec<Vec<u8>> {
+        self.tags
+            .iter()
+            .filter(|(_, objects)| objects.contains(&obj_id))
+            .map(|(tag, _)| tag.clone())
+            .collect()
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Attaches a tag to an object. Returns false if the object already
+    /// had the tag.
+    pub fn add_tag(&self, obj_id: u128, tag: impl AsRef<[u8]>) -> Result<bool, ObjectStoreError> {
+        let tag = tag.as_ref();
+        if tag.len() > MAX_TAG_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "tag too long").into());
+        }
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        get_dir_path(&fs, &b64)?.open_file(&b64)?;
+        let mut tags = self.tags.lock().unwrap();
+        Ok(update_meta(
+            &fs,
+            &self.meta_key(),
+            TAGS_PATH,
+            &mut *tags,
+            |tags| tags.add(obj_id, tag),
+        )?)
+    }
+
+    /// Removes a tag from an object. Returns false if the object didn't
+    /// have the tag.
+    pub fn remove_tag(
+        &self,
+        obj_id: u128,
+        tag: impl AsRef<[u8]>,
+    ) -> Result<bool, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let mut tags = self.tags.lock().unwrap();
+        Ok(update_meta(
+            &fs,
+            &self.meta_key(),
+            TAGS_PATH,
+            &mut *tags,
+            |tags| tags.remove(obj_id, tag.as_ref()),
+        )?)
+    }
+
+    /// Returns every tag attached to an object.
+    pub fn tags(&self, obj_id: u128) -> Vec<Vec<u8>> {
+        self.tags.lock().unwrap().tags_of(obj_id)
+    }
+
+    /// Returns every object carrying `tag`.
+    pub fn objects_with_tag(&self, tag: impl AsRef<[u8]>) -> Vec<u128> {
+        self.tags.lock().unwrap().objects_with(tag.as_ref())
+    }
+
+    pub(crate) fn forget_tags(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut tags = self.tags.lock().unwrap();
+        update_meta(fs, &self.meta_key(), TAGS_PATH, &mut *tags, |tags| {
+            tags.remove_object(obj_id)
+        })?;
+        Ok(())
+    }
+}
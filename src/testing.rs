@@ -0,0 +1,326 @@
+//! Helpers for fuzzing the store: random operation sequences over an
+//! in-memory disk with simulated crashes, and a checker for invariants
+//! that must hold whatever happened before.
+use crate::{
+    fs::{Disk, FatDir, PAGE_SIZE},
+    wal_log::WalOp,
+    wrapped_extent::WrappedExtent,
+    FatFlavor, FormatOptions, FsConfig, ObjectStore,
+};
+use fatfs::IoBase;
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+pub use crate::{fault::KmsFaults, mem_disk::MemDisk};
+
+const ROOT_KEY: [u8; 32] = [7; 32];
+/// Small enough to allocate on every fuzz run, formatted as FAT12.
+const IMAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// A disk that loses power after a number of writes. Every write after
+/// that fails, and the image is left as the last successful write put
+/// it, ready to be opened by a fresh `CrashDisk`.
+pub struct CrashDisk {
+    image: Arc<MemDisk>,
+    writes_left: AtomicU64,
+}
+
+impl CrashDisk {
+    pub fn new(image: Arc<MemDisk>, writes_left: u64) -> Self {
+        Self {
+            image,
+            writes_left: AtomicU64::new(writes_left),
+        }
+    }
+
+    /// A disk that never crashes.
+    pub fn reliable(image: Arc<MemDisk>) -> Self {
+        Self::new(image, u64::MAX)
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.writes_left.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl IoBase for CrashDisk {
+    type Error = Error;
+}
+
+impl Disk for CrashDisk {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.image.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let left = self
+            .writes_left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            });
+        if left.is_err() {
+            return Err(Error::other("the disk lost power"));
+        }
+        self.image.write_at(offset, buf)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        if self.crashed() {
+            return Err(Error::other("the disk lost power"));
+        }
+        self.image.flush()
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        self.image.size()
+    }
+}
+
+/// One step of a fuzzed run. Object ids are kept small so that
+/// operations keep hitting the same objects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Create(u8),
+    Write {
+        obj_id: u8,
+        offset: u16,
+        len: u16,
+        byte: u8,
+    },
+    Read {
+        obj_id: u8,
+        offset: u16,
+        len: u16,
+    },
+    Unlink(u8),
+    Epoch,
+    /// Cuts the power after this many more disk writes.
+    Crash {
+        after_writes: u16,
+    },
+}
+
+impl Op {
+    /// Decodes fuzzer input into operations, ignoring a trailing
+    /// partial operation.
+    pub fn decode(mut data: &[u8]) -> Vec<Op> {
+        let mut ops = Vec::new();
+        let u16_at = |data: &[u8], i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        while let Some((&tag, rest)) = data.split_first() {
+            let (op, len) = match tag % 6 {
+                0 if !rest.is_empty() => (Op::Create(rest[0]), 1),
+                1 if rest.len() >= 6 => (
+                    Op::Write {
+                        obj_id: rest[0],
+                        offset: u16_at(rest, 1),
+                        len: u16_at(rest, 3),
+                        byte: rest[5],
+                    },
+                    6,
+                ),
+                2 if rest.len() >= 5 => (
+                    Op::Read {
+                        obj_id: rest[0],
+                        offset: u16_at(rest, 1),
+                        len: u16_at(rest, 3),
+                    },
+                    5,
+                ),
+                3 if !rest.is_empty() => (Op::Unlink(rest[0]), 1),
+                4 => (Op::Epoch, 0),
+                5 if rest.len() >= 2 => (
+                    Op::Crash {
+                        after_writes: u16_at(rest, 0),
+                    },
+                    2,
+                ),
+                _ => break,
+            };
+            ops.push(op);
+            data = &rest[len..];
+        }
+        ops
+    }
+}
+
+/// A broken invariant found by `check_invariants`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// A page of an object couldn't be read back.
+    UnreadablePage { obj_id: u128, offset: u64 },
+    /// A key was derived for a chunk no file holds.
+    KeyWithoutExtent { chunk_id: u64 },
+}
+
+/// Adds the chunk of every page held by a file under `dir`.
+fn collect_chunks<D>(
+    os: &ObjectStore<D>,
+    dir: &FatDir<'_, D>,
+    chunks: &mut HashSet<u64>,
+) -> Result<(), Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    for entry in dir.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        if entry.is_dir() {
+            collect_chunks(os, &entry.to_dir(), chunks)?;
+            continue;
+        }
+        for extent in entry.to_file().extents() {
+            let extent = WrappedExtent::from(extent?);
+            chunks.extend(extent.page_offsets().map(|page| os.layout.chunk_id(page)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every page of every object can be read, and that every
+/// key derived since the store was opened belongs to a chunk some file
+/// holds.
+pub fn check_invariants<D>(os: &ObjectStore<D>) -> Result<Vec<Violation>, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut violations = Vec::new();
+    let (ids, held) = {
+        let fs = os.fs().lock().unwrap();
+        let mut held = HashSet::new();
+        collect_chunks(os, &fs.root_dir(), &mut held)?;
+        (os.scan_object_ids(&fs)?, held)
+    };
+    for obj_id in ids {
+        let len = os.disk_length(obj_id)?;
+        let mut page = vec![0u8; PAGE_SIZE];
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            let n = (len - offset).min(PAGE_SIZE as u64) as usize;
+            if os.read_exact(obj_id, &mut page[..n], offset).is_err() {
+                violations.push(Violation::UnreadablePage { obj_id, offset });
+            }
+        }
+    }
+    let entries = os.wal_journal(|journal| journal.entries())?;
+    for entry in entries.into_iter().flatten() {
+        if entry.op == WalOp::Derive && !held.contains(&entry.chunk_id) {
+            violations.push(Violation::KeyWithoutExtent {
+                chunk_id: entry.chunk_id,
+            });
+        }
+    }
+    Ok(violations)
+}
+
+fn open(image: &Arc<MemDisk>) -> ObjectStore<CrashDisk> {
+    ObjectStore::open_takeover(CrashDisk::reliable(image.clone()), ROOT_KEY)
+        .expect("the store failed to recover")
+}
+
+fn assert_invariants(os: &ObjectStore<CrashDisk>) {
+    let violations = check_invariants(os).expect("the invariant check failed");
+    assert!(violations.is_empty(), "broken invariants: {violations:?}");
+}
+
+/// Runs `ops` against a fresh store, panicking if the store fails to
+/// recover from a crash, breaks an invariant or, while no crash is
+/// pending, reads back something other than what was written.
+pub fn run_ops(ops: &[Op]) {
+    let image = Arc::new(MemDisk::new(IMAGE_SIZE));
+    let options = FormatOptions::new().fs_config(FsConfig::new().fat_flavor(FatFlavor::Fat12));
+    ObjectStore::format(CrashDisk::reliable(image.clone()), ROOT_KEY, options)
+        .expect("formatting failed")
+        .close()
+        .expect("closing failed");
+    let mut os = open(&image);
+    // what every object holds, forgotten once a crash makes it unknown.
+    let mut model: Option<BTreeMap<u128, Vec<u8>>> = Some(BTreeMap::new());
+    for op in ops {
+        if os.fs.disk().inner().crashed() {
+            drop(os);
+            os = open(&image);
+            assert_invariants(&os);
+        }
+        match *op {
+            Op::Create(obj_id) => {
+                if os.create_object(obj_id.into()).is_ok() {
+                    if let Some(model) = &mut model {
+                        model.entry(obj_id.into()).or_default();
+                    }
+                }
+            }
+            Op::Write {
+                obj_id,
+                offset,
+                len,
+                byte,
+            } => {
+                let buf = vec![byte; len as usize];
+                let res = os.write_all(obj_id.into(), &buf, offset.into());
+                if let (Ok(()), Some(model)) = (res, &mut model) {
+                    let data = model.entry(obj_id.into()).or_default();
+                    let end = offset as usize + buf.len();
+                    if data.len() < end {
+                        data.resize(end, 0);
+                    }
+                    data[offset as usize..end].copy_from_slice(&buf);
+                }
+            }
+            Op::Read {
+                obj_id,
+                offset,
+                len,
+            } => {
+                let mut buf = vec![0u8; len as usize];
+                let res = os.read_exact(obj_id.into(), &mut buf, offset.into());
+                let expected = model
+                    .as_ref()
+                    .and_then(|model| model.get(&u128::from(obj_id)))
+                    .and_then(|data| data.get(offset as usize..offset as usize + len as usize));
+                if let (Ok(()), Some(expected)) = (res, expected) {
+                    assert_eq!(buf, expected, "object {obj_id} read back wrong");
+                }
+            }
+            Op::Unlink(obj_id) => {
+                if os.unlink_object(obj_id.into()).is_ok() {
+                    if let Some(model) = &mut model {
+                        model.remove(&u128::from(obj_id));
+                    }
+                }
+            }
+            Op::Epoch => {
+                let _ = os.advance_epoch();
+            }
+            Op::Crash { after_writes } => {
+                let _ = os.close();
+                let disk = CrashDisk::new(image.clone(), after_writes.into());
+                // the power may go out while the store is opening.
+                os = ObjectStore::open_takeover(disk, ROOT_KEY).unwrap_or_else(|_| open(&image));
+                model = None;
+            }
+        }
+    }
+    if os.fs.disk().inner().crashed() {
+        drop(os);
+        os = open(&image);
+    }
+    assert_invariants(&os);
+}
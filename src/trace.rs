@@ -0,0 +1,37 @@
+//! Spans and events for the `tracing` crate. Without the `tracing`
+//! feature they compile to nothing. Keys are never recorded.
+
+/// Enters a debug level span for an operation, which lasts until the
+/// returned guard is dropped.
+#[cfg(feature = "tracing")]
+macro_rules! op_span {
+    ($($arg:tt)*) => {
+        tracing::debug_span!($($arg)*).entered()
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! op_span {
+    ($($arg:tt)*) => {
+        $crate::trace::NoSpan
+    };
+}
+
+/// Records a debug level event in the current span.
+#[cfg(feature = "tracing")]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_event {
+    ($($arg:tt)*) => {};
+}
+
+/// Stands in for a span guard when tracing is compiled out.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+pub(crate) use {debug_event, op_span};
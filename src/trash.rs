@@ -0,0 +1,203 @@
+use crate::{
+    expiry::to_secs,
+    fs::{Disk, FatFs},
+    meta::{read_meta, update_meta},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::MutexGuard,
+    time::{Duration, SystemTime},
+};
+
+pub(crate) const TRASH_PATH: &str = "meta/trash";
+const TRASH_DIR: &str = "trash";
+
+/// When each trashed object was unlinked, in seconds since the unix
+/// epoch.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct TrashIndex {
+    trashed_at: BTreeMap<u128, u64>,
+}
+
+fn trash_path(b64: &str) -> String {
+    format!("{}/{}", TRASH_DIR, b64)
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    fn trash_lock(&self, fs: &FatFs<D>) -> Result<MutexGuard<'_, Option<TrashIndex>>, Error> {
+        let mut trash = self.trash.lock().unwrap();
+        if trash.is_none() {
+            *trash = Some(read_meta(fs, &self.meta_key(), TRASH_PATH)?.unwrap_or_default());
+        }
+        Ok(trash)
+    }
+
+    /// Makes `unlink_object` move objects into the trash, where they
+    /// are kept for at least `retention` before `purge` deletes them.
+    /// Passing `None` turns soft deletes off again.
+    pub fn set_trash_retention(&self, retention: Option<Duration>) {
+        *self.trash_retention.lock().unwrap() = retention;
+    }
+
+    pub fn trash_retention(&self) -> Option<Duration> {
+        *self.trash_retention.lock().unwrap()
+    }
+
+    /// Moves an object into the trash. Its keys are left in place so
+    /// that it can be restored, while its tags and index entries are
+    /// kept until it is purged. An older trashed copy of the same
+    /// object is purged first.
+    pub(crate) fn move_to_trash(&self, obj_id: u128) -> Result<(), Error> {
+        let already_trashed = {
+            let fs = self.fs().lock().unwrap();
+            fs.root_dir().open_file(&self.object_path(obj_id))?;
+            let trash = self.trash_lock(&fs)?;
+            trash.as_ref().unwrap().trashed_at.contains_key(&obj_id)
+        };
+        if already_trashed {
+            self.purge_object(obj_id)?;
+        }
+        let fs = self.fs().lock().unwrap();
+        let root = fs.root_dir();
+        root.create_dir(TRASH_DIR)?;
+        root.rename(
+            &self.object_path(obj_id),
+            &root,
+            &trash_path(&self.encode_obj_id(obj_id)),
+        )?;
+        self.manifest_unlinked(&fs, obj_id)?;
+        let mut trash = self.trash_lock(&fs)?;
+        update_meta(
+            &fs,
+            &self.meta_key(),
+            TRASH_PATH,
+            trash.as_mut().unwrap(),
+            |trash| {
+                trash.trashed_at.insert(obj_id, to_secs(SystemTime::now()));
+                true
+            },
+        )?;
+        self.access.forget(obj_id);
+        self.extents.remove(obj_id);
+        self.forget_expiry(&fs, obj_id)?;
+        Ok(())
+    }
+
+    /// Moves an object back out of the trash. Creating an object with
+    /// the same id purges the trashed copy, so it can't be restored
+    /// after that.
+    ///
+    /// # Errors
+    /// When the object isn't in the trash, or when an object with the
+    /// same id exists.
+    pub fn restore(&self, obj_id: u128) -> Result<(), ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        match get_dir_path(&fs, &b64)?.open_file(&b64) {
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "object was recreated after being trashed",
+                )
+                .into())
+            }
+            Err(fatfs::Error::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut trash = self.trash_lock(&fs)?;
+        let trash = trash.as_mut().unwrap();
+        if !trash.trashed_at.contains_key(&obj_id) {
+            return Err(Error::new(ErrorKind::NotFound, "object is not in the trash").into());
+        }
+        self.manifest_created(&fs, obj_id)?;
+        let root = fs.root_dir();
+        root.rename(
+            &trash_path(&self.encode_obj_id(obj_id)),
+            &root,
+            &self.object_path(obj_id),
+        )?;
+        update_meta(&fs, &self.meta_key(), TRASH_PATH, trash, |trash| {
+            trash.trashed_at.remove(&obj_id).is_some()
+        })?;
+        Ok(())
+    }
+
+    /// Returns the ids of every object in the trash.
+    pub fn trashed_objects(&self) -> Result<Vec<u128>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let trash = self.trash_lock(&fs)?;
+        Ok(trash.as_ref().unwrap().trashed_at.keys().copied().collect())
+    }
+
+    /// Permanently deletes every trashed object that has outlived the
+    /// trash retention, returning their ids. With soft deletes off the
+    /// whole trash is purged.
+    /// # Safety
+    /// Like `unlink_object`, the purged objects are only securely
+    /// deleted once an epoch has been advanced.
+    pub fn purge(&self) -> Result<Vec<u128>, ObjectStoreError> {
+        let expired = self.purgeable_objects()?;
+        for obj_id in &expired {
+            self.purge_object(*obj_id)?;
+        }
+        Ok(expired)
+    }
+
+    /// Returns the ids of the trashed objects that have outlived the
+    /// trash retention.
+    pub(crate) fn purgeable_objects(&self) -> Result<Vec<u128>, Error> {
+        let retention = self.trash_retention().unwrap_or_default().as_secs();
+        let now = to_secs(SystemTime::now());
+        let fs = self.fs().lock().unwrap();
+        let trash = self.trash_lock(&fs)?;
+        Ok(trash
+            .as_ref()
+            .unwrap()
+            .trashed_at
+            .iter()
+            .filter(|(_, trashed_at)| trashed_at.saturating_add(retention) <= now)
+            .map(|(obj_id, _)| *obj_id)
+            .collect())
+    }
+
+    pub(crate) fn purge_object(&self, obj_id: u128) -> Result<(), Error> {
+        let fs = self.fs().lock().unwrap();
+        self.discard_trashed(&fs, obj_id)?;
+        // the tags and index entries belong to the live object if it
+        // has been recreated.
+        let b64 = self.encode_obj_id(obj_id);
+        let live = get_dir_path(&fs, &b64)?.open_file(&b64).is_ok();
+        if !live {
+            self.forget_metadata(&fs, obj_id)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the trashed copy of an object, if there is one, leaving
+    /// the metadata of the id alone.
+    pub(crate) fn discard_trashed(&self, fs: &FatFs<D>, obj_id: u128) -> Result<(), Error> {
+        let mut trash = self.trash_lock(fs)?;
+        let trash = trash.as_mut().unwrap();
+        if !trash.trashed_at.contains_key(&obj_id) {
+            return Ok(());
+        }
+        self.destroy_object(fs, obj_id, &trash_path(&self.encode_obj_id(obj_id)))?;
+        update_meta(fs, &self.meta_key(), TRASH_PATH, trash, |trash| {
+            trash.trashed_at.remove(&obj_id).is_some()
+        })?;
+        Ok(())
+    }
+}
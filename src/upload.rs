@@ -0,0 +1,244 @@
+use crate::{
+    flags::ObjectFlags,
+    fs::{Disk, PAGE_SIZE},
+    meta::{read_meta, write_meta},
+    object_store::get_dir_path,
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::{IoBase, Seek, SeekFrom};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+pub(crate) const UPLOAD_DIR: &str = "tmp/uploads";
+
+fn staging_path(b64: &str) -> String {
+    format!("{}/{}", UPLOAD_DIR, b64)
+}
+
+/// Where the progress of an upload is kept so that it survives a crash.
+fn state_path(b64: &str) -> String {
+    format!("{}/{}.state", UPLOAD_DIR, b64)
+}
+
+/// The byte ranges of an upload that have been durably written, kept
+/// sorted and merged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UploadState {
+    received: Vec<(u64, u64)>,
+}
+
+impl UploadState {
+    fn insert(&mut self, start: u64, end: u64) {
+        if start == end {
+            return;
+        }
+        let (mut start, mut end) = (start, end);
+        self.received.retain(|&(s, e)| {
+            if e < start || end < s {
+                return true;
+            }
+            start = start.min(s);
+            end = end.max(e);
+            false
+        });
+        let at = self.received.partition_point(|&(s, _)| s < start);
+        self.received.insert(at, (start, end));
+    }
+
+    /// End of the prefix of the object that has been received in full.
+    fn durable_offset(&self) -> u64 {
+        match self.received.first() {
+            Some(&(0, end)) => end,
+            _ => 0,
+        }
+    }
+}
+
+/// An upload that was left unfinished, as reported by
+/// `ObjectStore::pending_uploads`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingUpload {
+    pub obj_id: u128,
+    /// Every byte before this offset has been durably written.
+    pub durable_offset: u64,
+    /// Every byte range that has been durably written.
+    pub received: Vec<Range<u64>>,
+}
+
+/// A staged upload of an object, started by `ObjectStore::begin_upload`.
+/// Parts can be written in any order and nothing is visible in the
+/// object until the upload is committed.
+pub struct Upload<'a, D: Disk> {
+    store: &'a ObjectStore<D>,
+    obj_id: u128,
+    state: UploadState,
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Starts a staged upload of `obj_id`, discarding anything staged
+    /// for it earlier.
+    pub fn begin_upload(&self, obj_id: u128) -> Result<Upload<'_, D>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        // pending uploads are listed by name, so the name must be known.
+        self.remember_obj_name(&fs, obj_id)?;
+        let b64 = self.encode_obj_id(obj_id);
+        let root = fs.root_dir();
+        root.create_dir("tmp")?;
+        root.create_dir(UPLOAD_DIR)?;
+        let mut file = root.create_file(&staging_path(&b64))?;
+        self.discard_contents(&fs, &mut file, obj_id)?;
+        let state = UploadState::default();
+        write_meta(&fs, &self.meta_key(), &state_path(&b64), &state)?;
+        Ok(Upload {
+            store: self,
+            obj_id,
+            state,
+        })
+    }
+
+    /// Picks an unfinished upload back up, for instance after a crash.
+    /// Use `Upload::durable_offset` to find where to continue from.
+    ///
+    /// # Errors
+    /// `NotFound` if there is no upload in progress for `obj_id`.
+    pub fn resume_upload(&self, obj_id: u128) -> Result<Upload<'_, D>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let b64 = self.encode_obj_id(obj_id);
+        fs.root_dir().open_file(&staging_path(&b64))?;
+        let state = read_meta(&fs, &self.meta_key(), &state_path(&b64))?.unwrap_or_default();
+        Ok(Upload {
+            store: self,
+            obj_id,
+            state,
+        })
+    }
+
+    /// Lists every upload that was started but neither committed nor
+    /// aborted.
+    pub fn pending_uploads(&self) -> Result<Vec<PendingUpload>, ObjectStoreError> {
+        let fs = self.fs().lock().unwrap();
+        let dir = match fs.root_dir().open_dir(UPLOAD_DIR) {
+            Ok(dir) => dir,
+            Err(fatfs::Error::NotFound) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut out = Vec::new();
+        for entry in dir.iter() {
+            let name = entry?.file_name();
+            // skips ., .. and state files
+            let Some(obj_id) = self.decode_obj_name(&fs, &name)? else {
+                continue;
+            };
+            let b64 = self.encode_obj_id(obj_id);
+            let state: UploadState =
+                read_meta(&fs, &self.meta_key(), &state_path(&b64))?.unwrap_or_default();
+            out.push(PendingUpload {
+                obj_id,
+                durable_offset: state.durable_offset(),
+                received: state.received.iter().map(|&(s, e)| s..e).collect(),
+            });
+        }
+        Ok(out)
+    }
+}
+
+impl<'a, D> Upload<'a, D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn obj_id(&self) -> u128 {
+        self.obj_id
+    }
+
+    /// Every byte before this offset has been durably written.
+    pub fn durable_offset(&self) -> u64 {
+        self.state.durable_offset()
+    }
+
+    /// Writes the part of the object starting at `off`. Gaps that no
+    /// part has been written to yet read back as zeroes.
+    pub fn write_part(&mut self, off: u64, data: &[u8]) -> Result<(), ObjectStoreError> {
+        let fs = self.store.fs().lock().unwrap();
+        let mut file = fs
+            .root_dir()
+            .open_file(&staging_path(&self.store.encode_obj_id(self.obj_id)))?;
+        let mut len = file.seek(SeekFrom::End(0))?;
+        let zeroes = [0u8; PAGE_SIZE];
+        while len < off {
+            let n = (off - len).min(PAGE_SIZE as u64) as usize;
+            self.store.write_file(&mut file, &zeroes[..n])?;
+            len += n as u64;
+        }
+        file.seek(SeekFrom::Start(off))?;
+        self.store.write_file(&mut file, data)?;
+        // the part only counts as received once the file is flushed.
+        drop(file);
+        self.state.insert(off, off + data.len() as u64);
+        Ok(write_meta(
+            &fs,
+            &self.store.meta_key(),
+            &state_path(&self.store.encode_obj_id(self.obj_id)),
+            &self.state,
+        )?)
+    }
+
+    /// Replaces the contents of the object with the staged data in a
+    /// single step, creating the object if it doesn't exist yet. The
+    /// previous contents are securely deleted by the next epoch.
+    pub fn commit(self) -> Result<(), ObjectStoreError> {
+        let (store, obj_id) = (self.store, self.obj_id);
+        let mut fs = store.fs().lock().unwrap();
+        let b64 = store.encode_obj_id(obj_id);
+        let path = store.object_path(obj_id);
+        get_dir_path(&fs, &b64)?;
+        let exists = match fs.root_dir().open_file(&path) {
+            Ok(_) => true,
+            Err(fatfs::Error::NotFound) => false,
+            Err(e) => return Err(e.into()),
+        };
+        if !exists {
+            store.forget_previous_incarnation(&fs, obj_id)?;
+        }
+        store.check_flags(&fs, obj_id, ObjectFlags::IMMUTABLE | ObjectFlags::SEALED)?;
+        store.check_log_edit(&fs, obj_id, 0)?;
+        if exists {
+            store.forget_dedup(&fs, obj_id)?;
+            store.forget_holes(&fs, obj_id)?;
+            store.destroy_object(&fs, obj_id, &path)?;
+        }
+        store.manifest_created(&fs, obj_id)?;
+        let root = fs.root_dir();
+        root.rename(&staging_path(&b64), &root, &path)?;
+        root.remove(&state_path(&b64))?;
+        store.refresh_page_macs(&mut fs, obj_id)?;
+        store.versions.lock().unwrap().bump(obj_id);
+        Ok(())
+    }
+
+    /// Throws away the staged data.
+    pub fn abort(self) -> Result<(), ObjectStoreError> {
+        let fs = self.store.fs().lock().unwrap();
+        let path = staging_path(&self.store.encode_obj_id(self.obj_id));
+        {
+            let mut file = fs.root_dir().open_file(&path)?;
+            self.store.discard_contents(&fs, &mut file, self.obj_id)?;
+        }
+        fs.root_dir().remove(&path)?;
+        fs.root_dir()
+            .remove(&state_path(&self.store.encode_obj_id(self.obj_id)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{fs::Disk, ObjectStore, ObjectStoreError};
+use fatfs::IoBase;
+use std::{collections::HashMap, fmt, io::Error};
+
+/// The versions of the objects written since the store was opened.
+///
+/// Versions aren't written to disk. Instead every open starts them from
+/// a fresh random generation in the upper half, so that a version seen
+/// before the store was reopened doesn't match the object afterwards.
+#[derive(Debug)]
+pub(crate) struct VersionTable {
+    generation: u64,
+    versions: HashMap<u128, u64>,
+}
+
+impl VersionTable {
+    pub fn fresh() -> Self {
+        Self {
+            generation: u64::from(rand::random::<u32>()) << 32,
+            versions: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, obj_id: u128) -> u64 {
+        self.versions
+            .get(&obj_id)
+            .copied()
+            .unwrap_or(self.generation)
+    }
+
+    /// Records a write to an object, returning its new version.
+    pub fn bump(&mut self, obj_id: u128) -> u64 {
+        let version = self.versions.entry(obj_id).or_insert(self.generation);
+        *version = version.wrapping_add(1);
+        *version
+    }
+}
+
+/// Returned by `write_all_if_version` when the object has been written
+/// since the caller last saw it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected object version {} but found {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+impl From<VersionConflict> for Error {
+    fn from(value: VersionConflict) -> Self {
+        Error::other(value)
+    }
+}
+
+/// Returns the conflict carried by `err`, if it was caused by a failed
+/// conditional write.
+pub fn version_conflict(err: &Error) -> Option<&VersionConflict> {
+    err.get_ref()?.downcast_ref::<VersionConflict>()
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Returns the version of an object, which is bumped by every write.
+    /// Versions are kept in memory, and start from a new random value
+    /// each time the store is opened.
+    pub fn version(&self, obj_id: u128) -> u64 {
+        self.versions.lock().unwrap().get(obj_id)
+    }
+
+    /// Like `write_all`, but only writes if the object is still at
+    /// version `expected`. Returns the new version of the object.
+    ///
+    /// # Errors
+    /// A `VersionConflict` if the object has been written since.
+    pub fn write_all_if_version(
+        &self,
+        obj_id: u128,
+        expected: u64,
+        buf: &[u8],
+        off: u64,
+    ) -> Result<u64, ObjectStoreError> {
+        Ok(self.write_patch(obj_id, &[(off, buf)], Some(expected))?)
+    }
+}
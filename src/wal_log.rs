@@ -0,0 +1,127 @@
+use crate::{
+    fs::{Disk, FatFs},
+    ObjectStore, ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{collections::BTreeMap, io::Error};
+
+pub(crate) const WAL_PATH: &str = "lethe/wal";
+
+/// What a WAL entry does to the key of a chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalOp {
+    /// The key was used and has to be rotated by the next epoch.
+    Derive,
+    /// The key was deleted and is forgotten by the next epoch.
+    Delete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalEntry {
+    /// Order in which chunks were first logged since the last epoch.
+    pub sequence: u64,
+    pub chunk_id: u64,
+    pub op: WalOp,
+}
+
+/// What the next epoch, or the next open after a crash, will replay.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WalReport {
+    /// The latest entry of every chunk logged by this process, in
+    /// sequence order.
+    pub entries: Vec<WalEntry>,
+    /// Size of the WAL file on disk.
+    pub wal_bytes: u64,
+    /// Bytes that were already in the WAL when the store was opened.
+    /// obliviate keeps its WAL format private, so entries from earlier
+    /// sessions are only reported by size.
+    pub inherited_bytes: u64,
+}
+
+/// A plaintext mirror of the chunk ids appended to the WAL, kept next
+/// to the WAL itself and cleared with it.
+#[derive(Debug, Default)]
+pub(crate) struct WalJournal {
+    next_sequence: u64,
+    chunks: BTreeMap<u64, WalEntry>,
+    pub inherited_bytes: u64,
+}
+
+impl WalJournal {
+    pub fn new(inherited_bytes: u64) -> Self {
+        Self {
+            inherited_bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, chunk_id: u64, op: WalOp) {
+        if self
+            .chunks
+            .get(&chunk_id)
+            .is_some_and(|entry| entry.op == op)
+        {
+            return;
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.chunks.insert(
+            chunk_id,
+            WalEntry {
+                sequence,
+                chunk_id,
+                op,
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.inherited_bytes = 0;
+    }
+
+    pub fn entries(&self) -> Vec<WalEntry> {
+        let mut entries: Vec<WalEntry> = self.chunks.values().copied().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Lists what is pending in the WAL, for debugging. Read only, and
+    /// empty for stores that aren't keyed by a KHF.
+    pub fn inspect_wal(&self) -> Result<WalReport, ObjectStoreError> {
+        let Some((entries, inherited_bytes)) =
+            self.wal_journal(|journal| (journal.entries(), journal.inherited_bytes))?
+        else {
+            return Ok(WalReport::default());
+        };
+        let wal_bytes = wal_len(&self.wal_volume().fs().lock().unwrap())?;
+        Ok(WalReport {
+            entries,
+            wal_bytes,
+            inherited_bytes,
+        })
+    }
+}
+
+/// Returns the size of the WAL file, 0 if there is none.
+pub(crate) fn wal_len<D>(fs: &FatFs<D>) -> Result<u64, Error>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+{
+    match fs.root_dir().open_file(WAL_PATH) {
+        Ok(mut file) => Ok(fatfs::Seek::seek(&mut file, fatfs::SeekFrom::End(0))?),
+        Err(fatfs::Error::NotFound) => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -4,6 +4,26 @@ use std::hash::Hash;
 #[derive(Clone, Debug)]
 pub struct WrappedExtent(Extent);
 
+impl WrappedExtent {
+    /// Byte offset of the extent on disk.
+    pub fn offset(&self) -> u64 {
+        self.0.offset
+    }
+
+    /// Length of the extent in bytes.
+    pub fn size(&self) -> u64 {
+        self.0.size as u64
+    }
+
+    /// Disk offsets of every page covered by the extent.
+    pub fn page_offsets(&self) -> impl Iterator<Item = u64> {
+        let offset = self.offset();
+        (0..self.size())
+            .step_by(crate::fs::PAGE_SIZE)
+            .map(move |page| offset + page)
+    }
+}
+
 impl PartialEq for WrappedExtent {
     fn eq(&self, other: &Self) -> bool {
         self.0.offset == other.0.offset && self.0.size == other.0.size
@@ -0,0 +1,81 @@
+use crate::{
+    fs::{Disk, PAGE_SIZE},
+    ObjectStore,
+};
+use fatfs::IoBase;
+use std::io::{Error, ErrorKind};
+
+/// A dirty page frame handed to the store by the pager.
+#[derive(Clone, Copy, Debug)]
+pub struct DirtyPage<'a> {
+    pub obj_id: u128,
+    /// Index of the page within the object.
+    pub page_no: u64,
+    /// The contents of the frame, exactly one page long.
+    pub frame: &'a [u8],
+}
+
+impl DirtyPage<'_> {
+    fn offset(&self) -> u64 {
+        self.page_no * PAGE_SIZE as u64
+    }
+}
+
+/// Told the outcome of every page in a writeback batch, so the pager
+/// can mark frames clean or keep them dirty.
+pub trait WritebackCompletion {
+    fn complete(&mut self, page: &DirtyPage<'_>, result: Result<(), Error>);
+}
+
+impl<F: FnMut(&DirtyPage<'_>, Result<(), Error>)> WritebackCompletion for F {
+    fn complete(&mut self, page: &DirtyPage<'_>, result: Result<(), Error>) {
+        self(page, result)
+    }
+}
+
+impl<D> ObjectStore<D>
+where
+    D: Disk,
+    std::io::Error: From<fatfs::Error<D::Error>>,
+    fatfs::Error<std::io::Error>: From<<D as IoBase>::Error>,
+    fatfs::Error<<D as IoBase>::Error>: From<std::io::Error>,
+    std::io::Error: From<D::Error>,
+    D::Error: std::error::Error + Send + Sync + 'static,
+{
+    /// Writes back a batch of dirty pages with one `write_batch`,
+    /// reporting each page to `completion` exactly once.
+    ///
+    /// If the batch fails, its pages are retried one at a time so that
+    /// a single bad object doesn't hold back the pages of every other
+    /// object in the batch.
+    pub fn writeback(&self, pages: &[DirtyPage<'_>], mut completion: impl WritebackCompletion) {
+        let (valid, invalid): (Vec<_>, Vec<_>) =
+            pages.iter().partition(|page| page.frame.len() == PAGE_SIZE);
+        for page in invalid {
+            completion.complete(
+                page,
+                Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "dirty frames must be exactly one page",
+                )),
+            );
+        }
+        let writes: Vec<_> = valid
+            .iter()
+            .map(|page| (page.obj_id, page.offset(), page.frame))
+            .collect();
+        if self.write_batch(&writes).is_ok() {
+            for page in valid {
+                completion.complete(page, Ok(()));
+            }
+            return;
+        }
+        for page in valid {
+            completion.complete(
+                page,
+                self.write_all(page.obj_id, page.frame, page.offset())
+                    .map_err(Error::from),
+            );
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use crate::{
+    fs::{Disk, PAGE_SIZE},
+    ObjectStoreError,
+};
+use fatfs::IoBase;
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    sync::Mutex,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Marks the summary pages that record which logical pages the data
+/// pages before them hold.
+const SUMMARY_MAGIC: [u8; 8] = *b"TOSZSUM1";
+/// Magic, sequence number and entry count, then the entries, then a
+/// checksum in the last 8 bytes.
+const SUMMARY_HEADER: usize = 20;
+const SUMMARY_ENTRIES: usize = (PAGE_SIZE - SUMMARY_HEADER - 8) / 8;
+/// Zones beyond what the logical disk could fill, so that garbage
+/// collection always has somewhere to copy live pages to.
+const SPARE_ZONES: u64 = 2;
+const UNMAPPED: u64 = u64::MAX;
+
+/// A zoned block device, such as a ZNS NVMe namespace or a host
+/// managed SMR disk. Zones can only be appended to, and are erased as
+/// a whole by resetting them.
+pub trait ZonedDevice: IoBase {
+    /// Bytes per zone, a multiple of the page size.
+    fn zone_size(&self) -> u64;
+    fn zone_count(&self) -> u64;
+    /// Returns how many bytes were appended to `zone` since it was last
+    /// reset.
+    fn write_pointer(&self, zone: u64) -> Result<u64, Self::Error>;
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error>;
+    /// Writes `buf`, which is whole pages, at the write pointer of
+    /// `zone`.
+    fn append(&self, zone: u64, buf: &[u8]) -> Result<(), Self::Error>;
+    fn reset(&self, zone: u64) -> Result<(), Self::Error>;
+    fn flush(&self) -> Result<(), Self::Error>;
+}
+
+/// Where every logical page lives, and how full each zone is.
+struct Zones {
+    /// The physical page holding each logical page.
+    map: Vec<u64>,
+    /// The logical page each physical page holds, while it is current.
+    owner: Vec<u64>,
+    /// Pages appended to each zone since it was reset.
+    written: Vec<u64>,
+    /// Current pages in each zone.
+    live: Vec<u64>,
+    /// Data pages in each zone that have since been overwritten.
+    stale: Vec<u64>,
+    free: VecDeque<u64>,
+    /// The zone being appended to.
+    active: Option<u64>,
+    /// The logical pages appended to the active zone since its last
+    /// summary.
+    pending: Vec<u64>,
+    seq: u64,
+}
+
+impl Zones {
+    fn remap(&mut self, zone_pages: u64, logical: u64, physical: u64) {
+        let old = std::mem::replace(&mut self.map[logical as usize], physical);
+        if old != UNMAPPED {
+            self.owner[old as usize] = UNMAPPED;
+            self.live[(old / zone_pages) as usize] -= 1;
+            self.stale[(old / zone_pages) as usize] += 1;
+        }
+        self.owner[physical as usize] = logical;
+        self.live[(physical / zone_pages) as usize] += 1;
+    }
+}
+
+/// Runs the store on a zoned device. FAT updates its tables in place,
+/// which a zoned device can't do, so every write is appended to the
+/// open zone and a page map tracks where each logical page went. Each
+/// zone records the pages it holds in summary pages, which are written
+/// as the zone fills and on every flush, and are read back when the
+/// disk is opened.
+///
+/// Zones are reset once none of their pages are current anymore. On
+/// `reclaim`, which the store calls after every epoch, every zone
+/// holding overwritten data is emptied and reset, so the ciphertext
+/// the epoch replaced is erased along with the keys it deleted.
+pub struct ZonedDisk<Z> {
+    device: Z,
+    zone_pages: u64,
+    logical_pages: u64,
+    zones: Mutex<Zones>,
+}
+
+impl<Z> ZonedDisk<Z>
+where
+    Z: ZonedDevice,
+    std::io::Error: From<Z::Error>,
+{
+    /// Opens a zoned device, rebuilding the page map from the summary
+    /// pages of every zone, which reads every page written to the
+    /// device. Pages written after the last flush are lost, as on a
+    /// disk with a volatile cache.
+    ///
+    /// # Errors
+    /// `InvalidInput` if zones aren't a multiple of the page size or
+    /// the device has too few of them.
+    pub fn open(device: Z) -> Result<Self, ObjectStoreError> {
+        let page = PAGE_SIZE as u64;
+        let zone_count = device.zone_count();
+        if !device.zone_size().is_multiple_of(page) || device.zone_size() < 8 * page {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "zones must be at least 8 pages and a whole number of pages",
+            )
+            .into());
+        }
+        if zone_count <= SPARE_ZONES {
+            return Err(Error::new(ErrorKind::InvalidInput, "too few zones").into());
+        }
+        let zone_pages = device.zone_size() / page;
+        // leaves room for summaries and pages that flushes strand.
+        let logical_pages = (zone_count - SPARE_ZONES) * (zone_pages - zone_pages / 8);
+        let pages = (zone_count * zone_pages) as usize;
+        let disk = Self {
+            device,
+            zone_pages,
+            logical_pages,
+            zones: Mutex::new(Zones {
+                map: vec![UNMAPPED; logical_pages as usize],
+                owner: vec![UNMAPPED; pages],
+                written: vec![0; zone_count as usize],
+                live: vec![0; zone_count as usize],
+                stale: vec![0; zone_count as usize],
+                free: VecDeque::new(),
+                active: None,
+                pending: Vec::new(),
+                seq: 0,
+            }),
+        };
+        let mut zones = disk.zones.lock().unwrap();
+        let mut records = Vec::new();
+        let mut buf = [0u8; PAGE_SIZE];
+        for zone in 0..zone_count {
+            let written = disk.device.write_pointer(zone).map_err(Error::from)? / page;
+            zones.written[zone as usize] = written;
+            if written == 0 {
+                zones.free.push_back(zone);
+                continue;
+            }
+            let base = zone * zone_pages;
+            let mut start = 0;
+            for at in 0..written {
+                disk.read_device((base + at) * page, &mut buf)?;
+                let Some((seq, ids)) = parse_summary(&buf) else {
+                    continue;
+                };
+                if ids.len() as u64 == at - start {
+                    for (i, logical) in ids.into_iter().enumerate() {
+                        records.push((seq, i, logical, base + start + i as u64));
+                    }
+                    zones.seq = zones.seq.max(seq + 1);
+                }
+                start = at + 1;
+            }
+            // pages no summary covers were never flushed.
+            zones.stale[zone as usize] += written - start;
+        }
+        records.sort_unstable();
+        for (_, _, logical, physical) in records {
+            if logical < logical_pages {
+                zones.remap(zone_pages, logical, physical);
+            }
+        }
+        drop(zones);
+        Ok(disk)
+    }
+
+    pub fn inner(&self) -> &Z {
+        &self.device
+    }
+
+    pub fn into_inner(self) -> Z {
+        self.device
+    }
+
+    fn read_device(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            match self.device.read_at(offset, buf)? {
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "read past the write pointer of a zone",
+                    ))
+                }
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads logical page `logical`, which is zeros if never written.
+    fn read_page(&self, zones: &Zones, logical: u64, buf: &mut [u8]) -> Result<(), Error> {
+        match zones.map[logical as usize] {
+            UNMAPPED => {
+                buf.fill(0);
+                Ok(())
+            }
+            physical => self.read_device(physical * PAGE_SIZE as u64, buf),
+        }
+    }
+
+    /// Returns the zone to append to, opening a new one if needed.
+    /// Outside of garbage collection one free zone is always kept back
+    /// for it.
+    fn active_zone(&self, zones: &mut Zones, collecting: bool) -> Result<u64, Error> {
+        if let Some(zone) = zones.active {
+            return Ok(zone);
+        }
+        if !collecting {
+            for _ in 0..zones.written.len() {
+                if zones.free.len() > 1 {
+                    break;
+                }
+                if !self.collect(zones)? {
+                    break;
+                }
+            }
+        }
+        let zone = zones
+            .free
+            .pop_front()
+            .ok_or_else(|| Error::new(ErrorKind::StorageFull, "zoned disk has no free zones"))?;
+        zones.active = Some(zone);
+        Ok(zone)
+    }
+
+    /// Appends `data`, one page per entry of `ids`, as the new contents
+    /// of the logical pages `ids`.
+    fn append_pages(
+        &self,
+        zones: &mut Zones,
+        ids: &[u64],
+        data: &[u8],
+        collecting: bool,
+    ) -> Result<(), Error> {
+        let mut done = 0;
+        while done < ids.len() {
+            let zone = self.active_zone(zones, collecting)?;
+            let written = zones.written[zone as usize];
+            // the last page of a zone is kept for the summary closing it.
+            let room = ((self.zone_pages - 1 - written) as usize)
+                .min(SUMMARY_ENTRIES - zones.pending.len())
+                .min(ids.len() - done);
+            self.device
+                .append(zone, &data[done * PAGE_SIZE..(done + room) * PAGE_SIZE])?;
+            for (i, &logical) in ids[done..done + room].iter().enumerate() {
+                let physical = zone * self.zone_pages + written + i as u64;
+                zones.remap(self.zone_pages, logical, physical);
+                zones.pending.push(logical);
+            }
+            zones.written[zone as usize] += room as u64;
+            done += room;
+            if zones.pending.len() == SUMMARY_ENTRIES
+                || zones.written[zone as usize] == self.zone_pages - 1
+            {
+                self.write_summary(zones)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the pages appended to the active zone since its last
+    /// summary, closing the zone if that filled it.
+    fn write_summary(&self, zones: &mut Zones) -> Result<(), Error> {
+        let Some(zone) = zones.active else {
+            return Ok(());
+        };
+        if !zones.pending.is_empty() {
+            let mut page = [0u8; PAGE_SIZE];
+            page[..8].copy_from_slice(&SUMMARY_MAGIC);
+            page[8..16].copy_from_slice(&zones.seq.to_le_bytes());
+            page[16..20].copy_from_slice(&(zones.pending.len() as u32).to_le_bytes());
+            for (i, logical) in zones.pending.iter().enumerate() {
+                let at = SUMMARY_HEADER + i * 8;
+                page[at..at + 8].copy_from_slice(&logical.to_le_bytes());
+            }
+            let sum = xxh3_64(&page[..PAGE_SIZE - 8]);
+            page[PAGE_SIZE - 8..].copy_from_slice(&sum.to_le_bytes());
+            self.device.append(zone, &page)?;
+            zones.written[zone as usize] += 1;
+            zones.seq += 1;
+            zones.pending.clear();
+        }
+        if zones.written[zone as usize] >= self.zone_pages - 1 {
+            zones.active = None;
+        }
+        Ok(())
+    }
+
+    /// Frees the zone with the fewest current pages. Returns false if
+    /// every page is current, so no zone can be freed.
+    fn collect(&self, zones: &mut Zones) -> Result<bool, Error> {
+        let victim = (0..zones.written.len() as u64)
+            .filter(|&zone| Some(zone) != zones.active)
+            .filter(|&zone| zones.written[zone as usize] > zones.live[zone as usize])
+            .min_by_key(|&zone| zones.live[zone as usize]);
+        let Some(victim) = victim else {
+            return Ok(false);
+        };
+        self.evacuate(zones, victim)?;
+        Ok(true)
+    }
+
+    /// Copies the current pages of `zone` to the active zone and resets
+    /// it.
+    fn evacuate(&self, zones: &mut Zones, zone: u64) -> Result<(), Error> {
+        let base = zone * self.zone_pages;
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        for physical in base..base + zones.written[zone as usize] {
+            let logical = zones.owner[physical as usize];
+            if logical == UNMAPPED {
+                continue;
+            }
+            let at = data.len();
+            data.resize(at + PAGE_SIZE, 0);
+            self.read_device(physical * PAGE_SIZE as u64, &mut data[at..])?;
+            ids.push(logical);
+            if ids.len() == SUMMARY_ENTRIES {
+                self.append_pages(zones, &ids, &data, true)?;
+                ids.clear();
+                data.clear();
+            }
+        }
+        self.append_pages(zones, &ids, &data, true)?;
+        // the copies have to be durable before the originals are erased.
+        self.write_summary(zones)?;
+        self.device.flush()?;
+        self.device.reset(zone)?;
+        zones.written[zone as usize] = 0;
+        zones.stale[zone as usize] = 0;
+        zones.free.push_back(zone);
+        Ok(())
+    }
+}
+
+/// Returns the sequence number and entries of a summary page.
+fn parse_summary(page: &[u8; PAGE_SIZE]) -> Option<(u64, Vec<u64>)> {
+    if page[..8] != SUMMARY_MAGIC {
+        return None;
+    }
+    let sum = u64::from_le_bytes(page[PAGE_SIZE - 8..].try_into().unwrap());
+    if sum != xxh3_64(&page[..PAGE_SIZE - 8]) {
+        return None;
+    }
+    let seq = u64::from_le_bytes(page[8..16].try_into().unwrap());
+    let count = u32::from_le_bytes(page[16..20].try_into().unwrap()) as usize;
+    if count > SUMMARY_ENTRIES {
+        return None;
+    }
+    let ids = page[SUMMARY_HEADER..SUMMARY_HEADER + count * 8]
+        .chunks_exact(8)
+        .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+        .collect();
+    Some((seq, ids))
+}
+
+impl<Z: ZonedDevice> IoBase for ZonedDisk<Z> {
+    type Error = Error;
+}
+
+impl<Z> Disk for ZonedDisk<Z>
+where
+    Z: ZonedDevice,
+    std::io::Error: From<Z::Error>,
+{
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let page = PAGE_SIZE as u64;
+        let end = (offset + buf.len() as u64).min(self.logical_pages * page);
+        if offset >= end {
+            return Ok(0);
+        }
+        let zones = self.zones.lock().unwrap();
+        let mut data = [0u8; PAGE_SIZE];
+        let mut pos = offset;
+        while pos < end {
+            let within = (pos % page) as usize;
+            let n = (PAGE_SIZE - within).min((end - pos) as usize);
+            self.read_page(&zones, pos / page, &mut data)?;
+            let at = (pos - offset) as usize;
+            buf[at..at + n].copy_from_slice(&data[within..within + n]);
+            pos += n as u64;
+        }
+        Ok((end - offset) as usize)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Self::Error> {
+        let page = PAGE_SIZE as u64;
+        let end = (offset + buf.len() as u64).min(self.logical_pages * page);
+        if offset >= end {
+            return Ok(0);
+        }
+        let mut zones = self.zones.lock().unwrap();
+        let mut ids = Vec::new();
+        let mut data = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let within = (pos % page) as usize;
+            let n = (PAGE_SIZE - within).min((end - pos) as usize);
+            let at = data.len();
+            data.resize(at + PAGE_SIZE, 0);
+            if n < PAGE_SIZE {
+                // a partial write keeps the rest of the page.
+                self.read_page(&zones, pos / page, &mut data[at..])?;
+            }
+            let from = (pos - offset) as usize;
+            data[at + within..at + within + n].copy_from_slice(&buf[from..from + n]);
+            ids.push(pos / page);
+            pos += n as u64;
+        }
+        self.append_pages(&mut zones, &ids, &data, false)?;
+        Ok((end - offset) as usize)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.write_summary(&mut self.zones.lock().unwrap())?;
+        Ok(self.device.flush()?)
+    }
+
+    fn size(&self) -> Result<u64, Self::Error> {
+        Ok(self.logical_pages * PAGE_SIZE as u64)
+    }
+
+    fn reclaim(&self) -> Result<(), Self::Error> {
+        let mut zones = self.zones.lock().unwrap();
+        self.write_summary(&mut zones)?;
+        if zones
+            .active
+            .is_some_and(|zone| zones.stale[zone as usize] > 0)
+        {
+            zones.active = None;
+        }
+        let victims: Vec<u64> = (0..zones.written.len() as u64)
+            .filter(|&zone| Some(zone) != zones.active && zones.stale[zone as usize] > 0)
+            .collect();
+        for zone in victims {
+            self.evacuate(&mut zones, zone)?;
+        }
+        Ok(self.device.flush()?)
+    }
+}